@@ -1,10 +1,31 @@
 pub mod bus;
+pub mod cdl;
+pub mod cheats;
+pub mod compat;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod frontend;
+pub mod gdbstub;
+pub mod headless;
+pub mod json;
+pub mod log;
+pub mod memory;
+pub mod nes;
+pub mod nestest;
+pub mod opcodes;
 pub mod ppu;
+pub mod processor_tests;
+pub mod profiler;
+pub mod ramsearch;
 pub mod regs;
 pub mod render;
 pub mod rom;
+pub mod symbols;
+pub mod testrom;
 // pub mod snek;
 pub mod controller;
 pub mod tiles;
 pub mod trace;
+pub mod tracefile;
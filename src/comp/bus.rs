@@ -1,19 +1,45 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::comp::cdl::CodeDataLog;
+use crate::comp::cheats::Cheats;
 use crate::comp::controller::Controller;
-use crate::comp::cpu::Mem;
+use crate::comp::cpu::{Mem, Tick};
+use crate::comp::error::{Action, EmuError, ErrorClass, ErrorPolicy};
+use crate::comp::log::{Category, Level, Logger};
+use crate::comp::opcodes;
 use crate::comp::ppu::{NesPPU, PPU};
-use crate::comp::rom::Rom;
+use crate::comp::rom::{Mirroring, Rom};
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
 
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
+    // cartridge RAM at $6000; most headers don't say whether the board has it
+    prg_ram: [u8; 0x2000],
     prg_rom: Vec<u8>,
     ppu: NesPPU,
     cycles: usize,
+    frames: usize,
     gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Controller) + 'call>,
     cont1: Controller,
+    cdl: Option<CodeDataLog>,
+    cheats: Cheats,
+    policy: ErrorPolicy,
+    // the first error the policy says to halt on, until the CPU takes it
+    fault: Option<EmuError>,
+    log: Rc<Logger>,
+}
+
+fn prg_offset(prg_rom: &[u8], addr: u16) -> Option<usize> {
+    if addr < 0x8000 || prg_rom.is_empty() {
+        return None;
+    }
+    Some((addr - 0x8000) as usize % prg_rom.len())
 }
 
 impl<'a> Bus<'a> {
@@ -21,37 +47,167 @@ impl<'a> Bus<'a> {
     where
         F: FnMut(&NesPPU, &mut Controller) + 'call,
     {
-        let ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+        let log = Rc::new(Logger::default());
+        let mut ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+        ppu.set_logger(log.clone());
         Bus {
             cpu_vram: [0; 2048],
+            prg_ram: [0; 0x2000],
             prg_rom: rom.prg_rom,
             ppu,
             cycles: 0,
+            frames: 0,
             gameloop_callback: Box::from(gameloop_callback),
             cont1: Controller::new(),
+            cdl: None,
+            cheats: Cheats::new(),
+            policy: ErrorPolicy::default(),
+            fault: None,
+            log,
+        }
+    }
+
+    /// Chooses what happens when the program does something the emulator
+    /// doesn't model; see `EmuError`.
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn error_policy(&self) -> &ErrorPolicy {
+        &self.policy
+    }
+
+    /// Diagnostics from the bus and the PPU; its levels can be changed at any time.
+    pub fn logger(&self) -> &Rc<Logger> {
+        &self.log
+    }
+
+    fn log(&self, category: Category, level: Level, what: &'static str, details: fmt::Arguments) {
+        self.log
+            .log(category, level, self.ppu.scanline(), what, details);
+    }
+
+    fn ppu_faults(&mut self) {
+        if let Some(error) = self.ppu.take_fault() {
+            self.fault(error);
+        }
+    }
+
+    /// Starts recording which PRG/CHR bytes are code, data and graphics.
+    pub fn set_cdl(&mut self, cdl: CodeDataLog) {
+        self.cdl = Some(cdl);
+    }
+
+    pub fn cdl(&self) -> Option<&CodeDataLog> {
+        self.cdl.as_ref()
+    }
+
+    /// Game Genie patches for PRG ROM reads and RAM pokes applied every frame.
+    pub fn set_cheats(&mut self, cheats: Cheats) {
+        self.cheats = cheats;
+    }
+
+    pub fn cheats(&self) -> &Cheats {
+        &self.cheats
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut Cheats {
+        &mut self.cheats
+    }
+
+    pub fn ppu(&self) -> &NesPPU {
+        &self.ppu
+    }
+
+    /// For mappers that switch the nametable layout at runtime.
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.ppu.set_mirroring(mirroring);
+    }
+
+    /// Memory a RAM search scans, with the CPU address each region starts at.
+    pub fn ram_regions(&self) -> Vec<(u16, &[u8])> {
+        vec![(RAM, &self.cpu_vram[..]), (PRG_RAM, &self.prg_ram[..])]
+    }
+
+    /// CPU cycles elapsed since power-on
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    /// Vblanks started since power-on, whether or not they raised an NMI.
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Player 1's pad, for setting input from outside the gameloop callback.
+    pub fn controller_mut(&mut self) -> &mut Controller {
+        &mut self.cont1
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        let mut offset = addr - 0x8000;
+        if self.prg_rom.len() == 0x4000 && offset >= 0x4000 {
+            offset %= 0x4000;
         }
+        self.cheats.patch(addr, self.prg_rom[offset as usize])
     }
-    pub fn tick(&mut self, cycles: u8) {
+}
+impl Tick for Bus<'_> {
+    fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
 
         let nmi_before = self.ppu.nmi_interrupt.is_some();
+        let scanline_before = self.ppu.scanline();
         self.ppu.tick(cycles * 3);
         let nmi_after = self.ppu.nmi_interrupt.is_some();
+        if scanline_before != 241 && self.ppu.scanline() == 241 {
+            self.frames += 1;
+            // sampled at vblank whether or not the game takes the NMI
+            if let Some(cdl) = &mut self.cdl {
+                cdl.frame(&self.ppu);
+            }
+            for (addr, value) in self.cheats.pokes() {
+                self.mem_write(addr, value);
+            }
+        }
 
         if !nmi_before && nmi_after {
             (self.gameloop_callback)(&self.ppu, &mut self.cont1);
         }
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            addr = addr % 0x4000;
+    fn poll_nmi_status(&mut self) -> Option<u8> {
+        let nmi = self.ppu.poll_nmi_interrupt();
+        if nmi.is_some() {
+            self.log(Category::Cpu, Level::Trace, "NMI", format_args!("taken"));
+        }
+        nmi
+    }
+
+    fn fault(&mut self, error: EmuError) {
+        match self.policy.action(error.class()) {
+            Action::Ignore => {}
+            Action::Log => {
+                let category = match error.class() {
+                    ErrorClass::Opcode => Category::Cpu,
+                    ErrorClass::RomWrite => Category::Mapper,
+                    ErrorClass::PpuRegister | ErrorClass::Mirroring => Category::Ppu,
+                };
+                self.log(
+                    category,
+                    Level::Warn,
+                    error.class().name(),
+                    format_args!("{}", error),
+                );
+            }
+            Action::Halt => {
+                self.fault.get_or_insert(error);
+            }
         }
-        self.prg_rom[addr as usize]
     }
-    pub fn poll_nmi_status(&mut self) -> Option<u8> {
-        self.ppu.poll_nmi_interrupt()
+
+    fn take_fault(&mut self) -> Option<EmuError> {
+        self.fault.take()
     }
 }
 impl Mem for Bus<'_> {
@@ -64,26 +220,101 @@ impl Mem for Bus<'_> {
             PPU_REGISTERS | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => 0,
             0x2002 => self.ppu.read_status(),
             0x2004 => self.ppu.read_oam_data(),
-            0x2007 => self.ppu.read_data(),
+            0x2007 => {
+                if let Some(cdl) = &mut self.cdl {
+                    let addr = self.ppu.addr.get();
+                    if addr < 0x2000 {
+                        cdl.chr_read(addr);
+                    }
+                }
+                let data = self.ppu.read_data();
+                self.ppu_faults();
+                data
+            }
 
             0x4000..=0x4015 => 0,
 
-            0x4016 => self.cont1.read(),
+            0x4016 => {
+                let data = self.cont1.read();
+                self.log(
+                    Category::Input,
+                    Level::Trace,
+                    "pad read",
+                    format_args!("${:02X}", data),
+                );
+                data
+            }
 
-            0x4017 => 0,
+            0x4017 => {
+                self.log(
+                    Category::Input,
+                    Level::Debug,
+                    "no second pad",
+                    format_args!("$4017"),
+                );
+                0
+            }
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.mem_read(mirror_down_addr)
             }
-            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize],
+            0x8000..=0xFFFF => {
+                if let (Some(cdl), Some(offset)) = (&mut self.cdl, prg_offset(&self.prg_rom, addr))
+                {
+                    cdl.read(addr, offset);
+                }
+                self.read_prg_rom(addr)
+            }
 
             _ => {
-                println!("Ignoring mem access at {}", addr);
+                self.log(
+                    Category::Bus,
+                    Level::Warn,
+                    "ignored read",
+                    format_args!("${:04X}", addr),
+                );
                 0
             }
         }
     }
 
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b00000111_11111111) as usize],
+            0x2002 => self.ppu.peek_status(),
+            0x2004 => self.ppu.read_oam_data(),
+            0x2007 => self.ppu.peek_data(),
+            0x4016 => self.cont1.peek(),
+            0x2008..=PPU_REGISTERS_MIRRORS_END => self.peek(addr & 0b00100000_00000111),
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize],
+            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            _ => 0,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        prg_offset(&self.prg_rom, addr)
+    }
+
+    fn dummy_read(&mut self, addr: u16) {
+        // timing artefacts aren't data accesses as far as the CDL is concerned
+        let cdl = self.cdl.take();
+        self.mem_read(addr);
+        self.cdl = cdl;
+    }
+
+    fn begin_instruction(&mut self, pc: u16) {
+        self.log.set_pc(pc);
+        if self.cdl.is_some() {
+            let op = opcodes::lookup(self.peek(pc));
+            let prg_rom = &self.prg_rom;
+            if let Some(cdl) = &mut self.cdl {
+                cdl.instruction(pc, op, |addr| prg_offset(prg_rom, addr));
+            }
+        }
+    }
+
     fn mem_write(&mut self, addr: u16, data: u8) {
         match addr {
             RAM..=RAM_MIRRORS_END => {
@@ -92,25 +323,33 @@ impl Mem for Bus<'_> {
             }
             0x2000 => self.ppu.write_to_ctrl(data),
             0x2001 => self.ppu.write_to_mask(data),
-            0x2002 => panic!("attempt to write to PPU status register"),
+            0x2002 => self.fault(EmuError::PpuRegisterWrite { addr, data }),
             0x2003 => self.ppu.write_to_oam_addr(data),
             0x2004 => self.ppu.write_to_oam_data(data),
             0x2005 => self.ppu.write_to_scroll(data),
             0x2006 => self.ppu.write_to_ppu_addr(data),
-            0x2007 => self.ppu.write_to_data(data),
-
-            0x4000..=0x4013 | 0x4015 => {
-                //ignore APU
+            0x2007 => {
+                self.ppu.write_to_data(data);
+                self.ppu_faults();
             }
 
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.log(
+                Category::Apu,
+                Level::Debug,
+                "no APU",
+                format_args!("${:04X} = ${:02X}", addr, data),
+            ),
+
             0x4016 => {
+                self.log(
+                    Category::Input,
+                    Level::Trace,
+                    "strobe",
+                    format_args!("${:02X}", data),
+                );
                 self.cont1.write(data);
             }
 
-            0x4017 => {
-                // ignore 2nd controller
-            }
-
             0x4014 => {
                 let mut buffer: [u8; 256] = [0; 256];
                 let hi: u16 = (data as u16) << 8;
@@ -124,9 +363,15 @@ impl Mem for Bus<'_> {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.mem_write(mirror_down_addr, data);
             }
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize] = data,
 
-            0x8000..=0xFFFF => panic!("Attempt to write to Cartridge ROM space: {:x}", addr),
-            _ => println!("Ignoring mem write-access at {}", addr),
+            0x8000..=0xFFFF => self.fault(EmuError::RomWrite { addr, data }),
+            _ => self.log(
+                Category::Bus,
+                Level::Warn,
+                "ignored write",
+                format_args!("${:04X} = ${:02X}", addr, data),
+            ),
         }
     }
 }
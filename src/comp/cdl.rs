@@ -0,0 +1,270 @@
+use std::ops::Range;
+use std::path::Path;
+
+use crate::comp::opcodes::{OpCode, Operand};
+use crate::comp::ppu::NesPPU;
+use crate::comp::render::visible_nametables;
+
+// Code/Data Logger in the FCEUX .cdl format: one flag byte per PRG ROM byte,
+// followed by one per CHR ROM byte. Flags only ever get added, so loading an
+// existing log and playing more of the game refines it.
+//
+// PRG: xPdcAADC
+//   C  executed (opcode or operand)
+//   D  read as data
+//   AA which 8KB CPU window ($8000/$A000/$C000/$E000) it was last accessed through
+//   c  reached through JMP (indirect)
+//   d  read through (zp,X) / (zp),Y
+//   P  played as DPCM sample (no APU yet, so never set)
+// CHR: xxxxxxRD
+//   D  fetched by the PPU for rendering
+//   R  read through PPUDATA
+
+pub const PRG_CODE: u8 = 0x01;
+pub const PRG_DATA: u8 = 0x02;
+const PRG_BANK: u8 = 0x0c;
+pub const PRG_INDIRECT_CODE: u8 = 0x10;
+pub const PRG_INDIRECT_DATA: u8 = 0x20;
+
+pub const CHR_DRAWN: u8 = 0x01;
+pub const CHR_READ: u8 = 0x02;
+
+const JMP_INDIRECT: u8 = 0x6c;
+const VISIBLE_SPRITE_Y: u8 = 0xef;
+
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    /// bytes of the instruction being executed, whose reads are code not data
+    fetch: Range<u16>,
+    indirect_jump: bool,
+    indirect_data: bool,
+}
+
+impl CodeDataLog {
+    pub fn new(prg_len: usize, chr_len: usize) -> Self {
+        CodeDataLog {
+            prg: vec![0; prg_len],
+            chr: vec![0; chr_len],
+            fetch: 0..0,
+            indirect_jump: false,
+            indirect_data: false,
+        }
+    }
+
+    /// Continues an existing log, which must be for a ROM of the same size.
+    pub fn from_bytes(bytes: &[u8], prg_len: usize, chr_len: usize) -> Result<Self, String> {
+        if bytes.len() != prg_len + chr_len {
+            return Err(format!(
+                "CDL is {} bytes, expected {} for this ROM",
+                bytes.len(),
+                prg_len + chr_len
+            ));
+        }
+        let mut log = CodeDataLog::new(prg_len, chr_len);
+        log.prg.copy_from_slice(&bytes[..prg_len]);
+        log.chr.copy_from_slice(&bytes[prg_len..]);
+        Ok(log)
+    }
+
+    /// Loads `path` if it exists, otherwise starts an empty log.
+    pub fn load_or_new(path: &Path, prg_len: usize, chr_len: usize) -> Result<Self, String> {
+        match std::fs::read(path) {
+            Ok(bytes) => CodeDataLog::from_bytes(&bytes, prg_len, chr_len)
+                .map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(CodeDataLog::new(prg_len, chr_len))
+            }
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.prg[..], &self.chr[..]].concat()
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn prg(&self) -> &[u8] {
+        &self.prg
+    }
+
+    pub fn chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    fn mark_prg(&mut self, offset: usize, addr: u16, flags: u8) {
+        if let Some(byte) = self.prg.get_mut(offset) {
+            let bank = ((addr >> 13) & 0b11) as u8;
+            *byte = (*byte & !PRG_BANK) | flags | bank << 2;
+        }
+    }
+
+    fn mark_chr(&mut self, range: Range<usize>, flags: u8) {
+        let end = range.end.min(self.chr.len());
+        for byte in self.chr.get_mut(range.start..end).unwrap_or_default() {
+            *byte |= flags;
+        }
+    }
+
+    /// Logs the opcode and operand bytes of the instruction about to run at `pc`.
+    /// `offset` maps a CPU address to PRG ROM, as `Mem::prg_rom_offset` does.
+    pub fn instruction(&mut self, pc: u16, op: &OpCode, offset: impl Fn(u16) -> Option<usize>) {
+        let size = op.size() as u16;
+        for i in 0..size {
+            let addr = pc.wrapping_add(i);
+            let flags = match (i, self.indirect_jump) {
+                (0, true) => PRG_CODE | PRG_INDIRECT_CODE,
+                _ => PRG_CODE,
+            };
+            if let Some(offset) = offset(addr) {
+                self.mark_prg(offset, addr, flags);
+            }
+        }
+        self.fetch = pc..pc.saturating_add(size);
+        self.indirect_jump = op.code == JMP_INDIRECT;
+        self.indirect_data = matches!(op.mode, Operand::Indirect_X | Operand::Indirect_Y);
+    }
+
+    /// Logs a CPU read of PRG ROM. Opcode and operand fetches are already code.
+    pub fn read(&mut self, addr: u16, offset: usize) {
+        if self.fetch.contains(&addr) {
+            return;
+        }
+        let flags = if self.indirect_data {
+            PRG_DATA | PRG_INDIRECT_DATA
+        } else {
+            PRG_DATA
+        };
+        self.mark_prg(offset, addr, flags);
+    }
+
+    /// Logs a PPUDATA read of pattern table memory.
+    pub fn chr_read(&mut self, addr: u16) {
+        self.mark_chr(addr as usize..addr as usize + 1, CHR_READ);
+    }
+
+    /// Logs the pattern tiles the renderer fetches for the current frame.
+    pub fn frame(&mut self, ppu: &NesPPU) {
+        // nothing is drawn with a mirroring the renderer doesn't support
+        if let (true, Ok((main, secondary))) = (ppu.mask.show_background(), visible_nametables(ppu))
+        {
+            let bank = ppu.ctrl.bknd_pattern_addr() as usize;
+            let scrolled = ppu.scroll.scroll_x > 0 || ppu.scroll.scroll_y > 0;
+            let nametables = if scrolled {
+                vec![main, secondary]
+            } else {
+                vec![main]
+            };
+            for nametable in nametables {
+                for &tile in &nametable[..0x3c0] {
+                    let start = bank + tile as usize * 16;
+                    self.mark_chr(start..start + 16, CHR_DRAWN);
+                }
+            }
+        }
+        if ppu.mask.show_sprites() {
+            let bank = ppu.ctrl.sprt_pattern_addr() as usize;
+            for sprite in ppu.oam_data.chunks(4) {
+                if sprite[0] < VISIBLE_SPRITE_Y {
+                    let start = bank + sprite[1] as usize * 16;
+                    self.mark_chr(start..start + 16, CHR_DRAWN);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::bus::Bus;
+    use crate::comp::cpu::{CPU, Mem, Tick};
+    use crate::comp::rom::Mirroring;
+    use crate::comp::rom::test::{test_prg, test_rom};
+
+    fn logged_cpu(program: &[(u16, &[u8])]) -> CPU<Bus<'static>> {
+        let mut prg = test_prg(&[]);
+        for (addr, bytes) in program {
+            let at = (*addr - 0x8000) as usize;
+            prg[at..at + bytes.len()].copy_from_slice(bytes);
+        }
+        let mut bus = Bus::new(test_rom(prg), |_, _| {});
+        bus.set_cdl(CodeDataLog::new(0x8000, 0x2000));
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_code_data_and_indirect_flags() {
+        let mut cpu = logged_cpu(&[
+            // LDA $8010,X; LDA ($00),Y; JMP ($8030)
+            (0x8000, &[0xbd, 0x10, 0x80, 0xb1, 0x00, 0x6c, 0x30, 0x80]),
+            (0x8030, &[0x40, 0x80]),
+            // CLC; BCC +0; NOP
+            (0x8040, &[0x18, 0x90, 0x00, 0xea]),
+        ]);
+        cpu.mem_write(0x00, 0x20);
+        cpu.mem_write(0x01, 0x80);
+        for _ in 0..6 {
+            cpu.step();
+        }
+        let prg = cpu.bus.cdl().unwrap().prg();
+        assert_eq!(&prg[0..8], &[PRG_CODE; 8]);
+        assert_eq!(prg[0x10], PRG_DATA);
+        assert_eq!(prg[0x20], PRG_DATA | PRG_INDIRECT_DATA);
+        assert_eq!(&prg[0x30..0x32], &[PRG_DATA; 2]);
+        assert_eq!(prg[0x40], PRG_CODE | PRG_INDIRECT_CODE);
+        // the taken branch's dummy read of the next opcode isn't data
+        assert_eq!(prg[0x43], PRG_CODE);
+        // reset vector, read through the $E000 window
+        assert_eq!(prg[0x7ffc], PRG_DATA | 0b11 << 2);
+    }
+
+    #[test]
+    fn test_chr_reads_and_rendering() {
+        let mut cpu = logged_cpu(&[]);
+        cpu.mem_write(0x2006, 0x00);
+        cpu.mem_write(0x2006, 0x05);
+        cpu.mem_read(0x2007);
+        assert_eq!(cpu.bus.cdl().unwrap().chr()[5], CHR_READ);
+
+        let mut ppu = NesPPU::new(vec![0; 0x2000], Mirroring::VERTICAL);
+        ppu.vram = [1; 2048];
+        ppu.mask.update(0b0000_1000);
+        let mut cdl = CodeDataLog::new(0x8000, 0x2000);
+        cdl.frame(&ppu);
+        assert_eq!(
+            &cdl.chr()[0..0x20],
+            &[[0; 16], [CHR_DRAWN; 16]].concat()[..]
+        );
+        // sprites are disabled
+        assert!(cdl.chr()[0x20..].iter().all(|&flags| flags == 0));
+    }
+
+    #[test]
+    fn test_frames_are_logged_with_nmi_off() {
+        let mut cpu = logged_cpu(&[]);
+        cpu.mem_write(0x2000, 0x00);
+        cpu.mem_write(0x2001, 0b0000_1000);
+        while cpu.bus.frames() == 0 {
+            cpu.bus.tick(1);
+        }
+        // the nametables are all tile 0
+        let chr = cpu.bus.cdl().unwrap().chr();
+        assert_eq!(&chr[0..0x20], &[[CHR_DRAWN; 16], [0; 16]].concat()[..]);
+    }
+
+    #[test]
+    fn test_round_trips_file_format() {
+        let cpu = logged_cpu(&[]);
+        let bytes = cpu.bus.cdl().unwrap().to_bytes();
+        assert_eq!(bytes.len(), 0xa000);
+        let log = CodeDataLog::from_bytes(&bytes, 0x8000, 0x2000).unwrap();
+        assert_eq!(log.to_bytes(), bytes);
+        assert!(CodeDataLog::from_bytes(&bytes, 0x4000, 0x2000).is_err());
+    }
+}
@@ -0,0 +1,337 @@
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// Cheat codes, kept per ROM in `game.cht`:
+//
+//   Game Genie  6 or 8 letters, decoded to an address in $8000-$FFFF, a
+//               replacement value and (8 letters) a compare value. The bus
+//               returns the replacement for PRG ROM reads of that address,
+//               as long as the ROM holds the compare value there.
+//   RAM poke    `AAAA:VV` or `AAAAVV` hex (Pro Action Replay style), written
+//               to RAM once per frame, at the start of vblank.
+//
+// Each cheat can be switched on and off, and a shared switch turns them all
+// off at once, for the frontend's hotkey.
+
+const GENIE_LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Code {
+    GameGenie {
+        addr: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+    Poke {
+        addr: u16,
+        value: u8,
+    },
+}
+
+impl Code {
+    pub fn parse(text: &str) -> Result<Code, String> {
+        let code = text.trim().to_ascii_uppercase();
+        let genie: Option<Vec<u8>> = code
+            .bytes()
+            .map(|c| GENIE_LETTERS.iter().position(|&l| l == c).map(|n| n as u8))
+            .collect();
+        match genie {
+            Some(n) if n.len() == 6 || n.len() == 8 => Ok(decode_genie(&n)),
+            _ => parse_poke(&code).ok_or(format!(
+                "'{}' is neither a 6/8 letter Game Genie code nor an AAAA:VV RAM poke",
+                text.trim()
+            )),
+        }
+    }
+}
+
+fn decode_genie(n: &[u8]) -> Code {
+    let addr = 0x8000
+        | ((n[3] as u16 & 7) << 12)
+        | ((n[5] as u16 & 7) << 8)
+        | ((n[4] as u16 & 8) << 8)
+        | ((n[2] as u16 & 7) << 4)
+        | ((n[1] as u16 & 8) << 4)
+        | (n[4] as u16 & 7)
+        | (n[3] as u16 & 8);
+    let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7);
+    if n.len() == 6 {
+        Code::GameGenie {
+            addr,
+            value: value | (n[5] & 8),
+            compare: None,
+        }
+    } else {
+        Code::GameGenie {
+            addr,
+            value: value | (n[7] & 8),
+            compare: Some(((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8)),
+        }
+    }
+}
+
+fn parse_poke(code: &str) -> Option<Code> {
+    let digits = code.replace(':', "");
+    if digits.len() != 6 {
+        return None;
+    }
+    let addr = u16::from_str_radix(&digits[..4], 16).ok()?;
+    let value = u8::from_str_radix(&digits[4..], 16).ok()?;
+    // internal RAM or cartridge RAM
+    match addr {
+        0x0000..=0x1fff | 0x6000..=0x7fff => Some(Code::Poke { addr, value }),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    /// as entered, upper-cased
+    pub code: String,
+    pub decoded: Code,
+    pub name: String,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    pub fn describe(&self) -> String {
+        let effect = match self.decoded {
+            Code::GameGenie {
+                addr,
+                value,
+                compare: Some(compare),
+            } => format!("{:04X}?{:02X}:{:02X}", addr, compare, value),
+            Code::GameGenie { addr, value, .. } | Code::Poke { addr, value } => {
+                format!("{:04X}:{:02X}", addr, value)
+            }
+        };
+        let state = if self.enabled { "on " } else { "off" };
+        format!("{} {:<8} {:<10} {}", state, self.code, effect, self.name)
+            .trim_end()
+            .to_string()
+    }
+}
+
+#[derive(Debug)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+    active: Rc<Cell<bool>>,
+    path: Option<PathBuf>,
+}
+
+impl Default for Cheats {
+    fn default() -> Self {
+        Cheats::new()
+    }
+}
+
+impl Cheats {
+    pub fn new() -> Cheats {
+        Cheats {
+            cheats: vec![],
+            active: Rc::new(Cell::new(true)),
+            path: None,
+        }
+    }
+
+    /// One cheat per line: `+CODE name` when enabled, `-CODE name` when not.
+    pub fn parse(text: &str) -> Result<Cheats, String> {
+        let mut cheats = Cheats::new();
+        for line in text.lines().map(str::trim) {
+            let (enabled, rest) = match line.split_at_checked(1) {
+                Some(("+", rest)) => (true, rest),
+                Some(("-", rest)) => (false, rest),
+                Some(("#", _)) | None => continue,
+                _ => return Err(format!("bad cheat '{}', expected +CODE or -CODE", line)),
+            };
+            let (code, name) = rest.split_once(' ').unwrap_or((rest, ""));
+            cheats.add(code, name.trim())?;
+            cheats.cheats.last_mut().unwrap().enabled = enabled;
+        }
+        Ok(cheats)
+    }
+
+    /// Loads `path` if it exists, otherwise starts empty; `save` writes back to it.
+    pub fn load(path: &Path) -> Result<Cheats, String> {
+        let mut cheats = match std::fs::read_to_string(path) {
+            Ok(text) => Cheats::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Cheats::new(),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        cheats.path = Some(path.to_path_buf());
+        Ok(cheats)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for cheat in &self.cheats {
+            let state = if cheat.enabled { '+' } else { '-' };
+            out.push_str(format!("{}{} {}", state, cheat.code, cheat.name).trim_end());
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Writes the list back to the file it was loaded from, if any.
+    pub fn save(&self) -> Result<(), String> {
+        match &self.path {
+            Some(path) => std::fs::write(path, self.to_text())
+                .map_err(|e| format!("{}: {}", path.display(), e)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn list(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    /// Adds an enabled cheat, returning its index.
+    pub fn add(&mut self, code: &str, name: &str) -> Result<usize, String> {
+        let decoded = Code::parse(code)?;
+        self.cheats.push(Cheat {
+            code: code.trim().to_ascii_uppercase(),
+            decoded,
+            name: name.to_string(),
+            enabled: true,
+        });
+        Ok(self.cheats.len() - 1)
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<Cheat, String> {
+        if index >= self.cheats.len() {
+            return Err(format!("no cheat {}", index));
+        }
+        Ok(self.cheats.remove(index))
+    }
+
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> Result<(), String> {
+        let cheat = self
+            .cheats
+            .get_mut(index)
+            .ok_or(format!("no cheat {}", index))?;
+        cheat.enabled = enabled;
+        Ok(())
+    }
+
+    /// Shared switch for all cheats, so a hotkey can toggle them without the bus.
+    pub fn switch(&self) -> Rc<Cell<bool>> {
+        self.active.clone()
+    }
+
+    fn enabled(&self) -> impl Iterator<Item = &Code> {
+        let active = self.active.get();
+        self.cheats
+            .iter()
+            .filter(move |cheat| active && cheat.enabled)
+            .map(|cheat| &cheat.decoded)
+    }
+
+    /// What a PRG ROM read of `addr` returns, given the ROM holds `value` there.
+    pub fn patch(&self, addr: u16, value: u8) -> u8 {
+        if self.cheats.is_empty() {
+            return value;
+        }
+        self.enabled()
+            .find_map(|code| match *code {
+                Code::GameGenie {
+                    addr: at,
+                    value: replace,
+                    compare,
+                } if at == addr && compare.is_none_or(|c| c == value) => Some(replace),
+                _ => None,
+            })
+            .unwrap_or(value)
+    }
+
+    /// RAM writes to apply this frame.
+    pub fn pokes(&self) -> Vec<(u16, u8)> {
+        self.enabled()
+            .filter_map(|code| match *code {
+                Code::Poke { addr, value } => Some((addr, value)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::bus::Bus;
+    use crate::comp::cpu::{CPU, Mem};
+    use crate::comp::rom::test::{test_prg, test_rom};
+
+    #[test]
+    fn test_decodes_game_genie_codes() {
+        // Super Mario Bros. infinite lives
+        assert_eq!(
+            Code::parse("sxiopo").unwrap(),
+            Code::GameGenie {
+                addr: 0x91d9,
+                value: 0xad,
+                compare: None
+            }
+        );
+        // the same patch with compare value $DE
+        assert_eq!(
+            Code::parse("SXIOPOVS").unwrap(),
+            Code::GameGenie {
+                addr: 0x91d9,
+                value: 0xad,
+                compare: Some(0xde)
+            }
+        );
+        assert_eq!(
+            Code::parse("0075:09").unwrap(),
+            Code::Poke {
+                addr: 0x75,
+                value: 9
+            }
+        );
+        assert_eq!(
+            Code::parse("6010FF").unwrap(),
+            Code::Poke {
+                addr: 0x6010,
+                value: 0xff
+            }
+        );
+        assert!(Code::parse("SXIOP").is_err());
+        assert!(Code::parse("8000:01").is_err());
+    }
+
+    #[test]
+    fn test_patches_reads_and_pokes_ram() {
+        let mut prg = test_prg(&[0xea; 0x7ffc]);
+        prg[0x11d9] = 0xde;
+        let mut bus = Bus::new(test_rom(prg), |_, _| {});
+        let cheats = Cheats::parse("+SXIOPOUA wrong\n-0010:05 disabled\n+0011:07\n").unwrap();
+        bus.set_cheats(cheats);
+        let mut cpu = CPU::new(bus);
+        // the compare value doesn't match the ROM, so it has no effect
+        assert_eq!(cpu.mem_read(0x91d9), 0xde);
+        cpu.bus.cheats_mut().add("SXIOPOVS", "lives").unwrap();
+        assert_eq!(cpu.mem_read(0x91d9), 0xad);
+        assert_eq!(cpu.bus.cheats().patch(0xa000, 0xea), 0xea);
+        assert_eq!(cpu.bus.cheats().pokes(), vec![(0x11, 7)]);
+
+        cpu.bus.cheats().switch().set(false);
+        assert_eq!(cpu.mem_read(0x91d9), 0xde);
+        assert!(cpu.bus.cheats().pokes().is_empty());
+    }
+
+    #[test]
+    fn test_round_trips_cheat_file() {
+        let text = "+SXIOPO infinite lives\n-0075:09\n";
+        let mut cheats = Cheats::parse(text).unwrap();
+        assert_eq!(cheats.to_text(), text);
+        cheats.set_enabled(1, true).unwrap();
+        assert_eq!(cheats.list()[1].describe(), "on  0075:09  0075:09");
+        assert_eq!(
+            cheats.list()[0].describe(),
+            "on  SXIOPO   91D9:AD    infinite lives"
+        );
+        assert!(cheats.remove(2).is_err());
+        assert!(Cheats::parse("SXIOPO").is_err());
+    }
+}
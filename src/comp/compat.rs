@@ -0,0 +1,457 @@
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+
+use crate::comp::controller::ControllerButtons;
+use crate::comp::error::EmuError;
+use crate::comp::headless::InputScript;
+use crate::comp::nes::Nes;
+use crate::comp::render::frame::Frame;
+use crate::comp::render::image;
+use crate::comp::rom::{Mirroring, Rom};
+
+// Compatibility survey: boots every .nes file in a directory headless for a
+// number of frames and sorts out what happened to it, so one bad ROM doesn't
+// take the batch down and there's an overview of what runs.
+//
+// The core stops with an `EmuError` on things it doesn't handle (unknown
+// opcodes, writes to read-only PPU registers or cartridge ROM, nametable
+// layouts without the VRAM for them) and can
+// still panic on ones it doesn't check for; both end that ROM's run and are
+// classified by what they were. ROMs that survive are judged by
+// their frames: nothing but one colour is a blank screen, a picture that
+// stopped changing for the second half of the run is frozen. A static title
+// screen counts as frozen, so give it an input script that presses start.
+
+// the bus has no bank switching yet
+const SUPPORTED_MAPPERS: &[u8] = &[0];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Class {
+    /// the header didn't parse
+    BadRom,
+    UnsupportedMapper(u8),
+    IllegalOpcode {
+        pc: u16,
+        opcode: u8,
+    },
+    BadPpuAccess,
+    /// a write to cartridge ROM with no mapper to take it
+    RomWrite {
+        addr: u16,
+    },
+    UnsupportedMirroring(Mirroring),
+    /// any other panic
+    Crashed,
+    /// the CPU executed BRK
+    Halted {
+        pc: u16,
+    },
+    BlankScreen,
+    FrozenScreen,
+    Ok,
+}
+
+impl Class {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Class::BadRom => "bad rom",
+            Class::UnsupportedMapper(_) => "unsupported mapper",
+            Class::IllegalOpcode { .. } => "illegal opcode",
+            Class::BadPpuAccess => "bad ppu access",
+            Class::RomWrite { .. } => "rom write",
+            Class::UnsupportedMirroring(_) => "unsupported mirroring",
+            Class::Crashed => "crashed",
+            Class::Halted { .. } => "halted",
+            Class::BlankScreen => "blank screen",
+            Class::FrozenScreen => "frozen screen",
+            Class::Ok => "ok",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub file: PathBuf,
+    pub mapper: Option<u8>,
+    pub class: Class,
+    /// what went wrong, e.g. the panic message
+    pub detail: String,
+    /// frames completed before the run ended
+    pub frames: usize,
+    /// the last frame, relative to the report
+    pub thumbnail: Option<PathBuf>,
+}
+
+thread_local! {
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    static PANIC: RefCell<Option<(String, String)>> = const { RefCell::new(None) };
+}
+
+static HOOK: Once = Once::new();
+
+/// Runs `f`, returning the message and source file of a panic instead of
+/// unwinding further and printing it. Panics on other threads still print.
+fn catch_panic<F: FnOnce()>(f: F) -> Option<(String, String)> {
+    HOOK.call_once(|| {
+        let default = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !CATCHING.get() {
+                return default(info);
+            }
+            let message = info.payload_as_str().unwrap_or("panic").to_string();
+            let file = info
+                .location()
+                .map_or(String::new(), |l| l.file().to_string());
+            PANIC.set(Some((message, file)));
+        }));
+    });
+    CATCHING.set(true);
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING.set(false);
+    match result {
+        Ok(()) => None,
+        Err(_) => Some(PANIC.take().unwrap_or_default()),
+    }
+}
+
+fn is_blank(frame: &Frame) -> bool {
+    frame.data.chunks(3).all(|pixel| pixel == &frame.data[..3])
+}
+
+/// Boots `bytes` for `frames` frames, playing `script` on the pad, and
+/// classifies the run. Also returns a thumbnail PNG of the last complete
+/// frame; `file` and `thumbnail` in the entry are left for the caller.
+pub fn check(bytes: &[u8], frames: usize, script: &InputScript) -> (Entry, Option<Vec<u8>>) {
+    let mut entry = Entry {
+        file: PathBuf::new(),
+        mapper: None,
+        class: Class::BadRom,
+        detail: String::new(),
+        frames: 0,
+        thumbnail: None,
+    };
+    // a truncated file panics inside Rom::new
+    let mut parsed = Err(String::new());
+    if let Some((message, _)) = catch_panic(|| parsed = Rom::new(&bytes.to_vec())) {
+        entry.detail = message;
+        return (entry, None);
+    }
+    let rom = match parsed {
+        Ok(rom) => rom,
+        Err(e) => {
+            entry.detail = e;
+            return (entry, None);
+        }
+    };
+    entry.mapper = Some(rom.mapper);
+    let mapper = rom.mapper;
+
+    let mut nes = Nes::new(rom);
+    let mut checksums = vec![];
+    let mut shown_anything = false;
+    let mut error = None;
+    let panicked = catch_panic(|| {
+        for frame in 0..frames {
+            let pad = nes.controller();
+            pad.set_button_pressed_status(ControllerButtons::all(), false);
+            pad.set_button_pressed_status(script.buttons(frame), true);
+            if let Err(e) = nes.step_frame() {
+                error = Some(e);
+                break;
+            }
+            if nes.halted() {
+                break;
+            }
+            checksums.push(nes.frame().checksum());
+            shown_anything |= !is_blank(nes.frame());
+        }
+    });
+    entry.frames = checksums.len();
+
+    // the opcode has been fetched when the CPU stops at BRK
+    let pc = nes.cpu().pc.wrapping_sub(1);
+    entry.class = match (error, &panicked) {
+        _ if !SUPPORTED_MAPPERS.contains(&mapper) => Class::UnsupportedMapper(mapper),
+        (Some(EmuError::IllegalOpcode { pc, opcode }), _) => Class::IllegalOpcode { pc, opcode },
+        (Some(EmuError::PpuRegisterWrite { .. }), _) => Class::BadPpuAccess,
+        (Some(EmuError::RomWrite { addr, .. }), _) => Class::RomWrite { addr },
+        (Some(EmuError::Mirroring(mirroring)), _) => Class::UnsupportedMirroring(mirroring),
+        (None, Some((_, file))) if file.ends_with("ppu.rs") || file.ends_with("render.rs") => {
+            Class::BadPpuAccess
+        }
+        (None, Some(_)) => Class::Crashed,
+        _ if nes.halted() => Class::Halted { pc },
+        _ if !shown_anything => Class::BlankScreen,
+        _ if checksums.len() >= 2
+            && checksums[checksums.len() / 2..]
+                .iter()
+                .all(|&hash| hash == checksums[checksums.len() - 1]) =>
+        {
+            Class::FrozenScreen
+        }
+        _ => Class::Ok,
+    };
+    entry.detail = match (error, panicked) {
+        (Some(error), _) => error.to_string(),
+        (None, Some((message, file))) => format!("{} ({}) at ${:04X}", message, file, pc),
+        (None, None) if nes.halted() => format!("BRK at ${:04X}", pc),
+        (None, None) => String::new(),
+    };
+    let thumbnail = (entry.frames > 0).then(|| image::encode_thumbnail(nes.frame()));
+    (entry, thumbnail)
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn mapper_text(entry: &Entry) -> String {
+    entry
+        .mapper
+        .map_or(String::new(), |mapper| mapper.to_string())
+}
+
+fn path_text(path: &Option<PathBuf>) -> String {
+    path.as_ref()
+        .map_or(String::new(), |path| path.display().to_string())
+}
+
+/// Boots every `.nes` file in `dir` (sorted by name) and saves each one's
+/// last frame as a thumbnail under `out/thumbs`.
+pub fn survey(
+    dir: &Path,
+    frames: usize,
+    script: &InputScript,
+    out: &Path,
+) -> Result<Vec<Entry>, String> {
+    let error = |path: &Path, e: std::io::Error| format!("{}: {}", path.display(), e);
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| error(dir, e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("nes"))
+        })
+        .collect();
+    files.sort();
+    let thumbs = out.join("thumbs");
+    std::fs::create_dir_all(&thumbs).map_err(|e| error(&thumbs, e))?;
+
+    let mut entries = vec![];
+    for file in files {
+        let bytes = std::fs::read(&file).map_err(|e| error(&file, e))?;
+        let (mut entry, thumbnail) = check(&bytes, frames, script);
+        if let Some(png) = thumbnail {
+            let name = PathBuf::from("thumbs").join(file.file_stem().unwrap_or_default());
+            let name = name.with_extension("png");
+            let path = out.join(&name);
+            std::fs::write(&path, png).map_err(|e| error(&path, e))?;
+            entry.thumbnail = Some(name);
+        }
+        entry.file = file;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+pub fn to_csv(entries: &[Entry]) -> String {
+    let mut out = "file,mapper,result,detail,frames,thumbnail\n".to_string();
+    for entry in entries {
+        let fields = [
+            entry.file.display().to_string(),
+            mapper_text(entry),
+            entry.class.name().to_string(),
+            entry.detail.clone(),
+            entry.frames.to_string(),
+            path_text(&entry.thumbnail),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        out.push_str(&fields.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+/// Counts per result, in the order they first appear.
+pub fn totals(entries: &[Entry]) -> Vec<(&'static str, usize)> {
+    let mut totals: Vec<(&'static str, usize)> = vec![];
+    for entry in entries {
+        match totals
+            .iter_mut()
+            .find(|(name, _)| *name == entry.class.name())
+        {
+            Some((_, count)) => *count += 1,
+            None => totals.push((entry.class.name(), 1)),
+        }
+    }
+    totals
+}
+
+pub fn to_html(entries: &[Entry]) -> String {
+    let mut out = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>rnes compatibility</title>\n<style>\n\
+         table { border-collapse: collapse; font-family: sans-serif; }\n\
+         td, th { border: 1px solid #ccc; padding: 4px 8px; text-align: left; }\n\
+         .ok { background: #dfd; }\n\
+         </style>\n</head>\n<body>\n",
+    );
+    let totals: Vec<String> = totals(entries)
+        .iter()
+        .map(|(name, count)| format!("{} {}", count, name))
+        .collect();
+    out.push_str(&format!(
+        "<p>{} ROMs: {}</p>\n",
+        entries.len(),
+        totals.join(", ")
+    ));
+    out.push_str("<table>\n<tr><th></th><th>file</th><th>mapper</th><th>result</th><th>detail</th><th>frames</th></tr>\n");
+    for entry in entries {
+        let class = if entry.class == Class::Ok {
+            " class=\"ok\""
+        } else {
+            ""
+        };
+        let thumbnail = entry.thumbnail.as_ref().map_or(String::new(), |path| {
+            format!("<img src=\"{}\">", html_escape(&path.display().to_string()))
+        });
+        let name = entry
+            .file
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().to_string());
+        out.push_str(&format!(
+            "<tr{}><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            class,
+            thumbnail,
+            html_escape(&name),
+            mapper_text(entry),
+            entry.class.name(),
+            html_escape(&entry.detail),
+            entry.frames
+        ));
+    }
+    out.push_str("</table>\n</body>\n</html>\n");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::rom::test::test_prg;
+
+    fn ines(program: &[u8], mapper: u8) -> Vec<u8> {
+        let mut rom = vec![0x4e, 0x45, 0x53, 0x1a, 2, 1, mapper << 4, mapper & 0xf0];
+        rom.resize(16, 0);
+        rom.extend(test_prg(program));
+        // tiles with two colours in them
+        rom.extend([2; 0x2000]);
+        rom
+    }
+
+    fn class(program: &[u8], mapper: u8) -> (Class, String) {
+        let (entry, _) = check(&ines(program, mapper), 20, &InputScript::default());
+        (entry.class, entry.detail)
+    }
+
+    // LDA #$3F; STA $2006; LDA #$03; STA $2006
+    const PALETTE: [u8; 10] = [0xa9, 0x3f, 0x8d, 0x06, 0x20, 0xa9, 0x03, 0x8d, 0x06, 0x20];
+
+    #[test]
+    fn test_classifies_crashes() {
+        assert_eq!(
+            class(&[0xea, 0x02], 0),
+            (
+                Class::IllegalOpcode {
+                    pc: 0x8001,
+                    opcode: 0x02
+                },
+                "illegal opcode $02 at $8001".to_string()
+            )
+        );
+        // LDA #0; STA $2002
+        let program = [0xa9, 0x00, 0x8d, 0x02, 0x20];
+        assert_eq!(class(&program, 0).0, Class::BadPpuAccess);
+        // LDA #0; STA $8000
+        let program = [0xa9, 0x00, 0x8d, 0x00, 0x80];
+        assert_eq!(class(&program, 0).0, Class::RomWrite { addr: 0x8000 });
+        assert_eq!(class(&[0x4c, 0x00, 0x80], 1).0, Class::UnsupportedMapper(1));
+        assert_eq!(
+            class(&[0xea, 0x00], 0),
+            (Class::Halted { pc: 0x8001 }, "BRK at $8001".to_string())
+        );
+        let (entry, thumbnail) = check(b"NES\x1a", 20, &InputScript::default());
+        assert_eq!(entry.class, Class::BadRom);
+        assert!(thumbnail.is_none());
+    }
+
+    #[test]
+    fn test_classifies_screens() {
+        // JMP *, with nothing but the backdrop colour on screen
+        let mut blank = ines(&[0x4c, 0x00, 0x80], 0);
+        blank[16 + 0x8000..].fill(0);
+        let (entry, thumbnail) = check(&blank, 20, &InputScript::default());
+        assert_eq!(entry.class, Class::BlankScreen);
+        assert_eq!(entry.frames, 20);
+        assert!(thumbnail.is_some());
+
+        // one palette write, then JMP *
+        let mut program = PALETTE.to_vec();
+        program.extend([0xa9, 0x16, 0x8d, 0x07, 0x20, 0x4c, 0x0f, 0x80]);
+        assert_eq!(class(&program, 0).0, Class::FrozenScreen);
+
+        // keeps changing the colour: loop: ...; INX; TXA; AND #$3F; STA $2007; JMP loop
+        let mut program = PALETTE.to_vec();
+        program.extend([0xe8, 0x8a, 0x29, 0x3f, 0x8d, 0x07, 0x20, 0x4c, 0x00, 0x80]);
+        assert_eq!(class(&program, 0), (Class::Ok, String::new()));
+    }
+
+    #[test]
+    fn test_writes_reports() {
+        let dir = std::env::temp_dir().join(format!("rnes-compat-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("b, crash.nes"), ines(&[0x02], 0)).unwrap();
+        std::fs::write(dir.join("a.nes"), ines(&[0x00], 0)).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a rom").unwrap();
+
+        let out = dir.join("report");
+        let entries = survey(&dir, 5, &InputScript::default(), &out).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(totals(&entries), vec![("halted", 1), ("illegal opcode", 1)]);
+
+        let csv = to_csv(&entries);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "file,mapper,result,detail,frames,thumbnail");
+        assert!(
+            lines[1].ends_with("a.nes,0,halted,BRK at $8000,0,"),
+            "{}",
+            csv
+        );
+        assert!(
+            lines[2].ends_with("crash.nes\",0,illegal opcode,illegal opcode $02 at $8000,0,"),
+            "{}",
+            csv
+        );
+        let html = to_html(&entries);
+        assert!(
+            html.contains("<p>2 ROMs: 1 halted, 1 illegal opcode</p>"),
+            "{}",
+            html
+        );
+        assert!(html.contains("<td>b, crash.nes</td>"), "{}", html);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
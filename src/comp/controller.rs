@@ -44,6 +44,14 @@ impl Controller {
         resp
     }
 
+    /// The bit the next `read` returns, without advancing the shift register.
+    pub fn peek(&self) -> u8 {
+        if self.button_idx > 7 {
+            return 1;
+        }
+        (self.button_status.bits & (1 << self.button_idx)) >> self.button_idx
+    }
+
     pub fn set_button_pressed_status(&mut self, button: ControllerButtons, pressed: bool) {
         self.button_status.set(button, pressed);
     }
@@ -90,4 +98,17 @@ mod test {
             joypad.write(0);
         }
     }
+
+    #[test]
+    fn test_peek_does_not_shift() {
+        let mut joypad = Controller::new();
+        joypad.write(0);
+        joypad.set_button_pressed_status(ControllerButtons::BUTTON_B, true);
+
+        assert_eq!(joypad.peek(), 0);
+        assert_eq!(joypad.peek(), 0);
+        assert_eq!(joypad.read(), 0);
+        assert_eq!(joypad.peek(), 1);
+        assert_eq!(joypad.read(), 1);
+    }
 }
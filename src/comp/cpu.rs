@@ -1,6 +1,8 @@
-use crate::comp::bus::Bus;
 use bitflags::bitflags;
 
+use crate::comp::error::EmuError;
+use crate::comp::opcodes;
+
 bitflags! {
     /// Represents the 6502 Processor Status (P) register.
     // #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -77,21 +79,57 @@ pub static OPCODE_CYCLES: [u8; 256] = [
     2, 6, 2, 2, 3, 3, 5, 2, 4, 2, 2, 2, 4, 4, 6, 2, // 0xE0
     2, 5, 2, 2, 2, 4, 6, 2, 4, 4, 2, 2, 4, 6, 7, 2, // 0xF0
 ];
-pub struct CPU<'a> {
+pub struct CPU<M: Mem + Tick> {
     pub reg_a: u8,
     pub reg_x: u8,
     pub reg_y: u8,
     pub status: CpuFlags,
     pub pc: u16,
     pub stk_ptr: u8,
-    pub bus: Bus<'a>,
+    pub bus: M,
     pub cycles: usize,
+    pub variant: CpuVariant,
+}
+
+/// Which member of the 6502 family to emulate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuVariant {
+    /// Original NMOS 6502 with decimal mode and its undocumented flag behaviour.
+    Nmos6502,
+    /// NES CPU: an NMOS core with the BCD logic cut out.
+    Ricoh2A03,
+    /// WDC/Rockwell 65C02: fixed decimal flags and the extra CMOS instructions.
+    Cmos65C02,
 }
 const STK: u16 = 0x0100;
 const STK_RESET: u8 = 0xfd;
 pub trait Mem {
     fn mem_read(&mut self, addr: u16) -> u8;
 
+    /// Returns what `mem_read` would see without any read side effects
+    /// (status flags, latches, buffers, shift registers). Used by tracing and debuggers.
+    fn peek(&self, addr: u16) -> u8;
+
+    fn peek_u16(&self, pos: u16) -> u16 {
+        u16::from_le_bytes([self.peek(pos), self.peek(pos.wrapping_add(1))])
+    }
+
+    /// Offset into PRG ROM that `addr` is currently mapped to, for bank-aware
+    /// symbol lookup. `None` for RAM, registers and memories without a cartridge.
+    fn prg_rom_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    /// A read the CPU only makes as a side effect of its bus timing (indexing,
+    /// branches); the value is thrown away. Same as `mem_read` by default.
+    fn dummy_read(&mut self, addr: u16) {
+        self.mem_read(addr);
+    }
+
+    /// Called before the CPU fetches the instruction at `pc`, so a bus can tell
+    /// code fetches from data reads.
+    fn begin_instruction(&mut self, _pc: u16) {}
+
     fn mem_write(&mut self, addr: u16, data: u8);
 
     fn mem_read_u16(&mut self, pos: u16) -> u16 {
@@ -107,6 +145,27 @@ pub trait Mem {
     }
 }
 
+/// The clock and interrupt lines the CPU drives besides plain memory access.
+/// The NES `Bus` steps the PPU here, a flat memory just counts cycles.
+pub trait Tick {
+    fn tick(&mut self, cycles: u8);
+
+    fn poll_nmi_status(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// Something the program did that isn't emulated. The NES `Bus` applies
+    /// its error policy; a memory without one panics, as the core used to.
+    fn fault(&mut self, error: EmuError) {
+        panic!("{}", error);
+    }
+
+    /// The fault that should stop the CPU, if there is one.
+    fn take_fault(&mut self) -> Option<EmuError> {
+        None
+    }
+}
+
 #[derive(Debug)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
@@ -119,14 +178,31 @@ pub enum AddressingMode {
     Absolute_Y,
     Indirect_X,
     Indirect_Y,
+    ZeroPage_Indirect,
     NoneAddressing,
 }
 
-impl Mem for CPU<'_> {
+impl<M: Mem + Tick> Mem for CPU<M> {
     fn mem_read(&mut self, addr: u16) -> u8 {
         self.bus.mem_read(addr)
     }
 
+    fn peek(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.bus.prg_rom_offset(addr)
+    }
+
+    fn dummy_read(&mut self, addr: u16) {
+        self.bus.dummy_read(addr)
+    }
+
+    fn begin_instruction(&mut self, pc: u16) {
+        self.bus.begin_instruction(pc)
+    }
+
     fn mem_write(&mut self, addr: u16, data: u8) {
         self.bus.mem_write(addr, data);
     }
@@ -142,9 +218,36 @@ fn page_cross(addr1: u16, addr2: u16) -> bool {
     addr1 & 0xFF00 != addr2 & 0xFF00
 }
 
-impl<'a> CPU<'a> {
+// Opcodes that are unofficial NOPs/KILs on NMOS parts but real instructions on the 65C02.
+const CMOS_OPCODES: [u8; 27] = [
+    0x04, 0x0c, 0x12, 0x14, 0x1a, 0x1c, 0x32, 0x34, 0x3a, 0x3c, 0x52, 0x5a, 0x64, 0x72, 0x74, 0x7a,
+    0x7c, 0x80, 0x89, 0x92, 0x9c, 0x9e, 0xb2, 0xd2, 0xda, 0xf2, 0xfa,
+];
+
+// The 65C02 runs every opcode it doesn't define as a NOP instead of the NMOS
+// part's illegal instructions (LAX, SAX, DCP, ISB, SLO, ...) and KILs; this is
+// the length and cycle count of each. The Rockwell/WDC bit instructions in
+// the $x7 and $xF columns aren't emulated, those run as the original 65C02's
+// one byte NOPs.
+fn cmos_nop(opcode: u8) -> Option<(u16, u8)> {
+    match opcode {
+        0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xc2 | 0xe2 => Some((2, 2)),
+        0x44 => Some((2, 3)),
+        0x54 | 0xd4 | 0xf4 => Some((2, 4)),
+        0x5c => Some((3, 8)),
+        0xdc | 0xfc => Some((3, 4)),
+        op if op & 0x03 == 0x03 => Some((1, 1)),
+        _ => None,
+    }
+}
+
+impl<M: Mem + Tick> CPU<M> {
     //constructor i.e. associated function
-    pub fn new<'b>(bus: Bus<'b>) -> CPU<'b> {
+    pub fn new(bus: M) -> CPU<M> {
+        CPU::new_with_variant(bus, CpuVariant::Ricoh2A03)
+    }
+
+    pub fn new_with_variant(bus: M, variant: CpuVariant) -> CPU<M> {
         CPU {
             reg_a: 0,
             reg_x: 0,
@@ -154,6 +257,7 @@ impl<'a> CPU<'a> {
             status: CpuFlags::from_bits_truncate(0b100100),
             stk_ptr: STK_RESET,
             bus,
+            variant,
         }
     }
 
@@ -178,12 +282,10 @@ impl<'a> CPU<'a> {
     }
 
     pub fn load(&mut self, program: Vec<u8>) {
-        // self.memory[0x8000..(0x8000 + program.len())].copy_from_slice(&program[..]);
-        // self.mem_write_u16(0xFFFC, 0x8000);
         for i in 0..(program.len() as u16) {
-            self.mem_write(0x0000 + i, program[i as usize]);
+            self.mem_write(0x8000 + i, program[i as usize]);
         }
-        self.mem_write_u16(0xFFFC, 0x0000);
+        self.mem_write_u16(0xFFFC, 0x8000);
     }
 
     pub fn reset(&mut self) {
@@ -201,16 +303,19 @@ impl<'a> CPU<'a> {
     }
 
     fn bit(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.get_read_operand_address(mode);
         let val = self.mem_read(addr);
         let and = self.reg_a & val;
         self.status.set(CpuFlags::ZERO, and == 0);
         self.status.set(CpuFlags::NEGATIVE, val & 0b1000_0000 != 0);
         self.status.set(CpuFlags::OVERFLOW, val & 0b0100_0000 != 0);
+        if page_cross {
+            self.bus.tick(1);
+        }
     }
 
     fn lda(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.get_read_operand_address(mode);
         let val = self.mem_read(addr);
         self.reg_a = val;
         self.update_zero_and_neg_flag(self.reg_a);
@@ -219,7 +324,7 @@ impl<'a> CPU<'a> {
         }
     }
     fn ldx(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.get_read_operand_address(mode);
         let val = self.mem_read(addr);
         self.reg_x = val;
         self.update_zero_and_neg_flag(self.reg_x);
@@ -228,7 +333,7 @@ impl<'a> CPU<'a> {
         }
     }
     fn ldy(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.get_read_operand_address(mode);
         let val = self.mem_read(addr);
         self.reg_y = val;
         self.update_zero_and_neg_flag(self.reg_y);
@@ -280,7 +385,7 @@ impl<'a> CPU<'a> {
     }
 
     fn and(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.get_read_operand_address(mode);
         let data = self.mem_read(addr);
         self.reg_a = self.reg_a & data;
         self.update_zero_and_neg_flag(self.reg_a);
@@ -290,11 +395,11 @@ impl<'a> CPU<'a> {
     }
 
     fn asl(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
-        self.update_carry_flag_asl(data);
-        data = data << 1;
-        self.mem_write(addr, data);
+        let addr = self.get_write_operand_address(mode);
+        let old = self.mem_read(addr);
+        self.update_carry_flag_asl(old);
+        let data = old << 1;
+        self.rmw_write(addr, old, data);
         self.update_zero_and_neg_flag(data);
         data
     }
@@ -307,11 +412,11 @@ impl<'a> CPU<'a> {
         data
     }
     fn lsr(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
-        self.update_carry_flag_lsr(data);
-        data = data >> 1;
-        self.mem_write(addr, data);
+        let addr = self.get_write_operand_address(mode);
+        let old = self.mem_read(addr);
+        self.update_carry_flag_lsr(old);
+        let data = old >> 1;
+        self.rmw_write(addr, old, data);
         self.update_zero_and_neg_flag(data);
         data
     }
@@ -326,20 +431,13 @@ impl<'a> CPU<'a> {
     }
 
     fn sbc(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.get_read_operand_address(mode);
         let data = self.mem_read(addr);
-        let carry = self.status.contains(CpuFlags::CARRY) as u8;
-        let val = data ^ 0xFF;
-        let sum = self.reg_a as u16 + val as u16 + carry as u16;
-
-        self.status.set(CpuFlags::CARRY, sum > 0xFF);
-        let res = sum as u8;
-        self.status.set(
-            CpuFlags::OVERFLOW,
-            ((self.reg_a ^ res) & (val ^ res) & 0x80) != 0,
-        );
-        self.reg_a = res;
-        self.update_zero_and_neg_flag(self.reg_a);
+        if self.decimal_mode_active() {
+            self.sbc_decimal(data);
+        } else {
+            self.add_to_reg_a(data ^ 0xFF);
+        }
 
         if page_cross {
             self.bus.tick(1);
@@ -347,8 +445,19 @@ impl<'a> CPU<'a> {
     }
 
     fn adc(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.get_read_operand_address(mode);
         let data = self.mem_read(addr);
+        if self.decimal_mode_active() {
+            self.adc_decimal(data);
+        } else {
+            self.add_to_reg_a(data);
+        }
+        if page_cross {
+            self.bus.tick(1);
+        }
+    }
+
+    fn add_to_reg_a(&mut self, data: u8) {
         let carry = self.status.contains(CpuFlags::CARRY) as u8;
         let sum = self.reg_a as u16 + data as u16 + carry as u16;
 
@@ -360,20 +469,105 @@ impl<'a> CPU<'a> {
         );
         self.reg_a = res;
         self.update_zero_and_neg_flag(self.reg_a);
-        if page_cross {
+    }
+
+    // The 2A03 has the D flag but no BCD circuitry, so SED is a no-op for arithmetic.
+    fn decimal_mode_active(&self) -> bool {
+        self.variant != CpuVariant::Ricoh2A03 && self.status.contains(CpuFlags::DECIMAL_MODE)
+    }
+
+    /// http://www.6502.org/tutorials/decimal_mode.html
+    ///
+    /// On the NMOS part Z comes from the binary sum and N/V from the
+    /// intermediate result before the high nibble is adjusted.
+    /// The 65C02 fixes this and sets N/Z from the BCD result, at the cost of a cycle.
+    fn adc_decimal(&mut self, data: u8) {
+        let a = self.reg_a as u16;
+        let m = data as u16;
+        let carry = self.status.contains(CpuFlags::CARRY) as u16;
+
+        let mut lo = (a & 0x0f) + (m & 0x0f) + carry;
+        if lo > 0x09 {
+            lo = ((lo + 0x06) & 0x0f) + 0x10;
+        }
+        let mut sum = (a & 0xf0) + (m & 0xf0) + lo;
+        self.status
+            .set(CpuFlags::OVERFLOW, (!(a ^ m) & (a ^ sum) & 0x80) != 0);
+        let intermediate = sum as u8;
+        if sum > 0x9f {
+            sum += 0x60;
+        }
+        self.status.set(CpuFlags::CARRY, sum > 0xff);
+        self.reg_a = sum as u8;
+
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.update_zero_and_neg_flag(self.reg_a);
             self.bus.tick(1);
+        } else {
+            let binary = (a + m + carry) as u8;
+            self.status.set(CpuFlags::ZERO, binary == 0);
+            self.status
+                .set(CpuFlags::NEGATIVE, intermediate & 0x80 != 0);
         }
     }
 
-    // fn branch(&mut self, condition: bool) {
-    //     if condition {
-    //         let jump: i8 = self.mem_read(self.pc) as i8;
-    //         let jump_addr = self.pc.wrapping_add(1).wrapping_add(jump as u16);
-    //         self.pc = jump_addr;
-    //     }
-    // }
+    /// Flags behave as in binary mode on both NMOS and CMOS, except that the
+    /// 65C02 takes N/Z from the BCD result.
+    fn sbc_decimal(&mut self, data: u8) {
+        let a = self.reg_a as i16;
+        let m = data as i16;
+        let borrow = 1 - self.status.contains(CpuFlags::CARRY) as i16;
+
+        // flags from the plain binary subtraction
+        self.add_to_reg_a(data ^ 0xFF);
+        self.reg_a = a as u8;
+
+        let mut lo = (a & 0x0f) - (m & 0x0f) - borrow;
+        let res = if self.variant == CpuVariant::Cmos65C02 {
+            let mut res = a - m - borrow;
+            if res < 0 {
+                res -= 0x60;
+            }
+            if lo < 0 {
+                res -= 0x06;
+            }
+            res as u8
+        } else {
+            let mut hi = (a >> 4) - (m >> 4);
+            if lo < 0 {
+                lo -= 0x06;
+                hi -= 1;
+            }
+            if hi < 0 {
+                hi -= 0x06;
+            }
+            ((hi << 4) | (lo & 0x0f)) as u8
+        };
+        self.reg_a = res;
+
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.update_zero_and_neg_flag(self.reg_a);
+            self.bus.tick(1);
+        }
+    }
+
+    fn branch(&mut self, condition: bool) {
+        let offset = self.mem_read(self.pc) as i8;
+        self.pc += 1;
+        if condition {
+            // taken: +1 cycle, +1 more when the target is on another page
+            let target = self.pc.wrapping_add(offset as u16);
+            self.dummy_read(self.pc);
+            self.bus.tick(1);
+            if page_cross(self.pc, target) {
+                self.dummy_read((self.pc & 0xFF00) | (target & 0x00FF));
+                self.bus.tick(1);
+            }
+            self.pc = target;
+        }
+    }
     fn compare(&mut self, mode: &AddressingMode, cmp_with: u8) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.get_read_operand_address(mode);
         let data = self.mem_read(addr);
 
         self.status.set(CpuFlags::CARRY, data <= cmp_with);
@@ -406,10 +600,10 @@ impl<'a> CPU<'a> {
     }
 
     fn inc(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
-        data = data.wrapping_add(1);
-        self.mem_write(addr, data);
+        let addr = self.get_write_operand_address(mode);
+        let old = self.mem_read(addr);
+        let data = old.wrapping_add(1);
+        self.rmw_write(addr, old, data);
         self.update_zero_and_neg_flag(data);
         data
     }
@@ -425,10 +619,10 @@ impl<'a> CPU<'a> {
     }
 
     fn dec(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
-        data = data.wrapping_sub(1);
-        self.mem_write(addr, data);
+        let addr = self.get_write_operand_address(mode);
+        let old = self.mem_read(addr);
+        let data = old.wrapping_sub(1);
+        self.rmw_write(addr, old, data);
         self.update_zero_and_neg_flag(data);
         data
     }
@@ -441,7 +635,7 @@ impl<'a> CPU<'a> {
         self.update_zero_and_neg_flag(self.reg_y);
     }
     fn eor(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.get_read_operand_address(mode);
         let data = self.mem_read(addr);
         self.reg_a = data ^ self.reg_a;
         self.update_zero_and_neg_flag(self.reg_a);
@@ -451,7 +645,7 @@ impl<'a> CPU<'a> {
         }
     }
     fn ora(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.get_read_operand_address(mode);
         let data = self.mem_read(addr);
         self.reg_a = data | self.reg_a;
         self.update_zero_and_neg_flag(self.reg_a);
@@ -470,12 +664,12 @@ impl<'a> CPU<'a> {
         self.update_zero_and_neg_flag(self.reg_a);
     }
     fn rol(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
+        let addr = self.get_write_operand_address(mode);
         let old_val = self.mem_read(addr);
         let carry_in = self.status.contains(CpuFlags::CARRY) as u8;
         self.status.set(CpuFlags::CARRY, old_val & 0x80 != 0);
         let new_val = (old_val << 1) | carry_in;
-        self.mem_write(addr, new_val);
+        self.rmw_write(addr, old_val, new_val);
         self.update_zero_and_neg_flag(new_val);
     }
     fn ror_acc(&mut self) {
@@ -491,7 +685,7 @@ impl<'a> CPU<'a> {
         self.update_zero_and_neg_flag(self.reg_a);
     }
     fn ror(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
+        let addr = self.get_write_operand_address(mode);
         let carry_in = if self.status.contains(CpuFlags::CARRY) {
             0x80
         } else {
@@ -500,20 +694,20 @@ impl<'a> CPU<'a> {
         let old_val = self.mem_read(addr);
         let new_val = (old_val >> 1) | carry_in;
         self.status.set(CpuFlags::CARRY, old_val & 0x01 != 0);
-        self.mem_write(addr, new_val);
+        self.rmw_write(addr, old_val, new_val);
         self.update_zero_and_neg_flag(new_val);
     }
 
     fn sta(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
+        let addr = self.get_write_operand_address(mode);
         self.mem_write(addr, self.reg_a);
     }
     fn stx(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
+        let addr = self.get_write_operand_address(mode);
         self.mem_write(addr, self.reg_x);
     }
     fn sty(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
+        let addr = self.get_write_operand_address(mode);
         self.mem_write(addr, self.reg_y);
     }
 
@@ -563,11 +757,14 @@ impl<'a> CPU<'a> {
             AddressingMode::Absolute => (self.mem_read_u16(self.pc) as u16, false),
             AddressingMode::ZeroPage_X => {
                 let pos = self.mem_read(self.pc);
+                // the base address is read while the index is being added
+                self.dummy_read(pos as u16);
                 let addr = pos.wrapping_add(self.reg_x) as u16;
                 (addr, false)
             }
             AddressingMode::ZeroPage_Y => {
                 let pos = self.mem_read(self.pc);
+                self.dummy_read(pos as u16);
                 let addr = pos.wrapping_add(self.reg_y) as u16;
                 (addr, false)
             }
@@ -583,6 +780,7 @@ impl<'a> CPU<'a> {
             }
             AddressingMode::Indirect_X => {
                 let base = self.mem_read(self.pc);
+                self.dummy_read(base as u16);
                 let ptr: u8 = (base as u8).wrapping_add(self.reg_x);
                 let lo = self.mem_read(ptr as u16);
                 let hi = self.mem_read(ptr.wrapping_add(1) as u16);
@@ -596,12 +794,60 @@ impl<'a> CPU<'a> {
                 let deref = deref_base.wrapping_add(self.reg_y as u16);
                 (deref, page_cross(deref, deref_base))
             }
+            AddressingMode::ZeroPage_Indirect => {
+                let base = self.mem_read(self.pc);
+                let lo = self.mem_read(base as u16);
+                let hi = self.mem_read(base.wrapping_add(1) as u16);
+                ((hi as u16) << 8 | (lo as u16), false)
+            }
             AddressingMode::NoneAddressing => {
                 panic!("mode {:?} is not supported", mode);
             }
         }
     }
 
+    // Indexed absolute and (indirect),Y add the index to the low byte first and read
+    // from that un-carried address while the high byte is being fixed up.
+    // Reads only do this on a page cross, stores and read-modify-write always do.
+    fn dummy_read_indexed(&mut self, mode: &AddressingMode, addr: u16, page_cross: bool) {
+        if let AddressingMode::Absolute_X
+        | AddressingMode::Absolute_Y
+        | AddressingMode::Indirect_Y = mode
+        {
+            let uncarried = if page_cross {
+                addr.wrapping_sub(0x100)
+            } else {
+                addr
+            };
+            self.dummy_read(uncarried);
+        }
+    }
+
+    fn get_read_operand_address(&mut self, mode: &AddressingMode) -> (u16, bool) {
+        let (addr, page_cross) = self.get_operand_address(mode);
+        if page_cross {
+            self.dummy_read_indexed(mode, addr, page_cross);
+        }
+        (addr, page_cross)
+    }
+
+    fn get_write_operand_address(&mut self, mode: &AddressingMode) -> u16 {
+        let (addr, page_cross) = self.get_operand_address(mode);
+        self.dummy_read_indexed(mode, addr, page_cross);
+        addr
+    }
+
+    // Read-modify-write instructions write the unmodified value back
+    // before storing the result. The 65C02 re-reads the address instead.
+    fn rmw_write(&mut self, addr: u16, old: u8, data: u8) {
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.mem_read(addr);
+        } else {
+            self.mem_write(addr, old);
+        }
+        self.mem_write(addr, data);
+    }
+
     fn _interrupt_nmi(&mut self) {
         self.stk_push_u16(self.pc);
         let mut flag = self.status.clone();
@@ -614,638 +860,627 @@ impl<'a> CPU<'a> {
         self.bus.tick(2);
         self.pc = self.mem_read_u16(0xfffA);
     }
-    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    /// Runs until BRK, or until the bus reports a fault its policy says to
+    /// halt on, which is returned.
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> Result<(), EmuError>
     where
-        F: FnMut(&mut CPU),
+        F: FnMut(&mut CPU<M>),
     {
         loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt(interrupt::NMI);
+            self.poll_interrupts();
+            callback(self);
+            if !self.try_step()? {
+                return Ok(());
             }
+        }
+    }
 
-            callback(self);
-            let opcode = self.mem_read(self.pc);
-            self.pc += 1;
-            let pc_state = self.pc;
-            // if self.pc == 0x8008 {
-            //     self.pc += 1;
-            // } else {
-            //     self.pc += 1;
-            // }
-            let cycles = OPCODE_CYCLES[opcode as usize];
-            // self.bus.tick(cycles);
-            // self.cycles += cycles as usize;
-            match opcode {
-                0x00 => {
-                    return;
-                }
-                // LDA
-                0xa9 => {
-                    self.lda(&AddressingMode::Immediate);
-                    self.pc += 1;
-                }
-                0xa5 => {
-                    self.lda(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0xb5 => {
-                    self.lda(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0xad => {
-                    self.lda(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0xbd => {
-                    self.lda(&AddressingMode::Absolute_X);
-                    self.pc += 2;
-                }
-                0xb9 => {
-                    self.lda(&AddressingMode::Absolute_Y);
-                    self.pc += 2;
-                }
-                0xa1 => {
-                    self.lda(&AddressingMode::Indirect_X);
-                    self.pc += 1;
-                }
-                0xb1 => {
-                    self.lda(&AddressingMode::Indirect_Y);
-                    self.pc += 1;
-                }
+    /// `step`, returning the fault that should stop the machine if the
+    /// instruction caused one.
+    pub fn try_step(&mut self) -> Result<bool, EmuError> {
+        let running = self.step();
+        match self.bus.take_fault() {
+            Some(error) => Err(error),
+            None => Ok(running),
+        }
+    }
 
-                //LDX
-                0xa2 => {
-                    self.ldx(&AddressingMode::Immediate);
-                    self.pc += 1;
-                }
-                0xa6 => {
-                    self.ldx(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0xb6 => {
-                    self.ldx(&AddressingMode::ZeroPage_Y);
-                    self.pc += 1;
-                }
-                0xae => {
-                    self.ldx(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0xbe => {
-                    self.ldx(&AddressingMode::Absolute_Y);
-                    self.pc += 2;
-                }
+    /// Services a pending NMI, if any, before the next instruction.
+    pub fn poll_interrupts(&mut self) {
+        if let Some(_nmi) = self.bus.poll_nmi_status() {
+            self.interrupt(interrupt::NMI);
+        }
+    }
 
-                // LDY
-                0xa0 => {
-                    self.ldy(&AddressingMode::Immediate);
-                    self.pc += 1;
-                }
-                0xa4 => {
-                    self.ldy(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0xb4 => {
-                    self.ldy(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0xac => {
-                    self.ldy(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0xbc => {
-                    self.ldy(&AddressingMode::Absolute_X);
-                    self.pc += 2;
-                }
+    /// Fetches and executes a single instruction at `pc`.
+    /// Returns false on BRK, which ends `run_with_callback`.
+    pub fn step(&mut self) -> bool {
+        self.bus.begin_instruction(self.pc);
+        let opcode = self.mem_read(self.pc);
+        self.pc += 1;
+        let pc_state = self.pc;
+        // if self.pc == 0x8008 {
+        //     self.pc += 1;
+        // } else {
+        //     self.pc += 1;
+        // }
+        let mut cycles = OPCODE_CYCLES[opcode as usize];
+        // self.bus.tick(cycles);
+        // self.cycles += cycles as usize;
+        match opcode {
+            0x00 => {
+                return false;
+            }
+            op if self.variant == CpuVariant::Cmos65C02 && CMOS_OPCODES.contains(&op) => {
+                cycles = self.cmos_opcode(op);
+            }
+            op if self.variant == CpuVariant::Cmos65C02 && cmos_nop(op).is_some() => {
+                let (len, nop_cycles) = cmos_nop(op).unwrap();
+                self.pc += len - 1;
+                self.bus.tick(nop_cycles);
+                return true;
+            }
+            // LDA
+            0xa9 => {
+                self.lda(&AddressingMode::Immediate);
+                self.pc += 1;
+            }
+            0xa5 => {
+                self.lda(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0xb5 => {
+                self.lda(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0xad => {
+                self.lda(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0xbd => {
+                self.lda(&AddressingMode::Absolute_X);
+                self.pc += 2;
+            }
+            0xb9 => {
+                self.lda(&AddressingMode::Absolute_Y);
+                self.pc += 2;
+            }
+            0xa1 => {
+                self.lda(&AddressingMode::Indirect_X);
+                self.pc += 1;
+            }
+            0xb1 => {
+                self.lda(&AddressingMode::Indirect_Y);
+                self.pc += 1;
+            }
 
-                /*LSR - Logical Shift Right
-                value = value >> 1, or visually: 0 -> [76543210] -> C
-                LSR shifts all of the bits of a memory value or the accumulator one position to the right,
-                moving the value of each bit into the next bit. 0 is shifted into bit 7, and bit 0 is shifted into the carry flag.
-                This is equivalent to dividing an unsigned value by 2 and rounding down, with the remainder in carry. */
-                0x4a => {
-                    self.lsr_acc();
-                }
-                0x46 => {
-                    self.lsr(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0x56 => {
-                    self.lsr(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0x4e => {
-                    self.lsr(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0x5e => {
-                    self.lsr(&AddressingMode::Absolute_X);
-                    self.pc += 2;
-                }
+            //LDX
+            0xa2 => {
+                self.ldx(&AddressingMode::Immediate);
+                self.pc += 1;
+            }
+            0xa6 => {
+                self.ldx(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0xb6 => {
+                self.ldx(&AddressingMode::ZeroPage_Y);
+                self.pc += 1;
+            }
+            0xae => {
+                self.ldx(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0xbe => {
+                self.ldx(&AddressingMode::Absolute_Y);
+                self.pc += 2;
+            }
 
-                // STA
-                0x85 => {
-                    self.sta(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0x95 => {
-                    self.sta(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0x8D => {
-                    self.sta(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0x9D => {
-                    self.sta(&AddressingMode::Absolute_X);
-                    self.pc += 2;
-                }
-                0x99 => {
-                    self.sta(&AddressingMode::Absolute_Y);
-                    self.pc += 2;
-                }
-                0x81 => {
-                    self.sta(&AddressingMode::Indirect_X);
-                    self.pc += 1;
-                }
-                0x91 => {
-                    self.sta(&AddressingMode::Indirect_Y);
-                    self.pc += 1;
-                }
+            // LDY
+            0xa0 => {
+                self.ldy(&AddressingMode::Immediate);
+                self.pc += 1;
+            }
+            0xa4 => {
+                self.ldy(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0xb4 => {
+                self.ldy(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0xac => {
+                self.ldy(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0xbc => {
+                self.ldy(&AddressingMode::Absolute_X);
+                self.pc += 2;
+            }
 
-                //STX
-                0x86 => {
-                    self.stx(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0x96 => {
-                    self.stx(&AddressingMode::ZeroPage_Y);
-                    self.pc += 1;
-                }
-                0x8E => {
-                    self.stx(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
+            /*LSR - Logical Shift Right
+            value = value >> 1, or visually: 0 -> [76543210] -> C
+            LSR shifts all of the bits of a memory value or the accumulator one position to the right,
+            moving the value of each bit into the next bit. 0 is shifted into bit 7, and bit 0 is shifted into the carry flag.
+            This is equivalent to dividing an unsigned value by 2 and rounding down, with the remainder in carry. */
+            0x4a => {
+                self.lsr_acc();
+            }
+            0x46 => {
+                self.lsr(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0x56 => {
+                self.lsr(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0x4e => {
+                self.lsr(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0x5e => {
+                self.lsr(&AddressingMode::Absolute_X);
+                self.pc += 2;
+            }
 
-                //STY
-                0x84 => {
-                    self.sty(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0x94 => {
-                    self.sty(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0x8C => {
-                    self.sty(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
+            // STA
+            0x85 => {
+                self.sta(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0x95 => {
+                self.sta(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0x8D => {
+                self.sta(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0x9D => {
+                self.sta(&AddressingMode::Absolute_X);
+                self.pc += 2;
+            }
+            0x99 => {
+                self.sta(&AddressingMode::Absolute_Y);
+                self.pc += 2;
+            }
+            0x81 => {
+                self.sta(&AddressingMode::Indirect_X);
+                self.pc += 1;
+            }
+            0x91 => {
+                self.sta(&AddressingMode::Indirect_Y);
+                self.pc += 1;
+            }
 
-                //ADC
-                0x69 => {
-                    self.adc(&AddressingMode::Immediate);
-                    self.pc += 1;
-                }
+            //STX
+            0x86 => {
+                self.stx(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0x96 => {
+                self.stx(&AddressingMode::ZeroPage_Y);
+                self.pc += 1;
+            }
+            0x8E => {
+                self.stx(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
 
-                0x65 => {
-                    self.adc(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0x75 => {
-                    self.adc(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0x6d => {
-                    self.adc(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0x7d => {
-                    self.adc(&AddressingMode::Absolute_X);
-                    self.pc += 2;
-                }
-                0x79 => {
-                    self.adc(&AddressingMode::Absolute_Y);
-                    self.pc += 2;
-                }
-                0x61 => {
-                    self.adc(&AddressingMode::Indirect_X);
-                    self.pc += 1;
-                }
-                0x71 => {
-                    self.adc(&AddressingMode::Indirect_Y);
-                    self.pc += 1;
-                }
-                /*SBC - Subtract with Carry
-                A = A - memory - ~C, or equivalently: A = A + ~memory + C
-                SBC subtracts a memory value and the bitwise NOT of carry from the accumulator.
-                It does this by adding the bitwise NOT of the memory value using ADC.*/
-                0xe9 => {
-                    self.sbc(&AddressingMode::Immediate);
-                    self.pc += 1;
-                }
+            //STY
+            0x84 => {
+                self.sty(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0x94 => {
+                self.sty(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0x8C => {
+                self.sty(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
 
-                0xe5 => {
-                    self.sbc(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0xf5 => {
-                    self.sbc(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0xed => {
-                    self.sbc(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0xfd => {
-                    self.sbc(&AddressingMode::Absolute_X);
-                    self.pc += 2;
-                }
-                0xf9 => {
-                    self.sbc(&AddressingMode::Absolute_Y);
-                    self.pc += 2;
-                }
-                0xe1 => {
-                    self.sbc(&AddressingMode::Indirect_X);
-                    self.pc += 1;
-                }
-                0xf1 => {
-                    self.sbc(&AddressingMode::Indirect_Y);
-                    self.pc += 1;
-                }
+            //ADC
+            0x69 => {
+                self.adc(&AddressingMode::Immediate);
+                self.pc += 1;
+            }
 
-                // AND
-                0x29 => {
-                    self.and(&AddressingMode::Immediate);
-                    self.pc += 1;
-                }
-                0x25 => {
-                    self.and(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0x35 => {
-                    self.and(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0x2d => {
-                    self.and(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0x3d => {
-                    self.and(&AddressingMode::Absolute_X);
-                    self.pc += 2;
-                }
-                0x39 => {
-                    self.and(&AddressingMode::Absolute_Y);
-                    self.pc += 2;
-                }
-                0x21 => {
-                    self.and(&AddressingMode::Indirect_X);
-                    self.pc += 1;
-                }
-                0x31 => {
-                    self.and(&AddressingMode::Indirect_Y);
-                    self.pc += 1;
-                }
+            0x65 => {
+                self.adc(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0x75 => {
+                self.adc(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0x6d => {
+                self.adc(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0x7d => {
+                self.adc(&AddressingMode::Absolute_X);
+                self.pc += 2;
+            }
+            0x79 => {
+                self.adc(&AddressingMode::Absolute_Y);
+                self.pc += 2;
+            }
+            0x61 => {
+                self.adc(&AddressingMode::Indirect_X);
+                self.pc += 1;
+            }
+            0x71 => {
+                self.adc(&AddressingMode::Indirect_Y);
+                self.pc += 1;
+            }
+            /*SBC - Subtract with Carry
+            A = A - memory - ~C, or equivalently: A = A + ~memory + C
+            SBC subtracts a memory value and the bitwise NOT of carry from the accumulator.
+            It does this by adding the bitwise NOT of the memory value using ADC.*/
+            0xe9 => {
+                self.sbc(&AddressingMode::Immediate);
+                self.pc += 1;
+            }
 
-                //ASL
-                0x0a => {
-                    self.asl_acc();
-                }
-                0x06 => {
-                    self.asl(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0x16 => {
-                    self.asl(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0x0E => {
-                    self.asl(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0x1E => {
-                    self.asl(&AddressingMode::Absolute_X);
-                    self.pc += 2;
-                }
+            0xe5 => {
+                self.sbc(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0xf5 => {
+                self.sbc(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0xed => {
+                self.sbc(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0xfd => {
+                self.sbc(&AddressingMode::Absolute_X);
+                self.pc += 2;
+            }
+            0xf9 => {
+                self.sbc(&AddressingMode::Absolute_Y);
+                self.pc += 2;
+            }
+            0xe1 => {
+                self.sbc(&AddressingMode::Indirect_X);
+                self.pc += 1;
+            }
+            0xf1 => {
+                self.sbc(&AddressingMode::Indirect_Y);
+                self.pc += 1;
+            }
 
-                /*ROR - Rotate Right
-                value = value >> 1 through C, or visually: C -> [76543210] -> C
-                ROR shifts a memory value or the accumulator to the right, moving the value of each
-                bit into the next bit and treating the carry flag as though it is both above bit 7 and below bit 0.
-                Specifically, the value in carry is shifted into bit 7, and bit 0 is shifted into carry.
-                Rotating right 9 times simply returns the value and carry back to their original state. */
-                0x6a => {
-                    self.ror_acc();
-                }
-                0x66 => {
-                    self.ror(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0x76 => {
-                    self.ror(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0x6E => {
-                    self.ror(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0x7E => {
-                    self.ror(&AddressingMode::Absolute_X);
-                    self.pc += 2;
-                }
+            // AND
+            0x29 => {
+                self.and(&AddressingMode::Immediate);
+                self.pc += 1;
+            }
+            0x25 => {
+                self.and(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0x35 => {
+                self.and(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0x2d => {
+                self.and(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0x3d => {
+                self.and(&AddressingMode::Absolute_X);
+                self.pc += 2;
+            }
+            0x39 => {
+                self.and(&AddressingMode::Absolute_Y);
+                self.pc += 2;
+            }
+            0x21 => {
+                self.and(&AddressingMode::Indirect_X);
+                self.pc += 1;
+            }
+            0x31 => {
+                self.and(&AddressingMode::Indirect_Y);
+                self.pc += 1;
+            }
 
-                /*ROL - Rotate Left
-                value = value << 1 through C, or visually: C <- [76543210] <- C
-                ROL shifts a memory value or the accumulator to the left, moving the value of each bit into the next bit and treating
-                the carry flag as though it is both above bit 7 and below bit 0.
-                Specifically, the value in carry is shifted into bit 0, and bit 7 is shifted into carry.
-                Rotating left 9 times simply returns the value and carry back to their original state. */
-                0x2a => {
-                    self.rol_acc();
-                }
-                0x26 => {
-                    self.rol(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0x36 => {
-                    self.rol(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0x2E => {
-                    self.rol(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0x3E => {
-                    self.rol(&AddressingMode::Absolute_X);
-                    self.pc += 2;
-                }
+            //ASL
+            0x0a => {
+                self.asl_acc();
+            }
+            0x06 => {
+                self.asl(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0x16 => {
+                self.asl(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0x0E => {
+                self.asl(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0x1E => {
+                self.asl(&AddressingMode::Absolute_X);
+                self.pc += 2;
+            }
 
-                //BCC
-                0x90 => {
-                    let offset = self.mem_read(self.pc) as i8;
-                    self.pc += 1;
-                    if !self.status.contains(CpuFlags::CARRY) {
-                        self.pc = self.pc.wrapping_add(offset as u16);
-                    }
-                }
+            /*ROR - Rotate Right
+            value = value >> 1 through C, or visually: C -> [76543210] -> C
+            ROR shifts a memory value or the accumulator to the right, moving the value of each
+            bit into the next bit and treating the carry flag as though it is both above bit 7 and below bit 0.
+            Specifically, the value in carry is shifted into bit 7, and bit 0 is shifted into carry.
+            Rotating right 9 times simply returns the value and carry back to their original state. */
+            0x6a => {
+                self.ror_acc();
+            }
+            0x66 => {
+                self.ror(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0x76 => {
+                self.ror(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0x6E => {
+                self.ror(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0x7E => {
+                self.ror(&AddressingMode::Absolute_X);
+                self.pc += 2;
+            }
 
-                //BCS
-                0xB0 => {
-                    let offset = self.mem_read(self.pc) as i8;
-                    self.pc += 1;
-                    if self.status.contains(CpuFlags::CARRY) {
-                        self.pc = self.pc.wrapping_add(offset as u16);
-                    }
-                }
+            /*ROL - Rotate Left
+            value = value << 1 through C, or visually: C <- [76543210] <- C
+            ROL shifts a memory value or the accumulator to the left, moving the value of each bit into the next bit and treating
+            the carry flag as though it is both above bit 7 and below bit 0.
+            Specifically, the value in carry is shifted into bit 0, and bit 7 is shifted into carry.
+            Rotating left 9 times simply returns the value and carry back to their original state. */
+            0x2a => {
+                self.rol_acc();
+            }
+            0x26 => {
+                self.rol(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0x36 => {
+                self.rol(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0x2E => {
+                self.rol(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0x3E => {
+                self.rol(&AddressingMode::Absolute_X);
+                self.pc += 2;
+            }
 
-                //BEQ
-                0xF0 => {
-                    let offset = self.mem_read(self.pc) as i8;
-                    self.pc += 1;
-                    if self.status.contains(CpuFlags::ZERO) {
-                        self.pc = self.pc.wrapping_add(offset as u16);
-                    }
-                }
+            //BCC
+            0x90 => self.branch(!self.status.contains(CpuFlags::CARRY)),
 
-                //BMI
-                0x30 => {
-                    let offset = self.mem_read(self.pc) as i8;
-                    self.pc += 1;
-                    if self.status.contains(CpuFlags::NEGATIVE) {
-                        self.pc = self.pc.wrapping_add(offset as u16);
-                    }
-                }
-                //BNE
-                0xD0 => {
-                    let offset = self.mem_read(self.pc) as i8;
-                    self.pc += 1;
-                    if !self.status.contains(CpuFlags::ZERO) {
-                        self.pc = self.pc.wrapping_add(offset as u16);
-                    }
-                }
+            //BCS
+            0xB0 => self.branch(self.status.contains(CpuFlags::CARRY)),
 
-                //BPL
-                0x10 => {
-                    let offset = self.mem_read(self.pc) as i8;
-                    self.pc += 1;
-                    if !self.status.contains(CpuFlags::NEGATIVE) {
-                        self.pc = self.pc.wrapping_add(offset as u16);
-                    }
-                }
+            //BEQ
+            0xF0 => self.branch(self.status.contains(CpuFlags::ZERO)),
 
-                //BVC
-                0x50 => {
-                    let offset = self.mem_read(self.pc) as i8;
-                    self.pc += 1;
-                    if !self.status.contains(CpuFlags::OVERFLOW) {
-                        self.pc = self.pc.wrapping_add(offset as u16);
-                    }
-                }
-                //BVS
-                0x70 => {
-                    let offset = self.mem_read(self.pc) as i8;
-                    self.pc += 1;
-                    if self.status.contains(CpuFlags::OVERFLOW) {
-                        self.pc = self.pc.wrapping_add(offset as u16);
-                    }
-                }
+            //BMI
+            0x30 => self.branch(self.status.contains(CpuFlags::NEGATIVE)),
+            //BNE
+            0xD0 => self.branch(!self.status.contains(CpuFlags::ZERO)),
 
-                //CLC
-                0x18 => {
-                    self.status.remove(CpuFlags::CARRY);
-                }
-                //SEC
-                0x38 => {
-                    self.status.insert(CpuFlags::CARRY);
-                }
+            //BPL
+            0x10 => self.branch(!self.status.contains(CpuFlags::NEGATIVE)),
 
-                //CLI
-                0x58 => {
-                    self.status.remove(CpuFlags::INTERRUPT_DISABLE);
-                }
+            //BVC
+            0x50 => self.branch(!self.status.contains(CpuFlags::OVERFLOW)),
+            //BVS
+            0x70 => self.branch(self.status.contains(CpuFlags::OVERFLOW)),
 
-                //CLV
-                0xB8 => {
-                    self.status.remove(CpuFlags::OVERFLOW);
-                }
+            //CLC
+            0x18 => {
+                self.status.remove(CpuFlags::CARRY);
+            }
+            //SEC
+            0x38 => {
+                self.status.insert(CpuFlags::CARRY);
+            }
 
-                //BIT
-                0x24 => {
-                    self.bit(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0x2C => {
-                    self.bit(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
+            //CLI
+            0x58 => {
+                self.status.remove(CpuFlags::INTERRUPT_DISABLE);
+            }
 
-                //CompareA
-                0xc9 => {
-                    self.compare(&AddressingMode::Immediate, self.reg_a);
-                    self.pc += 1;
-                }
-                0xc5 => {
-                    self.compare(&AddressingMode::ZeroPage, self.reg_a);
-                    self.pc += 1;
-                }
-                0xd5 => {
-                    self.compare(&AddressingMode::ZeroPage_X, self.reg_a);
-                    self.pc += 1;
-                }
-                0xcd => {
-                    self.compare(&AddressingMode::Absolute, self.reg_a);
-                    self.pc += 2;
-                }
-                0xdd => {
-                    self.compare(&AddressingMode::Absolute_X, self.reg_a);
-                    self.pc += 2;
-                }
-                0xd9 => {
-                    self.compare(&AddressingMode::Absolute_Y, self.reg_a);
-                    self.pc += 2;
-                }
-                0xc1 => {
-                    self.compare(&AddressingMode::Indirect_X, self.reg_a);
-                    self.pc += 1;
-                }
-                0xd1 => {
-                    self.compare(&AddressingMode::Indirect_Y, self.reg_a);
-                    self.pc += 1;
-                }
+            //CLV
+            0xB8 => {
+                self.status.remove(CpuFlags::OVERFLOW);
+            }
 
-                //CompareX
-                0xe0 => {
-                    self.compare(&AddressingMode::Immediate, self.reg_x);
-                    self.pc += 1;
-                }
-                0xe4 => {
-                    self.compare(&AddressingMode::ZeroPage, self.reg_x);
-                    self.pc += 1;
-                }
-                0xec => {
-                    self.compare(&AddressingMode::Absolute, self.reg_x);
-                    self.pc += 2;
-                }
+            //BIT
+            0x24 => {
+                self.bit(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0x2C => {
+                self.bit(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
 
-                //CompareY
-                0xc0 => {
-                    self.compare(&AddressingMode::Immediate, self.reg_y);
-                    self.pc += 1;
-                }
-                0xc4 => {
-                    self.compare(&AddressingMode::ZeroPage, self.reg_y);
-                    self.pc += 1;
-                }
-                0xcc => {
-                    self.compare(&AddressingMode::Absolute, self.reg_y);
-                    self.pc += 2;
-                }
+            //CompareA
+            0xc9 => {
+                self.compare(&AddressingMode::Immediate, self.reg_a);
+                self.pc += 1;
+            }
+            0xc5 => {
+                self.compare(&AddressingMode::ZeroPage, self.reg_a);
+                self.pc += 1;
+            }
+            0xd5 => {
+                self.compare(&AddressingMode::ZeroPage_X, self.reg_a);
+                self.pc += 1;
+            }
+            0xcd => {
+                self.compare(&AddressingMode::Absolute, self.reg_a);
+                self.pc += 2;
+            }
+            0xdd => {
+                self.compare(&AddressingMode::Absolute_X, self.reg_a);
+                self.pc += 2;
+            }
+            0xd9 => {
+                self.compare(&AddressingMode::Absolute_Y, self.reg_a);
+                self.pc += 2;
+            }
+            0xc1 => {
+                self.compare(&AddressingMode::Indirect_X, self.reg_a);
+                self.pc += 1;
+            }
+            0xd1 => {
+                self.compare(&AddressingMode::Indirect_Y, self.reg_a);
+                self.pc += 1;
+            }
 
-                //DEC
-                0xc6 => {
-                    self.dec(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
+            //CompareX
+            0xe0 => {
+                self.compare(&AddressingMode::Immediate, self.reg_x);
+                self.pc += 1;
+            }
+            0xe4 => {
+                self.compare(&AddressingMode::ZeroPage, self.reg_x);
+                self.pc += 1;
+            }
+            0xec => {
+                self.compare(&AddressingMode::Absolute, self.reg_x);
+                self.pc += 2;
+            }
 
-                0xd6 => {
-                    self.dec(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0xce => {
-                    self.dec(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0xde => {
-                    self.dec(&AddressingMode::Absolute_X);
-                    self.pc += 2;
-                }
+            //CompareY
+            0xc0 => {
+                self.compare(&AddressingMode::Immediate, self.reg_y);
+                self.pc += 1;
+            }
+            0xc4 => {
+                self.compare(&AddressingMode::ZeroPage, self.reg_y);
+                self.pc += 1;
+            }
+            0xcc => {
+                self.compare(&AddressingMode::Absolute, self.reg_y);
+                self.pc += 2;
+            }
 
-                //DECX
-                0xCA => {
-                    self.dex();
-                }
+            //DEC
+            0xc6 => {
+                self.dec(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
 
-                //DECY
-                0x88 => {
-                    self.dey();
-                }
+            0xd6 => {
+                self.dec(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0xce => {
+                self.dec(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0xde => {
+                self.dec(&AddressingMode::Absolute_X);
+                self.pc += 2;
+            }
 
-                //EOR
-                0x49 => {
-                    self.eor(&AddressingMode::Immediate);
-                    self.pc += 1;
-                }
-                0x45 => {
-                    self.eor(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0x55 => {
-                    self.eor(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0x4d => {
-                    self.eor(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0x5d => {
-                    self.eor(&AddressingMode::Absolute_X);
-                    self.pc += 2;
-                }
-                0x59 => {
-                    self.eor(&AddressingMode::Absolute_Y);
-                    self.pc += 2;
-                }
-                0x41 => {
-                    self.eor(&AddressingMode::Indirect_X);
-                    self.pc += 1;
-                }
-                0x51 => {
-                    self.eor(&AddressingMode::Indirect_Y);
-                    self.pc += 1;
-                }
+            //DECX
+            0xCA => {
+                self.dex();
+            }
 
-                //INC
-                0xe6 => {
-                    self.inc(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
+            //DECY
+            0x88 => {
+                self.dey();
+            }
 
-                0xf6 => {
-                    self.inc(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0xee => {
-                    self.inc(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0xfe => {
-                    self.inc(&AddressingMode::Absolute_X);
-                    self.pc += 2;
-                }
+            //EOR
+            0x49 => {
+                self.eor(&AddressingMode::Immediate);
+                self.pc += 1;
+            }
+            0x45 => {
+                self.eor(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0x55 => {
+                self.eor(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0x4d => {
+                self.eor(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0x5d => {
+                self.eor(&AddressingMode::Absolute_X);
+                self.pc += 2;
+            }
+            0x59 => {
+                self.eor(&AddressingMode::Absolute_Y);
+                self.pc += 2;
+            }
+            0x41 => {
+                self.eor(&AddressingMode::Indirect_X);
+                self.pc += 1;
+            }
+            0x51 => {
+                self.eor(&AddressingMode::Indirect_Y);
+                self.pc += 1;
+            }
 
-                //INCX
-                0xe8 => {
-                    self.incx();
-                }
+            //INC
+            0xe6 => {
+                self.inc(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
 
-                //INCY
-                0xc8 => {
-                    self.incy();
-                }
+            0xf6 => {
+                self.inc(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0xee => {
+                self.inc(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0xfe => {
+                self.inc(&AddressingMode::Absolute_X);
+                self.pc += 2;
+            }
 
-                /*JMP - Jump
-                PC = memory
-                JMP sets the program counter to a new value, allowing code to execute from a new location.
-                */
-                //Absolute
-                0x4c => {
-                    let mem_addr = self.mem_read_u16(self.pc);
-                    self.pc = mem_addr;
-                }
-                //Indirect
-                0x6c => {
-                    let mem_addr = self.mem_read_u16(self.pc);
+            //INCX
+            0xe8 => {
+                self.incx();
+            }
+
+            //INCY
+            0xc8 => {
+                self.incy();
+            }
 
-                    let indirect_ref = if mem_addr & 0x00ff == 0x00ff {
+            /*JMP - Jump
+            PC = memory
+            JMP sets the program counter to a new value, allowing code to execute from a new location.
+            */
+            //Absolute
+            0x4c => {
+                let mem_addr = self.mem_read_u16(self.pc);
+                self.pc = mem_addr;
+            }
+            //Indirect
+            0x6c => {
+                let mem_addr = self.mem_read_u16(self.pc);
+
+                // the 65C02 fixed the page wrap bug at the cost of a cycle
+                let indirect_ref =
+                    if mem_addr & 0x00ff == 0x00ff && self.variant != CpuVariant::Cmos65C02 {
                         // this checks if we are at end of the page i.e.  0x30ff & 0x00ff => 0x00ff
                         let lo = self.mem_read(mem_addr);
                         let hi = self.mem_read(mem_addr & 0xff00);
@@ -1253,146 +1488,292 @@ impl<'a> CPU<'a> {
                     } else {
                         self.mem_read_u16(mem_addr)
                     };
-                    self.pc = indirect_ref;
+                self.pc = indirect_ref;
+                if self.variant == CpuVariant::Cmos65C02 {
+                    self.bus.tick(1);
                 }
+            }
 
-                /*JSR - Jump to Subroutine
-                push PC + 2 to stack
-                PC = memory
-                JSR pushes the current program counter to the stack and then sets the program counter to a new value.
-                This allows code to call a function and return with RTS back to the instruction after the JSR. */
-                0x20 => {
-                    self.stk_push_u16(self.pc + 2 - 1);
-                    let target = self.mem_read_u16(self.pc);
-                    self.pc = target
-                }
+            /*JSR - Jump to Subroutine
+            push PC + 2 to stack
+            PC = memory
+            JSR pushes the current program counter to the stack and then sets the program counter to a new value.
+            This allows code to call a function and return with RTS back to the instruction after the JSR. */
+            0x20 => {
+                self.stk_push_u16(self.pc + 2 - 1);
+                let target = self.mem_read_u16(self.pc);
+                self.pc = target
+            }
 
-                /* RTS - Return from Subroutine
-                pull PC from stack
-                PC = PC + 1
-                RTS pulls an address from the stack into the program counter and then increments the program counter.
-                It is normally used at the end of a function to return to the instruction after the JSR that called the function.
-                However, RTS is also sometimes used to implement jump tables (see Jump table and RTS Trick).
-                */
-                0x60 => {
-                    self.pc = self.stk_pop_u16() + 1;
-                }
+            /* RTS - Return from Subroutine
+            pull PC from stack
+            PC = PC + 1
+            RTS pulls an address from the stack into the program counter and then increments the program counter.
+            It is normally used at the end of a function to return to the instruction after the JSR that called the function.
+            However, RTS is also sometimes used to implement jump tables (see Jump table and RTS Trick).
+            */
+            0x60 => {
+                self.pc = self.stk_pop_u16() + 1;
+            }
 
-                //ORA
-                0x09 => {
-                    self.ora(&AddressingMode::Immediate);
-                    self.pc += 1;
-                }
-                0x05 => {
-                    self.ora(&AddressingMode::ZeroPage);
-                    self.pc += 1;
-                }
-                0x15 => {
-                    self.ora(&AddressingMode::ZeroPage_X);
-                    self.pc += 1;
-                }
-                0x0d => {
-                    self.ora(&AddressingMode::Absolute);
-                    self.pc += 2;
-                }
-                0x1d => {
-                    self.ora(&AddressingMode::Absolute_X);
-                    self.pc += 2;
-                }
-                0x19 => {
-                    self.ora(&AddressingMode::Absolute_Y);
-                    self.pc += 2;
-                }
-                0x01 => {
-                    self.ora(&AddressingMode::Indirect_X);
-                    self.pc += 1;
-                }
-                0x11 => {
-                    self.ora(&AddressingMode::Indirect_Y);
-                    self.pc += 1;
-                }
+            //ORA
+            0x09 => {
+                self.ora(&AddressingMode::Immediate);
+                self.pc += 1;
+            }
+            0x05 => {
+                self.ora(&AddressingMode::ZeroPage);
+                self.pc += 1;
+            }
+            0x15 => {
+                self.ora(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+            }
+            0x0d => {
+                self.ora(&AddressingMode::Absolute);
+                self.pc += 2;
+            }
+            0x1d => {
+                self.ora(&AddressingMode::Absolute_X);
+                self.pc += 2;
+            }
+            0x19 => {
+                self.ora(&AddressingMode::Absolute_Y);
+                self.pc += 2;
+            }
+            0x01 => {
+                self.ora(&AddressingMode::Indirect_X);
+                self.pc += 1;
+            }
+            0x11 => {
+                self.ora(&AddressingMode::Indirect_Y);
+                self.pc += 1;
+            }
 
-                //TAX
-                0xaa => {
-                    self.tax();
-                }
-                //TXA
-                0x8a => {
-                    self.txa();
-                }
-                //TAY
-                0xa8 => {
-                    self.tay();
-                }
-                //TYA
-                0x98 => {
-                    self.tya();
-                }
+            //TAX
+            0xaa => {
+                self.tax();
+            }
+            //TXA
+            0x8a => {
+                self.txa();
+            }
+            //TAY
+            0xa8 => {
+                self.tay();
+            }
+            //TYA
+            0x98 => {
+                self.tya();
+            }
 
-                //PHA
-                0x48 => {
-                    self.pha();
-                }
-                //PLA
-                0x68 => {
-                    self.pla();
-                }
-                //PLP
-                0x28 => {
-                    self.plp();
-                }
-                // PHP
-                0x08 => {
-                    self.php();
-                }
+            //PHA
+            0x48 => {
+                self.pha();
+            }
+            //PLA
+            0x68 => {
+                self.pla();
+            }
+            //PLP
+            0x28 => {
+                self.plp();
+            }
+            // PHP
+            0x08 => {
+                self.php();
+            }
 
-                //TXS
-                0x9a => {
-                    self.txs();
-                }
-                //TSX
-                0xba => {
-                    self.tsx();
-                }
+            //TXS
+            0x9a => {
+                self.txs();
+            }
+            //TSX
+            0xba => {
+                self.tsx();
+            }
 
-                // SEI
-                0x78 => {
-                    self.sei();
-                }
+            // SEI
+            0x78 => {
+                self.sei();
+            }
 
-                // SED
-                0xF8 => {
-                    self.sed();
-                }
+            // SED
+            0xF8 => {
+                self.sed();
+            }
 
-                // CLD
-                0xD8 => {
-                    self.cld();
-                }
+            // CLD
+            0xD8 => {
+                self.cld();
+            }
 
-                // NOP
-                0xEA => {}
-                //RTI
-                0x40 => {
-                    self.rti();
-                }
+            // NOP
+            0xEA => {}
+            //RTI
+            0x40 => {
+                self.rti();
+            }
+
+            _ => {
+                // runs on as a NOP of the opcode's length if the policy lets it
+                self.bus.fault(EmuError::IllegalOpcode {
+                    pc: pc_state - 1,
+                    opcode,
+                });
+                let len = opcodes::lookup(opcode).size() as u16;
+                self.pc = self.pc.wrapping_add(len - 1);
+                self.bus.tick(cycles);
+                return true;
+            }
+        }
+        self.bus.tick(cycles);
+        if pc_state == self.pc {
+            let len = match opcode {
+                0xaa | 0xe8 | 0x00 | 0xd8 | 0x58 | 0xb8 | 0x18 | 0x38 | 0x78 | 0xf8 | 0x48
+                | 0x68 | 0x08 | 0x28 | 0x4a | 0x0a | 0x2a | 0x6a | 0xc8 | 0xca | 0x88 | 0xea
+                | 0xa8 | 0xba | 0x8a | 0x9a | 0x98 | 0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => 1,
+                _ => 2,
+            };
+            self.pc += (len - 1) as u16;
+        }
+        true
+    }
+
+    /// Executes one of the instructions the 65C02 added and returns its cycle count.
+    fn cmos_opcode(&mut self, opcode: u8) -> u8 {
+        match opcode {
+            //BRA
+            0x80 => {
+                let offset = self.mem_read(self.pc) as i8;
+                self.pc += 1;
+                let target = self.pc.wrapping_add(offset as u16);
+                let cycles = if page_cross(self.pc, target) { 4 } else { 3 };
+                self.pc = target;
+                cycles
+            }
+
+            //PHX, PHY, PLX, PLY
+            0xda => {
+                self.stk_push(self.reg_x);
+                3
+            }
+            0x5a => {
+                self.stk_push(self.reg_y);
+                3
+            }
+            0xfa => {
+                self.reg_x = self.stk_pop();
+                self.update_zero_and_neg_flag(self.reg_x);
+                4
+            }
+            0x7a => {
+                self.reg_y = self.stk_pop();
+                self.update_zero_and_neg_flag(self.reg_y);
+                4
+            }
+
+            //INC A, DEC A
+            0x1a => {
+                self.reg_a = self.reg_a.wrapping_add(1);
+                self.update_zero_and_neg_flag(self.reg_a);
+                2
+            }
+            0x3a => {
+                self.reg_a = self.reg_a.wrapping_sub(1);
+                self.update_zero_and_neg_flag(self.reg_a);
+                2
+            }
 
-                _ => todo!("implement this"),
+            //STZ
+            0x64 => self.stz(&AddressingMode::ZeroPage, 3),
+            0x74 => self.stz(&AddressingMode::ZeroPage_X, 4),
+            0x9c => self.stz(&AddressingMode::Absolute, 4),
+            0x9e => self.stz(&AddressingMode::Absolute_X, 5),
+
+            //TSB, TRB
+            0x04 => self.test_and_modify_bits(&AddressingMode::ZeroPage, true, 5),
+            0x0c => self.test_and_modify_bits(&AddressingMode::Absolute, true, 6),
+            0x14 => self.test_and_modify_bits(&AddressingMode::ZeroPage, false, 5),
+            0x1c => self.test_and_modify_bits(&AddressingMode::Absolute, false, 6),
+
+            //BIT #imm only touches Z
+            0x89 => {
+                let data = self.mem_read(self.pc);
+                self.status.set(CpuFlags::ZERO, self.reg_a & data == 0);
+                self.pc += 1;
+                2
             }
-            self.bus.tick(cycles);
-            if pc_state == self.pc {
-                let len = match opcode {
-                    0xaa | 0xe8 | 0x00 | 0xd8 | 0x58 | 0xb8 | 0x18 | 0x38 | 0x78 | 0xf8 | 0x48
-                    | 0x68 | 0x08 | 0x28 | 0x4a | 0x0a | 0x2a | 0x6a | 0xc8 | 0xca | 0x88
-                    | 0xea | 0xa8 | 0xba | 0x8a | 0x9a | 0x98 | 0x1a | 0x3a | 0x5a | 0x7a
-                    | 0xda | 0xfa => 1,
-                    _ => 2,
-                };
-                self.pc += (len - 1) as u16;
+            0x34 => {
+                self.bit(&AddressingMode::ZeroPage_X);
+                self.pc += 1;
+                4
             }
+            0x3c => {
+                self.bit(&AddressingMode::Absolute_X);
+                self.pc += 2;
+                4
+            }
+
+            //JMP (abs,X)
+            0x7c => {
+                let base = self.mem_read_u16(self.pc);
+                self.pc = self.mem_read_u16(base.wrapping_add(self.reg_x as u16));
+                6
+            }
+
+            //(zp) addressing for the ALU group
+            0x12 => self.zero_page_indirect(Self::ora),
+            0x32 => self.zero_page_indirect(Self::and),
+            0x52 => self.zero_page_indirect(Self::eor),
+            0x72 => self.zero_page_indirect(Self::adc),
+            0x92 => self.zero_page_indirect(Self::sta),
+            0xb2 => self.zero_page_indirect(Self::lda),
+            0xd2 => {
+                self.compare(&AddressingMode::ZeroPage_Indirect, self.reg_a);
+                self.pc += 1;
+                5
+            }
+            0xf2 => self.zero_page_indirect(Self::sbc),
+
+            _ => unreachable!("{:02x} is not a 65C02 opcode", opcode),
         }
     }
 
+    fn zero_page_indirect(&mut self, op: fn(&mut Self, &AddressingMode)) -> u8 {
+        op(self, &AddressingMode::ZeroPage_Indirect);
+        self.pc += 1;
+        5
+    }
+
+    fn stz(&mut self, mode: &AddressingMode, cycles: u8) -> u8 {
+        let addr = self.get_write_operand_address(mode);
+        self.mem_write(addr, 0);
+        self.pc += match mode {
+            AddressingMode::ZeroPage | AddressingMode::ZeroPage_X => 1,
+            _ => 2,
+        };
+        cycles
+    }
+
+    // TSB sets and TRB clears the bits of A in memory. Z reflects A & M before the change.
+    fn test_and_modify_bits(&mut self, mode: &AddressingMode, set: bool, cycles: u8) -> u8 {
+        let addr = self.get_write_operand_address(mode);
+        let data = self.mem_read(addr);
+        self.status.set(CpuFlags::ZERO, self.reg_a & data == 0);
+        let res = if set {
+            data | self.reg_a
+        } else {
+            data & !self.reg_a
+        };
+        self.rmw_write(addr, data, res);
+        self.pc += match mode {
+            AddressingMode::ZeroPage => 1,
+            _ => 2,
+        };
+        cycles
+    }
+
     fn interrupt(&mut self, interrupt: interrupt::Interrupt) {
         self.stk_push_u16(self.pc);
         let mut flag = self.status.clone();
@@ -1401,318 +1782,560 @@ impl<'a> CPU<'a> {
 
         self.stk_push(flag.bits);
         self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.status.remove(CpuFlags::DECIMAL_MODE);
+        }
 
         self.bus.tick(interrupt.cpu_cycles);
         self.pc = self.mem_read_u16(interrupt.vector_addr);
     }
 
+    /// Runs until BRK or a fault that halts; `run_with_callback` says which.
     pub fn run(&mut self) {
-        self.run_with_callback(|_| {});
+        let _ = self.run_with_callback(|_| {});
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::bus::Bus;
+    use crate::comp::memory::FlatMemory;
+    use crate::comp::rom::test::test_rom;
+
+    #[test]
+    fn test_0xa9_lda_immediate_load_data() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        cpu.load_and_run(vec![0xa9, 0x05, 0x00]);
+        assert_eq!(cpu.reg_a, 0x05);
+        assert!(cpu.status.bits() & 0b0000_0010 == 0b00);
+        assert!(cpu.status.bits() & 0b1000_0000 == 0);
+    }
+
+    #[test]
+    fn test_0xa9_lda_zero_flag() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        cpu.load_and_run(vec![0xa9, 0x00, 0x00]);
+        assert!(cpu.status.bits() & 0b0000_0010 == 0b10);
+    }
+    #[test]
+    fn test_lda_from_memory() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        cpu.mem_write(0x10, 0x55);
+        cpu.load_and_run(vec![0xa5, 0x10, 0x00]);
+        assert_eq!(cpu.reg_a, 0x55);
+    }
+
+    #[test]
+    fn test_0xaa_tax_move_a_to_x() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        cpu.load_and_run(vec![0xa9, 10, 0xaa, 0x00]);
+        assert_eq!(cpu.reg_x, 10);
+    }
+
+    #[test]
+    fn test_5_ops_working_together() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
+        // println!("{} ,{}",cpu.reg_x,cpu.reg_a);
+        assert_eq!(cpu.reg_x, 0xc1)
+    }
+
+    #[test]
+    fn test_inx_overflow() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        cpu.reg_x = 0xff;
+        cpu.load_and_run(vec![0xe8, 0x00]);
+        assert_eq!(cpu.reg_x, 1)
+    }
+
+    #[test]
+    fn test_sta_absolute_x() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        // LDX #$0F
+        // LDA #$DD
+        // STA $0200,X ; Store at $0200 + $0F = $020F
+        cpu.load_and_run(vec![0xa2, 0x0f, 0xa9, 0xdd, 0x9d, 0x00, 0x02, 0x00]);
+        assert_eq!(cpu.mem_read(0x020f), 0xdd);
+    }
+
+    #[test]
+    fn test_lda_indirect_y() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        // Set up the indirect address pointer at zero-page location $F0.
+        // It will point to $0300.
+        cpu.mem_write_u16(0xf0, 0x0300);
+        // The value to be loaded is at $0300 + Y.
+        cpu.mem_write(0x0305, 0xAB);
+
+        // LDY #$05
+        // LDA ($F0),Y ; Load from address pointed to by $F0, offset by Y.
+        let program = vec![0xa0, 0x05, 0xb1, 0xf0, 0x00];
+        cpu.load_and_run(program);
+
+        assert_eq!(cpu.reg_a, 0xab);
+    }
+    #[test]
+    fn test_bne_branch_taken() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        // A simple program to check if BNE skips the next instruction.
+        // LDX #$01 ; X is not zero, so Zero flag is clear.
+        // BNE +2   ; Branch should be taken, skipping the INX.
+        // INX      ; This should be skipped.
+        // BRK
+        cpu.load_and_run(vec![0xa2, 0x01, 0xd0, 0x01, 0xe8, 0x00]);
+        assert_eq!(cpu.reg_x, 1, "X should be 1, as INX was skipped");
+    }
+
+    #[test]
+    fn test_bne_branch_not_taken() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        // DEX will make X=0, setting the Zero flag.
+        // BNE should not be taken.
+        // LDX #$01
+        // DEX      ; X becomes 0, Z flag set
+        // BNE +2   ; Branch not taken
+        // INX      ; This should execute
+        // BRK
+        cpu.load_and_run(vec![0xa2, 0x01, 0xca, 0xd0, 0x01, 0xe8, 0x00]);
+        assert_eq!(cpu.reg_x, 1, "X should be 1, as INX was executed");
+    }
+
+    #[test]
+    fn test_compare_and_branch_loop() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        // A simple countdown loop.
+        // LDX #$03
+        // LOOP:
+        // DEX
+        // CPX #$00
+        // BNE LOOP
+        // BRK
+        // Program in hex: A2 03 CA E0 00 D0 FB 00
+        // FB is the 2's complement of -5 bytes.
+        let program = vec![0xa2, 0x03, 0xca, 0xe0, 0x00, 0xd0, 0xfb, 0x00];
+        cpu.load_and_run(program);
+        assert_eq!(cpu.reg_x, 0, "Loop should terminate when X is 0");
+    }
+
+    #[test]
+    fn test_compare_flags() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        // LDA #$10, CMP #$20 (A < M)
+        // Carry should be clear, Zero clear, Negative set.
+        cpu.load_and_run(vec![0xa9, 0x10, 0xc9, 0x20, 0x00]);
+        assert!(
+            !cpu.status.contains(CpuFlags::CARRY),
+            "A < M, Carry should be clear"
+        );
+        assert!(
+            cpu.status.bits() & 0b0000_0010 == 0,
+            "A != M, Zero should be clear"
+        );
+        assert!(
+            cpu.status.bits() & 0b1000_0000 != 0,
+            "Result is negative, Negative flag should be set"
+        );
+    }
+    #[test]
+    fn test_asl_accumulator() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        // LDA #$C1 (11000001)
+        // ASL A
+        // Result in A should be 10000010 (0x82).
+        // Carry should be set (from original bit 7).
+        // Negative should be set (from new bit 7).
+        cpu.load_and_run(vec![0xa9, 0xc1, 0x0a, 0x00]);
+        assert_eq!(cpu.reg_a, 0x82);
+        assert!(
+            cpu.status.contains(CpuFlags::CARRY),
+            "Carry flag should be set"
+        );
+        assert!(
+            cpu.status.bits() & 0b1000_0000 != 0,
+            "Negative flag should be set"
+        );
+    }
+
+    #[test]
+    fn test_lsr_memory_and_flags() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        cpu.mem_write(0x20, 0x01);
+        cpu.load_and_run(vec![0x46, 0x20, 0x00]);
+
+        assert_eq!(cpu.mem_read(0x20), 0x00);
+        assert!(
+            cpu.status.contains(CpuFlags::CARRY),
+            "Carry flag should be set"
+        );
+        assert!(
+            cpu.status.bits() & 0b0000_0010 != 0,
+            "Zero flag should be set"
+        );
+    }
+
+    #[test]
+    fn test_rol_accumulator_with_carry() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        cpu.load_and_run(vec![0x38, 0xa9, 0x7f, 0x2a, 0x00]);
+
+        assert_eq!(cpu.reg_a, 0xff, "Accumulator should be 255");
+        assert!(
+            !cpu.status.contains(CpuFlags::CARRY),
+            "Carry flag should be cleared"
+        );
+        assert!(
+            cpu.status.bits() & 0b1000_0000 != 0,
+            "Negative flag should be set"
+        );
+    }
+
+    #[test]
+    fn test_ror_memory() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        cpu.mem_write(0x33, 0x02); // 00000010
+        // SEC (set carry)
+        // ROR $33
+        // Result at $33: 10000001 (0x81).
+        // Carry is cleared (from old bit 0).
+        cpu.load_and_run(vec![0x38, 0x6e, 0x33, 0x00]);
+        assert_eq!(cpu.mem_read(0x33), 0x81);
+        assert!(
+            !cpu.status.contains(CpuFlags::CARRY),
+            "Carry flag should be cleared"
+        );
+    }
+    #[test]
+    fn test_and_immediate() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        // LDA #$CF (11001111)
+        // AND #$3A (00111010)
+        // Result should be 00001010 = 0x0A
+        cpu.load_and_run(vec![0xa9, 0xcf, 0x29, 0x3a, 0x00]);
+        assert_eq!(cpu.reg_a, 0x0a);
+        assert!(
+            cpu.status.bits() & 0b0000_0010 == 0,
+            "Zero flag should not be set"
+        );
+    }
+
+    #[test]
+    fn test_eor_and_zero_flag() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        // LDA #$55
+        // EOR #$55
+        // Result should be 0, setting the Zero flag.
+        cpu.load_and_run(vec![0xa9, 0x55, 0x49, 0x55, 0x00]);
+        assert_eq!(cpu.reg_a, 0x00);
+        assert!(
+            cpu.status.bits() & 0b0000_0010 != 0,
+            "Zero flag should be set"
+        );
+    }
+
+    #[test]
+    fn test_bit_instruction() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        cpu.mem_write(0x20, 0b1100_0000); // Value to test against
+        // LDA #$01
+        // BIT $20
+        // Tests accumulator (0x01) against memory (0xC0).
+        // A & M = 0, so Zero flag (Z) is set.
+        // Bit 7 of M is 1, so Negative flag (N) is set.
+        // Bit 6 of M is 1, so Overflow flag (V) is set.
+        cpu.load_and_run(vec![0xa9, 0x01, 0x24, 0x20, 0x00]);
+        assert!(
+            cpu.status.bits() & 0b0000_0010 != 0,
+            "Zero flag should be set"
+        );
+        assert!(
+            cpu.status.bits() & 0b1000_0000 != 0,
+            "Negative flag should be set from mem bit 7"
+        );
+        assert!(
+            cpu.status.bits() & 0b0100_0000 != 0,
+            "Overflow flag should be set from mem bit 6"
+        );
+    }
+    #[test]
+    fn test_adc_simple_add_with_overflow() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        // Load accumulator with 127 (0x7F)
+        // Add 1
+        // Result should be 128 (0x80), setting Overflow and Negative flags.
+        cpu.load_and_run(vec![0xa9, 0x7f, 0x69, 0x01, 0x00]);
+        assert_eq!(cpu.reg_a, 0x80);
+        assert!(
+            cpu.status.bits() & 0b0100_0000 != 0,
+            "Overflow flag should be set"
+        );
+        assert!(
+            cpu.status.bits() & 0b1000_0000 != 0,
+            "Negative flag should be set"
+        );
+    }
+
+    #[test]
+    fn test_sbc_with_borrow() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        // CLC (clears carry, enabling borrow for SBC)
+        // LDA #$05
+        // SBC #$03
+        // Result should be 5 - 3 - 1 = 1
+        cpu.load_and_run(vec![0x18, 0xa9, 0x05, 0xe9, 0x03, 0x00]);
+        assert_eq!(cpu.reg_a, 1);
+    }
+
+    #[test]
+    fn test_sbc_no_borrow() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        // SEC (sets carry, disabling borrow for SBC)
+        // LDA #$05
+        // SBC #$03
+        // Result should be 5 - 3 = 2
+        cpu.load_and_run(vec![0x38, 0xa9, 0x05, 0xe9, 0x03, 0x00]);
+        assert_eq!(cpu.reg_a, 2);
+    }
+    #[test]
+    fn test_jsr_and_rts() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        // The program consists of a main routine and a subroutine.
+        // Main routine:
+        // 0x8000: LDA #$05   ; Load 5 into accumulator
+        // 0x8002: JSR $800A  ; Jump to subroutine at address 0x800A
+        // 0x8005: LDA #$0F   ; After returning, load 15 into accumulator
+        // 0x8007: BRK        ; Halt
+        // Subroutine:
+        // 0x800A: LDA #$AA   ; Load 170 into accumulator
+        // 0x800C: STA $0200  ; Store accumulator's value at memory location 0x0200
+        // 0x800F: RTS        ; Return from subroutine
+        let program = vec![
+            0xa9, 0x05, // LDA #$05
+            0x20, 0x0a, 0x80, // JSR $800A
+            0xa9, 0x0f, // LDA #$0F
+            0x00, // BRK
+            0x00, 0x00, // Padding to align subroutine
+            0xa9, 0xaa, // Subroutine starts here (0x800A)
+            0x8d, 0x00, 0x02, // STA $0200
+            0x60, // RTS
+        ];
+        cpu.load_and_run(program);
+
+        // 1. Verify the subroutine executed correctly by checking the memory it modified.
+        assert_eq!(
+            cpu.mem_read(0x0200),
+            0xaa,
+            "Subroutine should store 0xAA in memory."
+        );
+
+        // 2. Verify that the CPU returned to the correct location and executed the next instruction.
+        assert_eq!(
+            cpu.reg_a, 0x0f,
+            "Accumulator should be 0x0F after returning."
+        );
+
+        // 3. Verify that the stack pointer is back to its original state after push/pop.
+        assert_eq!(
+            cpu.stk_ptr, STK_RESET,
+            "Stack pointer should be reset after JSR/RTS cycle."
+        );
+    }
+
+    fn run_variant(variant: CpuVariant, program: Vec<u8>) -> CPU<FlatMemory> {
+        let mut cpu = CPU::new_with_variant(FlatMemory::new(), variant);
+        cpu.load_and_run(program);
+        cpu
+    }
+
+    #[test]
+    fn test_adc_decimal_mode() {
+        // SED, CLC, LDA #$09, ADC #$01
+        let cpu = run_variant(
+            CpuVariant::Nmos6502,
+            vec![0xf8, 0x18, 0xa9, 0x09, 0x69, 0x01, 0x00],
+        );
+        assert_eq!(cpu.reg_a, 0x10);
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_sbc_decimal_mode() {
+        // SED, SEC, LDA #$10, SBC #$01
+        let cpu = run_variant(
+            CpuVariant::Nmos6502,
+            vec![0xf8, 0x38, 0xa9, 0x10, 0xe9, 0x01, 0x00],
+        );
+        assert_eq!(cpu.reg_a, 0x09);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+
+        // SED, CLC, LDA #$00, SBC #$00 ; 0 - 0 - borrow wraps to 99
+        let cpu = run_variant(
+            CpuVariant::Nmos6502,
+            vec![0xf8, 0x18, 0xa9, 0x00, 0xe9, 0x00, 0x00],
+        );
+        assert_eq!(cpu.reg_a, 0x99);
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_2a03_ignores_decimal_flag() {
+        // SED, CLC, LDA #$09, ADC #$01
+        let cpu = run_variant(
+            CpuVariant::Ricoh2A03,
+            vec![0xf8, 0x18, 0xa9, 0x09, 0x69, 0x01, 0x00],
+        );
+        assert_eq!(cpu.reg_a, 0x0a);
+        assert!(cpu.status.contains(CpuFlags::DECIMAL_MODE));
+    }
+
+    #[test]
+    fn test_decimal_flags_nmos_vs_cmos() {
+        // SED, CLC, LDA #$99, ADC #$01
+        let program = vec![0xf8, 0x18, 0xa9, 0x99, 0x69, 0x01, 0x00];
+
+        // NMOS: Z from the binary sum ($9A), N from the unadjusted high nibble
+        let cpu = run_variant(CpuVariant::Nmos6502, program.clone());
+        assert_eq!(cpu.reg_a, 0x00);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(!cpu.status.contains(CpuFlags::ZERO));
+        assert!(cpu.status.contains(CpuFlags::NEGATIVE));
+
+        let cpu = run_variant(CpuVariant::Cmos65C02, program);
+        assert_eq!(cpu.reg_a, 0x00);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::ZERO));
+        assert!(!cpu.status.contains(CpuFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_cmos_stack_and_store_zero() {
+        // LDX #$12, PHX, PLY, LDA #$ff, STA $20, STZ $20, BRA +1, INY, BRK
+        let mut cpu = run_variant(
+            CpuVariant::Cmos65C02,
+            vec![
+                0xa2, 0x12, 0xda, 0x7a, 0xa9, 0xff, 0x85, 0x20, 0x64, 0x20, 0x80, 0x01, 0xc8, 0x00,
+            ],
+        );
+        assert_eq!(cpu.reg_y, 0x12);
+        assert_eq!(cpu.mem_read(0x20), 0x00);
+        assert_eq!(cpu.stk_ptr, STK_RESET);
+    }
+
+    #[test]
+    fn test_cmos_jmp_indirect_takes_a_cycle_more() {
+        // JMP ($0010) to $8004: BRK
+        let program = vec![0x6c, 0x10, 0x00, 0xea, 0x00];
+        let cycles = |variant| {
+            let mut cpu = CPU::new_with_variant(FlatMemory::new(), variant);
+            cpu.load(program.clone());
+            cpu.mem_write_u16(0x10, 0x8004);
+            cpu.reset();
+            let start = cpu.bus.cycles;
+            cpu.step();
+            assert_eq!(cpu.pc, 0x8004);
+            cpu.bus.cycles - start
+        };
+        assert_eq!(cycles(CpuVariant::Nmos6502), 5);
+        assert_eq!(cycles(CpuVariant::Cmos65C02), 6);
+    }
+
+    #[test]
+    fn test_cmos_runs_nmos_illegal_opcodes_as_nops() {
+        // LDA #$55, LAX (one byte here), INX, NOP $0000 ($5C), INX, BRK
+        let program = vec![0xa9, 0x55, 0xa7, 0xe8, 0x5c, 0x00, 0x00, 0xe8, 0x00];
+        let cpu = run_variant(CpuVariant::Cmos65C02, program);
+        assert_eq!(cpu.reg_x, 2);
+        assert_eq!(cpu.reg_a, 0x55);
+        assert_eq!(cpu.pc, 0x8009);
+        assert_eq!(cmos_nop(0xa7), Some((1, 1)));
+        assert_eq!(cmos_nop(0x5c), Some((3, 8)));
+        assert_eq!(cmos_nop(0xa9), None);
+    }
+
+    #[test]
+    fn test_cmos_tsb_trb() {
+        // LDA #$0f, STA $10, LDA #$f0, TSB $10, LDA #$03, TRB $10
+        let mut cpu = run_variant(
+            CpuVariant::Cmos65C02,
+            vec![
+                0xa9, 0x0f, 0x85, 0x10, 0xa9, 0xf0, 0x04, 0x10, 0xa9, 0x03, 0x14, 0x10, 0x00,
+            ],
+        );
+        assert_eq!(cpu.mem_read(0x10), 0xfc);
+        assert!(!cpu.status.contains(CpuFlags::ZERO));
+    }
+
+    fn run_from_ram(program: &[u8], setup: impl FnOnce(&mut CPU<Bus>)) -> CPU<Bus<'static>> {
+        let bus = Bus::new(test_rom(vec![]), |_, _| {});
+        let mut cpu = CPU::new(bus);
+        for (i, byte) in program.iter().enumerate() {
+            cpu.mem_write(0x0600 + i as u16, *byte);
+        }
+        setup(&mut cpu);
+        cpu.pc = 0x0600;
+        cpu.run();
+        cpu
+    }
+
+    fn read_vram(cpu: &mut CPU<Bus>, addr: u16) -> u8 {
+        cpu.mem_write(0x2006, (addr >> 8) as u8);
+        cpu.mem_write(0x2006, (addr & 0xff) as u8);
+        cpu.mem_read(0x2007); //load into buffer
+        cpu.mem_read(0x2007)
+    }
+
+    #[test]
+    fn test_rmw_writes_original_value_first() {
+        // INC $2007
+        let mut cpu = run_from_ram(&[0xee, 0x07, 0x20, 0x00], |cpu| {
+            cpu.mem_write(0x2006, 0x23);
+            cpu.mem_write(0x2006, 0x05);
+            cpu.mem_write(0x2007, 0x10);
+            cpu.mem_write(0x2007, 0xaa);
+            cpu.mem_write(0x2006, 0x23);
+            cpu.mem_write(0x2006, 0x05);
+        });
+
+        // the read returns the stale buffer (0), the dummy write stores it at
+        // $2306 and the incremented value lands on $2307
+        assert_eq!(read_vram(&mut cpu, 0x2306), 0x00);
+        assert_eq!(read_vram(&mut cpu, 0x2307), 0x01);
+    }
+
+    #[test]
+    fn test_indexed_store_dummy_reads_uncarried_address() {
+        // LDX #$20
+        // STA $40F6,X ; x8, each dummy-reads $4016 before writing $4116
+        // LDA $4016
+        let mut program = vec![0xa2, 0x20];
+        for _ in 0..8 {
+            program.extend([0x9d, 0xf6, 0x40]);
+        }
+        program.extend([0xad, 0x16, 0x40, 0x00]);
+        let cpu = run_from_ram(&program, |cpu| {
+            cpu.mem_write(0x4016, 1);
+            cpu.mem_write(0x4016, 0);
+        });
+
+        // all 8 buttons were shifted out by the dummy reads
+        assert_eq!(cpu.reg_a, 1);
+    }
+
+    #[test]
+    fn test_indexed_load_dummy_reads_only_on_page_cross() {
+        // LDX #$10
+        // LDA $4006,X ; x7, no page cross so $4016 is read once each
+        // LDA $4016   ; 8th read, still within the button bits
+        let mut program = vec![0xa2, 0x10];
+        for _ in 0..7 {
+            program.extend([0xbd, 0x06, 0x40]);
+        }
+        program.extend([0xad, 0x16, 0x40, 0x00]);
+        let cpu = run_from_ram(&program, |cpu| {
+            cpu.mem_write(0x4016, 1);
+            cpu.mem_write(0x4016, 0);
+        });
+
+        assert_eq!(cpu.reg_a, 0);
     }
-    // #[cfg(test)]
-    // mod test {
-    //     use super::*;
-    //
-    //     #[test]
-    //     fn test_0xa9_lda_immediate_load_data() {
-    //         let mut cpu = CPU::new();
-    //         cpu.load_and_run(vec![0xa9, 0x05, 0x00]);
-    //         assert_eq!(cpu.reg_a, 0x05);
-    //         assert!(cpu.status & 0b0000_0010 == 0b00);
-    //         assert!(cpu.status & 0b1000_0000 == 0);
-    //     }
-    //
-    //     #[test]
-    //     fn test_0xa9_lda_zero_flag() {
-    //         let mut cpu = CPU::new();
-    //         cpu.load_and_run(vec![0xa9, 0x00, 0x00]);
-    //         assert!(cpu.status & 0b0000_0010 == 0b10);
-    //     }
-    //     #[test]
-    //     fn test_lda_from_memory() {
-    //         let mut cpu = CPU::new();
-    //         cpu.mem_write(0x10, 0x55);
-    //         cpu.load_and_run(vec![0xa5, 0x10, 0x00]);
-    //         assert_eq!(cpu.reg_a, 0x55);
-    //     }
-    //
-    //     #[test]
-    //     fn test_0xaa_tax_move_a_to_x() {
-    //         let mut cpu = CPU::new();
-    //         cpu.load_and_run(vec![0xa9, 10, 0xaa, 0x00]);
-    //         assert_eq!(cpu.reg_x, 10);
-    //     }
-    //
-    //     #[test]
-    //     fn test_5_ops_working_together() {
-    //         let mut cpu = CPU::new();
-    //         cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
-    //         // println!("{} ,{}",cpu.reg_x,cpu.reg_a);
-    //         assert_eq!(cpu.reg_x, 0xc1)
-    //     }
-    //
-    //     #[test]
-    //     fn test_inx_overflow() {
-    //         let mut cpu = CPU::new();
-    //         cpu.reg_x = 0xff;
-    //         cpu.load_and_run(vec![0xe8, 0x00]);
-    //         println!("{}", cpu.reg_x);
-    //         assert_eq!(cpu.reg_x, 1)
-    //     }
-    //
-    //     #[test]
-    //     fn test_sta_absolute_x() {
-    //         let mut cpu = CPU::new();
-    //         // LDX #$0F
-    //         // LDA #$DD
-    //         // STA $0200,X ; Store at $0200 + $0F = $020F
-    //         cpu.load_and_run(vec![0xa2, 0x0f, 0xa9, 0xdd, 0x9d, 0x00, 0x02, 0x00]);
-    //         assert_eq!(cpu.mem_read(0x020f), 0xdd);
-    //     }
-    //
-    //     #[test]
-    //     fn test_lda_indirect_y() {
-    //         let mut cpu = CPU::new();
-    //         // Set up the indirect address pointer at zero-page location $F0.
-    //         // It will point to $0300.
-    //         cpu.mem_write_u16(0xf0, 0x0300);
-    //         // The value to be loaded is at $0300 + Y.
-    //         cpu.mem_write(0x0305, 0xAB);
-    //
-    //         // LDY #$05
-    //         // LDA ($F0),Y ; Load from address pointed to by $F0, offset by Y.
-    //         let program = vec![0xa0, 0x05, 0xb1, 0xf0, 0x00];
-    //         cpu.load_and_run(program);
-    //
-    //         assert_eq!(cpu.reg_a, 0xab);
-    //     }
-    //     #[test]
-    //     fn test_bne_branch_taken() {
-    //         let mut cpu = CPU::new();
-    //         // A simple program to check if BNE skips the next instruction.
-    //         // LDX #$01 ; X is not zero, so Zero flag is clear.
-    //         // BNE +2   ; Branch should be taken, skipping the INX.
-    //         // INX      ; This should be skipped.
-    //         // BRK
-    //         cpu.load_and_run(vec![0xa2, 0x01, 0xd0, 0x01, 0xe8, 0x00]);
-    //         assert_eq!(cpu.reg_x, 1, "X should be 1, as INX was skipped");
-    //     }
-    //
-    //     #[test]
-    //     fn test_bne_branch_not_taken() {
-    //         let mut cpu = CPU::new();
-    //         // DEX will make X=0, setting the Zero flag.
-    //         // BNE should not be taken.
-    //         // LDX #$01
-    //         // DEX      ; X becomes 0, Z flag set
-    //         // BNE +2   ; Branch not taken
-    //         // INX      ; This should execute
-    //         // BRK
-    //         cpu.load_and_run(vec![0xa2, 0x01, 0xca, 0xd0, 0x01, 0xe8, 0x00]);
-    //         assert_eq!(cpu.reg_x, 1, "X should be 1, as INX was executed");
-    //     }
-    //
-    //     #[test]
-    //     fn test_compare_and_branch_loop() {
-    //         let mut cpu = CPU::new();
-    //         // A simple countdown loop.
-    //         // LDX #$03
-    //         // LOOP:
-    //         // DEX
-    //         // CPX #$00
-    //         // BNE LOOP
-    //         // BRK
-    //         // Program in hex: A2 03 CA E0 00 D0 FB 00
-    //         // FB is the 2's complement of -5 bytes.
-    //         let program = vec![0xa2, 0x03, 0xca, 0xe0, 0x00, 0xd0, 0xfb, 0x00];
-    //         cpu.load_and_run(program);
-    //         assert_eq!(cpu.reg_x, 0, "Loop should terminate when X is 0");
-    //     }
-    //
-    //     #[test]
-    //     fn test_compare_flags() {
-    //         let mut cpu = CPU::new();
-    //         // LDA #$10, CMP #$20 (A < M)
-    //         // Carry should be clear, Zero clear, Negative set.
-    //         cpu.load_and_run(vec![0xa9, 0x10, 0xc9, 0x20, 0x00]);
-    //         assert!(!cpu.get_flag_carry(), "A < M, Carry should be clear");
-    //         assert!(
-    //             cpu.status & 0b0000_0010 == 0,
-    //             "A != M, Zero should be clear"
-    //         );
-    //         assert!(
-    //             cpu.status & 0b1000_0000 != 0,
-    //             "Result is negative, Negative flag should be set"
-    //         );
-    //     }
-    //     #[test]
-    //     fn test_asl_accumulator() {
-    //         let mut cpu = CPU::new();
-    //         // LDA #$C1 (11000001)
-    //         // ASL A
-    //         // Result in A should be 10000010 (0x82).
-    //         // Carry should be set (from original bit 7).
-    //         // Negative should be set (from new bit 7).
-    //         cpu.load_and_run(vec![0xa9, 0xc1, 0x0a, 0x00]);
-    //         assert_eq!(cpu.reg_a, 0x82);
-    //         assert!(cpu.get_flag_carry(), "Carry flag should be set");
-    //         assert!(cpu.status & 0b1000_0000 != 0, "Negative flag should be set");
-    //     }
-    //
-    //     #[test]
-    //     fn test_lsr_memory_and_flags() {
-    //         let mut cpu = CPU::new();
-    //         cpu.mem_write(0x20, 0x01);
-    //         cpu.load_and_run(vec![0x46, 0x20, 0x00]);
-    //
-    //         assert_eq!(cpu.mem_read(0x20), 0x00);
-    //         assert!(cpu.get_flag_carry(), "Carry flag should be set");
-    //         assert!(cpu.status & 0b0000_0010 != 0, "Zero flag should be set");
-    //     }
-    //
-    //     #[test]
-    //     fn test_rol_accumulator_with_carry() {
-    //         let mut cpu = CPU::new();
-    //         cpu.load_and_run(vec![0x38, 0xa9, 0x7f, 0x2a, 0x00]);
-    //
-    //         assert_eq!(cpu.reg_a, 0xff, "Accumulator should be 255");
-    //         assert!(!cpu.get_flag_carry(), "Carry flag should be cleared");
-    //         assert!(cpu.status & 0b1000_0000 != 0, "Negative flag should be set");
-    //     }
-    //
-    //     #[test]
-    //     fn test_ror_memory() {
-    //         let mut cpu = CPU::new();
-    //         cpu.mem_write(0x33, 0x02); // 00000010
-    //                                    // SEC (set carry)
-    //                                    // ROR $33
-    //                                    // Result at $33: 10000001 (0x81).
-    //                                    // Carry is cleared (from old bit 0).
-    //         cpu.load_and_run(vec![0x38, 0x6e, 0x33, 0x00]);
-    //         assert_eq!(cpu.mem_read(0x33), 0x81);
-    //         assert!(!cpu.get_flag_carry(), "Carry flag should be cleared");
-    //     }
-    //     #[test]
-    //     fn test_and_immediate() {
-    //         let mut cpu = CPU::new();
-    //         // LDA #$CF (11001111)
-    //         // AND #$3A (00111010)
-    //         // Result should be 00001010 = 0x0A
-    //         cpu.load_and_run(vec![0xa9, 0xcf, 0x29, 0x3a, 0x00]);
-    //         assert_eq!(cpu.reg_a, 0x0a);
-    //         assert!(cpu.status & 0b0000_0010 == 0, "Zero flag should not be set");
-    //     }
-    //
-    //     #[test]
-    //     fn test_eor_and_zero_flag() {
-    //         let mut cpu = CPU::new();
-    //         // LDA #$55
-    //         // EOR #$55
-    //         // Result should be 0, setting the Zero flag.
-    //         cpu.load_and_run(vec![0xa9, 0x55, 0x49, 0x55, 0x00]);
-    //         assert_eq!(cpu.reg_a, 0x00);
-    //         assert!(cpu.status & 0b0000_0010 != 0, "Zero flag should be set");
-    //     }
-    //
-    //     #[test]
-    //     fn test_bit_instruction() {
-    //         let mut cpu = CPU::new();
-    //         cpu.mem_write(0x20, 0b1100_0000); // Value to test against
-    //                                           // LDA #$01
-    //                                           // BIT $20
-    //                                           // Tests accumulator (0x01) against memory (0xC0).
-    //                                           // A & M = 0, so Zero flag (Z) is set.
-    //                                           // Bit 7 of M is 1, so Negative flag (N) is set.
-    //                                           // Bit 6 of M is 1, so Overflow flag (V) is set.
-    //         cpu.load_and_run(vec![0xa9, 0x01, 0x24, 0x20, 0x00]);
-    //         assert!(cpu.status & 0b0000_0010 != 0, "Zero flag should be set");
-    //         assert!(
-    //             cpu.status & 0b1000_0000 != 0,
-    //             "Negative flag should be set from mem bit 7"
-    //         );
-    //         assert!(
-    //             cpu.status & 0b0100_0000 != 0,
-    //             "Overflow flag should be set from mem bit 6"
-    //         );
-    //     }
-    //     #[test]
-    //     fn test_adc_simple_add_with_overflow() {
-    //         let mut cpu = CPU::new();
-    //         // Load accumulator with 127 (0x7F)
-    //         // Add 1
-    //         // Result should be 128 (0x80), setting Overflow and Negative flags.
-    //         cpu.load_and_run(vec![0xa9, 0x7f, 0x69, 0x01, 0x00]);
-    //         assert_eq!(cpu.reg_a, 0x80);
-    //         assert!(cpu.status & 0b0100_0000 != 0, "Overflow flag should be set");
-    //         assert!(cpu.status & 0b1000_0000 != 0, "Negative flag should be set");
-    //     }
-    //
-    //     #[test]
-    //     fn test_sbc_with_borrow() {
-    //         let mut cpu = CPU::new();
-    //         // CLC (clears carry, enabling borrow for SBC)
-    //         // LDA #$05
-    //         // SBC #$03
-    //         // Result should be 5 - 3 - 1 = 1
-    //         cpu.load_and_run(vec![0x18, 0xa9, 0x05, 0xe9, 0x03, 0x00]);
-    //         assert_eq!(cpu.reg_a, 1);
-    //     }
-    //
-    //     #[test]
-    //     fn test_sbc_no_borrow() {
-    //         let mut cpu = CPU::new();
-    //         // SEC (sets carry, disabling borrow for SBC)
-    //         // LDA #$05
-    //         // SBC #$03
-    //         // Result should be 5 - 3 = 2
-    //         cpu.load_and_run(vec![0x38, 0xa9, 0x05, 0xe9, 0x03, 0x00]);
-    //         assert_eq!(cpu.reg_a, 2);
-    //     }
-    //     #[test]
-    //     fn test_jsr_and_rts() {
-    //         let mut cpu = CPU::new();
-    //         // The program consists of a main routine and a subroutine.
-    //         // Main routine:
-    //         // 0x8000: LDA #$05   ; Load 5 into accumulator
-    //         // 0x8002: JSR $800A  ; Jump to subroutine at address 0x800A
-    //         // 0x8005: LDA #$0F   ; After returning, load 15 into accumulator
-    //         // 0x8007: BRK        ; Halt
-    //         // Subroutine:
-    //         // 0x800A: LDA #$AA   ; Load 170 into accumulator
-    //         // 0x800C: STA $0200  ; Store accumulator's value at memory location 0x0200
-    //         // 0x800F: RTS        ; Return from subroutine
-    //         let program = vec![
-    //             0xa9, 0x05, // LDA #$05
-    //             0x20, 0x0a, 0x80, // JSR $800A
-    //             0xa9, 0x0f, // LDA #$0F
-    //             0x00, // BRK
-    //             0x00, 0x00, // Padding to align subroutine
-    //             0xa9, 0xaa, // Subroutine starts here (0x800A)
-    //             0x8d, 0x00, 0x02, // STA $0200
-    //             0x60, // RTS
-    //         ];
-    //         cpu.load_and_run(program);
-    //
-    //         // 1. Verify the subroutine executed correctly by checking the memory it modified.
-    //         assert_eq!(
-    //             cpu.mem_read(0x0200),
-    //             0xaa,
-    //             "Subroutine should store 0xAA in memory."
-    //         );
-    //
-    //         // 2. Verify that the CPU returned to the correct location and executed the next instruction.
-    //         assert_eq!(
-    //             cpu.reg_a, 0x0f,
-    //             "Accumulator should be 0x0F after returning."
-    //         );
-    //
-    //         // 3. Verify that the stack pointer is back to its original state after push/pop.
-    //         assert_eq!(
-    //             cpu.stk_ptr, STK_RESET,
-    //             "Stack pointer should be reset after JSR/RTS cycle."
-    //         );
-    //     }
-    // }}
-    //
-    //
 }
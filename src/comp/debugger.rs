@@ -0,0 +1,915 @@
+use std::cell::{Cell, RefCell};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::comp::bus::Bus;
+use crate::comp::cpu::{CPU, CpuFlags, Mem, Tick};
+use crate::comp::opcodes::{self, OpCode, Operand};
+use crate::comp::ramsearch::{self, Filter, RamSearch, ValueType, WatchList};
+use crate::comp::symbols::{Location, Symbols};
+use crate::comp::trace::trace_nes;
+
+// Interactive debugger driven from the `run_with_callback` callback: it is
+// consulted before every instruction and drops into a command prompt when a
+// breakpoint, watchpoint, step or the hotkey asks for it.
+//
+// Watchpoints are checked before the instruction that would touch the address,
+// by decoding it and computing its effective address with side-effect-free peeks.
+//
+// The RAM search and watch list live here too: searching is done from the
+// prompt, while watched values and freezes are refreshed at every vblank.
+
+const HELP: &str = "\
+commands (numbers are decimal unless prefixed with $ or 0x, addresses may be labels):
+  s, step [n]              execute n instructions (default 1)
+  n, next                  step over a JSR
+  finish                   run until the current routine returns
+  c, continue              resume execution
+  scanline <n>             run until the PPU reaches scanline n
+  r, regs                  show registers and the next instruction
+  set <reg> <value>        set a, x, y, sp, pc, p or a flag (n v d i z c)
+  x <addr> [len]           hex dump CPU memory
+  xp <addr> [len]          hex dump PPU memory
+  b, break <addr> [if <cond>]       break before executing addr
+  break nmi                         break on entry to the NMI handler
+  w, watch <r|w|rw> <addr> [if <cond>]  break before an access to addr
+  l, list                  list breakpoints and watchpoints
+  d, delete <id>           remove a breakpoint or watchpoint
+  search [u8|s8|u16|s16]   start a RAM search with every address as a candidate
+  filter <how>             keep candidates that are equal, changed, increased,
+                           decreased or `value <n>` since the last search/filter
+  results                  list the remaining candidates
+  mon [<addr> [type] [name]]  add an address to the watch list, or show it
+  unmon <addr>             remove an address from the watch list
+  freeze <addr> <value> [type]  rewrite addr with value every frame
+  unfreeze <addr>          stop freezing addr
+  cheat                    list cheats
+  cheat add <code> [name]  add a Game Genie code or AAAA:VV RAM poke
+  cheat on|off|del <n>     enable, disable or remove cheat n
+  log [<category>=<level>,...]  show or change log levels (cpu bus ppu mapper
+                           input apu, or all; off error warn info debug trace)
+  q, quit                  exit the emulator
+conditions compare registers and numbers, e.g. `A == $40 && X != 0`.
+writes to $2008-$3FFF are matched against the mirrored $2000-$2007 register.
+an empty line repeats the last command.
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    A,
+    X,
+    Y,
+    SP,
+    P,
+    PC,
+    Const(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A conjunction of comparisons, e.g. `A == $40 && X < 3`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    source: String,
+    clauses: Vec<(Value, CmpOp, Value)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Trigger {
+    Exec(Location),
+    Nmi,
+    Watch(u16, WatchKind),
+}
+
+struct Breakpoint {
+    id: usize,
+    trigger: Trigger,
+    condition: Option<Condition>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Paused,
+    Running,
+    Step(usize),
+    Next { return_pc: u16, stk_ptr: u8 },
+    Finish { stk_ptr: u8 },
+    Scanline(u16),
+}
+
+pub struct Debugger<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+    mode: Mode,
+    breakpoints: Vec<Breakpoint>,
+    next_id: usize,
+    break_requested: Rc<Cell<bool>>,
+    // breakpoints don't fire again on the instruction we resumed from
+    resumed_at: Option<u16>,
+    prev_opcode: u8,
+    prev_scanline: u16,
+    last_command: String,
+    symbols: Symbols,
+    search: Option<RamSearch>,
+    watches: WatchList,
+    watch_file: Option<PathBuf>,
+    watch_status: Rc<RefCell<String>>,
+}
+
+fn parse_number(text: &str) -> Result<u16, String> {
+    let parsed = if let Some(hex) = text.strip_prefix('$') {
+        u16::from_str_radix(hex, 16)
+    } else if let Some(hex) = text.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16)
+    } else {
+        text.parse::<u16>()
+    };
+    parsed.map_err(|_| format!("bad number '{}'", text))
+}
+
+fn parse_value(text: &str) -> Result<Value, String> {
+    match text.to_ascii_lowercase().as_str() {
+        "a" => Ok(Value::A),
+        "x" => Ok(Value::X),
+        "y" => Ok(Value::Y),
+        "sp" => Ok(Value::SP),
+        "p" => Ok(Value::P),
+        "pc" => Ok(Value::PC),
+        _ => parse_number(text).map(Value::Const),
+    }
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        let mut clauses = vec![];
+        for clause in text.split("&&") {
+            // two-character operators first so `<=` isn't taken for `<`
+            let ops = [
+                ("==", CmpOp::Eq),
+                ("!=", CmpOp::Ne),
+                ("<=", CmpOp::Le),
+                (">=", CmpOp::Ge),
+                ("<", CmpOp::Lt),
+                (">", CmpOp::Gt),
+            ];
+            let (pos, token, op) = ops
+                .iter()
+                .find_map(|(token, op)| clause.find(token).map(|pos| (pos, *token, *op)))
+                .ok_or(format!("missing comparison in '{}'", clause.trim()))?;
+            let lhs = parse_value(clause[..pos].trim())?;
+            let rhs = parse_value(clause[pos + token.len()..].trim())?;
+            clauses.push((lhs, op, rhs));
+        }
+        Ok(Condition {
+            source: text.trim().to_string(),
+            clauses,
+        })
+    }
+
+    pub fn eval<M: Mem + Tick>(&self, cpu: &CPU<M>) -> bool {
+        let value = |v: &Value| match v {
+            Value::A => cpu.reg_a as u16,
+            Value::X => cpu.reg_x as u16,
+            Value::Y => cpu.reg_y as u16,
+            Value::SP => cpu.stk_ptr as u16,
+            Value::P => cpu.status.bits() as u16,
+            Value::PC => cpu.pc,
+            Value::Const(c) => *c,
+        };
+        self.clauses.iter().all(|(lhs, op, rhs)| {
+            let (l, r) = (value(lhs), value(rhs));
+            match op {
+                CmpOp::Eq => l == r,
+                CmpOp::Ne => l != r,
+                CmpOp::Lt => l < r,
+                CmpOp::Le => l <= r,
+                CmpOp::Gt => l > r,
+                CmpOp::Ge => l >= r,
+            }
+        })
+    }
+}
+
+fn is_write(op: &OpCode) -> bool {
+    matches!(
+        op.mnemonic,
+        "STA" | "STX" | "STY" | "SAX" | "AHX" | "SHX" | "SHY" | "TAS"
+    )
+}
+
+fn is_read_modify_write(op: &OpCode) -> bool {
+    op.mode != Operand::Accumulator
+        && matches!(
+            op.mnemonic,
+            "ASL"
+                | "LSR"
+                | "ROL"
+                | "ROR"
+                | "INC"
+                | "DEC"
+                | "SLO"
+                | "RLA"
+                | "SRE"
+                | "RRA"
+                | "DCP"
+                | "ISB"
+        )
+}
+
+/// Data address the instruction at `pc` is about to access, and how.
+/// Jumps, stack operations and the JMP indirect pointer are not data accesses.
+pub(crate) fn data_access<M: Mem + Tick>(cpu: &CPU<M>) -> Option<(u16, WatchKind)> {
+    let op = opcodes::lookup(cpu.peek(cpu.pc));
+    let arg = cpu.peek(cpu.pc.wrapping_add(1));
+    let arg16 = cpu.peek_u16(cpu.pc.wrapping_add(1));
+    let zero_page_u16 =
+        |ptr: u8| u16::from_le_bytes([cpu.peek(ptr as u16), cpu.peek(ptr.wrapping_add(1) as u16)]);
+
+    let addr = match op.mode {
+        Operand::ZeroPage => arg as u16,
+        Operand::ZeroPage_X => arg.wrapping_add(cpu.reg_x) as u16,
+        Operand::ZeroPage_Y => arg.wrapping_add(cpu.reg_y) as u16,
+        Operand::Absolute if matches!(op.code, 0x20 | 0x4c) => return None,
+        Operand::Absolute => arg16,
+        Operand::Absolute_X => arg16.wrapping_add(cpu.reg_x as u16),
+        Operand::Absolute_Y => arg16.wrapping_add(cpu.reg_y as u16),
+        Operand::Indirect_X => zero_page_u16(arg.wrapping_add(cpu.reg_x)),
+        Operand::Indirect_Y => zero_page_u16(arg).wrapping_add(cpu.reg_y as u16),
+        _ => return None,
+    };
+    let kind = if is_read_modify_write(op) {
+        WatchKind::ReadWrite
+    } else if is_write(op) {
+        WatchKind::Write
+    } else {
+        WatchKind::Read
+    };
+    Some((addr, kind))
+}
+
+// PPU registers repeat every 8 bytes up to $3FFF
+pub(crate) fn mirror_down(addr: u16) -> u16 {
+    match addr {
+        0x0000..=0x1fff => addr & 0x07ff,
+        0x2000..=0x3fff => addr & 0x2007,
+        _ => addr,
+    }
+}
+
+impl<R: BufRead, W: Write> Debugger<R, W> {
+    /// Starts paused, so the prompt shows up before the first instruction.
+    pub fn new(input: R, output: W) -> Self {
+        Debugger {
+            input,
+            output,
+            mode: Mode::Paused,
+            breakpoints: vec![],
+            next_id: 1,
+            break_requested: Rc::new(Cell::new(false)),
+            resumed_at: None,
+            prev_opcode: 0,
+            prev_scanline: 0,
+            last_command: String::new(),
+            symbols: Symbols::new(),
+            search: None,
+            watches: WatchList::new(),
+            watch_file: None,
+            watch_status: Rc::new(RefCell::new(String::new())),
+        }
+    }
+
+    /// Loads the watch list from `path` (if it exists) and saves it there on every change.
+    pub fn set_watch_file(&mut self, path: &Path) -> Result<(), String> {
+        self.watches = WatchList::load(path)?;
+        self.watch_file = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Shared `name=value` summary of the watch list, refreshed every frame.
+    pub fn watch_status(&self) -> Rc<RefCell<String>> {
+        self.watch_status.clone()
+    }
+
+    fn watches_changed(&mut self, cpu: &mut CPU<Bus>) -> Result<(), String> {
+        self.watches.apply_freezes(cpu);
+        *self.watch_status.borrow_mut() = self.watches.status(cpu);
+        match &self.watch_file {
+            Some(path) => self
+                .watches
+                .save(path)
+                .map_err(|e| format!("{}: {}", path.display(), e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Labels and source lines used for display and for `break <label>`.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// A number or a label currently visible in the CPU address space.
+    fn address(&self, cpu: &CPU<Bus>, text: &str) -> Result<u16, String> {
+        parse_number(text).or_else(|e| {
+            let location = self.symbols.lookup(text).ok_or(e)?;
+            Symbols::cpu_address(cpu, location).ok_or(format!("'{}' is not mapped in", text))
+        })
+    }
+
+    fn describe(&self, cpu: &CPU<Bus>, addr: u16) -> String {
+        match self.symbols.label(cpu, addr) {
+            Some(label) => format!("{:04X} <{}>", addr, label),
+            None => format!("{:04X}", addr),
+        }
+    }
+
+    /// Shared flag the frontend sets from its hotkey to pause at the next instruction.
+    pub fn break_request(&self) -> Rc<Cell<bool>> {
+        self.break_requested.clone()
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    /// Call before every instruction. Returns false when the user quits.
+    pub fn on_instruction(&mut self, cpu: &mut CPU<Bus>) -> bool {
+        let scanline = cpu.bus.ppu().scanline();
+        if scanline >= 241 && self.prev_scanline < 241 && !self.watches.is_empty() {
+            self.watches.apply_freezes(cpu);
+            *self.watch_status.borrow_mut() = self.watches.status(cpu);
+        }
+        let reason = self.break_reason(cpu, scanline);
+        self.prev_scanline = scanline;
+        self.resumed_at = None;
+
+        let keep_running = match reason {
+            Some(reason) => {
+                let _ = writeln!(self.output, "{}", reason);
+                self.prompt(cpu)
+            }
+            None => true,
+        };
+        self.prev_opcode = cpu.peek(cpu.pc);
+        keep_running
+    }
+
+    fn break_reason(&mut self, cpu: &CPU<Bus>, scanline: u16) -> Option<String> {
+        if self.break_requested.replace(false) {
+            return Some("interrupted".to_string());
+        }
+        match self.mode {
+            Mode::Paused => return Some("paused".to_string()),
+            Mode::Step(n) if n <= 1 => return Some("stepped".to_string()),
+            Mode::Step(n) => self.mode = Mode::Step(n - 1),
+            Mode::Next { return_pc, stk_ptr } if cpu.pc == return_pc && cpu.stk_ptr >= stk_ptr => {
+                return Some("stepped over".to_string());
+            }
+            Mode::Finish { stk_ptr }
+                if matches!(self.prev_opcode, 0x40 | 0x60) && cpu.stk_ptr > stk_ptr =>
+            {
+                return Some("returned".to_string());
+            }
+            Mode::Scanline(line) if scanline == line && self.prev_scanline != line => {
+                return Some(format!("reached scanline {}", line));
+            }
+            _ => {}
+        }
+        if self.resumed_at == Some(cpu.pc) {
+            return None;
+        }
+
+        let access = data_access(cpu);
+        for bp in &self.breakpoints {
+            let hit = match bp.trigger {
+                Trigger::Exec(location) => Symbols::matches(cpu, cpu.pc, location),
+                Trigger::Nmi => cpu.pc == cpu.peek_u16(0xfffa),
+                Trigger::Watch(addr, kind) => access.is_some_and(|(target, access_kind)| {
+                    mirror_down(target) == mirror_down(addr)
+                        && (kind == WatchKind::ReadWrite
+                            || access_kind == WatchKind::ReadWrite
+                            || kind == access_kind)
+                }),
+            };
+            if hit && bp.condition.as_ref().is_none_or(|c| c.eval(cpu)) {
+                return Some(match bp.trigger {
+                    Trigger::Exec(_) => {
+                        format!("breakpoint {} at {}", bp.id, self.describe(cpu, cpu.pc))
+                    }
+                    Trigger::Nmi => format!("breakpoint {} at NMI handler {:04X}", bp.id, cpu.pc),
+                    Trigger::Watch(addr, _) => {
+                        format!("watchpoint {} on {:04X} by {:04X}", bp.id, addr, cpu.pc)
+                    }
+                });
+            }
+        }
+        None
+    }
+
+    fn show_position(&mut self, cpu: &CPU<Bus>) {
+        let _ = writeln!(self.output, "{}", trace_nes(cpu, Some(&self.symbols)));
+        if let Some(line) = self.symbols.source_line(cpu, cpu.pc) {
+            let _ = writeln!(self.output, "  at {}", line.describe());
+        }
+    }
+
+    fn prompt(&mut self, cpu: &mut CPU<Bus>) -> bool {
+        self.mode = Mode::Paused;
+        self.show_position(cpu);
+        loop {
+            let _ = write!(self.output, "(rnes) ");
+            let _ = self.output.flush();
+            let mut line = String::new();
+            match self.input.read_line(&mut line) {
+                Ok(0) | Err(_) => return false,
+                Ok(_) => {}
+            }
+            let mut line = line.trim().to_string();
+            if line.is_empty() {
+                line = self.last_command.clone();
+            } else {
+                self.last_command = line.clone();
+            }
+            match self.command(cpu, &line) {
+                Ok(Some(keep_running)) => {
+                    self.resumed_at = Some(cpu.pc);
+                    return keep_running;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    let _ = writeln!(self.output, "error: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Runs one command. `Some(_)` resumes execution, `Some(false)` quits.
+    fn command(&mut self, cpu: &mut CPU<Bus>, line: &str) -> Result<Option<bool>, String> {
+        let (line, condition) = match line.split_once(" if ") {
+            Some((line, cond)) => (line, Some(Condition::parse(cond)?)),
+            None => (line, None),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let arg = |i: usize| words.get(i).copied().ok_or("missing argument".to_string());
+
+        match words.first().copied().unwrap_or("") {
+            "" => Ok(None),
+            "h" | "help" => {
+                let _ = write!(self.output, "{}", HELP);
+                Ok(None)
+            }
+            "s" | "step" => {
+                let count = match words.get(1) {
+                    Some(n) => parse_number(n)? as usize,
+                    None => 1,
+                };
+                self.mode = Mode::Step(count.max(1));
+                Ok(Some(true))
+            }
+            "n" | "next" => {
+                self.mode = if cpu.peek(cpu.pc) == 0x20 {
+                    Mode::Next {
+                        return_pc: cpu.pc.wrapping_add(3),
+                        stk_ptr: cpu.stk_ptr,
+                    }
+                } else {
+                    Mode::Step(1)
+                };
+                Ok(Some(true))
+            }
+            "finish" => {
+                self.mode = Mode::Finish {
+                    stk_ptr: cpu.stk_ptr,
+                };
+                Ok(Some(true))
+            }
+            "c" | "continue" => {
+                self.mode = Mode::Running;
+                Ok(Some(true))
+            }
+            "scanline" => {
+                self.mode = Mode::Scanline(parse_number(arg(1)?)?);
+                Ok(Some(true))
+            }
+            "q" | "quit" => Ok(Some(false)),
+            "r" | "regs" => {
+                self.show_position(cpu);
+                Ok(None)
+            }
+            "set" => {
+                set_register(cpu, arg(1)?, parse_number(arg(2)?)?)?;
+                let _ = writeln!(self.output, "{}", trace_nes(cpu, Some(&self.symbols)));
+                Ok(None)
+            }
+            "x" | "xp" => {
+                let start = self.address(cpu, arg(1)?)?;
+                let len = match words.get(2) {
+                    Some(n) => parse_number(n)?,
+                    None => 64,
+                };
+                let ppu = words[0] == "xp";
+                let dump = hex_dump(start, len, |addr| {
+                    if ppu {
+                        cpu.bus.ppu().peek_vram(addr)
+                    } else {
+                        cpu.peek(addr)
+                    }
+                });
+                let _ = write!(self.output, "{}", dump);
+                Ok(None)
+            }
+            "b" | "break" => {
+                let trigger = match arg(1)? {
+                    "nmi" => Trigger::Nmi,
+                    addr => match parse_number(addr) {
+                        Ok(addr) => Trigger::Exec(Location::Cpu(addr)),
+                        // labels keep their bank, so they only hit when that bank is mapped in
+                        Err(e) => Trigger::Exec(self.symbols.lookup(addr).ok_or(e)?),
+                    },
+                };
+                self.add_breakpoint(trigger, condition);
+                Ok(None)
+            }
+            "w" | "watch" => {
+                let kind = match arg(1)? {
+                    "r" => WatchKind::Read,
+                    "w" => WatchKind::Write,
+                    "rw" => WatchKind::ReadWrite,
+                    other => return Err(format!("unknown access '{}', use r, w or rw", other)),
+                };
+                let addr = self.address(cpu, arg(2)?)?;
+                self.add_breakpoint(Trigger::Watch(addr, kind), condition);
+                Ok(None)
+            }
+            "l" | "list" => {
+                for bp in &self.breakpoints {
+                    let what = match bp.trigger {
+                        Trigger::Exec(Location::Cpu(addr)) => format!("break {:04X}", addr),
+                        Trigger::Exec(Location::Prg(offset)) => {
+                            let label = self.symbols.label_at(Location::Prg(offset)).unwrap_or("");
+                            format!("break {} (PRG ${:05X})", label, offset)
+                        }
+                        Trigger::Nmi => "break nmi".to_string(),
+                        Trigger::Watch(addr, kind) => format!("watch {:?} {:04X}", kind, addr),
+                    };
+                    let cond = bp
+                        .condition
+                        .as_ref()
+                        .map(|c| format!(" if {}", c.source))
+                        .unwrap_or_default();
+                    let _ = writeln!(self.output, "{}: {}{}", bp.id, what, cond);
+                }
+                Ok(None)
+            }
+            "d" | "delete" => {
+                let id = parse_number(arg(1)?)? as usize;
+                let before = self.breakpoints.len();
+                self.breakpoints.retain(|bp| bp.id != id);
+                if self.breakpoints.len() == before {
+                    return Err(format!("no breakpoint {}", id));
+                }
+                Ok(None)
+            }
+            "search" => {
+                let kind = match words.get(1) {
+                    Some(kind) => ValueType::parse(kind)?,
+                    None => ValueType::U8,
+                };
+                let search = RamSearch::new(&cpu.bus.ram_regions(), kind);
+                let _ = writeln!(self.output, "{} candidates", search.candidates().len());
+                self.search = Some(search);
+                Ok(None)
+            }
+            "filter" => {
+                let filter = Filter::parse(&words[1..])?;
+                let regions = cpu.bus.ram_regions();
+                let search = self
+                    .search
+                    .as_mut()
+                    .ok_or("no search, start one with 'search'".to_string())?;
+                let left = search.filter(&regions, filter);
+                let _ = writeln!(self.output, "{} candidates", left);
+                if left <= 16 {
+                    let _ = write!(self.output, "{}", search.results(16));
+                }
+                Ok(None)
+            }
+            "results" => {
+                let search = self
+                    .search
+                    .as_ref()
+                    .ok_or("no search, start one with 'search'".to_string())?;
+                let _ = write!(self.output, "{}", search.results(64));
+                Ok(None)
+            }
+            "mon" if words.len() == 1 => {
+                let _ = write!(self.output, "{}", self.watches.show(cpu));
+                Ok(None)
+            }
+            "mon" => {
+                let addr = self.address(cpu, arg(1)?)?;
+                let (kind, name_at) = match words.get(2).map(|w| ValueType::parse(w)) {
+                    Some(Ok(kind)) => (kind, 3),
+                    _ => (ValueType::U8, 2),
+                };
+                self.watches
+                    .add(addr, kind, &words[name_at.min(words.len())..].join(" "));
+                self.watches_changed(cpu)?;
+                Ok(None)
+            }
+            "unmon" => {
+                let addr = self.address(cpu, arg(1)?)?;
+                if !self.watches.remove(addr) {
+                    return Err(format!("{:04X} is not watched", addr));
+                }
+                self.watches_changed(cpu)?;
+                Ok(None)
+            }
+            "freeze" => {
+                let addr = self.address(cpu, arg(1)?)?;
+                let value = ramsearch::parse_value(arg(2)?)?;
+                let kind = match words.get(3) {
+                    Some(kind) => ValueType::parse(kind)?,
+                    None => self
+                        .watches
+                        .watches()
+                        .iter()
+                        .find(|w| w.addr == addr)
+                        .map_or(ValueType::U8, |w| w.kind),
+                };
+                self.watches.freeze(addr, kind, value);
+                self.watches_changed(cpu)?;
+                Ok(None)
+            }
+            "unfreeze" => {
+                let addr = self.address(cpu, arg(1)?)?;
+                if !self.watches.unfreeze(addr) {
+                    return Err(format!("{:04X} is not frozen", addr));
+                }
+                self.watches_changed(cpu)?;
+                Ok(None)
+            }
+            "cheat" => {
+                let cheats = cpu.bus.cheats_mut();
+                match words.get(1).copied() {
+                    None => {
+                        for (i, cheat) in cheats.list().iter().enumerate() {
+                            let _ = writeln!(self.output, "{}: {}", i, cheat.describe());
+                        }
+                        if !cheats.switch().get() {
+                            let _ = writeln!(self.output, "(all cheats are switched off)");
+                        }
+                        return Ok(None);
+                    }
+                    Some("add") => {
+                        let i = cheats.add(arg(2)?, &words[3.min(words.len())..].join(" "))?;
+                        let _ = writeln!(self.output, "{}: {}", i, cheats.list()[i].describe());
+                    }
+                    Some(action @ ("on" | "off" | "del")) => {
+                        let i = parse_number(arg(2)?)? as usize;
+                        match action {
+                            "del" => cheats.remove(i).map(|_| ())?,
+                            _ => cheats.set_enabled(i, action == "on")?,
+                        }
+                    }
+                    Some(other) => return Err(format!("unknown cheat action '{}'", other)),
+                }
+                cheats.save()?;
+                Ok(None)
+            }
+            "log" => {
+                let log = cpu.bus.logger();
+                log.set_levels(log.levels().parse(&words[1..].join(","))?);
+                let _ = writeln!(self.output, "{}", log.levels());
+                Ok(None)
+            }
+            other => Err(format!("unknown command '{}', try 'help'", other)),
+        }
+    }
+
+    fn add_breakpoint(&mut self, trigger: Trigger, condition: Option<Condition>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint {
+            id,
+            trigger,
+            condition,
+        });
+        let _ = writeln!(self.output, "breakpoint {} set", id);
+    }
+}
+
+fn set_register<M: Mem + Tick>(cpu: &mut CPU<M>, name: &str, value: u16) -> Result<(), String> {
+    let flag = |f: CpuFlags, cpu: &mut CPU<M>| cpu.status.set(f, value != 0);
+    match name.to_ascii_lowercase().as_str() {
+        "a" => cpu.reg_a = value as u8,
+        "x" => cpu.reg_x = value as u8,
+        "y" => cpu.reg_y = value as u8,
+        "sp" => cpu.stk_ptr = value as u8,
+        "pc" => cpu.pc = value,
+        "p" => cpu.status = CpuFlags::from_bits_truncate(value as u8),
+        "n" => flag(CpuFlags::NEGATIVE, cpu),
+        "v" => flag(CpuFlags::OVERFLOW, cpu),
+        "d" => flag(CpuFlags::DECIMAL_MODE, cpu),
+        "i" => flag(CpuFlags::INTERRUPT_DISABLE, cpu),
+        "z" => flag(CpuFlags::ZERO, cpu),
+        "c" => flag(CpuFlags::CARRY, cpu),
+        other => return Err(format!("unknown register '{}'", other)),
+    }
+    Ok(())
+}
+
+fn hex_dump<F: FnMut(u16) -> u8>(start: u16, len: u16, mut read: F) -> String {
+    let mut out = String::new();
+    for row in (0..len).step_by(16) {
+        let addr = start.wrapping_add(row);
+        let bytes: Vec<String> = (row..len.min(row.saturating_add(16)))
+            .map(|i| format!("{:02X}", read(start.wrapping_add(i))))
+            .collect();
+        out.push_str(&format!("{:04X}: {}\n", addr, bytes.join(" ")));
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::log::{Category, Level};
+    use crate::comp::rom::test::{test_prg, test_rom};
+    use std::io::Cursor;
+
+    fn run_debugger(program: &[u8], script: &str) -> (CPU<Bus<'static>>, String) {
+        let mut cpu = CPU::new(Bus::new(test_rom(test_prg(program)), |_, _| {}));
+        cpu.reset();
+
+        let mut debugger = Debugger::new(Cursor::new(script.to_string()), Vec::new());
+        cpu.run_with_callback(|cpu| {
+            debugger.on_instruction(cpu);
+        })
+        .unwrap();
+        let output = String::from_utf8(debugger.output().clone()).unwrap();
+        (cpu, output)
+    }
+
+    #[test]
+    fn test_parse_condition() {
+        let cond = Condition::parse("A == $40 && x<3").unwrap();
+        assert_eq!(
+            cond.clauses,
+            vec![
+                (Value::A, CmpOp::Eq, Value::Const(0x40)),
+                (Value::X, CmpOp::Lt, Value::Const(3)),
+            ]
+        );
+        assert!(Condition::parse("A $40").is_err());
+        assert!(Condition::parse("Q == 1").is_err());
+    }
+
+    #[test]
+    fn test_breakpoint_and_registers() {
+        // LDA #$40; STA $10; LDX #$01; BRK
+        let program = [0xa9, 0x40, 0x85, 0x10, 0xa2, 0x01, 0x00];
+        let (_, output) = run_debugger(&program, "break $8004\nc\nregs\nc\n");
+        assert!(output.contains("breakpoint 1 at 8004"), "{}", output);
+        assert!(output.contains("8004  A2 01     LDX #$01"), "{}", output);
+        assert!(output.contains("A:40 X:00"), "{}", output);
+    }
+
+    #[test]
+    fn test_conditional_write_watchpoint() {
+        // LDA #$01; STA $10; LDA #$40; STA $10; BRK
+        let program = [0xa9, 0x01, 0x85, 0x10, 0xa9, 0x40, 0x85, 0x10, 0x00];
+        let (_, output) = run_debugger(&program, "watch w $10 if A == $40\nc\nc\n");
+        assert!(
+            output.contains("watchpoint 1 on 0010 by 8006"),
+            "{}",
+            output
+        );
+        assert!(!output.contains("by 8002"), "{}", output);
+    }
+
+    #[test]
+    fn test_ppu_register_watch_matches_mirrors() {
+        // LDA #$80; STA $2008 (mirror of $2000); BRK
+        let program = [0xa9, 0x80, 0x8d, 0x08, 0x20, 0x00];
+        let (_, output) = run_debugger(&program, "watch w $2000\nc\nc\n");
+        assert!(
+            output.contains("watchpoint 1 on 2000 by 8002"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn test_step_set_and_dump() {
+        // LDA #$40; STA $10; LDX #$01; BRK
+        let program = [0xa9, 0x40, 0x85, 0x10, 0xa2, 0x01, 0x00];
+        let (cpu, output) = run_debugger(&program, "step 2\nset y $12\nset c 1\nx $0010 4\nc\n");
+        assert!(output.contains("stepped"), "{}", output);
+        assert!(output.contains("0010: 40 00 00 00"), "{}", output);
+        assert_eq!(cpu.reg_y, 0x12);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_next_steps_over_jsr() {
+        // JSR $8006; LDX #$01; BRK; sub: LDY #$02; RTS
+        let program = [0x20, 0x06, 0x80, 0xa2, 0x01, 0x00, 0xa0, 0x02, 0x60];
+        let (_, output) = run_debugger(&program, "next\nc\n");
+        assert!(output.contains("stepped over"), "{}", output);
+        assert!(output.contains("8003  A2 01     LDX #$01"), "{}", output);
+        assert!(output.contains("Y:02"), "{}", output);
+    }
+
+    #[test]
+    fn test_break_on_label() {
+        // JSR sub; BRK; sub: LDY #$02; RTS
+        let program = [0x20, 0x04, 0x80, 0x00, 0xa0, 0x02, 0x60];
+        let mut cpu = CPU::new(Bus::new(test_rom(test_prg(&program)), |_, _| {}));
+        cpu.reset();
+
+        let mut symbols = Symbols::new();
+        symbols.add_label(Location::Prg(4), "sub");
+        let mut debugger = Debugger::new(Cursor::new("break sub\nc\nc\n".to_string()), Vec::new());
+        debugger.set_symbols(symbols);
+        cpu.run_with_callback(|cpu| {
+            debugger.on_instruction(cpu);
+        })
+        .unwrap();
+        let output = String::from_utf8(debugger.output().clone()).unwrap();
+        assert!(output.contains("JSR sub"), "{}", output);
+        assert!(output.contains("breakpoint 1 at 8004 <sub>"), "{}", output);
+    }
+
+    #[test]
+    fn test_ram_search_and_freeze() {
+        // DEC $10; INC $11; DEC $10; INC $11; BRK
+        let program = [0xc6, 0x10, 0xe6, 0x11, 0xc6, 0x10, 0xe6, 0x11, 0x00];
+        let script = "search s8\nstep 2\nfilter decreased\nmon $10 s8 lives\n\
+                      freeze $11 $40\nstep 2\nmon\nc\n";
+        let (cpu, output) = run_debugger(&program, script);
+        assert!(output.contains("10240 candidates"), "{}", output);
+        assert!(
+            output.contains("1 candidates\n0010: -1 ($FF)"),
+            "{}",
+            output
+        );
+        assert!(output.contains("0010 s8  lives: -2 ($FE)"), "{}", output);
+        assert!(
+            output.contains("0011 u8  0011: 65 ($41) frozen"),
+            "{}",
+            output
+        );
+        assert_eq!(cpu.peek(0x11), 0x41);
+    }
+
+    #[test]
+    fn test_hex_dump_near_full_length() {
+        let dump = hex_dump(0x0000, 0xfff1, |addr| addr as u8);
+        assert_eq!(dump.lines().count(), 0x1000);
+        assert_eq!(dump.lines().last(), Some("FFF0: F0"));
+        let dump = hex_dump(0x8000, 0xffff, |_| 0);
+        assert!(dump.ends_with("7FF0: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n"));
+    }
+
+    #[test]
+    fn test_cheat_commands() {
+        let program = [0x00];
+        let script = "cheat add AAAAAA zero\ncheat add 0010:05\ncheat off 1\ncheat\nc\n";
+        let (cpu, output) = run_debugger(&program, script);
+        assert!(
+            output.contains("0: on  AAAAAA   8000:00    zero"),
+            "{}",
+            output
+        );
+        assert!(output.contains("1: off 0010:05  0010:05"), "{}", output);
+        assert_eq!(cpu.bus.cheats().patch(0x8000, 0xea), 0);
+    }
+
+    #[test]
+    fn test_log_command() {
+        let (cpu, output) = run_debugger(&[0x00], "log all=error bus=debug\nlog nes=info\nc\n");
+        assert!(
+            output.contains("cpu=error,bus=debug,ppu=error,mapper=error,input=error,apu=error"),
+            "{}",
+            output
+        );
+        assert!(output.contains("unknown log category 'nes'"), "{}", output);
+        assert_eq!(cpu.bus.logger().levels().level(Category::Bus), Level::Debug);
+    }
+}
@@ -0,0 +1,807 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::comp::cdl::{PRG_CODE, PRG_DATA, PRG_INDIRECT_CODE};
+use crate::comp::opcodes::{self, OpCode, Operand};
+
+// Static disassembler: walks PRG ROM from the reset/NMI/IRQ vectors following
+// JSR/JMP/branches (plus a couple of jump-table idioms) and emits ca65 source
+// and an ld65 config that rebuild the exact same .nes file. Anything that isn't
+// reached as code is kept as `.byte` data.
+//
+// Bank layout: up to 32KB is mapped as a whole (16KB ROMs at $C000); UxROM and
+// anything bigger is treated as 16KB banks with the last one fixed at $C000 and
+// the rest switched in at $8000 (the layout most homebrew uses). Code in the fixed
+// bank can't know which bank is at $8000, so such references stay numeric.
+//
+// A code/data log recorded while playing (see cdl.rs) adds every routine that
+// actually ran as an entry point and keeps tracing out of bytes only ever read.
+
+const INES_HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const BANK_SIZE: usize = 0x4000;
+const BYTES_PER_LINE: usize = 16;
+const MAX_JUMP_TABLE_ENTRIES: usize = 128;
+const MAPPER_UXROM: u8 = 2;
+
+pub struct Disassembly {
+    /// ca65 source
+    pub source: String,
+    /// ld65 config placing each segment so the output is byte-identical
+    pub config: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Byte {
+    Data,
+    /// read but never executed according to the code/data log
+    LoggedData,
+    Code,
+    /// operand byte of the instruction before it
+    Operand,
+    /// `.word target-adjust`, followed by a `WordHi`
+    Word {
+        target: u16,
+        adjust: u16,
+    },
+    WordHi,
+    /// `.byte <(target-adjust)` / `.byte >(target-adjust)` in split tables
+    Lo {
+        target: u16,
+        adjust: u16,
+    },
+    Hi {
+        target: u16,
+        adjust: u16,
+    },
+}
+
+struct Bank {
+    base: u16,
+    offset: usize,
+    size: usize,
+}
+
+impl Bank {
+    fn contains(&self, addr: u16) -> bool {
+        addr >= self.base && (addr as usize) < self.base as usize + self.size
+    }
+}
+
+struct Analysis<'a> {
+    prg: &'a [u8],
+    banks: Vec<Bank>,
+    bytes: Vec<Byte>,
+    /// PRG offsets that need a label, with the names given by the vectors
+    labels: BTreeMap<usize, Option<&'static str>>,
+    queue: Vec<usize>,
+}
+
+fn banks_for(prg_len: usize, mapper: u8) -> Vec<Bank> {
+    if prg_len <= BANK_SIZE || (prg_len <= 2 * BANK_SIZE && mapper != MAPPER_UXROM) {
+        return vec![Bank {
+            base: (0x10000 - prg_len) as u16,
+            offset: 0,
+            size: prg_len,
+        }];
+    }
+    let count = prg_len / BANK_SIZE;
+    (0..count)
+        .map(|i| Bank {
+            base: if i == count - 1 { 0xc000 } else { 0x8000 },
+            offset: i * BANK_SIZE,
+            size: BANK_SIZE,
+        })
+        .collect()
+}
+
+impl<'a> Analysis<'a> {
+    fn new(prg: &'a [u8], mapper: u8) -> Self {
+        Analysis {
+            prg,
+            banks: banks_for(prg.len(), mapper),
+            bytes: vec![Byte::Data; prg.len()],
+            labels: BTreeMap::new(),
+            queue: vec![],
+        }
+    }
+
+    fn bank_of(&self, offset: usize) -> usize {
+        self.banks
+            .iter()
+            .position(|b| offset >= b.offset && offset < b.offset + b.size)
+            .unwrap_or(0)
+    }
+
+    fn fixed_bank(&self) -> usize {
+        self.banks.len() - 1
+    }
+
+    /// PRG offset `addr` refers to when used from code in `from_bank`.
+    fn resolve(&self, from_bank: usize, addr: u16) -> Option<usize> {
+        [from_bank, self.fixed_bank()]
+            .iter()
+            .map(|&b| &self.banks[b])
+            .find(|b| b.contains(addr))
+            .map(|b| b.offset + (addr - b.base) as usize)
+    }
+
+    fn cpu_addr(&self, offset: usize) -> u16 {
+        let bank = &self.banks[self.bank_of(offset)];
+        bank.base + (offset - bank.offset) as u16
+    }
+
+    fn reference(&mut self, from_bank: usize, addr: u16) {
+        if let Some(offset) = self.resolve(from_bank, addr) {
+            self.labels.entry(offset).or_insert(None);
+        }
+    }
+
+    fn entry_point(&mut self, from_bank: usize, addr: u16) {
+        if let Some(offset) = self.resolve(from_bank, addr) {
+            self.labels.entry(offset).or_insert(None);
+            self.queue.push(offset);
+        }
+    }
+
+    fn vectors(&mut self) {
+        let fixed = self.fixed_bank();
+        for (addr, name) in [(0xfffa, "nmi"), (0xfffc, "reset"), (0xfffe, "irq")] {
+            let Some(offset) = self.resolve(fixed, addr) else {
+                continue;
+            };
+            let target = u16::from_le_bytes([self.prg[offset], self.prg[offset + 1]]);
+            if let Some(target_offset) = self.resolve(fixed, target) {
+                self.bytes[offset] = Byte::Word { target, adjust: 0 };
+                self.bytes[offset + 1] = Byte::WordHi;
+                let label = self.labels.entry(target_offset).or_insert(None);
+                label.get_or_insert(name);
+                self.queue.push(target_offset);
+            }
+        }
+    }
+
+    fn code_log(&mut self, prg_flags: &[u8]) {
+        for (offset, &flags) in prg_flags.iter().enumerate().take(self.prg.len()) {
+            if flags & PRG_CODE == 0 {
+                if flags & PRG_DATA != 0 {
+                    self.bytes[offset] = Byte::LoggedData;
+                }
+                continue;
+            }
+            if flags & PRG_INDIRECT_CODE != 0 {
+                self.labels.entry(offset).or_insert(None);
+            }
+            // operands are logged as code too, so only runs of code start instructions
+            let run_start = offset == 0 || prg_flags[offset - 1] & PRG_CODE == 0;
+            if run_start || flags & PRG_INDIRECT_CODE != 0 {
+                self.queue.push(offset);
+            }
+        }
+    }
+
+    fn run(&mut self) {
+        self.vectors();
+        while let Some(offset) = self.queue.pop() {
+            self.trace_from(offset);
+        }
+    }
+
+    /// Decodes one straight-line run of code starting at `offset`.
+    fn trace_from(&mut self, mut offset: usize) {
+        let bank = self.bank_of(offset);
+        let bank_end = self.banks[bank].offset + self.banks[bank].size;
+        let mut recent: Vec<(&'static OpCode, u16)> = vec![];
+
+        loop {
+            if self.bytes[offset] == Byte::Code {
+                return;
+            }
+            let op = opcodes::lookup(self.prg[offset]);
+            let size = op.size() as usize;
+            // undocumented opcodes are far more likely to be data than code
+            if !op.official
+                || offset + size > bank_end
+                || self.bytes[offset..offset + size]
+                    .iter()
+                    .any(|b| *b != Byte::Data)
+            {
+                return;
+            }
+            self.bytes[offset] = Byte::Code;
+            for b in &mut self.bytes[offset + 1..offset + size] {
+                *b = Byte::Operand;
+            }
+            let operand = match size {
+                2 => self.prg[offset + 1] as u16,
+                3 => u16::from_le_bytes([self.prg[offset + 1], self.prg[offset + 2]]),
+                _ => 0,
+            };
+            let next = offset + size;
+            let pc = self.cpu_addr(offset);
+            recent.push((op, operand));
+
+            match (op.mnemonic, op.mode) {
+                ("JMP", Operand::Absolute) => {
+                    self.entry_point(bank, operand);
+                    return;
+                }
+                ("JMP", Operand::Indirect) => {
+                    self.reference(bank, operand);
+                    self.indirect_jump_table(bank, &recent, operand);
+                    return;
+                }
+                ("JSR", _) => self.entry_point(bank, operand),
+                ("RTS", _) => {
+                    self.rts_jump_table(bank, &recent);
+                    return;
+                }
+                ("RTI", _) | ("BRK", _) => return,
+                (_, Operand::Relative) => {
+                    let target = pc.wrapping_add(2).wrapping_add(operand as u8 as i8 as u16);
+                    self.entry_point(bank, target);
+                }
+                (_, Operand::Absolute | Operand::Absolute_X | Operand::Absolute_Y) => {
+                    self.reference(bank, operand)
+                }
+                _ => {}
+            }
+            if next >= bank_end {
+                return;
+            }
+            offset = next;
+        }
+    }
+
+    /// `LDA lo,X / STA ptr / LDA hi,X / STA ptr+1 / JMP (ptr)`
+    fn indirect_jump_table(&mut self, bank: usize, recent: &[(&OpCode, u16)], ptr: u16) {
+        let table_for = |target: u16| {
+            recent.windows(2).rev().find_map(|w| match (w[0], w[1]) {
+                ((load, table), (store, addr))
+                    if load.mnemonic == "LDA"
+                        && matches!(load.mode, Operand::Absolute_X | Operand::Absolute_Y)
+                        && store.mnemonic == "STA"
+                        && matches!(store.mode, Operand::ZeroPage | Operand::Absolute)
+                        && addr == target =>
+                {
+                    Some(table)
+                }
+                _ => None,
+            })
+        };
+        if let (Some(lo), Some(hi)) = (table_for(ptr), table_for(ptr.wrapping_add(1))) {
+            self.jump_table(bank, lo, hi, 0);
+        }
+    }
+
+    /// `LDA hi,X / PHA / LDA lo,X / PHA / RTS`, the pushed address is target-1
+    fn rts_jump_table(&mut self, bank: usize, recent: &[(&OpCode, u16)]) {
+        let [
+            ..,
+            (load_hi, hi),
+            (push_hi, _),
+            (load_lo, lo),
+            (push_lo, _),
+            _,
+        ] = recent
+        else {
+            return;
+        };
+        let is_load = |op: &OpCode| {
+            op.mnemonic == "LDA" && matches!(op.mode, Operand::Absolute_X | Operand::Absolute_Y)
+        };
+        if is_load(load_hi)
+            && is_load(load_lo)
+            && push_hi.mnemonic == "PHA"
+            && push_lo.mnemonic == "PHA"
+        {
+            self.jump_table(bank, *lo, *hi, 1);
+        }
+    }
+
+    fn jump_table(&mut self, bank: usize, lo: u16, hi: u16, adjust: u16) {
+        let interleaved = hi == lo.wrapping_add(1);
+        let stride = if interleaved { 2 } else { 1 };
+        let limit = if !interleaved && hi > lo {
+            ((hi - lo) as usize).min(MAX_JUMP_TABLE_ENTRIES)
+        } else {
+            MAX_JUMP_TABLE_ENTRIES
+        };
+        for i in 0..limit as u16 {
+            let lo_addr = lo.wrapping_add(i * stride);
+            let hi_addr = hi.wrapping_add(i * stride);
+            let (Some(lo_offset), Some(hi_offset)) =
+                (self.resolve(bank, lo_addr), self.resolve(bank, hi_addr))
+            else {
+                return;
+            };
+            let is_data = |b: Byte| matches!(b, Byte::Data | Byte::LoggedData);
+            if !is_data(self.bytes[lo_offset]) || !is_data(self.bytes[hi_offset]) {
+                return;
+            }
+            let target =
+                u16::from_le_bytes([self.prg[lo_offset], self.prg[hi_offset]]).wrapping_add(adjust);
+            // the first entry that doesn't point into mapped code ends the table
+            match self.resolve(bank, target) {
+                Some(t) if matches!(self.bytes[t], Byte::Data | Byte::Code) => {}
+                _ => return,
+            }
+            if interleaved {
+                self.bytes[lo_offset] = Byte::Word { target, adjust };
+                self.bytes[hi_offset] = Byte::WordHi;
+            } else {
+                self.bytes[lo_offset] = Byte::Lo { target, adjust };
+                self.bytes[hi_offset] = Byte::Hi { target, adjust };
+            }
+            if i == 0 {
+                self.labels.entry(lo_offset).or_insert(None);
+                self.labels.entry(hi_offset).or_insert(None);
+            }
+            self.entry_point(bank, target);
+        }
+    }
+}
+
+struct Emitter<'a> {
+    analysis: &'a Analysis<'a>,
+    names: HashMap<usize, String>,
+}
+
+impl Emitter<'_> {
+    fn new<'a>(analysis: &'a Analysis<'a>) -> Emitter<'a> {
+        let multi_bank = analysis.banks.len() > 1;
+        let mut names = HashMap::new();
+        for (&offset, name) in &analysis.labels {
+            // labels that ended up inside an instruction become `start+n`
+            if matches!(analysis.bytes[offset], Byte::Operand | Byte::WordHi) {
+                continue;
+            }
+            let name = match name {
+                Some(name) => name.to_string(),
+                None => {
+                    let prefix = if analysis.bytes[offset] == Byte::Code {
+                        "L"
+                    } else {
+                        "D"
+                    };
+                    let addr = analysis.cpu_addr(offset);
+                    if multi_bank {
+                        format!("{}{:02}_{:04X}", prefix, analysis.bank_of(offset), addr)
+                    } else {
+                        format!("{}_{:04X}", prefix, addr)
+                    }
+                }
+            };
+            names.insert(offset, name);
+        }
+        Emitter { analysis, names }
+    }
+
+    /// `addr` as seen from `bank`: a label expression when it is in mapped ROM.
+    fn expr(&self, bank: usize, addr: u16) -> Option<String> {
+        let offset = self.analysis.resolve(bank, addr)?;
+        let mut start = offset;
+        while matches!(self.analysis.bytes[start], Byte::Operand | Byte::WordHi) {
+            start -= 1;
+        }
+        let name = self.names.get(&start)?;
+        Some(match offset - start {
+            0 => name.clone(),
+            n => format!("{}+{}", name, n),
+        })
+    }
+
+    fn absolute(&self, bank: usize, addr: u16) -> String {
+        match self.expr(bank, addr) {
+            Some(expr) => expr,
+            // without `a:` ca65 would pick the shorter zero page encoding
+            None if addr < 0x100 => format!("a:${:04X}", addr),
+            None => format!("${:04X}", addr),
+        }
+    }
+
+    fn adjusted(&self, bank: usize, target: u16, adjust: u16) -> String {
+        let stored = target.wrapping_sub(adjust);
+        match (self.expr(bank, target), adjust) {
+            (Some(expr), 0) => expr,
+            (Some(expr), n) => format!("{}-{}", expr, n),
+            (None, _) => format!("${:04X}", stored),
+        }
+    }
+
+    fn instruction(&self, bank: usize, offset: usize) -> String {
+        let prg = self.analysis.prg;
+        let op = opcodes::lookup(prg[offset]);
+        let byte = prg.get(offset + 1).copied().unwrap_or(0);
+        let word = u16::from_le_bytes([byte, prg.get(offset + 2).copied().unwrap_or(0)]);
+        let operand = match op.mode {
+            Operand::Implied => String::new(),
+            Operand::Accumulator => "a".to_string(),
+            Operand::Immediate => format!("#${:02X}", byte),
+            Operand::ZeroPage => format!("${:02X}", byte),
+            Operand::ZeroPage_X => format!("${:02X},x", byte),
+            Operand::ZeroPage_Y => format!("${:02X},y", byte),
+            Operand::Absolute => self.absolute(bank, word),
+            Operand::Absolute_X => format!("{},x", self.absolute(bank, word)),
+            Operand::Absolute_Y => format!("{},y", self.absolute(bank, word)),
+            Operand::Indirect => format!("({})", self.absolute(bank, word)),
+            Operand::Indirect_X => format!("(${:02X},x)", byte),
+            Operand::Indirect_Y => format!("(${:02X}),y", byte),
+            Operand::Relative => {
+                let pc = self.analysis.cpu_addr(offset);
+                let target = pc.wrapping_add(2).wrapping_add(byte as i8 as u16);
+                self.expr(bank, target).unwrap_or_else(|| {
+                    let delta = byte as i8 as i32 + 2;
+                    format!("*{:+}", delta)
+                })
+            }
+        };
+        let mnemonic = op.mnemonic.to_ascii_lowercase();
+        if operand.is_empty() {
+            format!("    {}", mnemonic)
+        } else {
+            format!("    {} {}", mnemonic, operand)
+        }
+    }
+
+    fn bank(&self, out: &mut String, index: usize) {
+        let analysis = self.analysis;
+        let bank = &analysis.banks[index];
+        let mut data: Vec<u8> = vec![];
+        let flush = |out: &mut String, data: &mut Vec<u8>| {
+            if !data.is_empty() {
+                out.push_str(&byte_line(data));
+                data.clear();
+            }
+        };
+
+        let mut offset = bank.offset;
+        while offset < bank.offset + bank.size {
+            if let Some(name) = self.names.get(&offset) {
+                flush(out, &mut data);
+                out.push_str(&format!("{}:\n", name));
+            }
+            let item = match analysis.bytes[offset] {
+                Byte::Code => Some((
+                    self.instruction(index, offset),
+                    opcodes::lookup(analysis.prg[offset]).size() as usize,
+                )),
+                Byte::Word { target, adjust } => Some((
+                    format!("    .word {}", self.adjusted(index, target, adjust)),
+                    2,
+                )),
+                Byte::Lo { target, adjust } => Some((
+                    format!("    .byte <({})", self.adjusted(index, target, adjust)),
+                    1,
+                )),
+                Byte::Hi { target, adjust } => Some((
+                    format!("    .byte >({})", self.adjusted(index, target, adjust)),
+                    1,
+                )),
+                _ => None,
+            };
+            match item {
+                Some((line, size)) => {
+                    flush(out, &mut data);
+                    out.push_str(&line);
+                    out.push('\n');
+                    offset += size;
+                }
+                None => {
+                    data.push(analysis.prg[offset]);
+                    if data.len() == BYTES_PER_LINE {
+                        flush(out, &mut data);
+                    }
+                    offset += 1;
+                }
+            }
+        }
+        flush(out, &mut data);
+    }
+}
+
+fn byte_line(bytes: &[u8]) -> String {
+    let values: Vec<String> = bytes.iter().map(|b| format!("${:02X}", b)).collect();
+    format!("    .byte {}\n", values.join(","))
+}
+
+fn raw_segment(out: &mut String, name: &str, bytes: &[u8]) {
+    out.push_str(&format!("\n.segment \"{}\"\n", name));
+    for chunk in bytes.chunks(BYTES_PER_LINE) {
+        out.push_str(&byte_line(chunk));
+    }
+}
+
+/// Disassembles a whole iNES file. `rom_name` only appears in comments,
+/// `cdl` is an FCEUX code/data log recorded for the same ROM.
+pub fn disassemble(raw: &[u8], rom_name: &str, cdl: Option<&[u8]>) -> Result<Disassembly, String> {
+    if raw.len() < INES_HEADER_SIZE || raw[..4] != [0x4E, 0x45, 0x53, 0x1A] {
+        return Err("File is not in iNes file format".to_string());
+    }
+    let header_size = INES_HEADER_SIZE + if raw[6] & 0b100 != 0 { TRAINER_SIZE } else { 0 };
+    let prg_size = raw[4] as usize * BANK_SIZE;
+    if prg_size == 0 || raw.len() < header_size + prg_size {
+        return Err("PRG ROM is missing or truncated".to_string());
+    }
+    let prg = &raw[header_size..header_size + prg_size];
+
+    let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
+    let mut analysis = Analysis::new(prg, mapper);
+    if let Some(cdl) = cdl {
+        analysis.code_log(cdl);
+    }
+    analysis.run();
+    let emitter = Emitter::new(&analysis);
+
+    let mut source = format!(
+        "; {} disassembled by rnes\n; assemble with: ca65 game.s && ld65 -C game.cfg game.o -o game.nes\n\n.setcpu \"6502\"\n",
+        rom_name
+    );
+    let mut config = String::from("MEMORY {\n");
+    let mut segments = String::from("SEGMENTS {\n");
+
+    raw_segment(&mut source, "HEADER", &raw[..header_size]);
+    config.push_str(&format!(
+        "    HEADER: start = $0000, size = ${:04X}, file = %O;\n",
+        header_size
+    ));
+    segments.push_str("    HEADER: load = HEADER, type = ro;\n");
+
+    for (i, bank) in analysis.banks.iter().enumerate() {
+        source.push_str(&format!("\n.segment \"PRG{}\"\n", i));
+        emitter.bank(&mut source, i);
+        config.push_str(&format!(
+            "    PRG{}: start = ${:04X}, size = ${:04X}, file = %O;\n",
+            i, bank.base, bank.size
+        ));
+        segments.push_str(&format!("    PRG{0}: load = PRG{0}, type = ro;\n", i));
+    }
+
+    // CHR ROM and anything after it
+    let tail = &raw[header_size + prg_size..];
+    if !tail.is_empty() {
+        raw_segment(&mut source, "CHR", tail);
+        config.push_str(&format!(
+            "    CHR: start = $0000, size = ${:04X}, file = %O;\n",
+            tail.len()
+        ));
+        segments.push_str("    CHR: load = CHR, type = ro;\n");
+    }
+
+    config.push_str("}\n");
+    segments.push_str("}\n");
+    config.push_str(&segments);
+    Ok(Disassembly { source, config })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_file() -> Vec<u8> {
+        let mut prg = vec![0xff; 0x4000];
+        let code: &[(u16, &[u8])] = &[
+            // reset: SEI; LDX #$00; JSR $C010; LDA $C020,X; BNE $C003; JMP $C006
+            (
+                0xc000,
+                &[
+                    0x78, 0xa2, 0x00, 0x20, 0x10, 0xc0, 0xbd, 0x20, 0xc0, 0xd0, 0xf8, 0x4c, 0x06,
+                    0xc0,
+                ],
+            ),
+            // LDA hi,X; PHA; LDA lo,X; PHA; RTS
+            (
+                0xc010,
+                &[0xbd, 0x32, 0xc0, 0x48, 0xbd, 0x30, 0xc0, 0x48, 0x60],
+            ),
+            (0xc020, &[0x01, 0x02, 0x03, 0x00]),
+            (0xc030, &[0x3f, 0x44, 0xc0, 0xc0]),
+            // INX; RTS / DEX; RTS / nmi: RTI
+            (0xc040, &[0xe8, 0x60]),
+            (0xc045, &[0xca, 0x60]),
+            (0xc050, &[0x40]),
+            (0xfffa, &[0x50, 0xc0, 0x00, 0xc0, 0x50, 0xc0]),
+        ];
+        for (addr, bytes) in code {
+            let at = (*addr - 0xc000) as usize;
+            prg[at..at + bytes.len()].copy_from_slice(bytes);
+        }
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x00];
+        raw.resize(INES_HEADER_SIZE, 0);
+        raw.extend(prg);
+        raw.extend((0..0x2000).map(|i| i as u8));
+        raw
+    }
+
+    fn segment_starts(config: &str) -> HashMap<String, u16> {
+        config
+            .lines()
+            .filter_map(|line| {
+                let (name, rest) = line.trim().split_once(": start = $")?;
+                Some((
+                    name.to_string(),
+                    u16::from_str_radix(&rest[..4], 16).unwrap(),
+                ))
+            })
+            .collect()
+    }
+
+    fn eval(expr: &str, labels: &HashMap<String, u16>, pc: u16) -> u16 {
+        let expr = expr.trim_start_matches("a:");
+        if let Some(delta) = expr.strip_prefix('*') {
+            return pc.wrapping_add(delta.parse::<i32>().unwrap() as u16);
+        }
+        let (base, delta) = match expr[1..].find(['+', '-']) {
+            Some(i) => (&expr[..i + 1], expr[i + 1..].parse::<i32>().unwrap()),
+            None => (expr, 0),
+        };
+        let value = match base.strip_prefix('$') {
+            Some(hex) => u16::from_str_radix(hex, 16).unwrap(),
+            // unknown in the first pass, but every label is in ROM
+            None => labels.get(base).copied().unwrap_or(0x8000),
+        };
+        value.wrapping_add(delta as u16)
+    }
+
+    fn encode(mnemonic: &str, operand: &str, labels: &HashMap<String, u16>, pc: u16) -> Vec<u8> {
+        let mnemonic = mnemonic.to_ascii_uppercase();
+        let find = |mode: Operand| {
+            opcodes::OPCODES
+                .iter()
+                .find(|op| op.official && op.mnemonic == mnemonic && op.mode == mode)
+        };
+        let indexed = |operand: &str, zp: Operand, abs: Operand| {
+            let value = eval(operand, labels, pc);
+            if !operand.starts_with("a:") && value < 0x100 && find(zp).is_some() {
+                (zp, value)
+            } else {
+                (abs, value)
+            }
+        };
+        let (mode, value) = if operand.is_empty() {
+            (Operand::Implied, 0)
+        } else if operand == "a" {
+            (Operand::Accumulator, 0)
+        } else if let Some(imm) = operand.strip_prefix('#') {
+            (Operand::Immediate, eval(imm, labels, pc))
+        } else if let Some(inner) = operand.strip_prefix('(') {
+            if let Some(zp) = inner.strip_suffix(",x)") {
+                (Operand::Indirect_X, eval(zp, labels, pc))
+            } else if let Some(zp) = inner.strip_suffix("),y") {
+                (Operand::Indirect_Y, eval(zp, labels, pc))
+            } else {
+                (
+                    Operand::Indirect,
+                    eval(inner.trim_end_matches(')'), labels, pc),
+                )
+            }
+        } else if let Some(base) = operand.strip_suffix(",x") {
+            indexed(base, Operand::ZeroPage_X, Operand::Absolute_X)
+        } else if let Some(base) = operand.strip_suffix(",y") {
+            indexed(base, Operand::ZeroPage_Y, Operand::Absolute_Y)
+        } else if find(Operand::Relative).is_some() {
+            let target = eval(operand, labels, pc);
+            (
+                Operand::Relative,
+                target.wrapping_sub(pc.wrapping_add(2)) & 0xff,
+            )
+        } else {
+            indexed(operand, Operand::ZeroPage, Operand::Absolute)
+        };
+        let op = find(mode).unwrap_or_else(|| panic!("can't encode {} {}", mnemonic, operand));
+        let mut bytes = vec![op.code];
+        bytes.extend(&value.to_le_bytes()[..op.size() as usize - 1]);
+        bytes
+    }
+
+    /// Just enough of ca65/ld65 to rebuild what the disassembler emits.
+    fn assemble(source: &str, config: &str) -> Vec<u8> {
+        let starts = segment_starts(config);
+        let mut labels = HashMap::new();
+        let mut output = vec![];
+        for pass in 0..2 {
+            output.clear();
+            let mut pc = 0u16;
+            for line in source.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with(';') || line.starts_with(".setcpu") {
+                    continue;
+                }
+                if let Some(name) = line.strip_prefix(".segment ") {
+                    pc = starts[name.trim_matches('"')];
+                    continue;
+                }
+                if let Some(label) = line.strip_suffix(':') {
+                    if pass == 0 {
+                        labels.insert(label.to_string(), pc);
+                    }
+                    continue;
+                }
+                let (directive, operand) = line.split_once(' ').unwrap_or((line, ""));
+                let bytes = match directive {
+                    ".word" => eval(operand, &labels, pc).to_le_bytes().to_vec(),
+                    ".byte" if operand.starts_with("<(") => {
+                        vec![eval(&operand[2..operand.len() - 1], &labels, pc) as u8]
+                    }
+                    ".byte" if operand.starts_with(">(") => {
+                        vec![(eval(&operand[2..operand.len() - 1], &labels, pc) >> 8) as u8]
+                    }
+                    ".byte" => operand
+                        .split(',')
+                        .map(|b| eval(b, &labels, pc) as u8)
+                        .collect(),
+                    mnemonic => encode(mnemonic, operand, &labels, pc),
+                };
+                pc = pc.wrapping_add(bytes.len() as u16);
+                output.extend(bytes);
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn test_follows_code_and_jump_tables() {
+        let disassembly = disassemble(&test_file(), "test.nes", None).unwrap();
+        let source = &disassembly.source;
+        for expected in [
+            "reset:\n    sei\n    ldx #$00\n",
+            "L_C003:\n    jsr L_C010\n",
+            "    lda D_C020,x\n    bne L_C003\n",
+            "    lda D_C032,x\n    pha\n    lda D_C030,x\n",
+            "D_C030:\n    .byte <(L_C040-1)\n    .byte <(L_C045-1)\n",
+            "D_C032:\n    .byte >(L_C040-1)\n    .byte >(L_C045-1)\n",
+            "L_C040:\n    inx\n    rts\n",
+            "L_C045:\n    dex\n    rts\n",
+            "nmi:\n    rti\n",
+            "    .word nmi\n    .word reset\n    .word nmi\n",
+        ] {
+            assert!(
+                source.contains(expected),
+                "missing {:?} in\n{}",
+                expected,
+                source
+            );
+        }
+        // bytes nothing jumps to stay data
+        assert!(source.contains("D_C020:\n    .byte $01,$02,$03,$00,$FF"));
+    }
+
+    #[test]
+    fn test_code_log_adds_entry_points() {
+        // LDA #$01; RTS at $C060, only ever reached through a pointer in RAM
+        let mut raw = test_file();
+        raw[INES_HEADER_SIZE + 0x60..INES_HEADER_SIZE + 0x63].copy_from_slice(&[0xa9, 0x01, 0x60]);
+        let mut cdl = vec![0; 0x6000];
+        cdl[0x60..0x63].copy_from_slice(&[PRG_CODE | PRG_INDIRECT_CODE, PRG_CODE, PRG_CODE]);
+        let without = disassemble(&raw, "test.nes", None).unwrap();
+        assert!(!without.source.contains("    lda #$01\n"));
+        let with = disassemble(&raw, "test.nes", Some(&cdl)).unwrap();
+        assert!(with.source.contains("L_C060:\n    lda #$01\n    rts\n"));
+        assert_eq!(assemble(&with.source, &with.config), raw);
+    }
+
+    #[test]
+    fn test_reassembles_to_identical_file() {
+        let raw = test_file();
+        let disassembly = disassemble(&raw, "test.nes", None).unwrap();
+        assert_eq!(assemble(&disassembly.source, &disassembly.config), raw);
+    }
+
+    #[test]
+    fn test_banked_rom_reassembles() {
+        // UxROM: bank 0 at $8000 calls into the fixed bank, which can't name it
+        let mut raw = test_file();
+        raw[4] = 2;
+        raw[6] = MAPPER_UXROM << 4;
+        let mut bank0 = vec![0xea; 0x4000];
+        bank0[..4].copy_from_slice(&[0x20, 0x40, 0xc0, 0x60]);
+        raw.splice(INES_HEADER_SIZE..INES_HEADER_SIZE, bank0);
+        // reset: JMP $8000 (not followed from the fixed bank)
+        raw[INES_HEADER_SIZE + 0x4000..INES_HEADER_SIZE + 0x4003]
+            .copy_from_slice(&[0x4c, 0x00, 0x80]);
+        let disassembly = disassemble(&raw, "banked.nes", None).unwrap();
+        assert!(disassembly.source.contains("reset:\n    jmp $8000\n"));
+        assert!(disassembly.config.contains("PRG0: start = $8000"));
+        assert!(disassembly.config.contains("PRG1: start = $C000"));
+        assert_eq!(assemble(&disassembly.source, &disassembly.config), raw);
+    }
+}
@@ -0,0 +1,195 @@
+use std::fmt;
+
+use crate::comp::rom::Mirroring;
+
+// Things a ROM can ask of the hardware that the emulator doesn't model. The
+// core reports them to the bus instead of panicking, and the bus's
+// `ErrorPolicy` decides per class whether each one is ignored, logged or
+// stops the machine. A stop comes out of the stepping APIs (`CPU::try_step`,
+// `Nes::step_instruction` and friends) as the `EmuError` that caused it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmuError {
+    /// an opcode the CPU doesn't implement, fetched from `pc`
+    IllegalOpcode { pc: u16, opcode: u8 },
+    /// a write to a read-only PPU register ($2002)
+    PpuRegisterWrite { addr: u16, data: u8 },
+    /// a write to $8000-$FFFF with no mapper to take it
+    RomWrite { addr: u16, data: u8 },
+    /// a nametable layout that needs VRAM the cartridge doesn't have
+    Mirroring(Mirroring),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    Opcode,
+    PpuRegister,
+    RomWrite,
+    Mirroring,
+}
+
+const CLASSES: [ErrorClass; 4] = [
+    ErrorClass::Opcode,
+    ErrorClass::PpuRegister,
+    ErrorClass::RomWrite,
+    ErrorClass::Mirroring,
+];
+
+impl ErrorClass {
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorClass::Opcode => "opcode",
+            ErrorClass::PpuRegister => "ppu-register",
+            ErrorClass::RomWrite => "rom-write",
+            ErrorClass::Mirroring => "mirroring",
+        }
+    }
+}
+
+impl EmuError {
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            EmuError::IllegalOpcode { .. } => ErrorClass::Opcode,
+            EmuError::PpuRegisterWrite { .. } => ErrorClass::PpuRegister,
+            EmuError::RomWrite { .. } => ErrorClass::RomWrite,
+            EmuError::Mirroring(_) => ErrorClass::Mirroring,
+        }
+    }
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmuError::IllegalOpcode { pc, opcode } => {
+                write!(f, "illegal opcode ${:02X} at ${:04X}", opcode, pc)
+            }
+            EmuError::PpuRegisterWrite { addr, data } => {
+                write!(
+                    f,
+                    "write of ${:02X} to read-only PPU register ${:04X}",
+                    data, addr
+                )
+            }
+            EmuError::RomWrite { addr, data } => {
+                write!(
+                    f,
+                    "write of ${:02X} to cartridge ROM at ${:04X}",
+                    data, addr
+                )
+            }
+            EmuError::Mirroring(mirroring) => {
+                write!(
+                    f,
+                    "{:?} mirroring needs nametable RAM the cartridge doesn't have",
+                    mirroring
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmuError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Ignore,
+    /// print it and carry on
+    Log,
+    Halt,
+}
+
+impl Action {
+    pub fn parse(text: &str) -> Result<Action, String> {
+        match text {
+            "ignore" => Ok(Action::Ignore),
+            "log" => Ok(Action::Log),
+            "halt" => Ok(Action::Halt),
+            other => Err(format!(
+                "unknown action '{}', expected ignore, log or halt",
+                other
+            )),
+        }
+    }
+}
+
+/// What to do about each class of error. Everything halts by default, which
+/// is what the emulator did before, minus the panic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorPolicy {
+    actions: [Action; CLASSES.len()],
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        ErrorPolicy {
+            actions: [Action::Halt; CLASSES.len()],
+        }
+    }
+}
+
+impl ErrorPolicy {
+    /// `class=action` pairs separated by commas, applied over the default,
+    /// e.g. `rom-write=ignore,mirroring=log`. `all` sets every class.
+    pub fn parse(text: &str) -> Result<ErrorPolicy, String> {
+        let mut policy = ErrorPolicy::default();
+        for rule in text
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+        {
+            let (class, action) = rule
+                .split_once('=')
+                .ok_or(format!("bad rule '{}', expected class=action", rule))?;
+            let action = Action::parse(action.trim())?;
+            match class.trim() {
+                "all" => policy.actions = [action; CLASSES.len()],
+                name => {
+                    let class = CLASSES
+                        .into_iter()
+                        .find(|class| class.name() == name)
+                        .ok_or(format!(
+                            "unknown error class '{}', expected all or one of {}",
+                            name,
+                            CLASSES.map(ErrorClass::name).join(", ")
+                        ))?;
+                    policy.set(class, action);
+                }
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn action(&self, class: ErrorClass) -> Action {
+        self.actions[class as usize]
+    }
+
+    pub fn set(&mut self, class: ErrorClass, action: Action) {
+        self.actions[class as usize] = action;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        let policy = ErrorPolicy::parse("all=log, rom-write=ignore,opcode=halt").unwrap();
+        assert_eq!(policy.action(ErrorClass::RomWrite), Action::Ignore);
+        assert_eq!(policy.action(ErrorClass::Opcode), Action::Halt);
+        assert_eq!(policy.action(ErrorClass::Mirroring), Action::Log);
+        assert_eq!(ErrorPolicy::parse("").unwrap(), ErrorPolicy::default());
+        assert!(ErrorPolicy::parse("opcode").is_err());
+        assert!(ErrorPolicy::parse("opcode=skip").is_err());
+        assert!(
+            ErrorPolicy::parse("apu=log")
+                .unwrap_err()
+                .contains("opcode, ppu-register, rom-write, mirroring")
+        );
+        let error = EmuError::RomWrite {
+            addr: 0x8000,
+            data: 0x0f,
+        };
+        assert_eq!(error.to_string(), "write of $0F to cartridge ROM at $8000");
+    }
+}
@@ -0,0 +1,171 @@
+use crate::comp::controller::Controller;
+use crate::comp::error::EmuError;
+use crate::comp::nes::Nes;
+use crate::comp::render::frame::Frame;
+
+#[cfg(feature = "sdl")]
+pub mod sdl;
+
+// What the emulator needs from the machine it runs on: somewhere to show
+// frames, somewhere to get pad input and other requests from, and optionally
+// somewhere to play sound. The core never calls a frontend itself; the host
+// loop steps a `Nes` and hands each finished frame over through `FrameSync`.
+
+/// Requests from the user other than pad input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Window closed or quit key: stop and save whatever needs saving.
+    Quit,
+    /// Break into the debugger.
+    Break,
+    /// Switch all cheats on or off.
+    ToggleCheats,
+}
+
+pub trait VideoSink {
+    fn present(&mut self, frame: &Frame);
+
+    /// Short status text, e.g. watched RAM values, for a title bar or overlay.
+    fn set_status(&mut self, _status: &str) {}
+}
+
+pub trait InputSource {
+    /// Applies pending input to `controller` and returns the other requests.
+    /// Called once per frame.
+    fn poll(&mut self, controller: &mut Controller) -> Vec<Event>;
+}
+
+/// Signed 16-bit mono samples. Nothing produces sound until there is an APU.
+pub trait AudioSink {
+    fn queue(&mut self, samples: &[i16]);
+}
+
+pub trait Frontend: VideoSink + InputSource {
+    fn audio(&mut self) -> Option<&mut dyn AudioSink> {
+        None
+    }
+
+    /// Called once the host loop has stopped and saved its state.
+    fn on_exit(&mut self) {}
+}
+
+/// Hands each frame a `Nes` finishes to a frontend, then polls its input.
+#[derive(Default)]
+pub struct FrameSync {
+    presented: usize,
+}
+
+impl FrameSync {
+    pub fn new() -> FrameSync {
+        FrameSync::default()
+    }
+
+    /// Call after every step. `None` until `nes` has finished a new frame.
+    pub fn update(&mut self, nes: &mut Nes, frontend: &mut dyn Frontend) -> Option<Vec<Event>> {
+        if nes.frame_count() == self.presented {
+            return None;
+        }
+        self.presented = nes.frame_count();
+        frontend.present(nes.frame());
+        Some(frontend.poll(nes.controller()))
+    }
+}
+
+/// Runs `nes` until the frontend asks to quit or the CPU halts, passing the
+/// other events to `on_event`. Returns the number of frames run, or the error
+/// that halted the CPU.
+pub fn run<F>(
+    nes: &mut Nes,
+    frontend: &mut dyn Frontend,
+    mut on_event: F,
+) -> Result<usize, EmuError>
+where
+    F: FnMut(Event, &mut Nes),
+{
+    let start = nes.frame_count();
+    let mut sync = FrameSync::new();
+    let stopped = 'running: loop {
+        match nes.step_instruction() {
+            Ok(true) => {}
+            Ok(false) => break Ok(()),
+            Err(error) => break Err(error),
+        }
+        for event in sync.update(nes, frontend).unwrap_or_default() {
+            match event {
+                Event::Quit => break 'running Ok(()),
+                event => on_event(event, nes),
+            }
+        }
+    };
+    frontend.on_exit();
+    stopped.map(|()| nes.frame_count() - start)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::controller::ControllerButtons;
+    use crate::comp::cpu::Mem;
+    use crate::comp::rom::test::{test_prg, test_rom};
+
+    /// Presses the scripted buttons on each frame and keeps the first bytes of every frame.
+    struct MockFrontend {
+        script: Vec<(ControllerButtons, Vec<Event>)>,
+        frames: Vec<Vec<u8>>,
+        exited: bool,
+    }
+
+    impl VideoSink for MockFrontend {
+        fn present(&mut self, frame: &Frame) {
+            self.frames.push(frame.data[..3].to_vec());
+        }
+    }
+
+    impl InputSource for MockFrontend {
+        fn poll(&mut self, controller: &mut Controller) -> Vec<Event> {
+            let (buttons, events) = match self.script.get(self.frames.len() - 1) {
+                Some(step) => step.clone(),
+                None => (ControllerButtons::empty(), vec![Event::Quit]),
+            };
+            controller.set_button_pressed_status(ControllerButtons::all(), false);
+            controller.set_button_pressed_status(buttons, true);
+            events
+        }
+    }
+
+    impl Frontend for MockFrontend {
+        fn on_exit(&mut self) {
+            self.exited = true;
+        }
+    }
+
+    #[test]
+    fn test_runs_scripted_input_until_quit() {
+        // loop: strobe the pad, read A and add it to $10; JMP loop
+        let prg = test_prg(&[
+            0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40, 0xad, 0x16, 0x40, 0x18,
+            0x65, 0x10, 0x85, 0x10, 0x4c, 0x00, 0x80,
+        ]);
+        let mut nes = Nes::new(test_rom(prg));
+        let mut frontend = MockFrontend {
+            script: vec![
+                (ControllerButtons::BUTTON_A, vec![Event::ToggleCheats]),
+                (ControllerButtons::empty(), vec![]),
+            ],
+            frames: vec![],
+            exited: false,
+        };
+        let mut events = vec![];
+        let frames = run(&mut nes, &mut frontend, |event, _| events.push(event));
+
+        assert_eq!(frames, Ok(3));
+        assert_eq!(frontend.frames.len(), 3);
+        assert!(frontend.exited);
+        assert_eq!(events, vec![Event::ToggleCheats]);
+        // A was only held during the second frame
+        let during_second_frame = nes.cpu_mut().mem_read(0x10);
+        assert!(during_second_frame > 0);
+        nes.step_frame().unwrap();
+        assert_eq!(nes.cpu_mut().mem_read(0x10), during_second_frame);
+    }
+}
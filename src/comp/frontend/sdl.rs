@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use sdl2::EventPump;
+use sdl2::event::Event as SdlEvent;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Texture, TextureCreator, WindowCanvas};
+use sdl2::video::WindowContext;
+
+use crate::comp::controller::{Controller, ControllerButtons};
+use crate::comp::frontend::{Event, Frontend, InputSource, VideoSink};
+use crate::comp::render::frame::Frame;
+
+// SDL2 window and keyboard. Arrows, Space (select), Return (start), C (A) and
+// X (B) are the pad; Escape/Q quit, F12 breaks into the debugger and F9
+// toggles cheats.
+
+const TITLE: &str = "Rnes";
+
+/// Opens the emulator window. The texture creator for `SdlFrontend::new`
+/// comes from `canvas.texture_creator()`.
+pub fn open_window(scale: f32) -> Result<(WindowCanvas, EventPump), String> {
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let window = video_subsystem
+        .window(TITLE, (256.0 * scale) as u32, (240.0 * scale) as u32)
+        .position_centered()
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut canvas = window
+        .into_canvas()
+        .present_vsync()
+        .build()
+        .map_err(|e| e.to_string())?;
+    canvas.set_scale(scale, scale)?;
+    Ok((canvas, sdl_context.event_pump()?))
+}
+
+pub struct SdlFrontend<'t> {
+    canvas: WindowCanvas,
+    texture: Texture<'t>,
+    event_pump: EventPump,
+    key_map: HashMap<Keycode, ControllerButtons>,
+    status: String,
+}
+
+impl<'t> SdlFrontend<'t> {
+    pub fn new(
+        canvas: WindowCanvas,
+        event_pump: EventPump,
+        creator: &'t TextureCreator<WindowContext>,
+    ) -> Result<SdlFrontend<'t>, String> {
+        let texture = creator
+            .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
+            .map_err(|e| e.to_string())?;
+
+        let mut key_map = HashMap::new();
+        key_map.insert(Keycode::Down, ControllerButtons::DOWN);
+        key_map.insert(Keycode::Up, ControllerButtons::UP);
+        key_map.insert(Keycode::Right, ControllerButtons::RIGHT);
+        key_map.insert(Keycode::Left, ControllerButtons::LEFT);
+        key_map.insert(Keycode::Space, ControllerButtons::SELECT);
+        key_map.insert(Keycode::Return, ControllerButtons::START);
+        key_map.insert(Keycode::C, ControllerButtons::BUTTON_A);
+        key_map.insert(Keycode::X, ControllerButtons::BUTTON_B);
+
+        Ok(SdlFrontend {
+            canvas,
+            texture,
+            event_pump,
+            key_map,
+            status: String::new(),
+        })
+    }
+}
+
+impl VideoSink for SdlFrontend<'_> {
+    fn present(&mut self, frame: &Frame) {
+        self.texture
+            .update(None, &frame.data, 256 * 3)
+            .expect("Problem here");
+        self.canvas.copy(&self.texture, None, None).unwrap();
+        self.canvas.present();
+    }
+
+    fn set_status(&mut self, status: &str) {
+        if status == self.status {
+            return;
+        }
+        self.status = status.to_string();
+        let title = match status {
+            "" => TITLE.to_string(),
+            status => format!("{} - {}", TITLE, status),
+        };
+        let _ = self.canvas.window_mut().set_title(&title);
+    }
+}
+
+impl InputSource for SdlFrontend<'_> {
+    fn poll(&mut self, controller: &mut Controller) -> Vec<Event> {
+        let mut events = vec![];
+        for event in self.event_pump.poll_iter() {
+            match event {
+                SdlEvent::Quit { .. }
+                | SdlEvent::KeyDown {
+                    keycode: Some(Keycode::Escape | Keycode::Q),
+                    ..
+                } => events.push(Event::Quit),
+                SdlEvent::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => events.push(Event::Break),
+                SdlEvent::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    repeat: false,
+                    ..
+                } => events.push(Event::ToggleCheats),
+                SdlEvent::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    if let Some(key) = self.key_map.get(&keycode) {
+                        controller.set_button_pressed_status(*key, true);
+                    }
+                }
+                SdlEvent::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    if let Some(key) = self.key_map.get(&keycode) {
+                        controller.set_button_pressed_status(*key, false);
+                    }
+                }
+                _ => { /* do nothing */ }
+            }
+        }
+        events
+    }
+}
+
+impl Frontend for SdlFrontend<'_> {}
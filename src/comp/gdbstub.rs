@@ -0,0 +1,549 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::comp::bus::Bus;
+use crate::comp::cpu::{CPU, CpuFlags, Mem};
+use crate::comp::debugger::{WatchKind, data_access, mirror_down};
+
+// GDB remote serial protocol stub (https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html).
+// Like the debugger it is consulted before every instruction from the
+// `run_with_callback` callback, and talks to the client whenever the CPU is stopped.
+//
+// gdb has no 6502 target, so the register layout is described in target.xml:
+// a, x, y, p, sp (8 bit each) then pc (16 bit, little endian).
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rnes.6502">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="x" bitsize="8"/>
+    <reg name="y" bitsize="8"/>
+    <reg name="p" bitsize="8"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const SIGTRAP: u8 = 5;
+const SIGINT: u8 = 2;
+// checking the socket for ^C is a syscall, don't do it every instruction
+const INTERRUPT_POLL_INTERVAL: usize = 1024;
+
+/// A byte stream to the gdb client that can also tell if a ^C is waiting.
+pub trait Connection: Read + Write {
+    fn interrupt_pending(&mut self) -> bool;
+}
+
+impl Connection for TcpStream {
+    fn interrupt_pending(&mut self) -> bool {
+        let mut byte = [0u8; 1];
+        if self.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let pending = matches!(self.peek(&mut byte), Ok(1) if byte[0] == 0x03);
+        if pending {
+            let _ = self.read(&mut byte);
+        }
+        let _ = self.set_nonblocking(false);
+        pending
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Stopped,
+    Running,
+    Stepping,
+}
+
+pub struct GdbStub<C: Connection> {
+    conn: C,
+    state: State,
+    breakpoints: Vec<u16>,
+    watchpoints: Vec<(u16, u16, WatchKind)>,
+    resumed_at: Option<u16>,
+    since_poll: usize,
+    detached: bool,
+}
+
+/// Waits for a gdb client on 127.0.0.1:`port`.
+pub fn listen(port: u16) -> io::Result<GdbStub<TcpStream>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("waiting for gdb on 127.0.0.1:{}", port);
+    let (stream, addr) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    println!("gdb connected from {}", addr);
+    Ok(GdbStub::new(stream))
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+fn registers(cpu: &CPU<Bus>) -> Vec<u8> {
+    let [pc_lo, pc_hi] = cpu.pc.to_le_bytes();
+    vec![
+        cpu.reg_a,
+        cpu.reg_x,
+        cpu.reg_y,
+        cpu.status.bits(),
+        cpu.stk_ptr,
+        pc_lo,
+        pc_hi,
+    ]
+}
+
+fn set_register(cpu: &mut CPU<Bus>, index: usize, bytes: &[u8]) -> bool {
+    match (index, bytes) {
+        (0, [v]) => cpu.reg_a = *v,
+        (1, [v]) => cpu.reg_x = *v,
+        (2, [v]) => cpu.reg_y = *v,
+        (3, [v]) => cpu.status = CpuFlags::from_bits_truncate(*v),
+        (4, [v]) => cpu.stk_ptr = *v,
+        (5, [lo, hi]) => cpu.pc = u16::from_le_bytes([*lo, *hi]),
+        _ => return false,
+    }
+    true
+}
+
+impl<C: Connection> GdbStub<C> {
+    /// Starts stopped, waiting for the client's first command.
+    pub fn new(conn: C) -> Self {
+        GdbStub {
+            conn,
+            state: State::Stopped,
+            breakpoints: vec![],
+            watchpoints: vec![],
+            resumed_at: None,
+            since_poll: 0,
+            detached: false,
+        }
+    }
+
+    pub fn connection(&self) -> &C {
+        &self.conn
+    }
+
+    /// Call before every instruction. Returns false when the client kills the target.
+    pub fn on_instruction(&mut self, cpu: &mut CPU<Bus>) -> bool {
+        if self.detached {
+            return true;
+        }
+        let stop = match self.state {
+            State::Stopped => None,
+            State::Stepping => Some(format!("S{:02x}", SIGTRAP)),
+            State::Running => self.stop_reason(cpu),
+        };
+        self.resumed_at = None;
+        match stop {
+            Some(reply) => {
+                self.state = State::Stopped;
+                if self.send(&reply).is_err() {
+                    return false;
+                }
+            }
+            None if self.state == State::Running => return true,
+            None => {}
+        }
+        self.serve(cpu)
+    }
+
+    /// Reports that the program ended (BRK), so the client doesn't wait forever.
+    pub fn on_exit(&mut self) {
+        if !self.detached {
+            let _ = self.send("W00");
+        }
+    }
+
+    fn stop_reason(&mut self, cpu: &CPU<Bus>) -> Option<String> {
+        self.since_poll += 1;
+        if self.since_poll >= INTERRUPT_POLL_INTERVAL {
+            self.since_poll = 0;
+            if self.conn.interrupt_pending() {
+                return Some(format!("S{:02x}", SIGINT));
+            }
+        }
+        if self.resumed_at == Some(cpu.pc) {
+            return None;
+        }
+        if self.breakpoints.contains(&cpu.pc) {
+            return Some(format!("T{:02x}swbreak:;", SIGTRAP));
+        }
+        let (target, access) = data_access(cpu)?;
+        let target = mirror_down(target);
+        self.watchpoints.iter().find_map(|&(addr, len, kind)| {
+            let start = mirror_down(addr);
+            let hit = target >= start
+                && target < start.saturating_add(len.max(1))
+                && (kind == WatchKind::ReadWrite
+                    || access == WatchKind::ReadWrite
+                    || kind == access);
+            hit.then(|| {
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::ReadWrite => "awatch",
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, name, target)
+            })
+        })
+    }
+
+    /// Answers packets until the client resumes execution.
+    fn serve(&mut self, cpu: &mut CPU<Bus>) -> bool {
+        loop {
+            let packet = match self.receive() {
+                Ok(Some(packet)) => packet,
+                // ^C while already stopped
+                Ok(None) => {
+                    if self.send(&format!("S{:02x}", SIGINT)).is_err() {
+                        return false;
+                    }
+                    continue;
+                }
+                Err(_) => return false,
+            };
+            let reply = match packet.as_bytes().first() {
+                Some(b'c') | Some(b's') => {
+                    if let Some(addr) = parse_hex(&packet[1..]) {
+                        cpu.pc = addr;
+                    }
+                    self.state = if packet.starts_with('c') {
+                        State::Running
+                    } else {
+                        State::Stepping
+                    };
+                    self.resumed_at = Some(cpu.pc);
+                    return true;
+                }
+                Some(b'k') => return false,
+                Some(b'D') => {
+                    self.detached = true;
+                    let _ = self.send("OK");
+                    return true;
+                }
+                _ => self.handle(cpu, &packet),
+            };
+            if self.send(&reply).is_err() {
+                return false;
+            }
+        }
+    }
+
+    fn handle(&mut self, cpu: &mut CPU<Bus>, packet: &str) -> String {
+        let error = "E01".to_string();
+        if packet.is_empty() {
+            return String::new();
+        }
+        let (command, rest) = packet.split_at(1);
+        match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => hex_bytes(&registers(cpu)),
+            "G" => match parse_hex_bytes(rest) {
+                Some(bytes) if bytes.len() == 7 => {
+                    set_register(cpu, 0, &bytes[0..1]);
+                    set_register(cpu, 1, &bytes[1..2]);
+                    set_register(cpu, 2, &bytes[2..3]);
+                    set_register(cpu, 3, &bytes[3..4]);
+                    set_register(cpu, 4, &bytes[4..5]);
+                    set_register(cpu, 5, &bytes[5..7]);
+                    "OK".to_string()
+                }
+                _ => error,
+            },
+            "p" => match usize::from_str_radix(rest, 16) {
+                Ok(5) => hex_bytes(&cpu.pc.to_le_bytes()),
+                Ok(n) if n < 5 => hex_bytes(&registers(cpu)[n..n + 1]),
+                _ => error,
+            },
+            "P" => {
+                let parsed = rest.split_once('=').and_then(|(reg, value)| {
+                    Some((
+                        usize::from_str_radix(reg, 16).ok()?,
+                        parse_hex_bytes(value)?,
+                    ))
+                });
+                match parsed {
+                    Some((reg, bytes)) if set_register(cpu, reg, &bytes) => "OK".to_string(),
+                    _ => error,
+                }
+            }
+            "m" => {
+                let range = rest
+                    .split_once(',')
+                    .and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)?)));
+                match range {
+                    Some((addr, len)) => {
+                        let bytes: Vec<u8> =
+                            (0..len).map(|i| cpu.peek(addr.wrapping_add(i))).collect();
+                        hex_bytes(&bytes)
+                    }
+                    None => error,
+                }
+            }
+            "M" => {
+                let parsed = rest.split_once(':').and_then(|(range, data)| {
+                    let (addr, _) = range.split_once(',')?;
+                    Some((parse_hex(addr)?, parse_hex_bytes(data)?))
+                });
+                match parsed {
+                    Some((addr, bytes)) => {
+                        for (i, byte) in bytes.iter().enumerate() {
+                            cpu.mem_write(addr.wrapping_add(i as u16), *byte);
+                        }
+                        "OK".to_string()
+                    }
+                    None => error,
+                }
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", rest).unwrap_or(error),
+            "H" => "OK".to_string(),
+            "q" => self.query(rest),
+            // everything else (vCont, X, ...) is unsupported, gdb falls back to the basics
+            _ => String::new(),
+        }
+    }
+
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let addr = parse_hex(fields.next()?)?;
+        let len = parse_hex(fields.next()?)?;
+        let watch = match kind {
+            // hardware breakpoints behave the same as software ones here
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.push(addr);
+                } else if let Some(i) = self.breakpoints.iter().position(|&a| a == addr) {
+                    self.breakpoints.remove(i);
+                }
+                return Some("OK".to_string());
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::ReadWrite,
+            _ => return Some(String::new()),
+        };
+        if insert {
+            self.watchpoints.push((addr, len, watch));
+        } else {
+            self.watchpoints.retain(|&w| w != (addr, len, watch));
+        }
+        Some("OK".to_string())
+    }
+
+    fn query(&mut self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            "PacketSize=1000;qXfer:features:read+;swbreak+".to_string()
+        } else if query == "Attached" {
+            "1".to_string()
+        } else if query == "C" {
+            "QC1".to_string()
+        } else if query == "fThreadInfo" {
+            "m1".to_string()
+        } else if query == "sThreadInfo" {
+            "l".to_string()
+        } else if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let Some((offset, len)) = range.split_once(',') else {
+                return "E01".to_string();
+            };
+            let offset = usize::from_str_radix(offset, 16)
+                .unwrap_or(0)
+                .min(TARGET_XML.len());
+            let len = usize::from_str_radix(len, 16).unwrap_or(0);
+            let end = (offset + len).min(TARGET_XML.len());
+            let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+            format!("{}{}", marker, &TARGET_XML[offset..end])
+        } else {
+            String::new()
+        }
+    }
+
+    /// Reads the next packet, acking it. `None` means the client sent ^C.
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0u8; 1];
+        loop {
+            self.conn.read_exact(&mut byte)?;
+            match byte[0] {
+                b'$' => break,
+                0x03 => return Ok(None),
+                // acks for our replies and line noise
+                _ => continue,
+            }
+        }
+        let mut data = vec![];
+        loop {
+            self.conn.read_exact(&mut byte)?;
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut checksum = [0u8; 2];
+        self.conn.read_exact(&mut checksum)?;
+        let expected = std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|c| u8::from_str_radix(c, 16).ok());
+        let actual = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if expected != Some(actual) {
+            self.conn.write_all(b"-")?;
+            return self.receive();
+        }
+        self.conn.write_all(b"+")?;
+        Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.conn, "${}#{:02x}", data, checksum)?;
+        self.conn.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::rom::test::{test_prg, test_rom};
+    use std::io::Cursor;
+
+    struct MockConnection {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MockConnection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockConnection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for MockConnection {
+        fn interrupt_pending(&mut self) -> bool {
+            false
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        format!("${}#{:02x}", data, checksum)
+    }
+
+    /// Runs `program` at $8000 under the stub and returns the replies, acks stripped.
+    fn session(program: &[u8], packets: &[&str]) -> (CPU<Bus<'static>>, Vec<String>) {
+        let mut cpu = CPU::new(Bus::new(test_rom(test_prg(program)), |_, _| {}));
+        cpu.reset();
+
+        let input: String = packets.iter().map(|p| format!("+{}", packet(p))).collect();
+        let mut stub = GdbStub::new(MockConnection {
+            input: Cursor::new(input.into_bytes()),
+            output: vec![],
+        });
+        cpu.run_with_callback(|cpu| {
+            stub.on_instruction(cpu);
+        })
+        .unwrap();
+        stub.on_exit();
+
+        let output = String::from_utf8(stub.connection().output.clone()).unwrap();
+        let replies = output
+            .split('$')
+            .skip(1)
+            .map(|p| p.split('#').next().unwrap().to_string())
+            .collect();
+        (cpu, replies)
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        // LDA #$40; BRK
+        let (cpu, replies) = session(
+            &[0xa9, 0x40, 0x00],
+            &[
+                "?",
+                "g",
+                "m8000,3",
+                "M0010,2:beef",
+                "m0010,2",
+                "P1=12",
+                "p1",
+                "c",
+            ],
+        );
+        assert_eq!(replies[0], "S05");
+        assert_eq!(replies[1], "00000020fd0080");
+        assert_eq!(replies[2], "a94000");
+        assert_eq!(replies[3], "OK");
+        assert_eq!(replies[4], "beef");
+        assert_eq!(replies[5], "OK");
+        assert_eq!(replies[6], "12");
+        assert_eq!(replies.last().unwrap(), "W00");
+        assert_eq!(cpu.reg_x, 0x12);
+        assert_eq!(cpu.reg_a, 0x40);
+    }
+
+    #[test]
+    fn test_breakpoint_and_step() {
+        // LDA #$40; STA $10; LDX #$01; BRK
+        let (_, replies) = session(
+            &[0xa9, 0x40, 0x85, 0x10, 0xa2, 0x01, 0x00],
+            &["Z0,8004,1", "c", "g", "s", "g", "c"],
+        );
+        assert_eq!(replies[0], "OK");
+        assert_eq!(replies[1], "T05swbreak:;");
+        assert_eq!(replies[2], "40000020fd0480");
+        assert_eq!(replies[3], "S05");
+        assert_eq!(replies[4], "40010020fd0680");
+        assert_eq!(replies[5], "W00");
+    }
+
+    #[test]
+    fn test_write_watchpoint() {
+        // LDA #$40; STA $10; LDX #$01; BRK
+        let (_, replies) = session(
+            &[0xa9, 0x40, 0x85, 0x10, 0xa2, 0x01, 0x00],
+            &["Z2,0010,1", "c", "p5", "c"],
+        );
+        assert_eq!(replies[1], "T05watch:0010;");
+        assert_eq!(replies[2], "0280");
+    }
+
+    #[test]
+    fn test_target_description() {
+        let (_, replies) = session(
+            &[0x00],
+            &[
+                "qSupported:multiprocess+",
+                "qXfer:features:read:target.xml:0,ffff",
+                "c",
+            ],
+        );
+        assert!(replies[0].contains("qXfer:features:read+"));
+        assert!(replies[1].starts_with("l<?xml"));
+    }
+}
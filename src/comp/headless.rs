@@ -0,0 +1,175 @@
+use std::path::Path;
+
+use crate::comp::controller::{Controller, ControllerButtons};
+use crate::comp::frontend::{self, Event, Frontend, InputSource, VideoSink};
+use crate::comp::nes::Nes;
+use crate::comp::render::frame::Frame;
+
+// Running without a window, for regression tests in containers: pad input
+// comes from a script and every frame is reduced to a checksum.
+//
+// Script lines are `<frame> [buttons...]`, e.g. `60 start` then `62` to let go.
+// Buttons (up down left right a b select start) stay held from that frame
+// until the next line. `#` starts a comment.
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct InputScript {
+    changes: Vec<(usize, ControllerButtons)>,
+}
+
+fn parse_button(name: &str) -> Result<ControllerButtons, String> {
+    match name.to_ascii_lowercase().as_str() {
+        "up" => Ok(ControllerButtons::UP),
+        "down" => Ok(ControllerButtons::DOWN),
+        "left" => Ok(ControllerButtons::LEFT),
+        "right" => Ok(ControllerButtons::RIGHT),
+        "a" => Ok(ControllerButtons::BUTTON_A),
+        "b" => Ok(ControllerButtons::BUTTON_B),
+        "select" => Ok(ControllerButtons::SELECT),
+        "start" => Ok(ControllerButtons::START),
+        other => Err(format!("unknown button '{}'", other)),
+    }
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<InputScript, String> {
+        let mut changes: Vec<(usize, ControllerButtons)> = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut words = line.split_whitespace();
+            let Some(frame) = words.next() else {
+                continue;
+            };
+            let frame: usize = frame
+                .parse()
+                .map_err(|_| format!("line {}: bad frame number '{}'", n + 1, frame))?;
+            if changes.last().is_some_and(|&(last, _)| last >= frame) {
+                return Err(format!("line {}: frames must increase", n + 1));
+            }
+            let mut buttons = ControllerButtons::empty();
+            for name in words {
+                buttons |= parse_button(name).map_err(|e| format!("line {}: {}", n + 1, e))?;
+            }
+            changes.push((frame, buttons));
+        }
+        Ok(InputScript { changes })
+    }
+
+    pub fn load(path: &Path) -> Result<InputScript, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        InputScript::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Buttons held during `frame` (counting from 0).
+    pub fn buttons(&self, frame: usize) -> ControllerButtons {
+        self.changes
+            .iter()
+            .take_while(|(at, _)| *at <= frame)
+            .last()
+            .map_or(ControllerButtons::empty(), |(_, buttons)| *buttons)
+    }
+
+    fn apply(&self, frame: usize, controller: &mut Controller) {
+        controller.set_button_pressed_status(ControllerButtons::all(), false);
+        controller.set_button_pressed_status(self.buttons(frame), true);
+    }
+}
+
+/// Frontend that plays back an input script and keeps each frame's checksum.
+struct Headless<'a> {
+    script: &'a InputScript,
+    frames: usize,
+    checksums: Vec<u64>,
+}
+
+impl VideoSink for Headless<'_> {
+    fn present(&mut self, frame: &Frame) {
+        self.checksums.push(frame.checksum());
+    }
+}
+
+impl InputSource for Headless<'_> {
+    fn poll(&mut self, controller: &mut Controller) -> Vec<Event> {
+        if self.checksums.len() >= self.frames {
+            return vec![Event::Quit];
+        }
+        self.script.apply(self.checksums.len(), controller);
+        vec![]
+    }
+}
+
+impl Frontend for Headless<'_> {}
+
+/// Runs `frames` frames (fewer if the CPU halts) and returns their checksums.
+/// The last one is left in `nes.frame()`, and an error that halted the CPU
+/// in `nes.error()`.
+pub fn run(nes: &mut Nes, frames: usize, script: &InputScript) -> Vec<u64> {
+    let mut headless = Headless {
+        script,
+        frames,
+        checksums: vec![],
+    };
+    if frames > 0 {
+        script.apply(0, nes.controller());
+        let _ = frontend::run(nes, &mut headless, |_, _| {});
+    }
+    headless.checksums
+}
+
+/// One `<frame> <checksum>` line per frame.
+pub fn checksum_log(checksums: &[u64]) -> String {
+    checksums
+        .iter()
+        .enumerate()
+        .map(|(frame, checksum)| format!("{} {:016x}\n", frame, checksum))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::cpu::Mem;
+    use crate::comp::rom::test::{test_prg, test_rom};
+
+    #[test]
+    fn test_parse_script() {
+        let script =
+            InputScript::parse("# title screen\n60 start\n62\n\n90 right A # run\n").unwrap();
+        assert_eq!(script.buttons(0), ControllerButtons::empty());
+        assert_eq!(script.buttons(61), ControllerButtons::START);
+        assert_eq!(script.buttons(62), ControllerButtons::empty());
+        assert_eq!(
+            script.buttons(500),
+            ControllerButtons::RIGHT | ControllerButtons::BUTTON_A
+        );
+        assert!(InputScript::parse("10 up\n5 down").is_err());
+        assert!(InputScript::parse("10 turbo").is_err());
+    }
+
+    #[test]
+    fn test_runs_frames_with_scripted_input() {
+        // loop: strobe the pad, read A into $10; JMP loop
+        let prg = test_prg(&[
+            0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40, 0xad, 0x16, 0x40, 0x85,
+            0x10, 0x4c, 0x00, 0x80,
+        ]);
+        let rom = || test_rom(prg.clone());
+
+        let script = InputScript::parse("0 a\n2").unwrap();
+        let mut nes = Nes::new(rom());
+        let checksums = run(&mut nes, 2, &script);
+        assert_eq!(checksums.len(), 2);
+        assert_eq!(nes.frame_count(), 2);
+        assert_eq!(nes.cpu_mut().mem_read(0x10), 1);
+
+        // same ROM and input, same frames
+        let mut again = Nes::new(rom());
+        assert_eq!(run(&mut again, 3, &script)[..2], checksums[..]);
+        assert_eq!(again.cpu_mut().mem_read(0x10), 0);
+        assert_eq!(
+            checksum_log(&checksums[..1]),
+            format!("0 {:016x}\n", checksums[0])
+        );
+    }
+}
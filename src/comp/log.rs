@@ -0,0 +1,346 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+
+// Diagnostics from the core, one switch per part of the machine. Each line
+// says where the emulated program was when it happened:
+//
+//   [bus warn] $C123 sl 241: ignored read: $5000
+//
+// The bus owns the logger and shares it with the PPU, so the levels can be
+// changed while a game runs (the debugger's `log` command). A message kind is
+// printed the first `limit` times; after that it is only counted, with a line
+// at every power of ten, so a game hammering an unmapped register doesn't
+// slow emulation down to the speed of the terminal.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    Cpu,
+    Bus,
+    Ppu,
+    Mapper,
+    Input,
+    Apu,
+}
+
+const CATEGORIES: [Category; 6] = [
+    Category::Cpu,
+    Category::Bus,
+    Category::Ppu,
+    Category::Mapper,
+    Category::Input,
+    Category::Apu,
+];
+
+impl Category {
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Cpu => "cpu",
+            Category::Bus => "bus",
+            Category::Ppu => "ppu",
+            Category::Mapper => "mapper",
+            Category::Input => "input",
+            Category::Apu => "apu",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+const LEVELS: [Level; 6] = [
+    Level::Off,
+    Level::Error,
+    Level::Warn,
+    Level::Info,
+    Level::Debug,
+    Level::Trace,
+];
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Level, String> {
+        LEVELS
+            .into_iter()
+            .find(|level| level.name() == text)
+            .ok_or(format!(
+                "unknown log level '{}', expected one of {}",
+                text,
+                LEVELS.map(Level::name).join(", ")
+            ))
+    }
+}
+
+/// The most detailed level shown for each category. Warnings and errors are
+/// shown by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Levels {
+    levels: [Level; CATEGORIES.len()],
+}
+
+impl Default for Levels {
+    fn default() -> Self {
+        Levels {
+            levels: [Level::Warn; CATEGORIES.len()],
+        }
+    }
+}
+
+impl Levels {
+    /// `category=level` pairs separated by commas, applied over `self`, e.g.
+    /// `bus=debug,apu=off`. `all` sets every category.
+    pub fn parse(&self, text: &str) -> Result<Levels, String> {
+        let mut levels = *self;
+        for rule in text
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+        {
+            let (category, level) = rule
+                .split_once('=')
+                .ok_or(format!("bad rule '{}', expected category=level", rule))?;
+            let level = Level::parse(level.trim())?;
+            match category.trim() {
+                "all" => levels.levels = [level; CATEGORIES.len()],
+                name => {
+                    let category = CATEGORIES
+                        .into_iter()
+                        .find(|category| category.name() == name)
+                        .ok_or(format!(
+                            "unknown log category '{}', expected all or one of {}",
+                            name,
+                            CATEGORIES.map(Category::name).join(", ")
+                        ))?;
+                    levels.set(category, level);
+                }
+            }
+        }
+        Ok(levels)
+    }
+
+    pub fn level(&self, category: Category) -> Level {
+        self.levels[category as usize]
+    }
+
+    pub fn set(&mut self, category: Category, level: Level) {
+        self.levels[category as usize] = level;
+    }
+}
+
+impl fmt::Display for Levels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rules: Vec<String> = CATEGORIES
+            .into_iter()
+            .map(|category| format!("{}={}", category.name(), self.level(category).name()))
+            .collect();
+        write!(f, "{}", rules.join(","))
+    }
+}
+
+pub struct Logger {
+    levels: Cell<Levels>,
+    limit: Cell<usize>,
+    // the instruction being executed, set by the bus
+    pc: Cell<u16>,
+    seen: RefCell<HashMap<(Category, &'static str), usize>>,
+    out: RefCell<Box<dyn Write>>,
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Logger::new(Box::new(std::io::stderr()))
+    }
+}
+
+impl Logger {
+    pub fn new(out: Box<dyn Write>) -> Logger {
+        Logger {
+            levels: Cell::new(Levels::default()),
+            limit: Cell::new(10),
+            pc: Cell::new(0),
+            seen: RefCell::new(HashMap::new()),
+            out: RefCell::new(out),
+        }
+    }
+
+    pub fn levels(&self) -> Levels {
+        self.levels.get()
+    }
+
+    pub fn set_levels(&self, levels: Levels) {
+        self.levels.set(levels);
+    }
+
+    /// How many times each kind of message is printed before it's only counted.
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.set(limit);
+    }
+
+    pub fn set_pc(&self, pc: u16) {
+        self.pc.set(pc);
+    }
+
+    pub fn enabled(&self, category: Category, level: Level) -> bool {
+        level != Level::Off && level <= self.levels.get().level(category)
+    }
+
+    /// How often a kind of message has come up while its level was enabled.
+    pub fn count(&self, category: Category, what: &'static str) -> usize {
+        self.seen
+            .borrow()
+            .get(&(category, what))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Logs `what` with its `details` at the current PC and the given
+    /// scanline. `what` identifies the kind of message for rate limiting, so
+    /// it should stay the same while the details (addresses, values) vary.
+    pub fn log(
+        &self,
+        category: Category,
+        level: Level,
+        scanline: u16,
+        what: &'static str,
+        details: fmt::Arguments,
+    ) {
+        if !self.enabled(category, level) {
+            return;
+        }
+        let count = {
+            let mut seen = self.seen.borrow_mut();
+            let count = seen.entry((category, what)).or_insert(0);
+            *count += 1;
+            *count
+        };
+        let limit = self.limit.get();
+        let mut out = self.out.borrow_mut();
+        let prefix = format!("[{} {}]", category.name(), level.name());
+        let _ = if count <= limit {
+            writeln!(
+                out,
+                "{} ${:04X} sl {}: {}: {}",
+                prefix,
+                self.pc.get(),
+                scanline,
+                what,
+                details
+            )
+        } else if count == limit + 1 {
+            writeln!(
+                out,
+                "{} {}: further messages are only counted",
+                prefix, what
+            )
+        } else if is_power_of_ten(count) {
+            writeln!(out, "{} {}: {} times so far", prefix, what, count)
+        } else {
+            Ok(())
+        };
+    }
+}
+
+fn is_power_of_ten(mut n: usize) -> bool {
+    while n.is_multiple_of(10) {
+        n /= 10;
+    }
+    n == 1
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_parse_levels() {
+        let levels = Levels::default()
+            .parse("all=info, bus=trace,apu=off")
+            .unwrap();
+        assert_eq!(levels.level(Category::Bus), Level::Trace);
+        assert_eq!(levels.level(Category::Apu), Level::Off);
+        assert_eq!(levels.level(Category::Ppu), Level::Info);
+        assert_eq!(
+            levels.to_string(),
+            "cpu=info,bus=trace,ppu=info,mapper=info,input=info,apu=off"
+        );
+        assert_eq!(Levels::default().parse("").unwrap(), Levels::default());
+        assert!(Levels::default().parse("bus").is_err());
+        assert!(Levels::default().parse("bus=loud").is_err());
+        assert!(
+            Levels::default()
+                .parse("dma=info")
+                .unwrap_err()
+                .contains("cpu, bus, ppu, mapper, input, apu")
+        );
+    }
+
+    #[test]
+    fn test_levels_context_and_rate_limit() {
+        let text = Rc::new(RefCell::new(Vec::new()));
+        let logger = Logger::new(Box::new(Shared(text.clone())));
+        logger.set_limit(2);
+        logger.set_pc(0xc123);
+        logger.log(
+            Category::Bus,
+            Level::Info,
+            3,
+            "ignored read",
+            format_args!("$5000"),
+        );
+        for addr in 0..100u16 {
+            logger.log(
+                Category::Bus,
+                Level::Warn,
+                241,
+                "ignored write",
+                format_args!("${:04X} = $FF", 0x5000 + addr),
+            );
+        }
+        assert_eq!(logger.count(Category::Bus, "ignored read"), 0);
+        assert_eq!(logger.count(Category::Bus, "ignored write"), 100);
+        assert_eq!(
+            String::from_utf8(text.borrow().clone()).unwrap(),
+            "[bus warn] $C123 sl 241: ignored write: $5000 = $FF\n\
+             [bus warn] $C123 sl 241: ignored write: $5001 = $FF\n\
+             [bus warn] ignored write: further messages are only counted\n\
+             [bus warn] ignored write: 10 times so far\n\
+             [bus warn] ignored write: 100 times so far\n"
+        );
+
+        logger.set_levels(Levels::default().parse("bus=info,ppu=off").unwrap());
+        assert!(logger.enabled(Category::Bus, Level::Info));
+        assert!(!logger.enabled(Category::Ppu, Level::Error));
+        assert!(!logger.enabled(Category::Cpu, Level::Off));
+    }
+}
@@ -0,0 +1,132 @@
+use crate::comp::cpu::{Mem, Tick};
+
+/// A plain 64KB address space with no mirroring or memory-mapped registers.
+/// Runs the 6502 core outside the NES, e.g. for unit tests or the snake demo.
+pub struct FlatMemory {
+    pub memory: Vec<u8>,
+    pub cycles: usize,
+}
+
+impl FlatMemory {
+    pub fn new() -> Self {
+        FlatMemory {
+            memory: vec![0; 0x10000],
+            cycles: 0,
+        }
+    }
+}
+
+impl Default for FlatMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mem for FlatMemory {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.memory[addr as usize] = data;
+    }
+
+    fn mem_read_u16(&mut self, pos: u16) -> u16 {
+        let lo = self.mem_read(pos) as u16;
+        let hi = self.mem_read(pos.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
+    fn mem_write_u16(&mut self, pos: u16, data: u16) {
+        self.mem_write(pos, (data & 0xff) as u8);
+        self.mem_write(pos.wrapping_add(1), (data >> 8) as u8);
+    }
+}
+
+impl Tick for FlatMemory {
+    fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as usize;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub kind: Access,
+}
+
+/// A `FlatMemory` that logs every read and write in order, so the access
+/// sequence of an instruction can be compared against real hardware.
+pub struct RecordingMemory {
+    pub memory: FlatMemory,
+    pub accesses: Vec<BusAccess>,
+}
+
+impl RecordingMemory {
+    pub fn new() -> Self {
+        RecordingMemory {
+            memory: FlatMemory::new(),
+            accesses: vec![],
+        }
+    }
+}
+
+impl Default for RecordingMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mem for RecordingMemory {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        let value = self.memory.mem_read(addr);
+        self.accesses.push(BusAccess {
+            addr,
+            value,
+            kind: Access::Read,
+        });
+        value
+    }
+
+    // not recorded, peeks are not bus cycles
+    fn peek(&self, addr: u16) -> u8 {
+        self.memory.peek(addr)
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.memory.mem_write(addr, data);
+        self.accesses.push(BusAccess {
+            addr,
+            value: data,
+            kind: Access::Write,
+        });
+    }
+
+    fn mem_read_u16(&mut self, pos: u16) -> u16 {
+        let lo = self.mem_read(pos) as u16;
+        let hi = self.mem_read(pos.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
+    fn mem_write_u16(&mut self, pos: u16, data: u16) {
+        self.mem_write(pos, (data & 0xff) as u8);
+        self.mem_write(pos.wrapping_add(1), (data >> 8) as u8);
+    }
+}
+
+impl Tick for RecordingMemory {
+    fn tick(&mut self, cycles: u8) {
+        self.memory.tick(cycles);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::comp::bus::Bus;
+use crate::comp::controller::Controller;
+use crate::comp::cpu::{CPU, Tick};
+use crate::comp::error::EmuError;
+use crate::comp::ppu::NesPPU;
+use crate::comp::render::{self, frame::Frame};
+use crate::comp::rom::Rom;
+
+// The whole console as something a host program drives: it runs an
+// instruction, a frame or a number of cycles and then hands control back,
+// instead of `CPU::run` looping forever around the gameloop callback.
+//
+// A frame ends when the PPU enters vblank; the picture is rendered then, so
+// `frame()` always holds the last complete one. Set input on `controller()`
+// between calls.
+//
+// Errors the bus's policy says to halt on stop the machine like BRK does,
+// and come back from the call that ran into them.
+
+pub struct Nes<'call> {
+    cpu: CPU<Bus<'call>>,
+    frame: Frame,
+    halted: bool,
+    error: Option<EmuError>,
+}
+
+impl Nes<'static> {
+    /// Powers on with `rom` inserted, ready to run from the reset vector.
+    pub fn new(rom: Rom) -> Nes<'static> {
+        Nes::with_bus(Bus::new(rom, |_, _| {}))
+    }
+}
+
+impl<'call> Nes<'call> {
+    /// Uses an already set up bus (cheats, code/data log, gameloop callback).
+    pub fn with_bus(bus: Bus<'call>) -> Nes<'call> {
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        Nes {
+            cpu,
+            frame: Frame::new(),
+            halted: false,
+            error: None,
+        }
+    }
+
+    pub fn cpu(&self) -> &CPU<Bus<'call>> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU<Bus<'call>> {
+        &mut self.cpu
+    }
+
+    pub fn bus(&self) -> &Bus<'call> {
+        &self.cpu.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus<'call> {
+        &mut self.cpu.bus
+    }
+
+    pub fn ppu(&self) -> &NesPPU {
+        self.cpu.bus.ppu()
+    }
+
+    /// Player 1's pad; buttons set here are seen by the game from the next read.
+    pub fn controller(&mut self) -> &mut Controller {
+        self.cpu.bus.controller_mut()
+    }
+
+    /// The last complete frame.
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    /// Frames completed since power-on.
+    pub fn frame_count(&self) -> usize {
+        self.cpu.bus.frames()
+    }
+
+    /// CPU cycles since power-on.
+    pub fn cycles(&self) -> usize {
+        self.cpu.bus.cycles()
+    }
+
+    /// True once the CPU has executed BRK, a `step_with` hook stopped it or
+    /// an error halted it. Nothing runs after that.
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// The error that halted the machine, if that's what stopped it.
+    pub fn error(&self) -> Option<&EmuError> {
+        self.error.as_ref()
+    }
+
+    /// Presses the reset button: the CPU restarts from the reset vector, while
+    /// RAM, cartridge RAM and the PPU keep their state. Also clears a halt.
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.halted = false;
+        self.error = None;
+    }
+
+    /// Services a pending NMI and executes one instruction.
+    /// Returns false if the machine is halted.
+    pub fn step_instruction(&mut self) -> Result<bool, EmuError> {
+        self.step_with(|cpu| {
+            cpu.poll_interrupts();
+            cpu.step()
+        })
+    }
+
+    /// Like `step_instruction`, but `step` services interrupts and executes the
+    /// instruction itself, so tools (debugger, profiler, tracer) can look at the
+    /// CPU around it. Returning false from `step` halts the machine.
+    pub fn step_with<F>(&mut self, step: F) -> Result<bool, EmuError>
+    where
+        F: FnOnce(&mut CPU<Bus<'call>>) -> bool,
+    {
+        if self.halted {
+            return Ok(false);
+        }
+        let frames = self.frame_count();
+        self.halted = !step(&mut self.cpu);
+        if self.frame_count() != frames
+            && let Err(error) = render::render(self.cpu.bus.ppu(), &mut self.frame)
+        {
+            self.cpu.bus.fault(error);
+        }
+        if let Some(error) = self.cpu.bus.take_fault() {
+            self.halted = true;
+            self.error = Some(error);
+            return Err(error);
+        }
+        Ok(!self.halted)
+    }
+
+    /// Runs to the start of the next vblank and returns the finished frame.
+    pub fn step_frame(&mut self) -> Result<&Frame, EmuError> {
+        let end = self.frame_count() + 1;
+        while self.frame_count() < end && self.step_instruction()? {}
+        Ok(&self.frame)
+    }
+
+    /// Runs whole instructions until at least `cycles` CPU cycles have passed.
+    pub fn run_cycles(&mut self, cycles: usize) -> Result<(), EmuError> {
+        let end = self.cycles() + cycles;
+        while self.cycles() < end && self.step_instruction()? {}
+        Ok(())
+    }
+
+    /// Runs until `predicate` holds before an instruction. Returns false if the
+    /// machine halted first.
+    pub fn run_until<F>(&mut self, mut predicate: F) -> Result<bool, EmuError>
+    where
+        F: FnMut(&CPU<Bus<'call>>) -> bool,
+    {
+        while !predicate(&self.cpu) {
+            if !self.step_instruction()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::controller::ControllerButtons;
+    use crate::comp::cpu::Mem;
+    use crate::comp::error::ErrorPolicy;
+    use crate::comp::render::palette::SYSTEM_PALLETE;
+    use crate::comp::rom::test::{test_prg, test_rom};
+
+    fn nes(program: &[u8]) -> Nes<'static> {
+        Nes::new(test_rom(test_prg(program)))
+    }
+
+    #[test]
+    fn test_step_frame_renders_each_vblank() {
+        // set the backdrop colour to $21, then spin: JMP *
+        let program = [
+            0xa9, 0x3f, 0x8d, 0x06, 0x20, 0xa9, 0x00, 0x8d, 0x06, 0x20, 0xa9, 0x21, 0x8d, 0x07,
+            0x20, 0x4c, 0x0f, 0x80,
+        ];
+        let mut nes = nes(&program);
+        let frame = nes.step_frame().unwrap();
+        // pattern bytes are all 2, so the first column of each tile is backdrop
+        let backdrop = SYSTEM_PALLETE[0x21];
+        assert_eq!(&frame.data[0..3], &[backdrop.0, backdrop.1, backdrop.2]);
+        assert_eq!(nes.frame_count(), 1);
+        assert_eq!(nes.ppu().scanline(), 241);
+
+        let cycles = nes.cycles();
+        nes.step_frame().unwrap();
+        assert_eq!(nes.frame_count(), 2);
+        // 262 scanlines of 341 dots, 3 dots per CPU cycle
+        assert!((nes.cycles() - cycles).abs_diff(262 * 341 / 3) <= 3);
+    }
+
+    #[test]
+    fn test_run_until_and_cycles() {
+        // LDX #$00; loop: INX; JMP loop
+        let mut nes = nes(&[0xa2, 0x00, 0xe8, 0x4c, 0x02, 0x80]);
+        assert!(nes.run_until(|cpu| cpu.reg_x == 10).unwrap());
+        assert_eq!(nes.cpu().pc, 0x8003);
+
+        let start = nes.cycles();
+        nes.run_cycles(100).unwrap();
+        assert!(nes.cycles() - start >= 100 && nes.cycles() - start < 105);
+    }
+
+    #[test]
+    fn test_input_between_calls_and_halt() {
+        // strobe the pad, read A into $10, BRK
+        let program = [
+            0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40, 0xad, 0x16, 0x40, 0x85,
+            0x10, 0x00,
+        ];
+        let mut nes = nes(&program);
+        nes.controller()
+            .set_button_pressed_status(ControllerButtons::BUTTON_A, true);
+        assert!(!nes.run_until(|_| false).unwrap());
+        assert!(nes.halted());
+        assert!(!nes.step_instruction().unwrap());
+        assert_eq!(nes.cpu_mut().mem_read(0x10), 1);
+    }
+
+    #[test]
+    fn test_errors_follow_policy() {
+        // STA $8000; an illegal opcode; INX; BRK
+        let program = [0x8d, 0x00, 0x80, 0x02, 0xe8, 0x00];
+        let mut nes = nes(&program);
+        let error = EmuError::RomWrite {
+            addr: 0x8000,
+            data: 0,
+        };
+        assert_eq!(nes.run_until(|_| false), Err(error));
+        assert_eq!(nes.cpu().pc, 0x8003);
+        assert!(nes.halted());
+        assert_eq!(nes.error(), Some(&error));
+        assert_eq!(nes.step_instruction(), Ok(false));
+
+        let mut nes = self::nes(&program);
+        let policy = ErrorPolicy::parse("rom-write=ignore,opcode=log").unwrap();
+        nes.bus_mut().set_error_policy(policy);
+        assert_eq!(nes.run_until(|_| false), Ok(false));
+        assert_eq!(nes.cpu().reg_x, 1);
+        assert_eq!(nes.error(), None);
+    }
+
+    #[test]
+    fn test_ignored_opcodes_skip_their_operands() {
+        // NOP #$E8 ($80); NOP $E8E8 ($0C); INX; BRK
+        let mut nes = nes(&[0x80, 0xe8, 0x0c, 0xe8, 0xe8, 0xe8, 0x00]);
+        nes.bus_mut()
+            .set_error_policy(ErrorPolicy::parse("opcode=ignore").unwrap());
+        let start = nes.cycles();
+        nes.step_instruction().unwrap();
+        assert_eq!((nes.cpu().pc, nes.cycles() - start), (0x8002, 2));
+        nes.step_instruction().unwrap();
+        assert_eq!((nes.cpu().pc, nes.cycles() - start), (0x8005, 6));
+        assert_eq!(nes.run_until(|_| false), Ok(false));
+        assert_eq!(nes.cpu().reg_x, 1);
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::comp::bus::Bus;
+use crate::comp::cpu::{CPU, CpuFlags, Tick};
+use crate::comp::processor_tests::panic_message;
+use crate::comp::rom::Rom;
+use crate::comp::trace::trace_nes;
+
+// Headless conformance run against kevtris' nestest.nes. In "automation" mode
+// the ROM starts at $C000, needs no input and its reference log (nestest.log,
+// produced by Nintendulator) records the full CPU state before every
+// instruction, so we can compare line by line and stop at the first mismatch.
+
+const CONTEXT_LINES: usize = 5;
+
+pub struct Divergence {
+    /// 1-based line number in the reference log
+    pub line: usize,
+    /// the last matching lines before the divergence
+    pub context: Vec<String>,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl Divergence {
+    pub fn format(&self) -> String {
+        let mut out = format!("diverged at line {}:\n", self.line);
+        for line in &self.context {
+            out.push_str(&format!("  {}\n", line));
+        }
+        out.push_str(&format!("- {}\n", self.expected));
+        out.push_str(&format!("+ {}\n", self.actual));
+        let column = self
+            .expected
+            .chars()
+            .zip(self.actual.chars())
+            .position(|(e, a)| e != a)
+            .unwrap_or(self.expected.len().min(self.actual.len()));
+        out.push_str(&format!("  {}^\n", " ".repeat(column)));
+        out
+    }
+}
+
+/// Boots nestest.nes in automation mode with the CPU state nestest.log expects.
+pub fn nestest_cpu(rom: Rom) -> CPU<Bus<'static>> {
+    let bus = Bus::new(rom, |_, _| {});
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    cpu.pc = 0xC000;
+    cpu.status = CpuFlags::from_bits_truncate(0x24);
+    // the reset sequence takes 7 cycles before the first instruction
+    cpu.bus.tick(7);
+    cpu
+}
+
+/// Runs the CPU while streaming `golden` and returns how many lines matched.
+/// Stops at the first line that differs, or when the CPU panics or halts.
+pub fn compare<R: BufRead>(cpu: &mut CPU<Bus>, golden: R) -> Result<usize, Divergence> {
+    let mut context: VecDeque<String> = VecDeque::with_capacity(CONTEXT_LINES);
+    let mut halted = None;
+    let mut matched = 0;
+
+    for (i, expected) in golden.lines().enumerate() {
+        let expected = expected
+            .map_err(|e| Divergence {
+                line: i + 1,
+                context: context.iter().cloned().collect(),
+                expected: format!("<read error: {}>", e),
+                actual: String::new(),
+            })?
+            .trim_end()
+            .to_string();
+        let diverged = |actual: String, context: &VecDeque<String>| Divergence {
+            line: i + 1,
+            context: context.iter().cloned().collect(),
+            expected: expected.clone(),
+            actual,
+        };
+
+        if let Some(reason) = halted {
+            return Err(diverged(reason, &context));
+        }
+
+        cpu.poll_interrupts();
+        let actual = trace_nes(cpu, None);
+        if actual != expected {
+            return Err(diverged(actual, &context));
+        }
+        if context.len() == CONTEXT_LINES {
+            context.pop_front();
+        }
+        context.push_back(actual);
+        matched += 1;
+
+        match panic::catch_unwind(AssertUnwindSafe(|| cpu.try_step())) {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) => halted = Some("<CPU halted on BRK>".to_string()),
+            Ok(Err(error)) => halted = Some(format!("<CPU stopped: {}>", error)),
+            Err(payload) => halted = Some(format!("<CPU panicked: {}>", panic_message(&*payload))),
+        }
+    }
+    Ok(matched)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::rom::test::test_rom;
+
+    fn nestest_like(program: &[u8]) -> CPU<Bus<'static>> {
+        // a 32KB PRG maps $C000 to offset $4000
+        let mut prg = vec![0; 0x4000];
+        prg.extend_from_slice(program);
+        nestest_cpu(test_rom(prg))
+    }
+
+    fn line(asm: &str, state: &str) -> String {
+        format!("{:47} {}", asm, state)
+    }
+
+    fn golden() -> Vec<String> {
+        // LDX #$02; loop: DEX; BNE loop; BRK
+        vec![
+            line(
+                "C000  A2 02     LDX #$02",
+                "A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+            ),
+            line(
+                "C002  CA        DEX",
+                "A:00 X:02 Y:00 P:24 SP:FD PPU:  0, 27 CYC:9",
+            ),
+            line(
+                "C003  D0 FD     BNE $C002",
+                "A:00 X:01 Y:00 P:24 SP:FD PPU:  0, 33 CYC:11",
+            ),
+            line(
+                "C002  CA        DEX",
+                "A:00 X:01 Y:00 P:24 SP:FD PPU:  0, 42 CYC:14",
+            ),
+            line(
+                "C003  D0 FD     BNE $C002",
+                "A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 48 CYC:16",
+            ),
+            line(
+                "C005  00        BRK",
+                "A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 54 CYC:18",
+            ),
+        ]
+    }
+
+    const PROGRAM: [u8; 6] = [0xa2, 0x02, 0xca, 0xd0, 0xfd, 0x00];
+
+    #[test]
+    fn test_matching_log() {
+        let mut cpu = nestest_like(&PROGRAM);
+        let log = golden().join("\n");
+        assert_eq!(compare(&mut cpu, log.as_bytes()).ok(), Some(6));
+    }
+
+    #[test]
+    fn test_reports_first_divergence() {
+        let mut cpu = nestest_like(&PROGRAM);
+        let mut lines = golden();
+        lines[3] = lines[3].replace("CYC:14", "CYC:13");
+        let log = lines.join("\n");
+        let divergence = compare(&mut cpu, log.as_bytes()).err().unwrap();
+        assert_eq!(divergence.line, 4);
+        assert_eq!(divergence.context.len(), 3);
+        assert!(divergence.actual.ends_with("CYC:14"));
+        assert!(divergence.format().contains("- C002  CA"));
+    }
+
+    #[test]
+    fn test_halt_is_a_divergence() {
+        let mut cpu = nestest_like(&PROGRAM);
+        let mut lines = golden();
+        lines.push(line(
+            "C006  EA        NOP",
+            "A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 75 CYC:25",
+        ));
+        let log = lines.join("\n");
+        let divergence = compare(&mut cpu, log.as_bytes()).err().unwrap();
+        assert_eq!(divergence.line, 7);
+        assert_eq!(divergence.actual, "<CPU halted on BRK>");
+    }
+}
@@ -0,0 +1,331 @@
+// Static description of all 256 NMOS 6502 opcodes, used by the tracer and the
+// debugging tools. Execution still lives in `cpu.rs`; this table only knows how
+// an instruction is spelled and how many operand bytes follow it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum Operand {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPage_X,
+    ZeroPage_Y,
+    Absolute,
+    Absolute_X,
+    Absolute_Y,
+    Indirect,
+    Indirect_X,
+    Indirect_Y,
+    Relative,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OpCode {
+    pub code: u8,
+    pub mnemonic: &'static str,
+    pub mode: Operand,
+    /// false for the undocumented NMOS opcodes (printed with a `*` in traces)
+    pub official: bool,
+}
+
+impl OpCode {
+    /// Instruction length in bytes, opcode included.
+    pub fn size(&self) -> u8 {
+        match self.mode {
+            Operand::Implied | Operand::Accumulator => 1,
+            Operand::Absolute | Operand::Absolute_X | Operand::Absolute_Y | Operand::Indirect => 3,
+            _ => 2,
+        }
+    }
+}
+
+const fn op(code: u8, mnemonic: &'static str, mode: Operand, official: bool) -> OpCode {
+    OpCode {
+        code,
+        mnemonic,
+        mode,
+        official,
+    }
+}
+
+use Operand::*;
+
+pub static OPCODES: [OpCode; 256] = [
+    op(0x00, "BRK", Implied, true),
+    op(0x01, "ORA", Indirect_X, true),
+    op(0x02, "KIL", Implied, false),
+    op(0x03, "SLO", Indirect_X, false),
+    op(0x04, "NOP", ZeroPage, false),
+    op(0x05, "ORA", ZeroPage, true),
+    op(0x06, "ASL", ZeroPage, true),
+    op(0x07, "SLO", ZeroPage, false),
+    op(0x08, "PHP", Implied, true),
+    op(0x09, "ORA", Immediate, true),
+    op(0x0a, "ASL", Accumulator, true),
+    op(0x0b, "ANC", Immediate, false),
+    op(0x0c, "NOP", Absolute, false),
+    op(0x0d, "ORA", Absolute, true),
+    op(0x0e, "ASL", Absolute, true),
+    op(0x0f, "SLO", Absolute, false),
+    op(0x10, "BPL", Relative, true),
+    op(0x11, "ORA", Indirect_Y, true),
+    op(0x12, "KIL", Implied, false),
+    op(0x13, "SLO", Indirect_Y, false),
+    op(0x14, "NOP", ZeroPage_X, false),
+    op(0x15, "ORA", ZeroPage_X, true),
+    op(0x16, "ASL", ZeroPage_X, true),
+    op(0x17, "SLO", ZeroPage_X, false),
+    op(0x18, "CLC", Implied, true),
+    op(0x19, "ORA", Absolute_Y, true),
+    op(0x1a, "NOP", Implied, false),
+    op(0x1b, "SLO", Absolute_Y, false),
+    op(0x1c, "NOP", Absolute_X, false),
+    op(0x1d, "ORA", Absolute_X, true),
+    op(0x1e, "ASL", Absolute_X, true),
+    op(0x1f, "SLO", Absolute_X, false),
+    op(0x20, "JSR", Absolute, true),
+    op(0x21, "AND", Indirect_X, true),
+    op(0x22, "KIL", Implied, false),
+    op(0x23, "RLA", Indirect_X, false),
+    op(0x24, "BIT", ZeroPage, true),
+    op(0x25, "AND", ZeroPage, true),
+    op(0x26, "ROL", ZeroPage, true),
+    op(0x27, "RLA", ZeroPage, false),
+    op(0x28, "PLP", Implied, true),
+    op(0x29, "AND", Immediate, true),
+    op(0x2a, "ROL", Accumulator, true),
+    op(0x2b, "ANC", Immediate, false),
+    op(0x2c, "BIT", Absolute, true),
+    op(0x2d, "AND", Absolute, true),
+    op(0x2e, "ROL", Absolute, true),
+    op(0x2f, "RLA", Absolute, false),
+    op(0x30, "BMI", Relative, true),
+    op(0x31, "AND", Indirect_Y, true),
+    op(0x32, "KIL", Implied, false),
+    op(0x33, "RLA", Indirect_Y, false),
+    op(0x34, "NOP", ZeroPage_X, false),
+    op(0x35, "AND", ZeroPage_X, true),
+    op(0x36, "ROL", ZeroPage_X, true),
+    op(0x37, "RLA", ZeroPage_X, false),
+    op(0x38, "SEC", Implied, true),
+    op(0x39, "AND", Absolute_Y, true),
+    op(0x3a, "NOP", Implied, false),
+    op(0x3b, "RLA", Absolute_Y, false),
+    op(0x3c, "NOP", Absolute_X, false),
+    op(0x3d, "AND", Absolute_X, true),
+    op(0x3e, "ROL", Absolute_X, true),
+    op(0x3f, "RLA", Absolute_X, false),
+    op(0x40, "RTI", Implied, true),
+    op(0x41, "EOR", Indirect_X, true),
+    op(0x42, "KIL", Implied, false),
+    op(0x43, "SRE", Indirect_X, false),
+    op(0x44, "NOP", ZeroPage, false),
+    op(0x45, "EOR", ZeroPage, true),
+    op(0x46, "LSR", ZeroPage, true),
+    op(0x47, "SRE", ZeroPage, false),
+    op(0x48, "PHA", Implied, true),
+    op(0x49, "EOR", Immediate, true),
+    op(0x4a, "LSR", Accumulator, true),
+    op(0x4b, "ALR", Immediate, false),
+    op(0x4c, "JMP", Absolute, true),
+    op(0x4d, "EOR", Absolute, true),
+    op(0x4e, "LSR", Absolute, true),
+    op(0x4f, "SRE", Absolute, false),
+    op(0x50, "BVC", Relative, true),
+    op(0x51, "EOR", Indirect_Y, true),
+    op(0x52, "KIL", Implied, false),
+    op(0x53, "SRE", Indirect_Y, false),
+    op(0x54, "NOP", ZeroPage_X, false),
+    op(0x55, "EOR", ZeroPage_X, true),
+    op(0x56, "LSR", ZeroPage_X, true),
+    op(0x57, "SRE", ZeroPage_X, false),
+    op(0x58, "CLI", Implied, true),
+    op(0x59, "EOR", Absolute_Y, true),
+    op(0x5a, "NOP", Implied, false),
+    op(0x5b, "SRE", Absolute_Y, false),
+    op(0x5c, "NOP", Absolute_X, false),
+    op(0x5d, "EOR", Absolute_X, true),
+    op(0x5e, "LSR", Absolute_X, true),
+    op(0x5f, "SRE", Absolute_X, false),
+    op(0x60, "RTS", Implied, true),
+    op(0x61, "ADC", Indirect_X, true),
+    op(0x62, "KIL", Implied, false),
+    op(0x63, "RRA", Indirect_X, false),
+    op(0x64, "NOP", ZeroPage, false),
+    op(0x65, "ADC", ZeroPage, true),
+    op(0x66, "ROR", ZeroPage, true),
+    op(0x67, "RRA", ZeroPage, false),
+    op(0x68, "PLA", Implied, true),
+    op(0x69, "ADC", Immediate, true),
+    op(0x6a, "ROR", Accumulator, true),
+    op(0x6b, "ARR", Immediate, false),
+    op(0x6c, "JMP", Indirect, true),
+    op(0x6d, "ADC", Absolute, true),
+    op(0x6e, "ROR", Absolute, true),
+    op(0x6f, "RRA", Absolute, false),
+    op(0x70, "BVS", Relative, true),
+    op(0x71, "ADC", Indirect_Y, true),
+    op(0x72, "KIL", Implied, false),
+    op(0x73, "RRA", Indirect_Y, false),
+    op(0x74, "NOP", ZeroPage_X, false),
+    op(0x75, "ADC", ZeroPage_X, true),
+    op(0x76, "ROR", ZeroPage_X, true),
+    op(0x77, "RRA", ZeroPage_X, false),
+    op(0x78, "SEI", Implied, true),
+    op(0x79, "ADC", Absolute_Y, true),
+    op(0x7a, "NOP", Implied, false),
+    op(0x7b, "RRA", Absolute_Y, false),
+    op(0x7c, "NOP", Absolute_X, false),
+    op(0x7d, "ADC", Absolute_X, true),
+    op(0x7e, "ROR", Absolute_X, true),
+    op(0x7f, "RRA", Absolute_X, false),
+    op(0x80, "NOP", Immediate, false),
+    op(0x81, "STA", Indirect_X, true),
+    op(0x82, "NOP", Immediate, false),
+    op(0x83, "SAX", Indirect_X, false),
+    op(0x84, "STY", ZeroPage, true),
+    op(0x85, "STA", ZeroPage, true),
+    op(0x86, "STX", ZeroPage, true),
+    op(0x87, "SAX", ZeroPage, false),
+    op(0x88, "DEY", Implied, true),
+    op(0x89, "NOP", Immediate, false),
+    op(0x8a, "TXA", Implied, true),
+    op(0x8b, "XAA", Immediate, false),
+    op(0x8c, "STY", Absolute, true),
+    op(0x8d, "STA", Absolute, true),
+    op(0x8e, "STX", Absolute, true),
+    op(0x8f, "SAX", Absolute, false),
+    op(0x90, "BCC", Relative, true),
+    op(0x91, "STA", Indirect_Y, true),
+    op(0x92, "KIL", Implied, false),
+    op(0x93, "AHX", Indirect_Y, false),
+    op(0x94, "STY", ZeroPage_X, true),
+    op(0x95, "STA", ZeroPage_X, true),
+    op(0x96, "STX", ZeroPage_Y, true),
+    op(0x97, "SAX", ZeroPage_Y, false),
+    op(0x98, "TYA", Implied, true),
+    op(0x99, "STA", Absolute_Y, true),
+    op(0x9a, "TXS", Implied, true),
+    op(0x9b, "TAS", Absolute_Y, false),
+    op(0x9c, "SHY", Absolute_X, false),
+    op(0x9d, "STA", Absolute_X, true),
+    op(0x9e, "SHX", Absolute_Y, false),
+    op(0x9f, "AHX", Absolute_Y, false),
+    op(0xa0, "LDY", Immediate, true),
+    op(0xa1, "LDA", Indirect_X, true),
+    op(0xa2, "LDX", Immediate, true),
+    op(0xa3, "LAX", Indirect_X, false),
+    op(0xa4, "LDY", ZeroPage, true),
+    op(0xa5, "LDA", ZeroPage, true),
+    op(0xa6, "LDX", ZeroPage, true),
+    op(0xa7, "LAX", ZeroPage, false),
+    op(0xa8, "TAY", Implied, true),
+    op(0xa9, "LDA", Immediate, true),
+    op(0xaa, "TAX", Implied, true),
+    op(0xab, "LAX", Immediate, false),
+    op(0xac, "LDY", Absolute, true),
+    op(0xad, "LDA", Absolute, true),
+    op(0xae, "LDX", Absolute, true),
+    op(0xaf, "LAX", Absolute, false),
+    op(0xb0, "BCS", Relative, true),
+    op(0xb1, "LDA", Indirect_Y, true),
+    op(0xb2, "KIL", Implied, false),
+    op(0xb3, "LAX", Indirect_Y, false),
+    op(0xb4, "LDY", ZeroPage_X, true),
+    op(0xb5, "LDA", ZeroPage_X, true),
+    op(0xb6, "LDX", ZeroPage_Y, true),
+    op(0xb7, "LAX", ZeroPage_Y, false),
+    op(0xb8, "CLV", Implied, true),
+    op(0xb9, "LDA", Absolute_Y, true),
+    op(0xba, "TSX", Implied, true),
+    op(0xbb, "LAS", Absolute_Y, false),
+    op(0xbc, "LDY", Absolute_X, true),
+    op(0xbd, "LDA", Absolute_X, true),
+    op(0xbe, "LDX", Absolute_Y, true),
+    op(0xbf, "LAX", Absolute_Y, false),
+    op(0xc0, "CPY", Immediate, true),
+    op(0xc1, "CMP", Indirect_X, true),
+    op(0xc2, "NOP", Immediate, false),
+    op(0xc3, "DCP", Indirect_X, false),
+    op(0xc4, "CPY", ZeroPage, true),
+    op(0xc5, "CMP", ZeroPage, true),
+    op(0xc6, "DEC", ZeroPage, true),
+    op(0xc7, "DCP", ZeroPage, false),
+    op(0xc8, "INY", Implied, true),
+    op(0xc9, "CMP", Immediate, true),
+    op(0xca, "DEX", Implied, true),
+    op(0xcb, "AXS", Immediate, false),
+    op(0xcc, "CPY", Absolute, true),
+    op(0xcd, "CMP", Absolute, true),
+    op(0xce, "DEC", Absolute, true),
+    op(0xcf, "DCP", Absolute, false),
+    op(0xd0, "BNE", Relative, true),
+    op(0xd1, "CMP", Indirect_Y, true),
+    op(0xd2, "KIL", Implied, false),
+    op(0xd3, "DCP", Indirect_Y, false),
+    op(0xd4, "NOP", ZeroPage_X, false),
+    op(0xd5, "CMP", ZeroPage_X, true),
+    op(0xd6, "DEC", ZeroPage_X, true),
+    op(0xd7, "DCP", ZeroPage_X, false),
+    op(0xd8, "CLD", Implied, true),
+    op(0xd9, "CMP", Absolute_Y, true),
+    op(0xda, "NOP", Implied, false),
+    op(0xdb, "DCP", Absolute_Y, false),
+    op(0xdc, "NOP", Absolute_X, false),
+    op(0xdd, "CMP", Absolute_X, true),
+    op(0xde, "DEC", Absolute_X, true),
+    op(0xdf, "DCP", Absolute_X, false),
+    op(0xe0, "CPX", Immediate, true),
+    op(0xe1, "SBC", Indirect_X, true),
+    op(0xe2, "NOP", Immediate, false),
+    op(0xe3, "ISB", Indirect_X, false),
+    op(0xe4, "CPX", ZeroPage, true),
+    op(0xe5, "SBC", ZeroPage, true),
+    op(0xe6, "INC", ZeroPage, true),
+    op(0xe7, "ISB", ZeroPage, false),
+    op(0xe8, "INX", Implied, true),
+    op(0xe9, "SBC", Immediate, true),
+    op(0xea, "NOP", Implied, true),
+    op(0xeb, "SBC", Immediate, false),
+    op(0xec, "CPX", Absolute, true),
+    op(0xed, "SBC", Absolute, true),
+    op(0xee, "INC", Absolute, true),
+    op(0xef, "ISB", Absolute, false),
+    op(0xf0, "BEQ", Relative, true),
+    op(0xf1, "SBC", Indirect_Y, true),
+    op(0xf2, "KIL", Implied, false),
+    op(0xf3, "ISB", Indirect_Y, false),
+    op(0xf4, "NOP", ZeroPage_X, false),
+    op(0xf5, "SBC", ZeroPage_X, true),
+    op(0xf6, "INC", ZeroPage_X, true),
+    op(0xf7, "ISB", ZeroPage_X, false),
+    op(0xf8, "SED", Implied, true),
+    op(0xf9, "SBC", Absolute_Y, true),
+    op(0xfa, "NOP", Implied, false),
+    op(0xfb, "ISB", Absolute_Y, false),
+    op(0xfc, "NOP", Absolute_X, false),
+    op(0xfd, "SBC", Absolute_X, true),
+    op(0xfe, "INC", Absolute_X, true),
+    op(0xff, "ISB", Absolute_X, false),
+];
+
+pub fn lookup(code: u8) -> &'static OpCode {
+    &OPCODES[code as usize]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_table_is_indexed_by_code() {
+        for (i, op) in OPCODES.iter().enumerate() {
+            assert_eq!(op.code as usize, i);
+        }
+        assert_eq!(OPCODES.iter().filter(|op| op.official).count(), 151);
+        assert_eq!(lookup(0x6c).mode, Operand::Indirect);
+        assert_eq!(lookup(0x6c).size(), 3);
+        assert!(!lookup(0xeb).official);
+    }
+}
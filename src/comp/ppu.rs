@@ -1,3 +1,7 @@
+use std::rc::Rc;
+
+use crate::comp::error::EmuError;
+use crate::comp::log::{Category, Level, Logger};
 use crate::comp::regs::addr::AddrRegister;
 use crate::comp::regs::control::ControlRegister;
 use crate::comp::regs::mask::MaskRegister;
@@ -7,29 +11,37 @@ use crate::comp::rom::Mirroring;
 
 pub struct NesPPU {
     pub chr_rom: Vec<u8>,
-    pub mirroring: Mirroring,
+    mirroring: Mirroring,
     pub ctrl: ControlRegister,
     pub mask: MaskRegister,
     pub status: StatusRegister,
     pub scroll: ScrollRegister,
     pub addr: AddrRegister,
     pub vram: [u8; 2048],
+    // the extra 2KB of nametable RAM on four-screen cartridges, empty otherwise
+    pub cart_vram: Vec<u8>,
 
     pub oam_addr: u8,
     pub oam_data: [u8; 256],
     pub palette_table: [u8; 32],
 
     internal_data_buf: u8,
+    // the PPU's data bus latch: the last value written to or read from a register
+    open_bus: u8,
 
     scanline: u16,
     cycles: usize,
     pub nmi_interrupt: Option<u8>,
+    // an access the PPU doesn't map, for the bus to apply its policy to
+    fault: Option<EmuError>,
+    log: Rc<Logger>,
 }
 
 pub trait PPU {
     fn write_to_ctrl(&mut self, value: u8);
     fn write_to_mask(&mut self, value: u8);
     fn read_status(&mut self) -> u8;
+    fn peek_status(&self) -> u8;
     fn write_to_oam_addr(&mut self, value: u8);
     fn write_to_oam_data(&mut self, value: u8);
     fn read_oam_data(&self) -> u8;
@@ -37,9 +49,22 @@ pub trait PPU {
     fn write_to_ppu_addr(&mut self, value: u8);
     fn write_to_data(&mut self, value: u8);
     fn read_data(&mut self) -> u8;
+    fn peek_data(&self) -> u8;
     fn write_oam_dma(&mut self, value: &[u8; 256]);
 }
 
+/// Index into `palette_table` for an address in $3F00-$3FFF. The 32 bytes
+/// repeat every $20, and the sprite palettes' entry 0 ($3F10/$3F14/$3F18/$3F1C)
+/// is the same byte as the background palettes' ($3F00/$3F04/$3F08/$3F0C).
+pub fn palette_index(addr: u16) -> usize {
+    let index = (addr & 0x1f) as usize;
+    if index & 0x13 == 0x10 {
+        index & 0x0f
+    } else {
+        index
+    }
+}
+
 impl NesPPU {
     pub fn new_empty_rom() -> Self {
         NesPPU::new(vec![0; 2048], Mirroring::HORIZONTAL)
@@ -56,16 +81,38 @@ impl NesPPU {
             scroll: ScrollRegister::new(),
             addr: AddrRegister::new(),
             vram: [0; 2048],
+            cart_vram: match mirroring {
+                Mirroring::FOUR_SCREEN => vec![0; 2048],
+                _ => Vec::new(),
+            },
             oam_data: [0; 64 * 4],
             palette_table: [0; 32],
             internal_data_buf: 0,
+            open_bus: 0,
 
             cycles: 0,
             scanline: 0,
             nmi_interrupt: None,
+            fault: None,
+            log: Rc::new(Logger::default()),
         }
     }
 
+    /// Sends diagnostics to `log`, normally the one the bus owns.
+    pub fn set_logger(&mut self, log: Rc<Logger>) {
+        self.log = log;
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    /// Remaps the nametables, as mappers with switchable mirroring do.
+    /// Four-screen needs `cart_vram`, which only four-screen ROMs come with.
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.mirroring = mirroring;
+    }
+
     // Horizontal:
     //   [ A ] [ a ]
     //   [ B ] [ b ]
@@ -73,19 +120,46 @@ impl NesPPU {
     // Vertical:
     //   [ A ] [ B ]
     //   [ a ] [ b ]
+
+    // Offset of a nametable address ($2000-$3EFF) in VRAM, counting the
+    // cartridge's 2KB as following the console's; see `Mirroring::banks`.
     pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
-        let mirrored_vram = addr & 0b10111111111111; // mirror down 0x3000-0x3eff to 0x2000 - 0x2eff
-        let vram_index = mirrored_vram - 0x2000; // to vram vector
-        let name_table = vram_index / 0x400;
-        match (&self.mirroring, name_table) {
-            (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => vram_index - 0x800,
-            (Mirroring::HORIZONTAL, 2) => vram_index - 0x400,
-            (Mirroring::HORIZONTAL, 1) => vram_index - 0x400,
-            (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
-            _ => vram_index,
+        let vram_index = (addr & 0x0fff) as usize; // mirror down 0x3000-0x3eff to 0x2000 - 0x2eff
+        let bank = self.mirroring.banks()[vram_index / 0x400];
+        (bank * 0x400 + vram_index % 0x400) as u16
+    }
+
+    /// The 1KB nametable at $2000, $2400, $2800 or $2C00 (`table` 0-3), or
+    /// None if the mirroring asks for VRAM the cartridge doesn't have.
+    pub fn nametable(&self, table: usize) -> Option<&[u8]> {
+        let start = self.mirroring.banks()[table % 4] * 0x400;
+        match start {
+            0..0x800 => Some(&self.vram[start..start + 0x400]),
+            _ => self.cart_vram.get(start - 0x800..start - 0x400),
         }
     }
 
+    /// Reads the PPU address space ($0000-$3FFF) directly, bypassing $2007
+    /// and its read buffer. Used by the debugger.
+    pub fn peek_vram(&self, addr: u16) -> u8 {
+        let addr = addr & 0x3fff;
+        match addr {
+            0..=0x1fff => self.chr_rom.get(addr as usize).copied().unwrap_or(0),
+            0x2000..=0x3eff => {
+                let offset = addr as usize % 0x400;
+                self.nametable((addr as usize & 0x0fff) / 0x400)
+                    .map_or(0, |table| table[offset])
+            }
+            _ => self.palette_table[palette_index(addr)],
+        }
+    }
+
+    // the colour a palette read returns: palette RAM is only 6 bits wide, the
+    // top two bits are whatever was last on the PPU's data bus
+    fn palette_read(&self, addr: u16) -> u8 {
+        self.palette_table[palette_index(addr)] & 0x3f | self.open_bus & 0xc0
+    }
+
     fn inc_vram_addr(&mut self) {
         self.addr.inc(self.ctrl.vram_addr_inc());
     }
@@ -123,13 +197,40 @@ impl NesPPU {
         return false;
     }
 
+    pub fn take_fault(&mut self) -> Option<EmuError> {
+        self.fault.take()
+    }
+
+    // the VRAM byte behind a nametable address, if this mirroring has one
+    fn nametable_byte(&mut self, addr: u16) -> Option<&mut u8> {
+        let index = self.mirror_vram_addr(addr) as usize;
+        let byte = match index {
+            0..0x800 => self.vram.get_mut(index),
+            _ => self.cart_vram.get_mut(index - 0x800),
+        };
+        if byte.is_none() {
+            self.fault = Some(EmuError::Mirroring(self.mirroring));
+        }
+        byte
+    }
+
     pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
         self.nmi_interrupt.take()
     }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    /// PPU cycle within the current scanline (0..=340)
+    pub fn dot(&self) -> usize {
+        self.cycles
+    }
 }
 
 impl PPU for NesPPU {
     fn write_to_ctrl(&mut self, value: u8) {
+        self.open_bus = value;
         let before_nmi_status = self.ctrl.generate_vblank_nmi();
         self.ctrl.update(value);
         if !before_nmi_status && self.ctrl.generate_vblank_nmi() && self.status.is_in_vblank() {
@@ -138,6 +239,7 @@ impl PPU for NesPPU {
     }
 
     fn write_to_mask(&mut self, value: u8) {
+        self.open_bus = value;
         self.mask.update(value);
     }
 
@@ -146,14 +248,21 @@ impl PPU for NesPPU {
         self.status.reset_vblank_status();
         self.addr.reset_latch();
         self.scroll.reset_latch();
+        self.open_bus = data;
         data
     }
 
+    fn peek_status(&self) -> u8 {
+        self.status.snapshot()
+    }
+
     fn write_to_oam_addr(&mut self, value: u8) {
+        self.open_bus = value;
         self.oam_addr = value;
     }
 
     fn write_to_oam_data(&mut self, value: u8) {
+        self.open_bus = value;
         self.oam_data[self.oam_addr as usize] = value;
         self.oam_addr = self.oam_addr.wrapping_add(1);
     }
@@ -163,30 +272,32 @@ impl PPU for NesPPU {
     }
 
     fn write_to_scroll(&mut self, value: u8) {
+        self.open_bus = value;
         self.scroll.write(value);
     }
 
     fn write_to_ppu_addr(&mut self, value: u8) {
+        self.open_bus = value;
         self.addr.update(value);
     }
 
     fn write_to_data(&mut self, value: u8) {
+        self.open_bus = value;
         let addr = self.addr.get();
         match addr {
-            0..=0x1fff => println!("attempt to write to chr rom space {}", addr),
-            0x2000..=0x2fff => {
-                self.vram[self.mirror_vram_addr(addr) as usize] = value;
-            }
-            0x3000..=0x3eff => unimplemented!("addr {} shouldn't be used in reallity", addr),
-
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = value;
-            }
-            0x3f00..=0x3fff => {
-                self.palette_table[(addr - 0x3f00) as usize] = value;
+            0..=0x1fff => self.log.log(
+                Category::Ppu,
+                Level::Warn,
+                self.scanline,
+                "write to CHR ROM",
+                format_args!("${:04X} = ${:02X}", addr, value),
+            ),
+            0x2000..=0x3eff => {
+                if let Some(byte) = self.nametable_byte(addr) {
+                    *byte = value;
+                }
             }
-            _ => panic!("unexpected access to mirrored space {}", addr),
+            _ => self.palette_table[palette_index(addr)] = value & 0x3f,
         }
         self.inc_vram_addr();
     }
@@ -196,27 +307,44 @@ impl PPU for NesPPU {
 
         self.inc_vram_addr();
 
-        match addr {
+        let result = match addr {
             0..=0x1fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                // nothing answers past the end of a short (or missing) CHR
+                self.internal_data_buf = self
+                    .chr_rom
+                    .get(addr as usize)
+                    .copied()
+                    .unwrap_or(self.open_bus);
                 result
             }
-            0x2000..=0x2fff => {
+            0x2000..=0x3eff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                if let Some(&mut byte) = self.nametable_byte(addr) {
+                    self.internal_data_buf = byte;
+                }
                 result
             }
-            0x3000..=0x3eff => unimplemented!("addr {} shouldn't be used in reallity", addr),
-
-            //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize]
+            // palette reads skip the buffer, which is filled from the
+            // nametable byte underneath instead
+            _ => {
+                let result = self.palette_read(addr);
+                if let Some(&mut byte) = self.nametable_byte(addr & 0x2fff) {
+                    self.internal_data_buf = byte;
+                }
+                result
             }
+        };
+        self.open_bus = result;
+        result
+    }
 
-            0x3f00..=0x3fff => self.palette_table[(addr - 0x3f00) as usize],
-            _ => panic!("unexpected access to mirrored space {}", addr),
+    // what `read_data` would return: the buffered byte below the palette, the palette itself above
+    fn peek_data(&self) -> u8 {
+        let addr = self.addr.get();
+        match addr {
+            0x3f00..=0x3fff => self.palette_read(addr),
+            _ => self.internal_data_buf,
         }
     }
 
@@ -426,4 +554,250 @@ pub mod test {
         ppu.write_to_oam_addr(0x11);
         ppu.write_to_oam_addr(0x66);
     }
+
+    #[test]
+    fn test_peek_status_keeps_vblank() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.status.set_vblank_status(true);
+
+        assert_eq!(ppu.peek_status() >> 7, 1);
+        assert_eq!(ppu.peek_status() >> 7, 1);
+        assert_eq!(ppu.read_status() >> 7, 1);
+        assert_eq!(ppu.peek_status() >> 7, 0);
+    }
+
+    #[test]
+    fn test_peek_data_keeps_address_and_buffer() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0);
+        ppu.vram[0x0305] = 0x66;
+        ppu.vram[0x0306] = 0x77;
+
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load_into_buffer
+
+        assert_eq!(ppu.peek_data(), 0x66);
+        assert_eq!(ppu.addr.get(), 0x2306);
+        assert_eq!(ppu.read_data(), 0x66);
+        assert_eq!(ppu.peek_data(), 0x77);
+    }
+
+    fn set_addr(ppu: &mut NesPPU, addr: u16) {
+        ppu.write_to_ppu_addr((addr >> 8) as u8);
+        ppu.write_to_ppu_addr(addr as u8);
+    }
+
+    fn write_at(ppu: &mut NesPPU, addr: u16, value: u8) {
+        set_addr(ppu, addr);
+        ppu.write_to_data(value);
+    }
+
+    // palette reads aren't buffered, so a single read is enough; the top bits
+    // are open bus (the address's low byte here), see the test below
+    fn read_palette(ppu: &mut NesPPU, addr: u16) -> u8 {
+        set_addr(ppu, addr);
+        ppu.read_data() & 0x3f
+    }
+
+    #[test]
+    fn test_read_data_without_chr_is_open_bus() {
+        let mut ppu = NesPPU::new(vec![], Mirroring::HORIZONTAL);
+        set_addr(&mut ppu, 0x1234);
+        ppu.read_data();
+        // the last thing on the bus was the address's low byte
+        assert_eq!(ppu.read_data(), 0x34);
+        assert_eq!(ppu.take_fault(), None);
+    }
+
+    #[test]
+    fn test_3000_mirrors_nametables() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0);
+        write_at(&mut ppu, 0x3305, 0x66);
+        assert_eq!(ppu.vram[0x0305], 0x66);
+
+        write_at(&mut ppu, 0x2efe, 0x77);
+        set_addr(&mut ppu, 0x3efe);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0x77);
+        // horizontal: $2C00 and $2800 are the same table, so $3C00 is too
+        write_at(&mut ppu, 0x2805, 0x88);
+        set_addr(&mut ppu, 0x3c05);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0x88);
+        assert_eq!(ppu.take_fault(), None);
+    }
+
+    #[test]
+    fn test_palette_index() {
+        assert_eq!(palette_index(0x3f00), 0x00);
+        assert_eq!(palette_index(0x3f1f), 0x1f);
+        assert_eq!(palette_index(0x3f20), 0x00);
+        assert_eq!(palette_index(0x3fff), 0x1f);
+        for (sprite, background) in [(0x3f10, 0), (0x3f14, 4), (0x3f18, 8), (0x3f1c, 12)] {
+            assert_eq!(palette_index(sprite), background);
+            assert_eq!(palette_index(sprite + 0x20), background);
+            assert_eq!(palette_index(sprite + 1), background + 0x11);
+        }
+    }
+
+    #[test]
+    fn test_palette_mirrors_every_32_bytes() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0);
+        write_at(&mut ppu, 0x3f01, 0x11);
+        write_at(&mut ppu, 0x3fe2, 0x22);
+        assert_eq!(read_palette(&mut ppu, 0x3f21), 0x11);
+        assert_eq!(read_palette(&mut ppu, 0x3fe1), 0x11);
+        assert_eq!(read_palette(&mut ppu, 0x3f02), 0x22);
+        assert_eq!(ppu.palette_table[2], 0x22);
+
+        // stepping through the top of the palette wraps to $0000
+        ppu.write_to_ctrl(0);
+        set_addr(&mut ppu, 0x3fff);
+        ppu.read_data();
+        assert_eq!(ppu.addr.get(), 0x0000);
+    }
+
+    #[test]
+    fn test_backdrop_mirrors_both_ways() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0);
+        write_at(&mut ppu, 0x3f10, 0x01);
+        assert_eq!(read_palette(&mut ppu, 0x3f00), 0x01);
+        write_at(&mut ppu, 0x3f04, 0x02);
+        assert_eq!(read_palette(&mut ppu, 0x3f14), 0x02);
+        write_at(&mut ppu, 0x3f38, 0x03);
+        assert_eq!(read_palette(&mut ppu, 0x3f08), 0x03);
+        write_at(&mut ppu, 0x3f0c, 0x04);
+        assert_eq!(read_palette(&mut ppu, 0x3ffc), 0x04);
+
+        // only entry 0 is shared, the colours aren't
+        write_at(&mut ppu, 0x3f11, 0x05);
+        write_at(&mut ppu, 0x3f01, 0x06);
+        assert_eq!(read_palette(&mut ppu, 0x3f11), 0x05);
+        assert_eq!(read_palette(&mut ppu, 0x3f01), 0x06);
+        assert_eq!(ppu.palette_table[0x10], 0);
+    }
+
+    #[test]
+    fn test_palette_reads_are_6_bit_with_open_bus() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0);
+        write_at(&mut ppu, 0x3f00, 0xff);
+        assert_eq!(ppu.palette_table[0], 0x3f);
+
+        // the low byte of the address is the last thing on the bus
+        assert_eq!(read_palette(&mut ppu, 0x3f00), 0x3f);
+        set_addr(&mut ppu, 0x3fc0);
+        assert_eq!(ppu.peek_data(), 0xff);
+        assert_eq!(ppu.read_data(), 0xff);
+        ppu.write_to_mask(0x40);
+        set_addr(&mut ppu, 0x3f00);
+        ppu.write_to_mask(0x80);
+        assert_eq!(ppu.read_data(), 0xbf);
+    }
+
+    #[test]
+    fn test_palette_reads_fill_buffer_from_nametable() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0);
+        write_at(&mut ppu, 0x2f01, 0x55);
+        write_at(&mut ppu, 0x3f01, 0x21);
+
+        assert_eq!(read_palette(&mut ppu, 0x3f01), 0x21);
+        // the next buffered read returns the byte at $2F01, not the palette
+        set_addr(&mut ppu, 0x2000);
+        assert_eq!(ppu.read_data(), 0x55);
+        assert_eq!(ppu.read_data(), 0x00);
+    }
+
+    #[test]
+    fn test_four_screen_uses_cartridge_vram() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::FOUR_SCREEN);
+        ppu.write_to_ctrl(0);
+        for (table, value) in [0x2000, 0x2400, 0x2800, 0x2c00].into_iter().zip(1..) {
+            write_at(&mut ppu, table + 5, value);
+        }
+        assert_eq!((ppu.vram[0x005], ppu.vram[0x405]), (1, 2));
+        assert_eq!((ppu.cart_vram[0x005], ppu.cart_vram[0x405]), (3, 4));
+        set_addr(&mut ppu, 0x3c05);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 4);
+        assert_eq!(ppu.peek_vram(0x2805), 3);
+        assert_eq!(ppu.nametable(2).unwrap()[5], 3);
+        assert_eq!(ppu.take_fault(), None);
+    }
+
+    #[test]
+    fn test_single_screen() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::SINGLE_SCREEN_B);
+        ppu.write_to_ctrl(0);
+        write_at(&mut ppu, 0x2005, 0x66);
+        assert_eq!(ppu.vram[0x405], 0x66);
+        for table in [0x2400, 0x2800, 0x2c00, 0x3000] {
+            assert_eq!(ppu.peek_vram(table + 5), 0x66);
+        }
+
+        ppu.set_mirroring(Mirroring::SINGLE_SCREEN_A);
+        write_at(&mut ppu, 0x2c05, 0x77);
+        assert_eq!(ppu.vram[0x005], 0x77);
+        assert_eq!(ppu.peek_vram(0x2405), 0x77);
+        assert_eq!(ppu.nametable(3), ppu.nametable(0));
+    }
+
+    #[test]
+    fn test_mirroring_switched_at_runtime() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0);
+        write_at(&mut ppu, 0x2405, 0x66);
+        assert_eq!(ppu.peek_vram(0x2005), 0x66);
+
+        ppu.set_mirroring(Mirroring::VERTICAL);
+        assert_eq!(ppu.mirroring(), Mirroring::VERTICAL);
+        assert_eq!(ppu.peek_vram(0x2005), 0x66);
+        assert_eq!(ppu.peek_vram(0x2405), 0);
+        assert_eq!(ppu.peek_vram(0x2c05), 0);
+
+        // no cartridge VRAM to switch four-screen onto
+        ppu.set_mirroring(Mirroring::FOUR_SCREEN);
+        assert_eq!(ppu.nametable(2), None);
+        write_at(&mut ppu, 0x2805, 0x77);
+        assert_eq!(
+            ppu.take_fault(),
+            Some(EmuError::Mirroring(Mirroring::FOUR_SCREEN))
+        );
+        assert_eq!(ppu.peek_vram(0x2805), 0);
+    }
+
+    #[test]
+    fn test_rendering_follows_mirroring() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::FOUR_SCREEN);
+        ppu.cart_vram[0x400] = 0x44;
+        ppu.write_to_ctrl(0b11); // $2C00
+        let (main, secondary) = crate::comp::render::visible_nametables(&ppu).unwrap();
+        assert_eq!(main[0], 0x44);
+        assert_eq!(secondary.as_ptr(), ppu.vram[0x400..].as_ptr());
+
+        ppu.write_to_scroll(8);
+        let (_, secondary) = crate::comp::render::visible_nametables(&ppu).unwrap();
+        assert_eq!(secondary.as_ptr(), ppu.cart_vram.as_ptr());
+
+        ppu.set_mirroring(Mirroring::SINGLE_SCREEN_A);
+        let (main, secondary) = crate::comp::render::visible_nametables(&ppu).unwrap();
+        assert_eq!(
+            (main.as_ptr(), secondary.as_ptr()),
+            (ppu.vram.as_ptr(), ppu.vram.as_ptr())
+        );
+
+        let ppu = NesPPU {
+            cart_vram: Vec::new(),
+            ..NesPPU::new(vec![0; 2048], Mirroring::FOUR_SCREEN)
+        };
+        assert_eq!(
+            crate::comp::render::visible_nametables(&ppu),
+            Err(EmuError::Mirroring(Mirroring::FOUR_SCREEN))
+        );
+    }
 }
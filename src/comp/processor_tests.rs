@@ -0,0 +1,287 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use crate::comp::cpu::{CPU, CpuFlags, CpuVariant};
+use crate::comp::json::Json;
+use crate::comp::memory::{Access, BusAccess, RecordingMemory};
+
+// Runner for Tom Harte's ProcessorTests (https://github.com/SingleStepTests/ProcessorTests).
+// Every opcode has its own `xx.json` holding thousands of cases, each with the
+// initial and final registers/RAM and the bus activity of a single instruction.
+
+pub struct OpcodeReport {
+    pub opcode: u8,
+    pub total: usize,
+    /// registers, RAM and the bus access sequence all matched
+    pub passed: usize,
+    /// registers and RAM matched, bus sequence may differ
+    pub state_passed: usize,
+    pub first_failure: Option<String>,
+}
+
+struct CaseResult {
+    state_ok: bool,
+    bus_ok: bool,
+    message: Option<String>,
+}
+
+fn field(json: &Json, key: &str) -> Result<u64, String> {
+    json.get(key)
+        .and_then(Json::as_u64)
+        .ok_or(format!("missing field '{}'", key))
+}
+
+fn ram_entries(state: &Json) -> Result<Vec<(u16, u8)>, String> {
+    let ram = state
+        .get("ram")
+        .and_then(Json::as_array)
+        .ok_or("missing field 'ram'")?;
+    ram.iter()
+        .map(|entry| match entry.as_array() {
+            Some([addr, value]) => Ok((
+                addr.as_u64().ok_or("bad ram address")? as u16,
+                value.as_u64().ok_or("bad ram value")? as u8,
+            )),
+            _ => Err("bad ram entry".to_string()),
+        })
+        .collect()
+}
+
+fn bus_cycles(case: &Json) -> Result<Vec<BusAccess>, String> {
+    let cycles = case
+        .get("cycles")
+        .and_then(Json::as_array)
+        .ok_or("missing field 'cycles'")?;
+    cycles
+        .iter()
+        .map(|cycle| match cycle.as_array() {
+            Some([addr, value, kind]) => Ok(BusAccess {
+                addr: addr.as_u64().ok_or("bad cycle address")? as u16,
+                value: value.as_u64().ok_or("bad cycle value")? as u8,
+                kind: match kind.as_str() {
+                    Some("read") => Access::Read,
+                    Some("write") => Access::Write,
+                    _ => return Err("bad cycle kind".to_string()),
+                },
+            }),
+            _ => Err("bad cycle entry".to_string()),
+        })
+        .collect()
+}
+
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+fn run_case(case: &Json, variant: CpuVariant) -> Result<CaseResult, String> {
+    let name = case.get("name").and_then(Json::as_str).unwrap_or("?");
+    let initial = case.get("initial").ok_or("missing field 'initial'")?;
+    let expected = case.get("final").ok_or("missing field 'final'")?;
+
+    let mut cpu = CPU::new_with_variant(RecordingMemory::new(), variant);
+    cpu.pc = field(initial, "pc")? as u16;
+    cpu.stk_ptr = field(initial, "s")? as u8;
+    cpu.reg_a = field(initial, "a")? as u8;
+    cpu.reg_x = field(initial, "x")? as u8;
+    cpu.reg_y = field(initial, "y")? as u8;
+    cpu.status = CpuFlags::from_bits_truncate(field(initial, "p")? as u8);
+    for (addr, value) in ram_entries(initial)? {
+        cpu.bus.memory.memory[addr as usize] = value;
+    }
+
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| cpu.step())) {
+        return Ok(CaseResult {
+            state_ok: false,
+            bus_ok: false,
+            message: Some(format!("{}: panicked: {}", name, panic_message(&*payload))),
+        });
+    }
+
+    let mut diffs = vec![];
+    let registers = [
+        ("PC", field(expected, "pc")?, cpu.pc as u64),
+        ("SP", field(expected, "s")?, cpu.stk_ptr as u64),
+        ("A", field(expected, "a")?, cpu.reg_a as u64),
+        ("X", field(expected, "x")?, cpu.reg_x as u64),
+        ("Y", field(expected, "y")?, cpu.reg_y as u64),
+        ("P", field(expected, "p")?, cpu.status.bits() as u64),
+    ];
+    for (reg, want, got) in registers {
+        if want != got {
+            diffs.push(format!("{} expected {:02X} got {:02X}", reg, want, got));
+        }
+    }
+    for (addr, want) in ram_entries(expected)? {
+        let got = cpu.bus.memory.memory[addr as usize];
+        if want != got {
+            diffs.push(format!(
+                "${:04X} expected {:02X} got {:02X}",
+                addr, want, got
+            ));
+        }
+    }
+    let state_ok = diffs.is_empty();
+
+    let expected_bus = bus_cycles(case)?;
+    let bus_ok = expected_bus == cpu.bus.accesses;
+    if !bus_ok {
+        diffs.push(format!(
+            "bus expected {:?} got {:?}",
+            expected_bus, cpu.bus.accesses
+        ));
+    }
+
+    Ok(CaseResult {
+        state_ok,
+        bus_ok,
+        message: (!diffs.is_empty()).then(|| format!("{}: {}", name, diffs.join(", "))),
+    })
+}
+
+/// Runs every case of one opcode file.
+pub fn run_opcode_file(
+    path: &Path,
+    opcode: u8,
+    variant: CpuVariant,
+) -> Result<OpcodeReport, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let json = Json::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let cases = json
+        .as_array()
+        .ok_or(format!("{}: expected an array of tests", path.display()))?;
+
+    let mut report = OpcodeReport {
+        opcode,
+        total: cases.len(),
+        passed: 0,
+        state_passed: 0,
+        first_failure: None,
+    };
+    for case in cases {
+        let result = run_case(case, variant)?;
+        if result.state_ok {
+            report.state_passed += 1;
+        }
+        if result.state_ok && result.bus_ok {
+            report.passed += 1;
+        }
+        if report.first_failure.is_none() {
+            report.first_failure = result.message;
+        }
+    }
+    Ok(report)
+}
+
+/// Runs all `00.json`..`ff.json` found in `dir`; missing opcodes are skipped.
+pub fn run_suite(dir: &Path, variant: CpuVariant) -> Result<Vec<OpcodeReport>, String> {
+    // unimplemented opcodes panic, keep the hook from printing thousands of backtraces
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut reports = vec![];
+    let mut result = Ok(());
+    for opcode in 0..=255u8 {
+        let path = dir.join(format!("{:02x}.json", opcode));
+        if !path.exists() {
+            continue;
+        }
+        match run_opcode_file(&path, opcode, variant) {
+            Ok(report) => reports.push(report),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+
+    panic::set_hook(hook);
+    result.map(|_| reports)
+}
+
+pub fn format_report(reports: &[OpcodeReport]) -> String {
+    let mut out = String::new();
+    let (mut total, mut passed, mut state_passed) = (0, 0, 0);
+    for r in reports {
+        out.push_str(&format!(
+            "{:02x}: {:>5}/{:<5} passed, {:>5} state-only\n",
+            r.opcode,
+            r.passed,
+            r.total,
+            r.state_passed - r.passed
+        ));
+        if let Some(failure) = &r.first_failure {
+            out.push_str(&format!("    first failure: {}\n", failure));
+        }
+        total += r.total;
+        passed += r.passed;
+        state_passed += r.state_passed;
+    }
+    let full = reports.iter().filter(|r| r.passed == r.total).count();
+    out.push_str(&format!(
+        "total: {}/{} cases passed ({} with state only), {}/{} opcodes fully passing\n",
+        passed,
+        total,
+        state_passed - passed,
+        full,
+        reports.len()
+    ));
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LDA_IMMEDIATE: &str = r#"{
+        "name": "a9 4f 01",
+        "initial": {"pc": 1024, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36,
+                    "ram": [[1024, 169], [1025, 79]]},
+        "final": {"pc": 1026, "s": 253, "a": 79, "x": 0, "y": 0, "p": 36,
+                  "ram": [[1024, 169], [1025, 79]]},
+        "cycles": [[1024, 169, "read"], [1025, 79, "read"]]
+    }"#;
+
+    // INC $10 with $10 = $7f
+    const INC_ZERO_PAGE: &str = r#"{
+        "name": "e6 10 00",
+        "initial": {"pc": 1024, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36,
+                    "ram": [[1024, 230], [1025, 16], [16, 127]]},
+        "final": {"pc": 1026, "s": 253, "a": 0, "x": 0, "y": 0, "p": 164,
+                  "ram": [[1024, 230], [1025, 16], [16, 128]]},
+        "cycles": [[1024, 230, "read"], [1025, 16, "read"], [16, 127, "read"],
+                   [16, 127, "write"], [16, 128, "write"]]
+    }"#;
+
+    #[test]
+    fn test_passing_cases() {
+        for case in [LDA_IMMEDIATE, INC_ZERO_PAGE] {
+            let json = Json::parse(case).unwrap();
+            let result = run_case(&json, CpuVariant::Ricoh2A03).unwrap();
+            assert!(result.state_ok && result.bus_ok, "{:?}", result.message);
+        }
+    }
+
+    #[test]
+    fn test_reports_register_mismatch() {
+        let json = Json::parse(&LDA_IMMEDIATE.replace("\"a\": 79", "\"a\": 80")).unwrap();
+        let result = run_case(&json, CpuVariant::Ricoh2A03).unwrap();
+        assert!(!result.state_ok);
+        assert!(result.message.unwrap().contains("A expected 50 got 4F"));
+    }
+
+    // Set RNES_PROCESSOR_TESTS to e.g. ProcessorTests/nes6502/v1 to run the full suite.
+    #[test]
+    fn test_processor_tests_suite() {
+        let Ok(dir) = std::env::var("RNES_PROCESSOR_TESTS") else {
+            return;
+        };
+        let reports = run_suite(Path::new(&dir), CpuVariant::Ricoh2A03).unwrap();
+        println!("{}", format_report(&reports));
+    }
+}
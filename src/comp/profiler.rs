@@ -0,0 +1,451 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::comp::bus::Bus;
+use crate::comp::cpu::{CPU, Mem};
+use crate::comp::opcodes::{self, OpCode, Operand};
+use crate::comp::symbols::{Location, Symbols};
+
+// Cycle profiler: every instruction's cycles (from the bus cycle counter, so
+// page crossings, taken branches and interrupt entry are included) go to its PC
+// and to the call stack it ran in. The stack is rebuilt from JSR and NMI entry,
+// and a frame ends once the stack pointer climbs back above its return address,
+// which covers RTS, RTI and code that drops return addresses with PLA or TXS.
+//
+// Frames run from vblank start to vblank start, the way NMI-driven games count
+// them. Tight loops that only read and branch (waiting for the NMI or sprite 0)
+// are idle time, everything else is busy.
+
+const VBLANK_SCANLINE: u16 = 241;
+/// 20 scanlines of 341 dots at 3 dots per CPU cycle (NTSC)
+const VBLANK_CYCLES: u64 = 20 * 341 / 3;
+/// wait loops are short: `BIT $2002 / BPL` or `LDA flag / BEQ`
+const IDLE_LOOP_BYTES: u16 = 8;
+/// leaves room for deep recursion without letting a leaked frame grow forever
+const MAX_STACK_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Address {
+    cpu: u16,
+    location: Location,
+}
+
+struct StackFrame {
+    routine: Address,
+    /// stack pointer before the return address was pushed, `None` for the root
+    return_sp: Option<u8>,
+    /// cycle count on entry, for interrupt handlers
+    entered_at: Option<usize>,
+}
+
+#[derive(Default)]
+struct RoutineStats {
+    calls: u64,
+    self_cycles: u64,
+    total_cycles: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FrameStats {
+    pub cycles: u64,
+    /// spent in wait loops
+    pub idle: u64,
+    /// spent in the NMI handler
+    pub nmi: u64,
+}
+
+impl FrameStats {
+    pub fn busy(&self) -> u64 {
+        self.cycles - self.idle
+    }
+}
+
+pub struct Profiler {
+    symbols: Symbols,
+    stack: Vec<StackFrame>,
+    per_pc: HashMap<Address, u64>,
+    stacks: HashMap<Vec<Address>, u64>,
+    routines: HashMap<Address, RoutineStats>,
+    nmi_handlers: HashSet<Address>,
+    nmi_runs: Vec<u64>,
+    idle_pcs: HashSet<u16>,
+    frames: Vec<FrameStats>,
+    current: FrameStats,
+    last_scanline: u16,
+    seen_vblank: bool,
+    total: u64,
+}
+
+fn address(cpu: &CPU<Bus>, addr: u16) -> Address {
+    Address {
+        cpu: addr,
+        location: Symbols::location(cpu, addr),
+    }
+}
+
+/// Instructions a wait loop can be made of without doing any work.
+fn is_polling(op: &OpCode) -> bool {
+    matches!(
+        op.mnemonic,
+        "LDA" | "LDX" | "LDY" | "BIT" | "CMP" | "CPX" | "CPY" | "AND" | "ORA" | "EOR" | "NOP"
+    ) || op.mode == Operand::Relative
+        || (op.mnemonic == "JMP" && op.mode == Operand::Absolute)
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+impl Profiler {
+    pub fn new(symbols: Symbols) -> Self {
+        Profiler {
+            symbols,
+            stack: vec![],
+            per_pc: HashMap::new(),
+            stacks: HashMap::new(),
+            routines: HashMap::new(),
+            nmi_handlers: HashSet::new(),
+            nmi_runs: vec![],
+            idle_pcs: HashSet::new(),
+            frames: vec![],
+            current: FrameStats::default(),
+            last_scanline: 0,
+            seen_vblank: false,
+            total: 0,
+        }
+    }
+
+    /// Completed frames so far.
+    pub fn frames(&self) -> &[FrameStats] {
+        &self.frames
+    }
+
+    /// Cycles each NMI handler run took, from interrupt entry to its RTI.
+    pub fn nmi_runs(&self) -> &[u64] {
+        &self.nmi_runs
+    }
+
+    fn push(&mut self, frame: StackFrame) {
+        if self.stack.len() < MAX_STACK_DEPTH {
+            self.routines.entry(frame.routine).or_default().calls += 1;
+            self.stack.push(frame);
+        }
+    }
+
+    fn unwind(&mut self, sp: u8, cycles: usize) {
+        while let Some(frame) = self.stack.last() {
+            match frame.return_sp {
+                Some(return_sp) if sp >= return_sp => {
+                    if let Some(entered_at) = frame.entered_at {
+                        self.nmi_runs.push((cycles - entered_at) as u64);
+                    }
+                    self.stack.pop();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Services interrupts and runs one instruction, like one turn of
+    /// `run_with_callback`. Returns false when the CPU halts.
+    pub fn step(&mut self, cpu: &mut CPU<Bus>) -> bool {
+        let start = cpu.bus.cycles();
+        if self.stack.is_empty() {
+            let root = address(cpu, cpu.pc);
+            self.push(StackFrame {
+                routine: root,
+                return_sp: None,
+                entered_at: None,
+            });
+        }
+
+        let sp = cpu.stk_ptr;
+        let interrupted_pc = cpu.pc;
+        cpu.poll_interrupts();
+        if cpu.pc != interrupted_pc {
+            let handler = address(cpu, cpu.pc);
+            self.nmi_handlers.insert(handler);
+            self.push(StackFrame {
+                routine: handler,
+                return_sp: Some(sp),
+                entered_at: Some(start),
+            });
+        }
+
+        let pc = cpu.pc;
+        let here = address(cpu, pc);
+        let op = opcodes::lookup(cpu.peek(pc));
+        let sp = cpu.stk_ptr;
+        let running = cpu.step();
+        let elapsed = (cpu.bus.cycles() - start) as u64;
+
+        self.attribute(here, elapsed);
+        if self.stack.iter().any(|frame| frame.entered_at.is_some()) {
+            self.current.nmi += elapsed;
+        }
+        if self.idle_pcs.contains(&pc) {
+            self.current.idle += elapsed;
+        }
+
+        if op.mnemonic == "JSR" {
+            let routine = address(cpu, cpu.pc);
+            self.push(StackFrame {
+                routine,
+                return_sp: Some(sp),
+                entered_at: None,
+            });
+        } else {
+            self.unwind(cpu.stk_ptr, cpu.bus.cycles());
+        }
+        let jumped_back = cpu.pc <= pc && pc - cpu.pc <= IDLE_LOOP_BYTES;
+        if jumped_back && (op.mode == Operand::Relative || op.mnemonic == "JMP") {
+            self.check_wait_loop(cpu, cpu.pc, pc);
+        }
+
+        let scanline = cpu.bus.ppu().scanline();
+        if self.last_scanline < VBLANK_SCANLINE && scanline >= VBLANK_SCANLINE {
+            let frame = std::mem::take(&mut self.current);
+            // the partial frame from power-on to the first vblank isn't one
+            if self.seen_vblank {
+                self.frames.push(frame);
+            }
+            self.seen_vblank = true;
+        }
+        self.last_scanline = scanline;
+        running
+    }
+
+    fn attribute(&mut self, here: Address, cycles: u64) {
+        self.total += cycles;
+        self.current.cycles += cycles;
+        *self.per_pc.entry(here).or_default() += cycles;
+
+        let stack: Vec<Address> = self.stack.iter().map(|frame| frame.routine).collect();
+        if let Some(top) = stack.last() {
+            self.routines.entry(*top).or_default().self_cycles += cycles;
+        }
+        let mut seen = HashSet::new();
+        for routine in &stack {
+            // recursion counts once towards a routine's total
+            if seen.insert(*routine) {
+                self.routines.entry(*routine).or_default().total_cycles += cycles;
+            }
+        }
+        *self.stacks.entry(stack).or_default() += cycles;
+    }
+
+    /// Marks `start..=end` as a wait loop if all it does is read and branch.
+    fn check_wait_loop(&mut self, cpu: &CPU<Bus>, start: u16, end: u16) {
+        let mut pcs = vec![];
+        let mut pc = start;
+        while pc <= end {
+            let op = opcodes::lookup(cpu.peek(pc));
+            if !op.official || !is_polling(op) {
+                return;
+            }
+            pcs.push(pc);
+            pc = pc.wrapping_add(op.size() as u16);
+            if pc < start {
+                return;
+            }
+        }
+        self.idle_pcs.extend(pcs);
+    }
+
+    fn name(&self, routine: Address) -> String {
+        let label = self
+            .symbols
+            .label_at(routine.location)
+            .or_else(|| self.symbols.label_at(Location::Cpu(routine.cpu)));
+        match (label, self.nmi_handlers.contains(&routine)) {
+            (Some(label), _) => label.to_string(),
+            (None, true) => format!("NMI@${:04X}", routine.cpu),
+            (None, false) => format!("${:04X}", routine.cpu),
+        }
+    }
+
+    /// Folded stacks (`root;caller;callee cycles`) for flamegraph.pl or inferno.
+    pub fn collapsed_stacks(&self) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(stack, cycles)| {
+                let names: Vec<String> = stack.iter().map(|r| self.name(*r)).collect();
+                format!("{} {}", names.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+        lines.join("\n") + "\n"
+    }
+
+    /// Text report with the `top` hottest routines and instructions.
+    pub fn report(&self, top: usize) -> String {
+        let mut out = format!(
+            "profile: {} frames, {} cycles\n",
+            self.frames.len(),
+            self.total
+        );
+
+        if let Some(longest) = self.frames.iter().map(|f| f.cycles).max() {
+            let count = self.frames.len() as u64;
+            let avg_cycles = self.frames.iter().map(|f| f.cycles).sum::<u64>() / count;
+            let avg_busy = self.frames.iter().map(|f| f.busy()).sum::<u64>() / count;
+            let (worst, worst_frame) = self
+                .frames
+                .iter()
+                .enumerate()
+                .map(|(i, f)| (f.busy(), i))
+                .max()
+                .unwrap_or_default();
+            out.push_str(&format!(
+                "\nframe time (vblank to vblank, avg {} cycles, longest {}):\n",
+                avg_cycles, longest
+            ));
+            out.push_str(&format!(
+                "  busy  avg {:>6} ({:5.1}%)  max {:>6} ({:5.1}%) in frame {}\n",
+                avg_busy,
+                percent(avg_busy, avg_cycles),
+                worst,
+                percent(worst, avg_cycles),
+                worst_frame
+            ));
+            let avg_nmi = self.frames.iter().map(|f| f.nmi).sum::<u64>() / count;
+            out.push_str(&format!(
+                "  nmi   avg {:>6} ({:5.1}%)\n",
+                avg_nmi,
+                percent(avg_nmi, avg_cycles)
+            ));
+        }
+
+        if !self.nmi_runs.is_empty() {
+            let avg = self.nmi_runs.iter().sum::<u64>() / self.nmi_runs.len() as u64;
+            let max = self.nmi_runs.iter().copied().max().unwrap_or_default();
+            let over = self.nmi_runs.iter().filter(|&&c| c > VBLANK_CYCLES).count();
+            out.push_str(&format!(
+                "\nNMI handler vs vblank ({} cycles):\n  avg {:>6} ({:5.1}%)  max {:>6} ({:5.1}%)  overran vblank {} of {} times\n",
+                VBLANK_CYCLES,
+                avg,
+                percent(avg, VBLANK_CYCLES),
+                max,
+                percent(max, VBLANK_CYCLES),
+                over,
+                self.nmi_runs.len()
+            ));
+        }
+
+        let mut routines: Vec<(&Address, &RoutineStats)> = self.routines.iter().collect();
+        routines
+            .sort_by_key(|(routine, stats)| (std::cmp::Reverse(stats.self_cycles), routine.cpu));
+        out.push_str(
+            "\nhottest routines:\n         self  %self       total %total     calls  routine\n",
+        );
+        for (routine, stats) in routines.into_iter().take(top) {
+            out.push_str(&format!(
+                "  {:>11} {:5.1}% {:>11} {:5.1}% {:>9}  {}\n",
+                stats.self_cycles,
+                percent(stats.self_cycles, self.total),
+                stats.total_cycles,
+                percent(stats.total_cycles, self.total),
+                stats.calls,
+                self.name(*routine)
+            ));
+        }
+
+        let mut pcs: Vec<(&Address, &u64)> = self.per_pc.iter().collect();
+        pcs.sort_by_key(|(pc, cycles)| (std::cmp::Reverse(**cycles), pc.cpu));
+        out.push_str("\nhottest instructions:\n       cycles      %  pc\n");
+        for (pc, cycles) in pcs.into_iter().take(top) {
+            let idle = if self.idle_pcs.contains(&pc.cpu) {
+                "  (wait loop)"
+            } else {
+                ""
+            };
+            let label = self
+                .symbols
+                .label_at(pc.location)
+                .map(|l| format!(" {}", l))
+                .unwrap_or_default();
+            out.push_str(&format!(
+                "  {:>11} {:5.1}%  ${:04X}{}{}\n",
+                cycles,
+                percent(*cycles, self.total),
+                pc.cpu,
+                label,
+                idle
+            ));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::rom::test::test_rom;
+
+    fn profiled_cpu() -> CPU<Bus<'static>> {
+        let mut prg = vec![0; 0x8000];
+        let program: &[(u16, &[u8])] = &[
+            // LDA #$80; STA $2000; JSR $8010; wait: JMP wait
+            (
+                0x8000,
+                &[
+                    0xa9, 0x80, 0x8d, 0x00, 0x20, 0x20, 0x10, 0x80, 0x4c, 0x08, 0x80,
+                ],
+            ),
+            // LDX #$05; loop: DEX; BNE loop; RTS
+            (0x8010, &[0xa2, 0x05, 0xca, 0xd0, 0xfd, 0x60]),
+            // nmi: JSR $8030; RTI
+            (0x8020, &[0x20, 0x30, 0x80, 0x40]),
+            (0x8030, &[0x60]),
+            (0xfffa, &[0x20, 0x80, 0x00, 0x80]),
+        ];
+        for (addr, bytes) in program {
+            let at = (*addr - 0x8000) as usize;
+            prg[at..at + bytes.len()].copy_from_slice(bytes);
+        }
+        let mut cpu = CPU::new(Bus::new(test_rom(prg), |_, _| {}));
+        cpu.reset();
+        cpu
+    }
+
+    fn profile(frames: usize) -> Profiler {
+        let mut cpu = profiled_cpu();
+        let mut profiler = Profiler::new(Symbols::new());
+        while profiler.frames().len() < frames {
+            assert!(profiler.step(&mut cpu));
+        }
+        profiler
+    }
+
+    #[test]
+    fn test_call_stacks() {
+        let profiler = profile(2);
+        let folded = profiler.collapsed_stacks();
+        // LDA, STA and JSR ran in the reset routine before the first vblank
+        assert!(folded.contains("$8000 "), "{}", folded);
+        // LDX 2 + 5 DEX 10 + 4 taken BNE 12 + 1 not taken 2 + RTS 6
+        assert!(folded.contains("$8000;$8010 32\n"), "{}", folded);
+        // two NMIs: interrupt entry lands on the handler's JSR, the RTI is its own
+        assert!(folded.contains("$8000;NMI@$8020 28\n"), "{}", folded);
+        assert!(folded.contains("$8000;NMI@$8020;$8030 12\n"), "{}", folded);
+    }
+
+    #[test]
+    fn test_frame_and_nmi_time() {
+        let profiler = profile(3);
+        // interrupt entry 2 + JSR 6 + RTS 6 + RTI 6
+        assert_eq!(profiler.nmi_runs()[0], 20);
+        let frame = &profiler.frames()[1];
+        assert!(frame.cycles > 29000 && frame.cycles < 30000);
+        // everything but the NMI and a few jumps before the loop was spotted
+        assert!(frame.busy() < 40, "{:?}", frame);
+        assert_eq!(frame.nmi, 20);
+        let report = profiler.report(5);
+        assert!(report.contains("(wait loop)"), "{}", report);
+        assert!(report.contains("overran vblank 0 of 3 times"), "{}", report);
+    }
+}
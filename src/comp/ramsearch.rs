@@ -0,0 +1,469 @@
+use std::path::Path;
+
+use crate::comp::cpu::Mem;
+
+// Cheat-finder style RAM search: snapshot the searchable memory, then narrow
+// the candidate addresses down by comparing the next snapshot with the last one,
+// e.g. "decreased" after losing a life and "equal" after standing still.
+//
+// The watch list keeps the addresses found that way, shows their live values
+// every frame and can freeze them, which rewrites the value once per frame.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    U8,
+    S8,
+    U16,
+    S16,
+}
+
+impl ValueType {
+    pub fn parse(text: &str) -> Result<ValueType, String> {
+        match text.to_ascii_lowercase().as_str() {
+            "u8" => Ok(ValueType::U8),
+            "s8" | "i8" => Ok(ValueType::S8),
+            "u16" => Ok(ValueType::U16),
+            "s16" | "i16" => Ok(ValueType::S16),
+            other => Err(format!("unknown type '{}', use u8, s8, u16 or s16", other)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueType::U8 => "u8",
+            ValueType::S8 => "s8",
+            ValueType::U16 => "u16",
+            ValueType::S16 => "s16",
+        }
+    }
+
+    pub fn size(self) -> u16 {
+        match self {
+            ValueType::U8 | ValueType::S8 => 1,
+            ValueType::U16 | ValueType::S16 => 2,
+        }
+    }
+
+    /// `bytes` are little-endian, `size()` of them.
+    fn decode(self, bytes: &[u8]) -> i32 {
+        match self {
+            ValueType::U8 => bytes[0] as i32,
+            ValueType::S8 => bytes[0] as i8 as i32,
+            ValueType::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as i32,
+            ValueType::S16 => i16::from_le_bytes([bytes[0], bytes[1]]) as i32,
+        }
+    }
+
+    /// Out of range values wrap, so `-1` freezes a u8 at $FF.
+    fn encode(self, value: i32) -> Vec<u8> {
+        (value as u16).to_le_bytes()[..self.size() as usize].to_vec()
+    }
+
+    fn read<M: Mem>(self, mem: &M, addr: u16) -> i32 {
+        let bytes: Vec<u8> = (0..self.size())
+            .map(|i| mem.peek(addr.wrapping_add(i)))
+            .collect();
+        self.decode(&bytes)
+    }
+
+    pub fn format(self, value: i32) -> String {
+        let hex = match self.size() {
+            1 => format!("${:02X}", value as u8),
+            _ => format!("${:04X}", value as u16),
+        };
+        format!("{} ({})", value, hex)
+    }
+}
+
+/// A number in decimal, `$hex` or `0xhex`, optionally negative.
+pub fn parse_value(text: &str) -> Result<i32, String> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let parsed = if let Some(hex) = digits.strip_prefix('$') {
+        i32::from_str_radix(hex, 16)
+    } else if let Some(hex) = digits.strip_prefix("0x") {
+        i32::from_str_radix(hex, 16)
+    } else {
+        digits.parse::<i32>()
+    };
+    match parsed {
+        Ok(value) if value <= 0xffff => Ok(if negative { -value } else { value }),
+        _ => Err(format!("bad value '{}'", text)),
+    }
+}
+
+/// How a candidate's value must relate to the previous snapshot to stay in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    Value(i32),
+}
+
+impl Filter {
+    pub fn parse(words: &[&str]) -> Result<Filter, String> {
+        match words {
+            ["equal" | "eq" | "same"] => Ok(Filter::Equal),
+            ["changed" | "ne"] => Ok(Filter::Changed),
+            ["increased" | "inc" | "gt"] => Ok(Filter::Increased),
+            ["decreased" | "dec" | "lt"] => Ok(Filter::Decreased),
+            ["value" | "=", value] => parse_value(value).map(Filter::Value),
+            _ => Err("filter by equal, changed, increased, decreased or value <n>".to_string()),
+        }
+    }
+
+    fn keep(self, previous: i32, current: i32) -> bool {
+        match self {
+            Filter::Equal => current == previous,
+            Filter::Changed => current != previous,
+            Filter::Increased => current > previous,
+            Filter::Decreased => current < previous,
+            Filter::Value(value) => current == value,
+        }
+    }
+}
+
+/// A copy of each searchable region and the CPU address it starts at.
+struct Snapshot(Vec<(u16, Vec<u8>)>);
+
+impl Snapshot {
+    fn take(regions: &[(u16, &[u8])]) -> Snapshot {
+        Snapshot(
+            regions
+                .iter()
+                .map(|(base, bytes)| (*base, bytes.to_vec()))
+                .collect(),
+        )
+    }
+
+    /// `None` when the value doesn't fit inside a single region.
+    fn read(&self, addr: u16, kind: ValueType) -> Option<i32> {
+        self.0.iter().find_map(|(base, bytes)| {
+            let start = addr.checked_sub(*base)? as usize;
+            let value = bytes.get(start..start + kind.size() as usize)?;
+            Some(kind.decode(value))
+        })
+    }
+
+    fn addresses(&self) -> impl Iterator<Item = u16> + '_ {
+        self.0
+            .iter()
+            .flat_map(|(base, bytes)| (0..bytes.len()).map(move |i| base.wrapping_add(i as u16)))
+    }
+}
+
+pub struct RamSearch {
+    kind: ValueType,
+    snapshot: Snapshot,
+    candidates: Vec<u16>,
+}
+
+impl RamSearch {
+    /// Starts with every address of `regions` as a candidate.
+    pub fn new(regions: &[(u16, &[u8])], kind: ValueType) -> RamSearch {
+        let snapshot = Snapshot::take(regions);
+        let candidates = snapshot
+            .addresses()
+            .filter(|&addr| snapshot.read(addr, kind).is_some())
+            .collect();
+        RamSearch {
+            kind,
+            snapshot,
+            candidates,
+        }
+    }
+
+    pub fn kind(&self) -> ValueType {
+        self.kind
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    /// The candidate's value when it was last filtered.
+    pub fn value(&self, addr: u16) -> Option<i32> {
+        self.snapshot.read(addr, self.kind)
+    }
+
+    /// Keeps the candidates whose value in `regions` passes `filter` against the
+    /// previous snapshot, then takes `regions` as the new snapshot.
+    pub fn filter(&mut self, regions: &[(u16, &[u8])], filter: Filter) -> usize {
+        let current = Snapshot::take(regions);
+        let kind = self.kind;
+        let previous = &self.snapshot;
+        self.candidates.retain(|&addr| {
+            match (previous.read(addr, kind), current.read(addr, kind)) {
+                (Some(before), Some(now)) => filter.keep(before, now),
+                _ => false,
+            }
+        });
+        self.snapshot = current;
+        self.candidates.len()
+    }
+
+    /// The first `limit` candidates with their values.
+    pub fn results(&self, limit: usize) -> String {
+        let mut out = String::new();
+        for &addr in self.candidates.iter().take(limit) {
+            let value = self.value(addr).unwrap_or_default();
+            out.push_str(&format!("{:04X}: {}\n", addr, self.kind.format(value)));
+        }
+        if self.candidates.len() > limit {
+            out.push_str(&format!("... {} more\n", self.candidates.len() - limit));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watch {
+    pub addr: u16,
+    pub kind: ValueType,
+    pub name: String,
+    pub frozen: Option<i32>,
+}
+
+impl Watch {
+    pub fn value<M: Mem>(&self, mem: &M) -> i32 {
+        self.kind.read(mem, self.addr)
+    }
+
+    fn label(&self) -> String {
+        if self.name.is_empty() {
+            format!("{:04X}", self.addr)
+        } else {
+            self.name.clone()
+        }
+    }
+}
+
+/// Saved next to the ROM, one watch per line: `<addr> <type> [=<frozen>] [name]`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct WatchList {
+    watches: Vec<Watch>,
+}
+
+impl WatchList {
+    pub fn new() -> WatchList {
+        WatchList::default()
+    }
+
+    pub fn parse(text: &str) -> Result<WatchList, String> {
+        let mut list = WatchList::new();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let bad = || format!("bad watch '{}'", line);
+            let mut words = line.splitn(3, ' ');
+            let addr = parse_value(words.next().ok_or_else(bad)?).map_err(|_| bad())? as u16;
+            let kind = ValueType::parse(words.next().ok_or_else(bad)?)?;
+            let mut rest = words.next().unwrap_or("").trim();
+            let mut frozen = None;
+            if let Some(value) = rest.strip_prefix('=') {
+                let (value, name) = value.split_once(' ').unwrap_or((value, ""));
+                frozen = Some(parse_value(value)?);
+                rest = name.trim();
+            }
+            list.add(addr, kind, rest);
+            if let Some(value) = frozen {
+                list.freeze(addr, kind, value);
+            }
+        }
+        Ok(list)
+    }
+
+    /// Loads `path` if it exists, otherwise starts an empty list.
+    pub fn load(path: &Path) -> Result<WatchList, String> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => WatchList::parse(&text).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(WatchList::new()),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for watch in &self.watches {
+            out.push_str(&format!("${:04X} {}", watch.addr, watch.kind.name()));
+            if let Some(value) = watch.frozen {
+                out.push_str(&format!(" ={}", value));
+            }
+            if !watch.name.is_empty() {
+                out.push_str(&format!(" {}", watch.name));
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, self.to_text())
+    }
+
+    pub fn watches(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    /// Adds `addr`, or renames and retypes it if it's already watched.
+    pub fn add(&mut self, addr: u16, kind: ValueType, name: &str) {
+        match self.watches.iter_mut().find(|w| w.addr == addr) {
+            Some(watch) => {
+                watch.kind = kind;
+                watch.name = name.to_string();
+            }
+            None => self.watches.push(Watch {
+                addr,
+                kind,
+                name: name.to_string(),
+                frozen: None,
+            }),
+        }
+    }
+
+    pub fn remove(&mut self, addr: u16) -> bool {
+        let before = self.watches.len();
+        self.watches.retain(|w| w.addr != addr);
+        self.watches.len() != before
+    }
+
+    /// Freezing an address that isn't watched yet adds it.
+    pub fn freeze(&mut self, addr: u16, kind: ValueType, value: i32) {
+        if !self.watches.iter().any(|w| w.addr == addr) {
+            self.add(addr, kind, "");
+        }
+        let watch = self.watches.iter_mut().find(|w| w.addr == addr).unwrap();
+        watch.kind = kind;
+        watch.frozen = Some(value);
+    }
+
+    pub fn unfreeze(&mut self, addr: u16) -> bool {
+        match self.watches.iter_mut().find(|w| w.addr == addr) {
+            Some(watch) => watch.frozen.take().is_some(),
+            None => false,
+        }
+    }
+
+    /// Writes every frozen value back through the CPU bus.
+    pub fn apply_freezes<M: Mem>(&self, mem: &mut M) {
+        for watch in &self.watches {
+            if let Some(value) = watch.frozen {
+                for (i, byte) in watch.kind.encode(value).into_iter().enumerate() {
+                    mem.mem_write(watch.addr.wrapping_add(i as u16), byte);
+                }
+            }
+        }
+    }
+
+    /// One line per watch with its live value; frozen ones are marked.
+    pub fn show<M: Mem>(&self, mem: &M) -> String {
+        let mut out = String::new();
+        for watch in &self.watches {
+            let frozen = if watch.frozen.is_some() {
+                " frozen"
+            } else {
+                ""
+            };
+            out.push_str(&format!(
+                "{:04X} {:<3} {}: {}{}\n",
+                watch.addr,
+                watch.kind.name(),
+                watch.label(),
+                watch.kind.format(watch.value(mem)),
+                frozen
+            ));
+        }
+        out
+    }
+
+    /// Compact `name=value` summary for a status line or window title.
+    pub fn status<M: Mem>(&self, mem: &M) -> String {
+        self.watches
+            .iter()
+            .map(|watch| {
+                let frozen = if watch.frozen.is_some() { "*" } else { "" };
+                format!("{}={}{}", watch.label(), watch.value(mem), frozen)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::bus::Bus;
+    use crate::comp::cpu::CPU;
+    use crate::comp::rom::test::{test_prg, test_rom};
+
+    #[test]
+    fn test_filters_narrow_down_candidates() {
+        let mut ram = [0u8; 16];
+        ram[3] = 5;
+        ram[7] = 5;
+        ram[9] = 0x80;
+        let mut search = RamSearch::new(&[(0x0000, &ram[..])], ValueType::U8);
+        assert_eq!(search.candidates().len(), 16);
+
+        ram[3] = 4;
+        ram[7] = 6;
+        assert_eq!(search.filter(&[(0, &ram[..])], Filter::Changed), 2);
+        ram[3] = 3;
+        ram[7] = 7;
+        assert_eq!(search.filter(&[(0, &ram[..])], Filter::Decreased), 1);
+        assert_eq!(search.candidates(), &[3]);
+        assert_eq!(search.filter(&[(0, &ram[..])], Filter::Equal), 1);
+        assert_eq!(search.filter(&[(0, &ram[..])], Filter::Value(2)), 0);
+
+        // $80 is 128 unsigned but -128 signed
+        let mut search = RamSearch::new(&[(0x0000, &ram[..])], ValueType::S8);
+        assert_eq!(search.filter(&[(0, &ram[..])], Filter::Value(-128)), 1);
+        assert_eq!(search.results(10), "0009: -128 ($80)\n");
+    }
+
+    #[test]
+    fn test_16_bit_values_stay_inside_a_region() {
+        let ram = [0x34, 0x12, 0xff, 0xff];
+        let prg_ram = [0x00, 0x80];
+        let regions = [(0x0000, &ram[..]), (0x6000, &prg_ram[..])];
+        let mut search = RamSearch::new(&regions, ValueType::U16);
+        // no candidate straddles $0003/$0004 or the end of PRG RAM
+        assert_eq!(search.candidates(), &[0, 1, 2, 0x6000]);
+        assert_eq!(search.value(0), Some(0x1234));
+        assert_eq!(search.filter(&regions, Filter::Value(0x8000)), 1);
+
+        let mut search = RamSearch::new(&regions, ValueType::S16);
+        assert_eq!(search.filter(&regions, Filter::Value(-1)), 1);
+        assert_eq!(search.candidates(), &[2]);
+    }
+
+    #[test]
+    fn test_watch_list_freezes_and_round_trips() {
+        let mut cpu = CPU::new(Bus::new(test_rom(test_prg(&[])), |_, _| {}));
+        cpu.mem_write(0x0010, 3);
+
+        let mut list = WatchList::parse("$0010 u8 lives\n$0020 s16 =-2 speed x\n").unwrap();
+        list.add(0x0030, ValueType::U8, "");
+        list.apply_freezes(&mut cpu);
+        assert_eq!(cpu.mem_read(0x0020), 0xfe);
+        assert_eq!(cpu.mem_read(0x0021), 0xff);
+        assert_eq!(list.status(&cpu), "lives=3 speed x=-2* 0030=0");
+
+        list.freeze(0x0010, ValueType::U8, 9);
+        assert!(list.unfreeze(0x0020));
+        list.apply_freezes(&mut cpu);
+        assert_eq!(cpu.mem_read(0x0010), 9);
+        assert_eq!(
+            list.to_text(),
+            "$0010 u8 =9 lives\n$0020 s16 speed x\n$0030 u8\n"
+        );
+        assert_eq!(WatchList::parse(&list.to_text()).unwrap(), list);
+        assert!(WatchList::parse("$0010 u32").is_err());
+    }
+}
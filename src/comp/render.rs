@@ -1,6 +1,7 @@
-use crate::comp::{ppu::NesPPU, render::frame::Frame, rom::Mirroring};
+use crate::comp::{error::EmuError, ppu::NesPPU, render::frame::Frame};
 
 pub mod frame;
+pub mod image;
 pub mod palette;
 
 fn bg_pallette(ppu: &NesPPU, attr_table: &[u8], tile_column: usize, tile_row: usize) -> [u8; 4] {
@@ -24,23 +25,28 @@ fn bg_pallette(ppu: &NesPPU, attr_table: &[u8], tile_column: usize, tile_row: us
     ]
 }
 
-pub fn render(ppu: &NesPPU, frame: &mut Frame) {
+/// The nametable selected in PPUCTRL and the one scrolling brings into view.
+pub fn visible_nametables(ppu: &NesPPU) -> Result<(&[u8], &[u8]), EmuError> {
+    let table = (ppu.ctrl.nametable_addr() - 0x2000) as usize / 0x400;
+    // horizontal scrolling brings in the table to the right, vertical the one below
+    let next = if ppu.scroll.scroll_x > 0 {
+        table ^ 1
+    } else {
+        table ^ 2
+    };
+    match (ppu.nametable(table), ppu.nametable(next)) {
+        (Some(main), Some(secondary)) => Ok((main, secondary)),
+        _ => Err(EmuError::Mirroring(ppu.mirroring())),
+    }
+}
+
+/// Draws the current nametables and sprites into `frame`. Fails, leaving
+/// `frame` as it was, if the mirroring needs VRAM the cartridge doesn't have.
+pub fn render(ppu: &NesPPU, frame: &mut Frame) -> Result<(), EmuError> {
     let scroll_x = (ppu.scroll.scroll_x) as usize;
     let scroll_y = (ppu.scroll.scroll_y) as usize;
 
-    let (main_nametable, sec_nametable) = match (&ppu.mirroring, ppu.ctrl.nametable_addr()) {
-        (Mirroring::VERTICAL, 0x2000)
-        | (Mirroring::VERTICAL, 0x2800)
-        | (Mirroring::HORIZONTAL, 0x2000)
-        | (Mirroring::HORIZONTAL, 0x2400) => (&ppu.vram[0..0x400], &ppu.vram[0x400..0x800]),
-        (Mirroring::VERTICAL, 0x2400)
-        | (Mirroring::VERTICAL, 0x2C00)
-        | (Mirroring::HORIZONTAL, 0x2800)
-        | (Mirroring::HORIZONTAL, 0x2C00) => (&ppu.vram[0x400..0x800], &ppu.vram[0..0x400]),
-        (_, _) => {
-            panic!("No waying {:?}", ppu.mirroring);
-        }
-    };
+    let (main_nametable, sec_nametable) = visible_nametables(ppu)?;
 
     render_name_table(
         ppu,
@@ -116,6 +122,7 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
             }
         }
     }
+    Ok(())
 }
 fn sprite_palette(ppu: &NesPPU, pallete_idx: u8) -> [u8; 4] {
     let start = 0x11 + (pallete_idx * 4) as usize;
@@ -19,4 +19,11 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
+
+    /// FNV-1a over the pixels, to compare frames without storing them.
+    pub fn checksum(&self) -> u64 {
+        self.data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
 }
@@ -0,0 +1,165 @@
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::comp::render::frame::Frame;
+
+// Frame dumps without an image library: binary PPM, and PNG with the pixel
+// data in uncompressed deflate blocks. Both are 256x240 RGB.
+
+const WIDTH: usize = 256;
+const HEIGHT: usize = 240;
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const MAX_STORED_BLOCK: usize = 0xffff;
+
+pub fn write_ppm<W: Write>(frame: &Frame, mut out: W) -> io::Result<()> {
+    write!(out, "P6\n{} {}\n255\n", WIDTH, HEIGHT)?;
+    out.write_all(&frame.data)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+pub fn encode_png(frame: &Frame) -> Vec<u8> {
+    encode_rgb(WIDTH, HEIGHT, &frame.data)
+}
+
+/// Half-size PNG (128x120, every other pixel), for reports listing many frames.
+pub fn encode_thumbnail(frame: &Frame) -> Vec<u8> {
+    let pixels: Vec<u8> = frame
+        .data
+        .chunks(WIDTH * 3)
+        .step_by(2)
+        .flat_map(|row| row.chunks(3).step_by(2).flatten().copied())
+        .collect();
+    encode_rgb(WIDTH / 2, HEIGHT / 2, &pixels)
+}
+
+fn encode_rgb(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    // every scanline starts with filter type 0 (none)
+    let mut raw = Vec::with_capacity(height * (width * 3 + 1));
+    for row in pixels.chunks(width * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = raw.chunks(MAX_STORED_BLOCK).count();
+    for (i, block) in raw.chunks(MAX_STORED_BLOCK).enumerate() {
+        zlib.push((i + 1 == blocks) as u8);
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = vec![];
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, RGB, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    png_chunk(&mut png, b"IHDR", &header);
+    png_chunk(&mut png, b"IDAT", &zlib);
+    png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// Writes a `.ppm` file, or a PNG for any other extension.
+pub fn save(frame: &Frame, path: &Path) -> io::Result<()> {
+    let file = std::fs::File::create(path)?;
+    let mut out = io::BufWriter::new(file);
+    if path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("ppm"))
+    {
+        write_ppm(frame, &mut out)?;
+    } else {
+        out.write_all(&encode_png(frame))?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn test_png_and_ppm_layout() {
+        let mut frame = Frame::new();
+        frame.set_pixel(1, 0, (0x12, 0x34, 0x56));
+        let png = encode_png(&frame);
+        assert_eq!(&png[..8], PNG_SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..29], &[0, 0, 1, 0, 0, 0, 0, 240, 8, 2, 0, 0, 0]);
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xaeB`\x82");
+
+        // undo the stored deflate blocks and check the pixels come back
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        let zlib = &png[41..41 + idat_len];
+        let mut raw = vec![];
+        let mut at = 2;
+        loop {
+            let last = zlib[at] == 1;
+            let len = u16::from_le_bytes([zlib[at + 1], zlib[at + 2]]) as usize;
+            raw.extend_from_slice(&zlib[at + 5..at + 5 + len]);
+            at += 5 + len;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(raw.len(), 240 * (256 * 3 + 1));
+        assert_eq!(&raw[0..7], &[0, 0, 0, 0, 0x12, 0x34, 0x56]);
+        assert_eq!(&zlib[at..], &adler32(&raw).to_be_bytes());
+
+        let mut ppm = vec![];
+        write_ppm(&frame, &mut ppm).unwrap();
+        assert_eq!(&ppm[..15], b"P6\n256 240\n255\n");
+        assert_eq!(ppm.len(), 15 + 256 * 240 * 3);
+
+        let thumbnail = encode_thumbnail(&frame);
+        assert_eq!(&thumbnail[16..24], &[0, 0, 0, 128, 0, 0, 0, 120]);
+        // signature, IHDR, one stored block in IDAT, IEND
+        assert_eq!(
+            thumbnail.len(),
+            8 + 25 + 12 + 2 + 5 + 120 * (128 * 3 + 1) + 4 + 12
+        );
+    }
+}
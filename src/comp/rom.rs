@@ -2,11 +2,30 @@ const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
 #[allow(non_camel_case_types)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
     FOUR_SCREEN,
+    /// every nametable shows the first 1KB of VRAM
+    SINGLE_SCREEN_A,
+    /// every nametable shows the second 1KB of VRAM
+    SINGLE_SCREEN_B,
+}
+
+impl Mirroring {
+    /// The 1KB bank of VRAM behind each of the nametables at $2000, $2400,
+    /// $2800 and $2C00. Banks 0 and 1 are the console's 2KB, 2 and 3 the
+    /// extra 2KB a four-screen cartridge brings.
+    pub fn banks(self) -> [usize; 4] {
+        match self {
+            Mirroring::HORIZONTAL => [0, 0, 1, 1],
+            Mirroring::VERTICAL => [0, 1, 0, 1],
+            Mirroring::FOUR_SCREEN => [0, 1, 2, 3],
+            Mirroring::SINGLE_SCREEN_A => [0; 4],
+            Mirroring::SINGLE_SCREEN_B => [1; 4],
+        }
+    }
 }
 pub struct Rom {
     pub prg_rom: Vec<u8>,
@@ -76,6 +95,14 @@ pub mod test {
         result
     }
 
+    /// `program` at $8000 in a 32KB PRG ROM whose reset vector points at it.
+    pub fn test_prg(program: &[u8]) -> Vec<u8> {
+        let mut prg = program.to_vec();
+        prg.resize(2 * PRG_ROM_PAGE_SIZE, 0);
+        prg[0x7ffc..0x7ffe].copy_from_slice(&[0x00, 0x80]);
+        prg
+    }
+
     pub fn test_rom(program: Vec<u8>) -> Rom {
         let mut pgp_rom_contents = program;
         pgp_rom_contents.resize(2 * PRG_ROM_PAGE_SIZE, 0);
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::comp::cpu::Mem;
+
+// Labels and source lines from assembler/debugger output files:
+//  - ca65/ld65 `--dbgfile` (.dbg): symbols, segments and line spans
+//  - FCEUX name lists (`game.nes.0.nl`, `game.nes.ram.nl`)
+//  - Mesen label files (.mlb)
+// ROM labels are keyed by PRG ROM offset wherever the file says which bank
+// they belong to, so they resolve correctly whatever is currently mapped in.
+
+/// Where a symbol lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Location {
+    /// a CPU address that isn't banked (RAM, registers) or whose bank is unknown
+    Cpu(u16),
+    /// an offset into PRG ROM
+    Prg(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: PathBuf,
+    pub line: usize,
+}
+
+#[derive(Default)]
+pub struct Symbols {
+    labels: HashMap<Location, String>,
+    by_name: HashMap<String, Location>,
+    lines: HashMap<Location, SourceLine>,
+}
+
+// FCEUX banks are 16KB regardless of mapper
+const NL_BANK_SIZE: usize = 0x4000;
+const INES_HEADER_SIZE: usize = 16;
+
+fn parse_int(text: &str) -> Option<usize> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix('$')) {
+        usize::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+/// Splits a .dbg record like `sym id=0,name="a,b",val=0x10` into key/value pairs.
+fn dbg_fields(text: &str) -> HashMap<&str, &str> {
+    let mut fields = HashMap::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let Some((key, after)) = rest.split_once('=') else {
+            break;
+        };
+        let (value, next) = if let Some(quoted) = after.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            let next = quoted[end..].trim_start_matches('"');
+            (&quoted[..end], next.strip_prefix(',').unwrap_or(next))
+        } else {
+            match after.split_once(',') {
+                Some((value, next)) => (value, next),
+                None => (after, ""),
+            }
+        };
+        fields.insert(key.trim(), value);
+        rest = next;
+    }
+    fields
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.lines.is_empty()
+    }
+
+    /// The first name given to a location wins, later aliases still resolve by name.
+    pub fn add_label(&mut self, location: Location, name: &str) {
+        if name.is_empty() {
+            return;
+        }
+        self.labels
+            .entry(location)
+            .or_insert_with(|| name.to_string());
+        self.by_name.insert(name.to_string(), location);
+    }
+
+    /// Loads every symbol file found next to `rom_path`:
+    /// `game.dbg`, `game.mlb`, `game.nes.ram.nl` and `game.nes.<bank>.nl`.
+    pub fn load_for_rom(rom_path: &Path) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        for ext in ["dbg", "mlb"] {
+            let path = rom_path.with_extension(ext);
+            if path.exists() {
+                symbols.load(&path)?;
+            }
+        }
+        let dir = match rom_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let rom_name = rom_path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if let Ok(entries) = std::fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with(&format!("{}.", rom_name)) && name.ends_with(".nl") {
+                    symbols.load(&entry.path())?;
+                }
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Loads one file, picking the format from its extension.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("dbg") => self.parse_dbg(&text, path.parent().unwrap_or(Path::new(""))),
+            Some("mlb") => self.parse_mlb(&text),
+            Some("nl") => {
+                // game.nes.ram.nl or game.nes.<bank>.nl
+                let bank = file_name
+                    .trim_end_matches(".nl")
+                    .rsplit('.')
+                    .next()
+                    .and_then(|b| b.parse::<usize>().ok());
+                self.parse_nl(&text, bank);
+            }
+            _ => return Err(format!("{}: unknown symbol file type", path.display())),
+        }
+        Ok(())
+    }
+
+    /// ca65/ld65 debug info, version 2. Source file names are resolved against `base_dir`.
+    pub fn parse_dbg(&mut self, text: &str, base_dir: &Path) {
+        let mut files: HashMap<usize, PathBuf> = HashMap::new();
+        // id -> (cpu start, PRG ROM offset of the segment start)
+        let mut segments: HashMap<usize, (usize, Option<usize>)> = HashMap::new();
+        // id -> (segment, offset in segment)
+        let mut spans: HashMap<usize, (usize, usize)> = HashMap::new();
+        let mut records = vec![];
+
+        for line in text.lines() {
+            let Some((kind, rest)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let fields = dbg_fields(rest.trim());
+            let id = fields.get("id").and_then(|v| parse_int(v));
+            match (kind, id) {
+                ("file", Some(id)) => {
+                    if let Some(name) = fields.get("name") {
+                        files.insert(id, base_dir.join(name));
+                    }
+                }
+                ("seg", Some(id)) => {
+                    let start = fields.get("start").and_then(|v| parse_int(v)).unwrap_or(0);
+                    let rom_offset = fields
+                        .get("ooffs")
+                        .and_then(|v| parse_int(v))
+                        .and_then(|o| o.checked_sub(INES_HEADER_SIZE));
+                    segments.insert(id, (start, rom_offset));
+                }
+                ("span", Some(id)) => {
+                    let seg = fields.get("seg").and_then(|v| parse_int(v));
+                    let start = fields.get("start").and_then(|v| parse_int(v));
+                    if let (Some(seg), Some(start)) = (seg, start) {
+                        spans.insert(id, (seg, start));
+                    }
+                }
+                ("sym", _) | ("line", _) => records.push((kind, fields)),
+                _ => {}
+            }
+        }
+
+        let locate = |seg: Option<usize>, addr: usize| -> Location {
+            match seg.and_then(|s| segments.get(&s)) {
+                Some(&(start, Some(rom_offset))) if addr >= start => {
+                    Location::Prg(rom_offset + addr - start)
+                }
+                _ => Location::Cpu(addr as u16),
+            }
+        };
+
+        for (kind, fields) in records {
+            if kind == "sym" {
+                // equates are constants, not addresses; cheap locals (@loop) are too noisy
+                let name = fields.get("name").copied().unwrap_or("");
+                if fields.get("type") != Some(&"lab") || name.starts_with('@') {
+                    continue;
+                }
+                let Some(val) = fields.get("val").and_then(|v| parse_int(v)) else {
+                    continue;
+                };
+                let seg = fields.get("seg").and_then(|v| parse_int(v));
+                self.add_label(locate(seg, val), name);
+            } else {
+                let file = fields
+                    .get("file")
+                    .and_then(|v| parse_int(v))
+                    .and_then(|f| files.get(&f));
+                let line = fields.get("line").and_then(|v| parse_int(v));
+                let (Some(file), Some(line)) = (file, line) else {
+                    continue;
+                };
+                // macro expansions (type=2) would point into the macro body
+                if fields.get("type") == Some(&"2") {
+                    continue;
+                }
+                for span in fields.get("span").copied().unwrap_or("").split('+') {
+                    let Some(&(seg, offset)) = parse_int(span).and_then(|s| spans.get(&s)) else {
+                        continue;
+                    };
+                    let start = segments.get(&seg).map_or(0, |s| s.0);
+                    self.lines.insert(
+                        locate(Some(seg), start + offset),
+                        SourceLine {
+                            file: file.clone(),
+                            line,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// FCEUX name list: `$C0F3#init_ppu#comment`. `bank` is the 16KB PRG bank
+    /// for `game.nes.<bank>.nl`, `None` for `game.nes.ram.nl`.
+    pub fn parse_nl(&mut self, text: &str, bank: Option<usize>) {
+        for line in text.lines() {
+            let mut parts = line.split('#');
+            let (Some(addr), Some(name)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            // `$0300/10` labels an array, the size doesn't matter here
+            let addr = addr.split('/').next().unwrap_or("");
+            let Some(addr) = parse_int(addr) else {
+                continue;
+            };
+            let location = match bank {
+                Some(bank) if addr >= 0x8000 => {
+                    Location::Prg(bank * NL_BANK_SIZE + (addr % NL_BANK_SIZE))
+                }
+                _ => Location::Cpu(addr as u16),
+            };
+            self.add_label(location, name.trim());
+        }
+    }
+
+    /// Mesen label file: `P:1F3:init_ppu:comment`, `R:0010:player_x`, ...
+    /// Both the Mesen 1 single letters and the Mesen 2 memory type names are accepted.
+    pub fn parse_mlb(&mut self, text: &str) {
+        for line in text.lines() {
+            let mut parts = line.splitn(4, ':');
+            let (Some(kind), Some(addr), Some(name)) = (parts.next(), parts.next(), parts.next())
+            else {
+                continue;
+            };
+            // ranges like `10-11` label their first byte
+            let start = addr.split('-').next().unwrap_or("");
+            let Ok(addr) = usize::from_str_radix(start, 16) else {
+                continue;
+            };
+            let location = match kind {
+                "P" | "NesPrgRom" => Location::Prg(addr),
+                "R" | "NesInternalRam" => Location::Cpu(addr as u16),
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => Location::Cpu(0x6000 + addr as u16),
+                "G" | "Register" | "NesMemory" => Location::Cpu(addr as u16),
+                _ => continue,
+            };
+            self.add_label(location, name.trim());
+        }
+    }
+
+    /// The banked location of `addr` given what `mem` currently maps there.
+    pub fn location<M: Mem>(mem: &M, addr: u16) -> Location {
+        match mem.prg_rom_offset(addr) {
+            Some(offset) => Location::Prg(offset),
+            None => Location::Cpu(addr),
+        }
+    }
+
+    pub fn label<M: Mem>(&self, mem: &M, addr: u16) -> Option<&str> {
+        self.labels
+            .get(&Symbols::location(mem, addr))
+            .or_else(|| self.labels.get(&Location::Cpu(addr)))
+            .map(String::as_str)
+    }
+
+    pub fn source_line<M: Mem>(&self, mem: &M, addr: u16) -> Option<&SourceLine> {
+        self.lines
+            .get(&Symbols::location(mem, addr))
+            .or_else(|| self.lines.get(&Location::Cpu(addr)))
+    }
+
+    pub fn label_at(&self, location: Location) -> Option<&str> {
+        self.labels.get(&location).map(String::as_str)
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<Location> {
+        self.by_name.get(name).copied()
+    }
+
+    /// True when `addr` is currently mapped to `location`.
+    pub fn matches<M: Mem>(mem: &M, addr: u16, location: Location) -> bool {
+        match location {
+            Location::Cpu(a) => a == addr,
+            Location::Prg(_) => Symbols::location(mem, addr) == location,
+        }
+    }
+
+    /// A CPU address `location` is visible at right now, if any.
+    pub fn cpu_address<M: Mem>(mem: &M, location: Location) -> Option<u16> {
+        match location {
+            Location::Cpu(addr) => Some(addr),
+            Location::Prg(_) => {
+                (0x8000..=0xffff).find(|&addr| Symbols::matches(mem, addr, location))
+            }
+        }
+    }
+}
+
+impl SourceLine {
+    /// `file:line: text`, the text only when the source file can be read.
+    pub fn describe(&self) -> String {
+        let text = std::fs::read_to_string(&self.file).ok().and_then(|src| {
+            src.lines()
+                .nth(self.line.saturating_sub(1))
+                .map(|l| l.trim().to_string())
+        });
+        match text {
+            Some(text) => format!("{}:{}: {}", self.file.display(), self.line, text),
+            None => format!("{}:{}", self.file.display(), self.line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::memory::FlatMemory;
+
+    // trimmed from `ld65 --dbgfile` output for a 16KB NROM build
+    const DBG: &str = r#"version	major=2,minor=0
+file	id=0,name="main.s",size=100,mtime=0x00000000,mod=0
+seg	id=0,name="CODE",start=0x00C000,size=0x0010,addrsize=absolute,type=ro,oname="game.nes",ooffs=16
+seg	id=1,name="ZEROPAGE",start=0x000000,size=0x0002,addrsize=zeropage,type=rw
+span	id=0,seg=0,start=0,size=3
+span	id=1,seg=0,start=3,size=1
+line	id=0,file=0,line=12,span=0
+line	id=1,file=0,line=13,span=1
+sym	id=0,name="init_ppu",addrsize=absolute,scope=0,def=1,val=0xC003,seg=0,type=lab
+sym	id=1,name="frame",addrsize=zeropage,scope=0,def=2,val=0x1,seg=1,type=lab
+sym	id=2,name="PPUCTRL",addrsize=absolute,scope=0,def=3,val=0x2000,type=equ
+sym	id=3,name="@loop",addrsize=absolute,scope=0,def=4,val=0xC000,seg=0,type=lab
+"#;
+
+    #[test]
+    fn test_parse_dbg() {
+        let mut symbols = Symbols::new();
+        symbols.parse_dbg(DBG, Path::new("src"));
+        assert_eq!(symbols.lookup("init_ppu"), Some(Location::Prg(3)));
+        assert_eq!(symbols.lookup("frame"), Some(Location::Cpu(1)));
+        assert_eq!(symbols.lookup("PPUCTRL"), None);
+        assert_eq!(symbols.lookup("@loop"), None);
+        assert_eq!(
+            symbols.lines.get(&Location::Prg(3)),
+            Some(&SourceLine {
+                file: PathBuf::from("src/main.s"),
+                line: 13,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_nl_and_mlb() {
+        let mut symbols = Symbols::new();
+        symbols.parse_nl("$C0F3#init_ppu#sets up the PPU\n$8000#reset#\n", Some(1));
+        symbols.parse_nl("$0300/10#buffer#\n", None);
+        symbols.parse_mlb("P:1F3:nmi:vblank handler\nR:0010:player_x\nG:2000:PPUCTRL\n");
+        assert_eq!(symbols.lookup("init_ppu"), Some(Location::Prg(0x40f3)));
+        assert_eq!(symbols.lookup("reset"), Some(Location::Prg(0x4000)));
+        assert_eq!(symbols.lookup("buffer"), Some(Location::Cpu(0x300)));
+        assert_eq!(symbols.lookup("nmi"), Some(Location::Prg(0x1f3)));
+        assert_eq!(symbols.lookup("player_x"), Some(Location::Cpu(0x10)));
+        assert_eq!(symbols.lookup("PPUCTRL"), Some(Location::Cpu(0x2000)));
+    }
+
+    #[test]
+    fn test_unbanked_memory_falls_back_to_cpu_labels() {
+        let mut symbols = Symbols::new();
+        symbols.add_label(Location::Cpu(0xc000), "start");
+        symbols.add_label(Location::Prg(0), "banked");
+        let mem = FlatMemory::new();
+        assert_eq!(symbols.label(&mem, 0xc000), Some("start"));
+        assert_eq!(Symbols::cpu_address(&mem, Location::Prg(0)), None);
+    }
+}
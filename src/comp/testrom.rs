@@ -0,0 +1,232 @@
+use crate::comp::bus::Bus;
+use crate::comp::cpu::Mem;
+use crate::comp::error::EmuError;
+use crate::comp::nes::Nes;
+
+// Runs a test ROM without a window and says whether it passed.
+//
+// Newer blargg ROMs report through cartridge RAM: once $6001-$6003 hold
+// DE B0 61, $6000 is the status ($80 still running, $81 press reset, below
+// $80 the result code, 0 meaning passed) and the text the ROM prints on
+// screen is also at $6004, NUL-terminated.
+//
+// Older ROMs only draw their result, so for those the last frame's checksum
+// is compared against one taken from a known-good run.
+
+const STATUS: u16 = 0x6000;
+const SIGNATURE: u16 = 0x6001;
+const MESSAGE: u16 = 0x6004;
+const SIGNATURE_BYTES: [u8; 3] = [0xde, 0xb0, 0x61];
+const RUNNING: u8 = 0x80;
+const NEEDS_RESET: u8 = 0x81;
+// the ROM wants the button pressed at least 100ms after asking
+const RESET_DELAY: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// result code from $6000
+    Failed(u8),
+    /// no $6000 result, but the last frame is the expected one
+    ScreenMatched,
+    ScreenMismatch {
+        expected: u64,
+    },
+    /// still running at the frame limit
+    TimedOut,
+    /// no $6000 result and no screen checksum to compare against
+    NoResult,
+    /// the CPU executed BRK
+    Halted,
+    /// the ROM did something the emulator stops on
+    Error(EmuError),
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub outcome: Outcome,
+    /// text at $6004, if the ROM uses the protocol
+    pub message: String,
+    pub frames: usize,
+    /// checksum of the last frame
+    pub screen: u64,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        matches!(self.outcome, Outcome::Passed | Outcome::ScreenMatched)
+    }
+
+    /// One line for the terminal, followed by the ROM's text if it has any.
+    pub fn summary(&self) -> String {
+        let result = match self.outcome {
+            Outcome::Passed => "passed".to_string(),
+            Outcome::Failed(code) => format!("failed with code {}", code),
+            Outcome::ScreenMatched => format!("passed (screen {:016x})", self.screen),
+            Outcome::ScreenMismatch { expected } => format!(
+                "failed (screen {:016x}, expected {:016x})",
+                self.screen, expected
+            ),
+            Outcome::TimedOut => "timed out".to_string(),
+            Outcome::NoResult => "no result at $6000".to_string(),
+            Outcome::Halted => "CPU halted".to_string(),
+            Outcome::Error(error) => format!("stopped by {}", error),
+        };
+        let mut out = format!("{} after {} frames", result, self.frames);
+        if self.outcome == Outcome::NoResult {
+            out.push_str(&format!(
+                "\nlast screen {:016x}, pass it to --expect-hash if it shows a pass",
+                self.screen
+            ));
+        }
+        let message = self.message.trim_end();
+        if !message.is_empty() {
+            out.push('\n');
+            out.push_str(message);
+        }
+        out
+    }
+}
+
+fn has_signature(bus: &Bus) -> bool {
+    (0..3).all(|i| bus.peek(SIGNATURE + i) == SIGNATURE_BYTES[i as usize])
+}
+
+fn message(bus: &Bus) -> String {
+    (MESSAGE..=0x7fff)
+        .map(|addr| bus.peek(addr))
+        .take_while(|&c| c != 0)
+        .map(char::from)
+        .collect()
+}
+
+/// Runs `nes` for up to `max_frames`, pressing reset whenever the ROM asks.
+/// `expected` is the last frame's checksum for ROMs without the $6000 protocol.
+pub fn run(nes: &mut Nes, max_frames: usize, expected: Option<u64>) -> Report {
+    let start = nes.frame_count();
+    let mut reset_at = None;
+    let mut outcome = None;
+    while outcome.is_none() && nes.frame_count() - start < max_frames {
+        let stepped = nes.step_frame().map(|_| ());
+        let frame = nes.frame_count() - start;
+        if let Err(error) = stepped {
+            outcome = Some(Outcome::Error(error));
+        } else if nes.halted() {
+            outcome = Some(Outcome::Halted);
+        } else if has_signature(nes.bus()) {
+            match nes.bus().peek(STATUS) {
+                RUNNING => {}
+                NEEDS_RESET => match reset_at {
+                    None => reset_at = Some(frame + RESET_DELAY),
+                    Some(at) if frame >= at => {
+                        nes.reset();
+                        reset_at = None;
+                    }
+                    Some(_) => {}
+                },
+                0 => outcome = Some(Outcome::Passed),
+                code => outcome = Some(Outcome::Failed(code)),
+            }
+        }
+    }
+
+    let bus = nes.bus();
+    let screen = nes.frame().checksum();
+    let outcome = outcome.unwrap_or(match expected {
+        _ if has_signature(bus) => Outcome::TimedOut,
+        Some(hash) if hash == screen => Outcome::ScreenMatched,
+        Some(hash) => Outcome::ScreenMismatch { expected: hash },
+        None => Outcome::NoResult,
+    });
+    Report {
+        outcome,
+        message: if has_signature(bus) {
+            message(bus)
+        } else {
+            String::new()
+        },
+        frames: nes.frame_count() - start,
+        screen,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::rom::test::{test_prg, test_rom};
+
+    fn nes(program: &[u8]) -> Nes<'static> {
+        Nes::new(test_rom(test_prg(program)))
+    }
+
+    // writes the signature, "ok" and then `code` as the status
+    fn reporting(code: u8) -> Vec<u8> {
+        vec![
+            0xa9, 0x80, 0x8d, 0x00, 0x60, // LDA #$80; STA $6000
+            0xa9, 0xde, 0x8d, 0x01, 0x60, // signature
+            0xa9, 0xb0, 0x8d, 0x02, 0x60, //
+            0xa9, 0x61, 0x8d, 0x03, 0x60, //
+            0xa9, b'o', 0x8d, 0x04, 0x60, // message
+            0xa9, b'k', 0x8d, 0x05, 0x60, //
+            0xa9, 0x00, 0x8d, 0x06, 0x60, //
+            0xa9, code, 0x8d, 0x00, 0x60, // LDA #code; STA $6000
+            0x4c, 0x28, 0x80, // JMP *
+        ]
+    }
+
+    #[test]
+    fn test_reads_result_from_cartridge_ram() {
+        let report = run(&mut nes(&reporting(0)), 60, None);
+        assert_eq!(report.outcome, Outcome::Passed);
+        assert_eq!(report.message, "ok");
+        assert_eq!(report.frames, 1);
+        assert!(report.passed());
+
+        let report = run(&mut nes(&reporting(3)), 60, None);
+        assert_eq!(report.outcome, Outcome::Failed(3));
+        assert_eq!(report.summary(), "failed with code 3 after 1 frames\nok");
+        assert!(!report.passed());
+    }
+
+    #[test]
+    fn test_presses_reset_when_asked() {
+        let program = [
+            0xa9, 0xde, 0x8d, 0x01, 0x60, // signature
+            0xa9, 0xb0, 0x8d, 0x02, 0x60, //
+            0xa9, 0x61, 0x8d, 0x03, 0x60, //
+            0xee, 0x10, 0x60, // INC $6010, survives the reset
+            0xad, 0x10, 0x60, // LDA $6010
+            0xc9, 0x02, // CMP #2
+            0xf0, 0x08, // BEQ done
+            0xa9, 0x81, 0x8d, 0x00, 0x60, // LDA #$81; STA $6000
+            0x4c, 0x1e, 0x80, // JMP *
+            0xa9, 0x00, 0x8d, 0x00, 0x60, // done: LDA #0; STA $6000
+            0x4c, 0x26, 0x80, // JMP *
+        ];
+        let report = run(&mut nes(&program), 60, None);
+        assert_eq!(report.outcome, Outcome::Passed);
+        assert!(report.frames > RESET_DELAY);
+    }
+
+    #[test]
+    fn test_falls_back_to_screen_checksum() {
+        let program = [0x4c, 0x00, 0x80]; // JMP *
+        let report = run(&mut nes(&program), 10, None);
+        assert_eq!(report.outcome, Outcome::NoResult);
+        assert_eq!(report.frames, 10);
+
+        let screen = report.screen;
+        let report = run(&mut nes(&program), 10, Some(screen));
+        assert_eq!(report.outcome, Outcome::ScreenMatched);
+        let report = run(&mut nes(&program), 10, Some(screen ^ 1));
+        assert_eq!(
+            report.outcome,
+            Outcome::ScreenMismatch {
+                expected: screen ^ 1
+            }
+        );
+
+        let report = run(&mut nes(&reporting(0x80)), 10, Some(screen));
+        assert_eq!(report.outcome, Outcome::TimedOut);
+    }
+}
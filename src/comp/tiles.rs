@@ -1,11 +1,12 @@
+#[cfg(feature = "sdl")]
 use std::env::args;
 
+#[cfg(feature = "sdl")]
 use sdl2::{event::Event, keyboard::Keycode, pixels::PixelFormatEnum};
 
-use crate::comp::{
-    render::{frame::Frame, palette},
-    rom::Rom,
-};
+use crate::comp::render::{frame::Frame, palette};
+#[cfg(feature = "sdl")]
+use crate::comp::rom::Rom;
 fn _show_tile(chr_rom: &Vec<u8>, bank: usize, tile_n: usize) -> Frame {
     assert!(bank <= 1);
 
@@ -33,7 +34,8 @@ fn _show_tile(chr_rom: &Vec<u8>, bank: usize, tile_n: usize) -> Frame {
     frame
 }
 
-fn show_tile_bank(chr_rom: &Vec<u8>, bank: usize) -> Frame {
+/// The first 255 tiles of a 4KB pattern table bank, 20 per row.
+pub fn show_tile_bank(chr_rom: &[u8], bank: usize) -> Frame {
     assert!(bank <= 1);
     let mut frame = Frame::new();
     let mut tile_y = 0;
@@ -71,6 +73,7 @@ fn show_tile_bank(chr_rom: &Vec<u8>, bank: usize) -> Frame {
     frame
 }
 
+#[cfg(feature = "sdl")]
 pub fn tile() {
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
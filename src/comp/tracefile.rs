@@ -0,0 +1,567 @@
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use bitflags::bitflags;
+
+use crate::comp::bus::Bus;
+use crate::comp::cpu::{CPU, Mem};
+use crate::comp::opcodes;
+
+// Binary instruction traces for long sessions, and diffing them against each
+// other or against text logs from other emulators.
+//
+// File layout: the 8 byte magic, one byte of `Fields`, then fixed 23 byte
+// little-endian records:
+//   pc:2 len:1 bytes:3 a x y p sp scanline:2 dot:2 cycle:8
+//
+// Text import understands our own and Nintendulator's nestest format, Mesen's
+// and FCEUX's trace loggers: the PC with its opcode bytes first (or FCEUX's
+// `$C000:4C F5 C5`), then `KEY:value` registers and counters in any order.
+
+pub const MAGIC: &[u8; 8] = b"RNESTRC1";
+const RECORD_SIZE: usize = 23;
+/// how far into either trace to look for the other one's first instruction
+const ALIGN_WINDOW: usize = 100_000;
+/// B and the unused bit only exist on the stack, emulators log them differently
+const STATUS_MASK: u8 = 0b1100_1111;
+
+bitflags! {
+    /// What a trace has recorded besides the PC. Imported logs often lack some.
+    pub struct Fields: u8 {
+        const BYTES     = 0b0001;
+        const REGISTERS = 0b0010;
+        const CYCLES    = 0b0100;
+        const PPU       = 0b1000;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Record {
+    pub pc: u16,
+    pub len: u8,
+    pub bytes: [u8; 3],
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    pub scanline: u16,
+    pub dot: u16,
+    pub cycle: u64,
+}
+
+impl Record {
+    /// The state before the instruction at `cpu.pc` runs, as `trace_nes` prints it.
+    pub fn capture(cpu: &CPU<Bus>) -> Record {
+        let op = opcodes::lookup(cpu.peek(cpu.pc));
+        let mut bytes = [0; 3];
+        for (i, byte) in bytes.iter_mut().enumerate().take(op.size() as usize) {
+            *byte = cpu.peek(cpu.pc.wrapping_add(i as u16));
+        }
+        Record {
+            pc: cpu.pc,
+            len: op.size(),
+            bytes,
+            a: cpu.reg_a,
+            x: cpu.reg_x,
+            y: cpu.reg_y,
+            p: cpu.status.bits(),
+            sp: cpu.stk_ptr,
+            scanline: cpu.bus.ppu().scanline(),
+            dot: cpu.bus.ppu().dot() as u16,
+            cycle: cpu.bus.cycles() as u64,
+        }
+    }
+
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut out = [0; RECORD_SIZE];
+        out[0..2].copy_from_slice(&self.pc.to_le_bytes());
+        out[2] = self.len;
+        out[3..6].copy_from_slice(&self.bytes);
+        out[6..11].copy_from_slice(&[self.a, self.x, self.y, self.p, self.sp]);
+        out[11..13].copy_from_slice(&self.scanline.to_le_bytes());
+        out[13..15].copy_from_slice(&self.dot.to_le_bytes());
+        out[15..23].copy_from_slice(&self.cycle.to_le_bytes());
+        out
+    }
+
+    fn decode(raw: &[u8]) -> Record {
+        Record {
+            pc: u16::from_le_bytes([raw[0], raw[1]]),
+            len: raw[2],
+            bytes: [raw[3], raw[4], raw[5]],
+            a: raw[6],
+            x: raw[7],
+            y: raw[8],
+            p: raw[9],
+            sp: raw[10],
+            scanline: u16::from_le_bytes([raw[11], raw[12]]),
+            dot: u16::from_le_bytes([raw[13], raw[14]]),
+            cycle: u64::from_le_bytes(raw[15..23].try_into().unwrap()),
+        }
+    }
+
+    /// nestest-style line with the fields `fields` says are known.
+    pub fn format(&self, fields: Fields) -> String {
+        let mut out = format!("{:04X} ", self.pc);
+        if fields.contains(Fields::BYTES) {
+            let bytes: Vec<String> = self.bytes[..(self.len as usize).min(3)]
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect();
+            out.push_str(&format!(" {:8}", bytes.join(" ")));
+        }
+        if fields.contains(Fields::REGISTERS) {
+            out.push_str(&format!(
+                " A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+                self.a, self.x, self.y, self.p, self.sp
+            ));
+        }
+        if fields.contains(Fields::PPU) {
+            out.push_str(&format!(" PPU:{:>3},{:>3}", self.scanline, self.dot));
+        }
+        if fields.contains(Fields::CYCLES) {
+            out.push_str(&format!(" CYC:{}", self.cycle));
+        }
+        out.trim_end().to_string()
+    }
+}
+
+pub struct TraceWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Starts a trace of everything `Record::capture` sees.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[Fields::all().bits()])?;
+        Ok(TraceWriter { out })
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        self.out.write_all(&record.encode())
+    }
+
+    pub fn record(&mut self, cpu: &CPU<Bus>) -> io::Result<()> {
+        self.write(&Record::capture(cpu))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+pub struct Trace {
+    pub fields: Fields,
+    pub records: Vec<Record>,
+}
+
+/// The text right after `key` where it starts a whitespace-separated token.
+fn after_key<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    line.match_indices(key)
+        .find(|(i, _)| *i == 0 || line[..*i].ends_with(char::is_whitespace))
+        .map(|(i, _)| line[i + key.len()..].trim_start())
+}
+
+fn value<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    after_key(line, key).and_then(|rest| rest.split_whitespace().next())
+}
+
+fn hex_u8(text: &str) -> Option<u8> {
+    u8::from_str_radix(text.trim_start_matches('$'), 16).ok()
+}
+
+fn decimal<T: std::str::FromStr>(text: &str) -> Option<T> {
+    text.trim_end_matches(',').parse().ok()
+}
+
+/// `24` or Mesen/FCEUX's `nvUbdIzc`, where capitals are set flags.
+fn status(text: &str) -> Option<u8> {
+    if text.len() == 8 && text.chars().all(|c| c.is_ascii_alphabetic()) {
+        Some(
+            text.chars()
+                .fold(0, |p, c| p << 1 | c.is_ascii_uppercase() as u8),
+        )
+    } else {
+        hex_u8(text)
+    }
+}
+
+fn is_hex_pair(token: &str) -> bool {
+    token.len() == 2 && token.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// One instruction from another emulator's text log, with what it recorded.
+pub fn parse_line(line: &str) -> Option<(Record, Fields)> {
+    let mut record = Record::default();
+    let mut fields = Fields::empty();
+
+    // FCEUX puts counters first and the instruction as `$C000:4C F5 C5`
+    let fceux = line
+        .match_indices('$')
+        .find(|(i, _)| {
+            line.get(i + 5..i + 6) == Some(":")
+                && line[i + 1..i + 5].chars().all(|c| c.is_ascii_hexdigit())
+        })
+        .map(|(i, _)| i);
+    let (pc_text, rest) = match fceux {
+        Some(i) => (&line[i + 1..i + 5], &line[i + 6..]),
+        None => {
+            let mut parts = line.trim_start().splitn(2, char::is_whitespace);
+            let pc = parts.next()?.trim_start_matches('$');
+            (pc, parts.next().unwrap_or(""))
+        }
+    };
+    if pc_text.len() != 4 {
+        return None;
+    }
+    record.pc = u16::from_str_radix(pc_text, 16).ok()?;
+
+    let opcode_bytes: Vec<u8> = rest
+        .split_whitespace()
+        .map(|token| token.trim_start_matches('$'))
+        .take_while(|token| is_hex_pair(token))
+        .take(3)
+        .filter_map(hex_u8)
+        .collect();
+    if let Some(&opcode) = opcode_bytes.first() {
+        record.len = opcodes::lookup(opcode).size();
+        record.bytes[..opcode_bytes.len()].copy_from_slice(&opcode_bytes);
+        fields |= Fields::BYTES;
+    }
+
+    let registers = (
+        value(line, "A:").and_then(hex_u8),
+        value(line, "X:").and_then(hex_u8),
+        value(line, "Y:").and_then(hex_u8),
+        value(line, "P:").and_then(status),
+        value(line, "SP:")
+            .or_else(|| value(line, "S:"))
+            .and_then(hex_u8),
+    );
+    if let (Some(a), Some(x), Some(y), Some(p), Some(sp)) = registers {
+        (record.a, record.x, record.y, record.p, record.sp) = (a, x, y, p, sp);
+        fields |= Fields::REGISTERS;
+    }
+
+    // Mesen 1 writes the PPU dot as `CYC:` next to `SL:`, nestest means CPU cycles
+    let scanline = value(line, "SL:").or_else(|| value(line, "V:"));
+    let (ppu, cycle) = match scanline {
+        Some(scanline) => {
+            let dot = value(line, "H:").or_else(|| value(line, "CYC:"));
+            let cycle = value(line, "Cycle:").or_else(|| value(line, "Cyc:"));
+            (Some((scanline, dot)), cycle)
+        }
+        None => {
+            let ppu = after_key(line, "PPU:").and_then(|rest| {
+                let (scanline, rest) = rest.split_once(',')?;
+                Some((scanline.trim(), rest.split_whitespace().next()))
+            });
+            let fceux_cycle = line
+                .split_whitespace()
+                .find(|t| {
+                    t.len() > 1 && t.starts_with('c') && t[1..].chars().all(|c| c.is_ascii_digit())
+                })
+                .map(|t| &t[1..]);
+            let cycle = value(line, "CYC:")
+                .or_else(|| value(line, "Cycle:"))
+                .or(fceux_cycle);
+            (ppu, cycle)
+        }
+    };
+    if let Some((Some(scanline), Some(dot))) = ppu.map(|(s, d)| (decimal(s), d.and_then(decimal))) {
+        record.scanline = scanline;
+        record.dot = dot;
+        fields |= Fields::PPU;
+    }
+    if let Some(cycle) = cycle.and_then(decimal) {
+        record.cycle = cycle;
+        fields |= Fields::CYCLES;
+    }
+    Some((record, fields))
+}
+
+impl Trace {
+    pub fn read_binary(raw: &[u8]) -> Result<Trace, String> {
+        if raw.len() < MAGIC.len() + 1 || &raw[..MAGIC.len()] != MAGIC {
+            return Err("not an rnes binary trace".to_string());
+        }
+        let fields = Fields::from_bits_truncate(raw[MAGIC.len()]);
+        let body = &raw[MAGIC.len() + 1..];
+        // a session killed mid-write leaves a partial last record
+        let records = body.chunks_exact(RECORD_SIZE).map(Record::decode).collect();
+        Ok(Trace { fields, records })
+    }
+
+    /// Imports a text log. Lines that don't look like an instruction
+    /// (headers, interrupt markers) are skipped; fields missing from any line
+    /// are treated as unknown for the whole trace.
+    pub fn parse_text<R: BufRead>(text: R) -> Result<Trace, String> {
+        let mut fields = Fields::all();
+        let mut records = vec![];
+        for line in text.lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            if let Some((record, line_fields)) = parse_line(&line) {
+                fields &= line_fields;
+                records.push(record);
+            }
+        }
+        if records.is_empty() {
+            return Err("no instructions found in the log".to_string());
+        }
+        Ok(Trace { fields, records })
+    }
+
+    /// Reads a binary trace or imports a text log, whichever `path` is.
+    pub fn load(path: &Path) -> Result<Trace, String> {
+        let raw = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if raw.starts_with(MAGIC) {
+            Trace::read_binary(&raw)
+        } else {
+            Trace::parse_text(&raw[..])
+        }
+        .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+pub struct TraceDivergence {
+    /// 0-based record indices of the divergence in each trace
+    pub a_index: usize,
+    pub b_index: usize,
+    /// the last matching records of trace A
+    pub context: Vec<Record>,
+    /// `None` when that trace ended first
+    pub a: Option<Record>,
+    pub b: Option<Record>,
+    pub differences: Vec<&'static str>,
+    pub fields: Fields,
+}
+
+impl TraceDivergence {
+    pub fn format(&self) -> String {
+        let mut out = format!(
+            "traces diverge at record {} of A / {} of B ({})\n",
+            self.a_index,
+            self.b_index,
+            self.differences.join(", ")
+        );
+        for record in &self.context {
+            out.push_str(&format!("  {}\n", record.format(self.fields)));
+        }
+        let show = |record: &Option<Record>| match record {
+            Some(record) => record.format(self.fields),
+            None => "<end of trace>".to_string(),
+        };
+        out.push_str(&format!("- {}\n", show(&self.a)));
+        out.push_str(&format!("+ {}\n", show(&self.b)));
+        out
+    }
+}
+
+fn same_state(a: &Record, b: &Record, fields: Fields) -> bool {
+    a.pc == b.pc
+        && (!fields.contains(Fields::REGISTERS)
+            || (a.a, a.x, a.y, a.p & STATUS_MASK, a.sp) == (b.a, b.x, b.y, b.p & STATUS_MASK, b.sp))
+}
+
+/// Where the two traces first show the same instruction and registers:
+/// one of them usually starts logging later than the other.
+fn align(a: &Trace, b: &Trace, fields: Fields) -> Option<(usize, usize)> {
+    let first_a = a.records.first()?;
+    let first_b = b.records.first()?;
+    let in_b = b
+        .records
+        .iter()
+        .take(ALIGN_WINDOW)
+        .position(|r| same_state(first_a, r, fields));
+    let in_a = a
+        .records
+        .iter()
+        .take(ALIGN_WINDOW)
+        .position(|r| same_state(r, first_b, fields));
+    match (in_a, in_b) {
+        (Some(i), Some(j)) if i <= j => Some((i, 0)),
+        (_, Some(j)) => Some((0, j)),
+        (Some(i), None) => Some((i, 0)),
+        (None, None) => None,
+    }
+}
+
+/// Compares `a` and `b` record by record after aligning their starts, on the
+/// fields both recorded minus `ignore`. Cycle counts only need to advance in
+/// step, since emulators count from different points. Returns the number of
+/// matching records, or the first divergence with `context` records before it.
+pub fn diff(
+    a: &Trace,
+    b: &Trace,
+    ignore: Fields,
+    context: usize,
+) -> Result<usize, Box<TraceDivergence>> {
+    let fields = a.fields & b.fields & !ignore;
+    let diverged = |i: usize, j: usize, differences: Vec<&'static str>| {
+        Box::new(TraceDivergence {
+            a_index: i,
+            b_index: j,
+            context: a.records[i.saturating_sub(context)..i.min(a.records.len())].to_vec(),
+            a: a.records.get(i).copied(),
+            b: b.records.get(j).copied(),
+            differences,
+            fields,
+        })
+    };
+    let Some((start_a, start_b)) = align(a, b, fields) else {
+        return Err(diverged(0, 0, vec!["no common starting point"]));
+    };
+    let cycle_offset = b.records[start_b]
+        .cycle
+        .wrapping_sub(a.records[start_a].cycle);
+
+    let mut matched = 0;
+    let (mut i, mut j) = (start_a, start_b);
+    loop {
+        let (ra, rb) = match (a.records.get(i), b.records.get(j)) {
+            (None, None) => return Ok(matched),
+            (None, Some(_)) | (Some(_), None) => return Err(diverged(i, j, vec!["length"])),
+            (Some(ra), Some(rb)) => (ra, rb),
+        };
+        let mut differences = vec![];
+        if ra.pc != rb.pc {
+            differences.push("pc");
+        }
+        let common_bytes = ra.len.min(rb.len).min(3) as usize;
+        if fields.contains(Fields::BYTES) && ra.bytes[..common_bytes] != rb.bytes[..common_bytes] {
+            differences.push("opcode");
+        }
+        if fields.contains(Fields::REGISTERS) {
+            for (name, x, y) in [
+                ("a", ra.a, rb.a),
+                ("x", ra.x, rb.x),
+                ("y", ra.y, rb.y),
+                ("p", ra.p & STATUS_MASK, rb.p & STATUS_MASK),
+                ("sp", ra.sp, rb.sp),
+            ] {
+                if x != y {
+                    differences.push(name);
+                }
+            }
+        }
+        if fields.contains(Fields::PPU) && (ra.scanline, ra.dot) != (rb.scanline, rb.dot) {
+            differences.push("ppu");
+        }
+        if fields.contains(Fields::CYCLES) && ra.cycle.wrapping_add(cycle_offset) != rb.cycle {
+            differences.push("cycles");
+        }
+        if !differences.is_empty() {
+            return Err(diverged(i, j, differences));
+        }
+        matched += 1;
+        i += 1;
+        j += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comp::rom::test::{test_prg, test_rom};
+
+    fn trace_of(program: &[u8], steps: usize) -> Trace {
+        let mut cpu = CPU::new(Bus::new(test_rom(test_prg(program)), |_, _| {}));
+        cpu.reset();
+        let mut writer = TraceWriter::new(vec![]).unwrap();
+        for _ in 0..steps {
+            writer.record(&cpu).unwrap();
+            cpu.step();
+        }
+        Trace::read_binary(&writer.out).unwrap()
+    }
+
+    // LDX #$03; loop: DEX; BNE loop; LDA #$42; INX
+    const PROGRAM: [u8; 8] = [0xa2, 0x03, 0xca, 0xd0, 0xfd, 0xa9, 0x42, 0xe8];
+
+    #[test]
+    fn test_binary_round_trip() {
+        let trace = trace_of(&PROGRAM, 4);
+        assert_eq!(trace.fields, Fields::all());
+        assert_eq!(trace.records.len(), 4);
+        let bne = trace.records[2];
+        assert_eq!((bne.pc, bne.len, bne.bytes), (0x8003, 2, [0xd0, 0xfd, 0]));
+        assert_eq!((bne.x, bne.p, bne.sp), (2, 0x20, 0xfd));
+        assert_eq!(bne.cycle, 4);
+        assert_eq!(
+            bne.format(Fields::all()),
+            "8003  D0 FD    A:00 X:02 Y:00 P:20 SP:FD PPU:  0, 12 CYC:4"
+        );
+    }
+
+    #[test]
+    fn test_imports_other_emulators() {
+        let nestest = "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7";
+        let (record, fields) = parse_line(nestest).unwrap();
+        assert_eq!(fields, Fields::all());
+        assert_eq!(
+            (record.pc, record.bytes, record.p, record.sp),
+            (0xc000, [0x4c, 0xf5, 0xc5], 0x24, 0xfd)
+        );
+        assert_eq!((record.scanline, record.dot, record.cycle), (0, 21, 7));
+
+        let mesen = "8000  78        SEI                A:00 X:00 Y:00 P:24 SP:FD CYC:  0 SL:241 CPU Cycle:7";
+        let (record, fields) = parse_line(mesen).unwrap();
+        assert_eq!(fields, Fields::all());
+        assert_eq!(
+            (
+                record.pc,
+                record.len,
+                record.scanline,
+                record.dot,
+                record.cycle
+            ),
+            (0x8000, 1, 241, 0, 7)
+        );
+
+        let fceux =
+            "f1     c14725   i4012    A:00 X:05 Y:00 S:FD P:nvUbdIzc  $C000:4C F5 C5  JMP $C5F5";
+        let (record, fields) = parse_line(fceux).unwrap();
+        assert_eq!(fields, Fields::BYTES | Fields::REGISTERS | Fields::CYCLES);
+        assert_eq!(
+            (record.pc, record.x, record.p, record.cycle),
+            (0xc000, 5, 0x24, 14725)
+        );
+
+        assert!(parse_line("NMI").is_none());
+    }
+
+    #[test]
+    fn test_diff_aligns_and_finds_divergence() {
+        let ours = trace_of(&PROGRAM, 9);
+        // another emulator that started logging two instructions later, with
+        // its own cycle origin, PPU position unknown and B set in P
+        let log: Vec<String> = ours.records[2..]
+            .iter()
+            .map(|r| {
+                let r = Record {
+                    cycle: r.cycle + 1000,
+                    p: r.p | 0x10,
+                    ..*r
+                };
+                r.format(Fields::BYTES | Fields::REGISTERS | Fields::CYCLES)
+            })
+            .collect();
+        let theirs = Trace::parse_text(log.join("\n").as_bytes()).unwrap();
+        assert_eq!(diff(&ours, &theirs, Fields::empty(), 3).ok(), Some(7));
+
+        let mut broken = log.clone();
+        broken[4] = broken[4].replace("X:00", "X:01");
+        let theirs = Trace::parse_text(broken.join("\n").as_bytes()).unwrap();
+        let divergence = diff(&ours, &theirs, Fields::empty(), 3).err().unwrap();
+        assert_eq!((divergence.a_index, divergence.b_index), (6, 4));
+        assert_eq!(divergence.differences, vec!["x"]);
+        assert_eq!(divergence.context.len(), 3);
+        assert!(
+            divergence.format().contains("+ 8003  D0 FD    A:00 X:01"),
+            "{}",
+            divergence.format()
+        );
+    }
+}
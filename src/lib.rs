@@ -0,0 +1,19 @@
+//! rnes as a library: the emulator core without any frontend.
+//!
+//! [`Nes`] is the simplest way in: it owns the whole console and runs it an
+//! instruction, a frame or a number of cycles at a time.
+//!
+//! The types below are the stable entry points for embedding the emulator;
+//! everything else is reachable through [`comp`] but may change between versions.
+//! SDL2 is only needed for the `rnes` binary and sits behind the `sdl` feature,
+//! so depend on this crate with `default-features = false` to leave it out.
+
+pub mod comp;
+
+pub use comp::bus::Bus;
+pub use comp::controller::{Controller, ControllerButtons};
+pub use comp::cpu::{CPU, Mem};
+pub use comp::nes::Nes;
+pub use comp::ppu::NesPPU;
+pub use comp::render::frame::Frame;
+pub use comp::rom::{Mirroring, Rom};
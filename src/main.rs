@@ -1,108 +1,311 @@
-use std::collections::HashMap;
 use std::env::args;
+use std::path::Path;
 
-use comp::bus::Bus;
 // use comp::cpu::Mem;
-use comp::controller::{Controller, ControllerButtons};
-use comp::cpu::CPU;
-use comp::rom::Rom;
-use comp::tiles::tile;
-// use comp::trace::*;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::PixelFormatEnum;
+use rnes::comp::compat;
+use rnes::comp::cpu::CpuVariant;
+use rnes::comp::disasm;
+use rnes::comp::error::ErrorPolicy;
+use rnes::comp::headless::{self, InputScript};
+use rnes::comp::log::Levels;
+use rnes::comp::nestest;
+use rnes::comp::processor_tests;
+use rnes::comp::render::image;
+use rnes::comp::testrom;
+#[cfg(feature = "sdl")]
+use rnes::comp::tiles::tile;
+use rnes::comp::trace::trace_nes;
+use rnes::comp::tracefile::{self, Fields, Trace};
+use rnes::{Nes, Rom};
 
-use crate::comp::ppu::NesPPU;
-use crate::comp::render;
-use crate::comp::render::frame::Frame;
+#[cfg(feature = "sdl")]
+mod window;
+
+#[cfg(not(feature = "sdl"))]
+fn without_sdl() -> ! {
+    eprintln!("this rnes was built without the `sdl` feature, only headless modes are available");
+    std::process::exit(2);
+}
 
-pub mod comp;
 fn main() {
-    let args: Vec<String> = args().collect();
+    let mut args: Vec<String> = args().collect();
+    // `--on-error class=action,...` (see ErrorPolicy::parse) for every mode that plays a ROM
+    let policy = match args.iter().position(|arg| arg == "--on-error") {
+        Some(at) => {
+            let rules = args
+                .get(at + 1)
+                .expect("usage: --on-error class=ignore|log|halt,...");
+            let policy = ErrorPolicy::parse(rules).unwrap_or_else(|e| {
+                eprintln!("--on-error: {}", e);
+                std::process::exit(2);
+            });
+            args.drain(at..at + 2);
+            policy
+        }
+        None => ErrorPolicy::default(),
+    };
+    // `--log category=level,...` (see Levels::parse), warnings by default
+    let levels = match args.iter().position(|arg| arg == "--log") {
+        Some(at) => {
+            let rules = args
+                .get(at + 1)
+                .expect("usage: --log category=off|error|warn|info|debug|trace,...");
+            let levels = Levels::default().parse(rules).unwrap_or_else(|e| {
+                eprintln!("--log: {}", e);
+                std::process::exit(2);
+            });
+            args.drain(at..at + 2);
+            levels
+        }
+        None => Levels::default(),
+    };
     if args.get(1).unwrap() == "--tiles" {
+        #[cfg(feature = "sdl")]
         tile();
-    } else {
-        // init sdl2
-
-        //
-        let sdl_context = sdl2::init().unwrap();
-        let video_subsystem = sdl_context.video().unwrap();
-        let window = video_subsystem
-            .window("Rnes", (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
-            .position_centered()
-            .build()
-            .unwrap();
-
-        let mut canvas = window.into_canvas().present_vsync().build().unwrap();
-        let mut event_pump = sdl_context.event_pump().unwrap();
-        canvas.set_scale(5.0, 5.0).unwrap();
-
-        let creator = canvas.texture_creator();
-        let mut texture = creator
-            .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
-            .unwrap();
-
-        let mut frame = Frame::new();
-
-        let mut key_map = HashMap::new();
-        key_map.insert(Keycode::Down, ControllerButtons::DOWN);
-        key_map.insert(Keycode::Up, ControllerButtons::UP);
-        key_map.insert(Keycode::Right, ControllerButtons::RIGHT);
-        key_map.insert(Keycode::Left, ControllerButtons::LEFT);
-        key_map.insert(Keycode::Space, ControllerButtons::SELECT);
-        key_map.insert(Keycode::Return, ControllerButtons::START);
-        key_map.insert(Keycode::C, ControllerButtons::BUTTON_A);
-        key_map.insert(Keycode::X, ControllerButtons::BUTTON_B);
-
-        let rom_name = args.get(1).unwrap();
+        #[cfg(not(feature = "sdl"))]
+        without_sdl();
+    } else if args.get(1).unwrap() == "--processor-tests" {
+        let dir = args.get(2).expect("usage: rnes --processor-tests <dir>");
+        match processor_tests::run_suite(std::path::Path::new(dir), CpuVariant::Ricoh2A03) {
+            Ok(reports) => print!("{}", processor_tests::format_report(&reports)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if args.get(1).unwrap() == "--nestest" {
+        let rom_name = args
+            .get(2)
+            .expect("usage: rnes --nestest <nestest.nes> [nestest.log]");
         let bytes: Vec<u8> = std::fs::read(rom_name).unwrap();
         let rom = Rom::new(&bytes).unwrap();
-
-        let bus = Bus::new(rom, move |ppu: &NesPPU, cont: &mut Controller| {
-            render::render(ppu, &mut frame);
-            texture
-                .update(None, &frame.data, 256 * 3)
-                .expect("Problem here");
-            canvas.copy(&texture, None, None).unwrap();
-            canvas.present();
-            for event in event_pump.poll_iter() {
-                match event {
-                    Event::Quit { .. }
-                    | Event::KeyDown {
-                        keycode: Some(Keycode::Escape),
-                        ..
-                    } => std::process::exit(0),
-                    Event::KeyDown {
-                        keycode: Some(Keycode::Q),
-                        ..
-                    } => std::process::exit(0),
-                    Event::KeyDown { keycode, .. } => {
-                        if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                            cont.set_button_pressed_status(*key, true);
-                        }
+        let mut cpu = nestest::nestest_cpu(rom);
+        match args.get(3) {
+            Some(log_name) => {
+                let log = std::fs::File::open(log_name).unwrap();
+                match nestest::compare(&mut cpu, std::io::BufReader::new(log)) {
+                    Ok(lines) => println!("nestest: all {} lines match", lines),
+                    Err(divergence) => {
+                        print!("nestest: {}", divergence.format());
+                        std::process::exit(1);
                     }
-                    Event::KeyUp { keycode, .. } => {
-                        if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                            cont.set_button_pressed_status(*key, false);
-                        }
+                }
+            }
+            None => {
+                if let Err(error) =
+                    cpu.run_with_callback(|cpu| println!("{}", trace_nes(cpu, None)))
+                {
+                    eprintln!("stopped: {}", error);
+                    std::process::exit(1);
+                }
+            }
+        }
+    } else if args.get(1).unwrap() == "trace-diff" {
+        let usage =
+            "usage: rnes trace-diff <a> <b> [--context N] [--ignore bytes,registers,cycles,ppu]";
+        let (a, b) = match (args.get(2), args.get(3)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => panic!("{}", usage),
+        };
+        let mut context = 8;
+        let mut ignore = Fields::empty();
+        let mut options = args[4..].iter();
+        while let Some(option) = options.next() {
+            let value = options.next().expect(usage);
+            match option.as_str() {
+                "--context" => context = value.parse().expect(usage),
+                "--ignore" => {
+                    for field in value.split(',') {
+                        ignore |= match field {
+                            "bytes" => Fields::BYTES,
+                            "registers" => Fields::REGISTERS,
+                            "cycles" => Fields::CYCLES,
+                            "ppu" => Fields::PPU,
+                            _ => panic!("{}", usage),
+                        };
                     }
-                    _ => { /* do nothing */ }
                 }
+                _ => panic!("{}", usage),
+            }
+        }
+        let load = |path: &String| {
+            Trace::load(std::path::Path::new(path)).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            })
+        };
+        match tracefile::diff(&load(a), &load(b), ignore, context) {
+            Ok(matched) => println!("traces match ({} records)", matched),
+            Err(divergence) => {
+                print!("{}", divergence.format());
+                std::process::exit(1);
+            }
+        }
+    } else if args.get(1).unwrap() == "disasm" {
+        let rom_name = args.get(2).expect("usage: rnes disasm <rom.nes> [out.s]");
+        let out = match args.get(3) {
+            Some(out) => std::path::PathBuf::from(out),
+            None => std::path::Path::new(rom_name).with_extension("s"),
+        };
+        let bytes: Vec<u8> = std::fs::read(rom_name).unwrap();
+        let name = std::path::Path::new(rom_name)
+            .file_name()
+            .unwrap()
+            .to_string_lossy();
+        // a log recorded with `--cdl` marks the code the static analysis can't reach
+        let cdl = std::fs::read(std::path::Path::new(rom_name).with_extension("cdl")).ok();
+        match disasm::disassemble(&bytes, &name, cdl.as_deref()) {
+            Ok(disassembly) => {
+                std::fs::write(&out, disassembly.source).unwrap();
+                std::fs::write(out.with_extension("cfg"), disassembly.config).unwrap();
+                println!(
+                    "wrote {} and {}",
+                    out.display(),
+                    out.with_extension("cfg").display()
+                );
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if args.get(1).unwrap() == "test-rom" {
+        let usage = "usage: rnes test-rom [--frames N] [--expect-hash HEX] <rom>";
+        // a minute of emulated time covers the slowest blargg ROMs
+        let mut frames = 3600;
+        let mut expected = None;
+        let mut rom_name = None;
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            let mut value = || options.next().expect(usage);
+            match option.as_str() {
+                "--frames" => frames = value().parse().expect(usage),
+                "--expect-hash" => {
+                    let hash = value().trim_start_matches("0x");
+                    expected = Some(u64::from_str_radix(hash, 16).expect(usage));
+                }
+                rom if !rom.starts_with("--") && rom_name.is_none() => rom_name = Some(rom),
+                _ => panic!("{}", usage),
+            }
+        }
+        let bytes: Vec<u8> = std::fs::read(rom_name.expect(usage)).unwrap();
+        let mut nes = Nes::new(Rom::new(&bytes).unwrap());
+        nes.bus_mut().set_error_policy(policy);
+        nes.bus().logger().set_levels(levels);
+        let report = testrom::run(&mut nes, frames, expected);
+        println!("{}", report.summary());
+        if !report.passed() {
+            std::process::exit(1);
+        }
+    } else if args.get(1).unwrap() == "compat" {
+        let usage =
+            "usage: rnes compat [--frames N] [--input script.txt] [--out report-dir] <rom-dir>";
+        let mut frames = 600;
+        let mut script = InputScript::default();
+        let mut out = None;
+        let mut dir = None;
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            let mut value = || options.next().expect(usage);
+            match option.as_str() {
+                "--frames" => frames = value().parse().expect(usage),
+                "--input" => {
+                    script = InputScript::load(Path::new(value())).unwrap_or_else(|e| {
+                        eprintln!("{}", e);
+                        std::process::exit(2);
+                    })
+                }
+                "--out" => out = Some(value()),
+                path if !path.starts_with("--") && dir.is_none() => dir = Some(path),
+                _ => panic!("{}", usage),
             }
+        }
+        let dir = Path::new(dir.expect(usage));
+        let out = out.map_or(dir.join("compat"), std::path::PathBuf::from);
+        let entries = compat::survey(dir, frames, &script, &out).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(2);
         });
-        let mut cpu = CPU::new(bus);
-        cpu.reset();
-        cpu.run();
-
-        //     cpu.run_with_callback(
-        //         move |cpu| {
-        //             println!("{}", trace(cpu));
-        //             // cpu.mem_write(0xfe, rng.gen_range(1, 16));
-        //             // if read_screen_state(cpu, &mut screen_state) {
-        //             //     texture.update(None, &screen_state, 32 * 3).unwrap();
-        //             //     canvas.copy(&texture, None, None).unwrap();
-        //             //     canvas.present();
-        //         }, // ::std::thread::sleep(std::time::Duration::new(0, 70_000));
-        //     );
+        std::fs::write(out.join("report.csv"), compat::to_csv(&entries)).unwrap();
+        std::fs::write(out.join("report.html"), compat::to_html(&entries)).unwrap();
+        for (name, count) in compat::totals(&entries) {
+            println!("{:>5} {}", count, name);
+        }
+        println!("wrote {}", out.join("report.html").display());
+    } else if args.get(1).unwrap() == "run" {
+        let usage = "usage: rnes run [--headless --frames N [--input script.txt] \
+                     [--out-frame out.png|out.ppm] [--out-ram ram.bin] [--out-hashes hashes.txt]] <rom>";
+        let mut headless = false;
+        let mut frames = 0;
+        let mut script = InputScript::default();
+        let (mut out_frame, mut out_ram, mut out_hashes) = (None, None, None);
+        let mut rom_name = None;
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            let mut value = || options.next().expect(usage);
+            match option.as_str() {
+                "--headless" => headless = true,
+                "--frames" => frames = value().parse().expect(usage),
+                "--input" => {
+                    script = InputScript::load(Path::new(value())).unwrap_or_else(|e| {
+                        eprintln!("{}", e);
+                        std::process::exit(2);
+                    })
+                }
+                "--out-frame" => out_frame = Some(value()),
+                "--out-ram" => out_ram = Some(value()),
+                "--out-hashes" => out_hashes = Some(value()),
+                rom if !rom.starts_with("--") && rom_name.is_none() => rom_name = Some(rom),
+                _ => panic!("{}", usage),
+            }
+        }
+        let rom_name = rom_name.expect(usage);
+        if !headless {
+            #[cfg(feature = "sdl")]
+            window::play(&[args[0].clone(), rom_name.to_string()], policy, levels);
+            #[cfg(not(feature = "sdl"))]
+            without_sdl();
+        } else {
+            // CI wants an exit code and a message, not a panic
+            let fail = |what: &str, e: &dyn std::fmt::Display| -> ! {
+                eprintln!("{}: {}", what, e);
+                std::process::exit(2);
+            };
+            let bytes: Vec<u8> = std::fs::read(rom_name).unwrap_or_else(|e| fail(rom_name, &e));
+            let rom = Rom::new(&bytes).unwrap_or_else(|e| fail(rom_name, &e));
+            let mut nes = Nes::new(rom);
+            nes.bus_mut().set_error_policy(policy);
+            nes.bus().logger().set_levels(levels);
+            let checksums = headless::run(&mut nes, frames, &script);
+            if let Some(error) = nes.error() {
+                eprintln!("stopped after {} frames: {}", checksums.len(), error);
+            } else if checksums.len() < frames {
+                eprintln!(
+                    "CPU halted at {:04X} after {} frames",
+                    nes.cpu().pc,
+                    checksums.len()
+                );
+            }
+            let log = headless::checksum_log(&checksums);
+            match out_hashes {
+                Some(path) => std::fs::write(path, log).unwrap_or_else(|e| fail(path, &e)),
+                None => print!("{}", log),
+            }
+            if let Some(path) = out_frame {
+                image::save(nes.frame(), Path::new(path)).unwrap_or_else(|e| fail(path, &e));
+            }
+            if let Some(path) = out_ram {
+                let (_, ram) = nes.bus().ram_regions()[0];
+                std::fs::write(path, ram).unwrap_or_else(|e| fail(path, &e));
+            }
+            if checksums.len() < frames {
+                std::process::exit(1);
+            }
+        }
+    } else {
+        #[cfg(feature = "sdl")]
+        window::play(&args, policy, levels);
+        #[cfg(not(feature = "sdl"))]
+        without_sdl();
     }
 }
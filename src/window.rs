@@ -0,0 +1,188 @@
+use std::fs::File;
+use std::io::{BufWriter, StdinLock, Stdout};
+use std::net::TcpStream;
+use std::path::Path;
+
+use rnes::comp::cdl::CodeDataLog;
+use rnes::comp::cheats::Cheats;
+use rnes::comp::debugger::Debugger;
+use rnes::comp::error::ErrorPolicy;
+use rnes::comp::frontend::sdl::{self, SdlFrontend};
+use rnes::comp::frontend::{Event, FrameSync, Frontend, VideoSink};
+use rnes::comp::gdbstub::{self, GdbStub};
+use rnes::comp::log::Levels;
+use rnes::comp::profiler::Profiler;
+use rnes::comp::symbols::Symbols;
+use rnes::comp::tracefile::TraceWriter;
+use rnes::{Bus, Nes, Rom};
+
+// Playing in the SDL window, optionally with a debugging tool attached.
+// Only built with the `sdl` feature.
+
+/// What runs alongside the game in the window.
+enum Tool {
+    None,
+    Debugger(Debugger<StdinLock<'static>, Stdout>),
+    Gdb(GdbStub<TcpStream>),
+    Trace(TraceWriter<BufWriter<File>>),
+    Profiler(Profiler),
+}
+
+pub fn play(args: &[String], policy: ErrorPolicy, levels: Levels) {
+    // `--debug <rom>` starts paused in the debugger, F12 breaks into it while running
+    let debug = args.get(1).unwrap() == "--debug";
+    // `--gdb <port> <rom>` waits for a gdb client on localhost before running
+    let gdb_port = (args.get(1).unwrap() == "--gdb").then(|| {
+        args.get(2)
+            .and_then(|port| port.parse::<u16>().ok())
+            .expect("usage: rnes --gdb <port> <rom>")
+    });
+    // `--cdl <rom>` records game.cdl (FCEUX format) while playing, saved on quit
+    let record_cdl = args.get(1).unwrap() == "--cdl";
+    // `--trace <out.trace> <rom>` records a binary trace of every instruction
+    let trace_path = (args.get(1).unwrap() == "--trace")
+        .then(|| args.get(2).expect("usage: rnes --trace <out.trace> <rom>"));
+    // `--profile <rom>` writes game.profile.txt and game.folded (flamegraph) on quit
+    let profile = args.get(1).unwrap() == "--profile";
+    let rom_index = match (debug || record_cdl || profile, gdb_port, trace_path) {
+        (true, _, _) => 2,
+        (_, Some(_), _) | (_, _, Some(_)) => 3,
+        _ => 1,
+    };
+    let rom_name = args
+        .get(rom_index)
+        .expect("usage: rnes [--debug | --gdb <port> | --cdl | --profile | --trace <out>] <rom>");
+    let rom_path = Path::new(rom_name);
+    let bytes: Vec<u8> = std::fs::read(rom_name).unwrap();
+    let rom = Rom::new(&bytes).unwrap();
+
+    let cdl_path = rom_path.with_extension("cdl");
+    let cdl = record_cdl.then(|| {
+        CodeDataLog::load_or_new(&cdl_path, rom.prg_rom.len(), rom.chr_rom.len()).unwrap()
+    });
+    // game.cht next to the ROM, F9 switches all cheats on and off
+    let cheats = Cheats::load(&rom_path.with_extension("cht")).unwrap_or_else(|e| {
+        eprintln!("ignoring cheats: {}", e);
+        Cheats::new()
+    });
+    let mut bus = Bus::new(rom, |_, _| {});
+    if let Some(cdl) = cdl {
+        bus.set_cdl(cdl);
+    }
+    bus.set_cheats(cheats);
+    bus.set_error_policy(policy);
+    bus.logger().set_levels(levels);
+    let mut nes = Nes::with_bus(bus);
+
+    let (canvas, event_pump) = sdl::open_window(3.0).unwrap();
+    let creator = canvas.texture_creator();
+    let mut frontend = SdlFrontend::new(canvas, event_pump, &creator).unwrap();
+
+    // game.dbg / game.mlb / game.nes.*.nl next to the ROM
+    let symbols = || match Symbols::load_for_rom(rom_path) {
+        Ok(symbols) => symbols,
+        Err(e) => {
+            eprintln!("ignoring symbols: {}", e);
+            Symbols::new()
+        }
+    };
+    let mut tool = if let Some(port) = gdb_port {
+        Tool::Gdb(gdbstub::listen(port).unwrap())
+    } else if debug {
+        let mut debugger = Debugger::new(std::io::stdin().lock(), std::io::stdout());
+        debugger.set_symbols(symbols());
+        if let Err(e) = debugger.set_watch_file(&rom_path.with_extension("watch")) {
+            eprintln!("ignoring watch list: {}", e);
+        }
+        Tool::Debugger(debugger)
+    } else if let Some(trace_path) = trace_path {
+        let file = File::create(trace_path).unwrap();
+        Tool::Trace(TraceWriter::new(BufWriter::new(file)).unwrap())
+    } else if profile {
+        Tool::Profiler(Profiler::new(symbols()))
+    } else {
+        Tool::None
+    };
+
+    let mut sync = FrameSync::new();
+    let mut quit = false;
+    while !quit {
+        let running = match &mut tool {
+            Tool::Profiler(profiler) => nes.step_with(|cpu| profiler.step(cpu)),
+            tool => nes.step_with(|cpu| {
+                cpu.poll_interrupts();
+                let keep_going = match tool {
+                    Tool::Debugger(debugger) => debugger.on_instruction(cpu),
+                    Tool::Gdb(stub) => stub.on_instruction(cpu),
+                    Tool::Trace(writer) => writer.record(cpu).is_ok(),
+                    _ => true,
+                };
+                keep_going && cpu.step()
+            }),
+        };
+        match running {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(error) => {
+                eprintln!("stopped at ${:04X}: {}", nes.cpu().pc, error);
+                break;
+            }
+        }
+        let Some(events) = sync.update(&mut nes, &mut frontend) else {
+            continue;
+        };
+        if let Tool::Debugger(debugger) = &tool {
+            // live watch list values go in the window title
+            frontend.set_status(&debugger.watch_status().borrow());
+        }
+        for event in events {
+            match event {
+                Event::Quit => quit = true,
+                Event::Break => {
+                    if let Tool::Debugger(debugger) = &tool {
+                        debugger.break_request().set(true);
+                    }
+                }
+                Event::ToggleCheats => {
+                    let switch = nes.bus().cheats().switch();
+                    switch.set(!switch.get());
+                    println!("cheats {}", if switch.get() { "on" } else { "off" });
+                }
+            }
+        }
+    }
+
+    // stopped by the user, BRK or an error: write out everything recorded so far
+    match tool {
+        Tool::Gdb(mut stub) => stub.on_exit(),
+        Tool::Trace(mut writer) => {
+            if let Some(path) = trace_path {
+                report_save(Path::new(path), writer.flush());
+            }
+        }
+        Tool::Profiler(profiler) => {
+            let report = profiler.report(20);
+            print!("{}", report);
+            let path = rom_path.with_extension("profile.txt");
+            report_save(&path, std::fs::write(&path, report));
+            let path = rom_path.with_extension("folded");
+            report_save(&path, std::fs::write(&path, profiler.collapsed_stacks()));
+        }
+        Tool::Debugger(_) | Tool::None => {}
+    }
+    if let Some(cdl) = nes.bus().cdl()
+        && report_save(&cdl_path, cdl.save(&cdl_path))
+    {
+        println!("wrote {}", cdl_path.display());
+    }
+    frontend.on_exit();
+}
+
+// A file that can't be written at shutdown is reported and skipped, so the
+// rest still get saved. Returns whether it was written.
+fn report_save(path: &Path, result: std::io::Result<()>) -> bool {
+    if let Err(e) = &result {
+        eprintln!("could not write {}: {}", path.display(), e);
+    }
+    result.is_ok()
+}